@@ -0,0 +1,335 @@
+// ========== Batch conversion ==========
+//
+// Converts every PDF under a directory into a mirrored output tree. Inputs
+// are picked by a glob; an output newer than its input is left alone, and a
+// file that fails is recorded while the others are still converted. The
+// report lists every file with its outcome, time and warnings, printable
+// for the console and as JSON.
+
+use crate::render::RenderStats;
+use crate::{Error, Result};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+#[derive(Clone, Debug)]
+pub struct BatchOptions {
+    /// Glob for the inputs, see [`glob_match`]
+    pub pattern: String,
+    /// Also convert the files in subdirectories
+    pub recursive: bool,
+    /// Convert even when the output is newer than the input
+    pub force: bool,
+}
+
+impl Default for BatchOptions {
+    fn default() -> Self {
+        BatchOptions { pattern: "*.pdf".to_string(), recursive: false, force: false }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FileStatus {
+    Converted,
+    /// The output was newer than the input and kept
+    UpToDate,
+    Failed(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct FileResult {
+    /// Input and output path, both relative to their directory
+    pub path: PathBuf,
+    pub status: FileStatus,
+    pub seconds: f64,
+    /// Pages written, 0 unless converted
+    pub pages: usize,
+    pub warnings: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct BatchReport {
+    pub files: Vec<FileResult>,
+    pub seconds: f64,
+}
+
+impl BatchReport {
+    /// Number of files with a status like `status`; failures count regardless of their message.
+    pub fn count(&self, status: &FileStatus) -> usize {
+        self.files.iter().filter(|file| std::mem::discriminant(&file.status) == std::mem::discriminant(status)).count()
+    }
+
+    pub fn failed(&self) -> usize {
+        self.count(&FileStatus::Failed(String::new()))
+    }
+
+    /// Counts and total time in one line.
+    pub fn summary(&self) -> String {
+        format!(
+            "{} converted, {} up to date, {} failed in {:.2} s",
+            self.count(&FileStatus::Converted),
+            self.count(&FileStatus::UpToDate),
+            self.failed(),
+            self.seconds
+        )
+    }
+
+    /// The report as a JSON document.
+    pub fn to_json(&self) -> String {
+        let files: Vec<String> = self
+            .files
+            .iter()
+            .map(|file| {
+                let (status, error) = match &file.status {
+                    FileStatus::Converted => ("converted", "null".to_string()),
+                    FileStatus::UpToDate => ("up-to-date", "null".to_string()),
+                    FileStatus::Failed(error) => ("failed", json_string(error)),
+                };
+                let warnings: Vec<String> = file.warnings.iter().map(|w| json_string(w)).collect();
+                format!(
+                    "    {{\"path\": {}, \"status\": \"{}\", \"error\": {}, \"seconds\": {:.3}, \"pages\": {}, \"warnings\": [{}]}}",
+                    json_string(&file.path.to_string_lossy()),
+                    status,
+                    error,
+                    file.seconds,
+                    file.pages,
+                    warnings.join(", ")
+                )
+            })
+            .collect();
+        format!(
+            "{{\n  \"converted\": {},\n  \"up_to_date\": {},\n  \"failed\": {},\n  \"seconds\": {:.3},\n  \"files\": [\n{}\n  ]\n}}\n",
+            self.count(&FileStatus::Converted),
+            self.count(&FileStatus::UpToDate),
+            self.failed(),
+            self.seconds,
+            files.join(",\n")
+        )
+    }
+}
+
+impl fmt::Display for FileResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.status {
+            FileStatus::Converted => write!(f, "converted   {}  ({} pages, {:.2} s)", self.path.display(), self.pages, self.seconds)?,
+            FileStatus::UpToDate => write!(f, "up to date  {}", self.path.display())?,
+            FileStatus::Failed(error) => write!(f, "FAILED      {}  ({:.2} s): {}", self.path.display(), self.seconds, error)?,
+        }
+        for warning in &self.warnings {
+            write!(f, "\n            warning: {}", warning)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for BatchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for file in &self.files {
+            writeln!(f, "{}", file)?;
+        }
+        writeln!(f, "{}", self.summary())
+    }
+}
+
+/// Convert every input under `input_dir` matching `opts` with `convert(input,
+/// output)` into the same relative path under `output_dir`, calling
+/// `on_file` after each file. Only errors reading the input directory end
+/// the batch; an output directory that is the input directory is refused.
+pub fn convert_dir<E: fmt::Display>(
+    input_dir: &Path,
+    output_dir: &Path,
+    opts: &BatchOptions,
+    mut convert: impl FnMut(&str, &str) -> Result<RenderStats, E>,
+    mut on_file: impl FnMut(&FileResult),
+) -> Result<BatchReport> {
+    let start = Instant::now();
+    if fs::canonicalize(output_dir).ok() == Some(fs::canonicalize(input_dir)?) {
+        return Err(Error::OutputIsInput(output_dir.display().to_string()));
+    }
+    let inputs = find_inputs(input_dir, output_dir, opts)?;
+    log::debug!("{} inputs match {} in {}", inputs.len(), opts.pattern, input_dir.display());
+    let mut report = BatchReport::default();
+    for path in inputs {
+        let (input, output) = (input_dir.join(&path), output_dir.join(&path));
+        let file_start = Instant::now();
+        let mut result = FileResult { path, status: FileStatus::Converted, seconds: 0.0, pages: 0, warnings: Vec::new() };
+        if !opts.force && is_up_to_date(&input, &output) {
+            result.status = FileStatus::UpToDate;
+        } else {
+            match convert_file(&input, &output, &mut convert) {
+                Ok(stats) => {
+                    result.pages = stats.pages;
+                    if !stats.missing.is_empty() {
+                        result.warnings.push(format!("no font can render {}", missing_chars(&stats.missing)));
+                    }
+                }
+                Err(error) => {
+                    // A partial output would otherwise count as up to date next time
+                    let _ = fs::remove_file(&output);
+                    result.status = FileStatus::Failed(error);
+                }
+            }
+        }
+        result.seconds = file_start.elapsed().as_secs_f64();
+        on_file(&result);
+        report.files.push(result);
+    }
+    report.seconds = start.elapsed().as_secs_f64();
+    Ok(report)
+}
+
+fn convert_file<E: fmt::Display>(
+    input: &Path,
+    output: &Path,
+    convert: &mut impl FnMut(&str, &str) -> Result<RenderStats, E>,
+) -> Result<RenderStats, String> {
+    let (Some(input), Some(output)) = (input.to_str(), output.to_str()) else {
+        return Err("path is not valid UTF-8".to_string());
+    };
+    if let Some(dir) = Path::new(output).parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+    }
+    // One line per file; some errors span several
+    convert(input, output).map_err(|e| e.to_string().split_whitespace().collect::<Vec<_>>().join(" "))
+}
+
+/// Characters no font can render, as the console reports them.
+pub fn missing_chars(missing: &BTreeSet<char>) -> String {
+    missing.iter().map(|c| format!("{:?} U+{:04X}", c, *c as u32)).collect::<Vec<_>>().join(", ")
+}
+
+fn is_up_to_date(input: &Path, output: &Path) -> bool {
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+    matches!((modified(input), modified(output)), (Some(input), Some(output)) if output >= input)
+}
+
+/// Paths relative to `dir` of the files matching `opts`, sorted. The output
+/// directory is skipped wherever it lies inside `dir`; symlinked directories
+/// are not followed.
+pub fn find_inputs(dir: &Path, output_dir: &Path, opts: &BatchOptions) -> Result<Vec<PathBuf>> {
+    let skip = fs::canonicalize(output_dir).ok();
+    let mut found = Vec::new();
+    let mut pending = vec![PathBuf::new()];
+    while let Some(relative) = pending.pop() {
+        let mut entries = fs::read_dir(dir.join(&relative))?.collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let path = relative.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                if opts.recursive && fs::canonicalize(entry.path()).ok() != skip {
+                    pending.push(path);
+                }
+            } else if entry.path().is_file() && glob_match(&opts.pattern, &path) {
+                found.push(path);
+            }
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+/// Match `path` against `pattern`, ignoring ASCII case. `*` and `?` match
+/// within one path component and `**` across them; a pattern without `/`
+/// is matched against the file name alone.
+pub fn glob_match(pattern: &str, path: &Path) -> bool {
+    let path = path.to_string_lossy().replace('\\', "/");
+    let subject = match pattern.contains('/') {
+        true => path.as_str(),
+        false => path.rsplit('/').next().unwrap_or_default(),
+    };
+    let (pattern, subject): (Vec<char>, Vec<char>) = (pattern.to_ascii_lowercase().chars().collect(), subject.to_ascii_lowercase().chars().collect());
+    matches_from(&pattern, &subject)
+}
+
+fn matches_from(pattern: &[char], subject: &[char]) -> bool {
+    match pattern {
+        [] => subject.is_empty(),
+        ['*', '*', '/', rest @ ..] => (0..=subject.len()).any(|i| (i == 0 || subject[i - 1] == '/') && matches_from(rest, &subject[i..])),
+        ['*', '*', rest @ ..] => (0..=subject.len()).any(|i| matches_from(rest, &subject[i..])),
+        ['*', rest @ ..] => (0..=subject.len()).take_while(|&i| i == 0 || subject[i - 1] != '/').any(|i| matches_from(rest, &subject[i..])),
+        ['?', rest @ ..] => subject.first().is_some_and(|&c| c != '/') && matches_from(rest, &subject[1..]),
+        [c, rest @ ..] => subject.first() == Some(c) && matches_from(rest, &subject[1..]),
+    }
+}
+
+/// `s` as a JSON string literal.
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, path: &str) -> bool {
+        glob_match(pattern, Path::new(path))
+    }
+
+    #[test]
+    fn star_and_question_mark_stay_within_a_name() {
+        assert!(matches("*.pdf", "paper.pdf"));
+        assert!(matches("*.pdf", "sub/dir/paper.pdf"));
+        assert!(!matches("*.pdf", "paper.pdf.bak"));
+        assert!(matches("paper-?.pdf", "paper-1.pdf"));
+        assert!(!matches("paper-?.pdf", "paper-10.pdf"));
+        assert!(matches("sub/*.pdf", "sub/a.pdf"));
+        assert!(!matches("sub/*.pdf", "sub/dir/a.pdf"));
+        assert!(!matches("sub?a.pdf", "sub/a.pdf"));
+    }
+
+    #[test]
+    fn double_star_spans_directories() {
+        assert!(matches("**/*.pdf", "a.pdf"));
+        assert!(matches("**/*.pdf", "sub/dir/a.pdf"));
+        assert!(matches("sub/**/a.pdf", "sub/a.pdf"));
+        assert!(matches("sub/**/a.pdf", "sub/x/y/a.pdf"));
+        assert!(!matches("sub/**/a.pdf", "other/a.pdf"));
+        assert!(!matches("**/a.pdf", "suba.pdf"));
+        assert!(matches("sub/**", "sub/x/y.txt"));
+    }
+
+    #[test]
+    fn ignores_ascii_case() {
+        assert!(matches("*.pdf", "PAPER.PDF"));
+        assert!(matches("Sub/**/*.PDF", "sub/x/a.pdf"));
+    }
+
+    #[test]
+    fn refuses_the_input_directory_as_output() {
+        let dir = std::env::temp_dir().join(format!("pdf_convert-batch-{:016x}", rand::random::<u64>()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        let convert = |_: &str, _: &str| -> Result<RenderStats, String> { unreachable!() };
+        let result = convert_dir(&dir, &dir.join("sub/.."), &BatchOptions::default(), convert, |_| {});
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(result, Err(Error::OutputIsInput(_))));
+    }
+
+    #[test]
+    fn skips_the_output_directory_at_any_depth() {
+        let dir = std::env::temp_dir().join(format!("pdf_convert-batch-{:016x}", rand::random::<u64>()));
+        for file in ["a.pdf", "x/b.pdf", "x/out/c.pdf", "x/out/y/d.pdf"] {
+            fs::create_dir_all(dir.join(file).parent().unwrap()).unwrap();
+            fs::write(dir.join(file), b"").unwrap();
+        }
+        let opts = BatchOptions { recursive: true, ..Default::default() };
+        let found = find_inputs(&dir, &dir.join("x/out"), &opts);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(found.unwrap(), [PathBuf::from("a.pdf"), PathBuf::from("x/b.pdf")]);
+    }
+}
@@ -0,0 +1,238 @@
+// ========== Visual regression comparison ==========
+//
+// Rasterizes both documents with pdfium and compares them page by page,
+// holding only the current page of each in memory. Metrics are computed on the grayscale image: PSNR over all pixels and a
+// mean SSIM over 8x8 windows. Differing pixels are highlighted in a PNG.
+
+use crate::extract::load_document;
+use crate::{load_pdfium, OpenOptions, Result};
+use image::{GrayImage, Rgba, RgbaImage};
+use pdfium_render::prelude::*;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+const SSIM_WINDOW: u32 = 8;
+
+#[derive(Clone, Debug)]
+pub struct CompareOptions {
+    pub dpi: f32,
+    /// Per-pixel gray difference (0-255) above which a pixel counts as changed.
+    pub threshold: u8,
+    /// Directory for highlighted diff PNGs; nothing is written when unset.
+    pub diff_dir: Option<PathBuf>,
+    pub min_psnr: Option<f64>,
+    pub min_ssim: Option<f64>,
+    /// Password and libpdfium for both documents; they are always rendered
+    /// with pdfium, and every page is compared.
+    pub open: OpenOptions,
+}
+
+impl Default for CompareOptions {
+    fn default() -> Self {
+        CompareOptions { dpi: 96.0, threshold: 16, diff_dir: None, min_psnr: None, min_ssim: None, open: OpenOptions::default() }
+    }
+}
+
+pub struct PageComparison {
+    pub page: usize,
+    pub psnr: f64,
+    pub ssim: f64,
+    pub changed_pixels: usize,
+    pub total_pixels: usize,
+    pub diff_image: Option<PathBuf>,
+    pub passed: bool,
+}
+
+pub struct CompareReport {
+    pub pages: Vec<PageComparison>,
+    pub page_counts: (usize, usize),
+}
+
+impl CompareReport {
+    pub fn passed(&self) -> bool {
+        self.page_counts.0 == self.page_counts.1 && self.pages.iter().all(|p| p.passed)
+    }
+}
+
+impl fmt::Display for CompareReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.page_counts.0 != self.page_counts.1 {
+            writeln!(f, "page count differs: {} vs {}", self.page_counts.0, self.page_counts.1)?;
+        }
+        for p in &self.pages {
+            let psnr = if p.psnr.is_infinite() { "inf".to_string() } else { format!("{:.2} dB", p.psnr) };
+            write!(
+                f,
+                "page {:>3}  {}  PSNR {:>9}  SSIM {:.4}  changed {:.3}%",
+                p.page,
+                if p.passed { "ok  " } else { "FAIL" },
+                psnr,
+                p.ssim,
+                p.changed_pixels as f64 * 100.0 / p.total_pixels.max(1) as f64
+            )?;
+            if let Some(path) = &p.diff_image {
+                write!(f, "  -> {}", path.display())?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn compare_files(left: &str, right: &str, opts: &CompareOptions) -> Result<CompareReport> {
+    let pdfium = load_pdfium(opts.open.pdfium_lib.as_deref())?;
+    let password = opts.open.password.as_deref();
+    let (a, b) = (load_document(&pdfium, left, password)?, load_document(&pdfium, right, password)?);
+    if let Some(dir) = &opts.diff_dir {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let config = PdfRenderConfig::new().scale_page_by_factor(opts.dpi / 72.0);
+    let mut pages = Vec::new();
+    for (index, (pa, pb)) in a.pages().iter().zip(b.pages().iter()).enumerate() {
+        let page = index + 1;
+        let (ga, gb) = pad_to_same_size(render(&pa, &config)?, render(&pb, &config)?);
+        let psnr = psnr(&ga, &gb);
+        let ssim = mean_ssim(&ga, &gb);
+        let (highlight, changed_pixels) = highlight_differences(&ga, &gb, opts.threshold);
+        let passed = opts.min_psnr.is_none_or(|min| psnr >= min) && opts.min_ssim.is_none_or(|min| ssim >= min);
+        let diff_image = match &opts.diff_dir {
+            Some(dir) if changed_pixels > 0 => {
+                let path = diff_image_path(dir, page);
+                highlight.save(&path)?;
+                Some(path)
+            }
+            _ => None,
+        };
+        pages.push(PageComparison {
+            page,
+            psnr,
+            ssim,
+            changed_pixels,
+            total_pixels: (ga.width() * ga.height()) as usize,
+            diff_image,
+            passed,
+        });
+    }
+    Ok(CompareReport { pages, page_counts: (a.pages().len() as usize, b.pages().len() as usize) })
+}
+
+fn diff_image_path(dir: &Path, page: usize) -> PathBuf {
+    dir.join(format!("page-{:04}.png", page))
+}
+
+/// One page in grayscale.
+fn render(page: &PdfPage, config: &PdfRenderConfig) -> Result<GrayImage> {
+    Ok(page.render_with_config(config)?.as_image().to_luma8())
+}
+
+/// Pad the smaller image with white so both have the same dimensions.
+fn pad_to_same_size(a: GrayImage, b: GrayImage) -> (GrayImage, GrayImage) {
+    if a.dimensions() == b.dimensions() {
+        return (a, b);
+    }
+    let (w, h) = (a.width().max(b.width()), a.height().max(b.height()));
+    let pad = |img: GrayImage| {
+        let mut out = GrayImage::from_pixel(w, h, image::Luma([255]));
+        image::imageops::replace(&mut out, &img, 0, 0);
+        out
+    };
+    (pad(a), pad(b))
+}
+
+fn psnr(a: &GrayImage, b: &GrayImage) -> f64 {
+    let n = a.as_raw().len().max(1) as f64;
+    let mse = a.as_raw().iter().zip(b.as_raw()).map(|(&x, &y)| (x as f64 - y as f64).powi(2)).sum::<f64>() / n;
+    if mse == 0.0 { f64::INFINITY } else { 10.0 * (255.0 * 255.0 / mse).log10() }
+}
+
+/// SSIM averaged over non-overlapping windows (no Gaussian weighting).
+fn mean_ssim(a: &GrayImage, b: &GrayImage) -> f64 {
+    let c1 = (0.01f64 * 255.0).powi(2);
+    let c2 = (0.03f64 * 255.0).powi(2);
+    let (w, h) = a.dimensions();
+    let (mut total, mut windows) = (0.0, 0usize);
+    for wy in (0..h).step_by(SSIM_WINDOW as usize) {
+        for wx in (0..w).step_by(SSIM_WINDOW as usize) {
+            let (mut sa, mut sb, mut saa, mut sbb, mut sab, mut n) = (0.0, 0.0, 0.0, 0.0, 0.0, 0.0);
+            for y in wy..(wy + SSIM_WINDOW).min(h) {
+                for x in wx..(wx + SSIM_WINDOW).min(w) {
+                    let pa = a.get_pixel(x, y)[0] as f64;
+                    let pb = b.get_pixel(x, y)[0] as f64;
+                    sa += pa;
+                    sb += pb;
+                    saa += pa * pa;
+                    sbb += pb * pb;
+                    sab += pa * pb;
+                    n += 1.0;
+                }
+            }
+            let (ma, mb) = (sa / n, sb / n);
+            let va = saa / n - ma * ma;
+            let vb = sbb / n - mb * mb;
+            let cov = sab / n - ma * mb;
+            total += ((2.0 * ma * mb + c1) * (2.0 * cov + c2)) / ((ma * ma + mb * mb + c1) * (va + vb + c2));
+            windows += 1;
+        }
+    }
+    if windows == 0 { 1.0 } else { total / windows as f64 }
+}
+
+/// Faded copy of `b` with pixels that differ from `a` painted red.
+fn highlight_differences(a: &GrayImage, b: &GrayImage, threshold: u8) -> (RgbaImage, usize) {
+    let mut changed = 0;
+    let out = RgbaImage::from_fn(a.width(), a.height(), |x, y| {
+        let pa = a.get_pixel(x, y)[0];
+        let pb = b.get_pixel(x, y)[0];
+        if pa.abs_diff(pb) > threshold {
+            changed += 1;
+            Rgba([255, 0, 0, 255])
+        } else {
+            let faded = 255 - (255 - pb) / 4;
+            Rgba([faded, faded, faded, 255])
+        }
+    });
+    (out, changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A white image with a black square of `size` at the top left.
+    fn square(size: u32) -> GrayImage {
+        GrayImage::from_fn(32, 32, |x, y| image::Luma([if x < size && y < size { 0 } else { 255 }]))
+    }
+
+    #[test]
+    fn identical_images_score_perfectly() {
+        let a = square(8);
+        assert!(psnr(&a, &a).is_infinite());
+        assert_eq!(mean_ssim(&a, &a), 1.0);
+        assert_eq!(highlight_differences(&a, &a, 16).1, 0);
+    }
+
+    #[test]
+    fn measures_changed_pixels() {
+        let (a, b) = (square(8), square(16));
+        // 192 of 1024 pixels differ by 255: MSE 255² · 3/16
+        assert!((psnr(&a, &b) - 10.0 * (16.0f64 / 3.0).log10()).abs() < 1e-9);
+        // Three of the 16 windows turn from white to black, the others are unchanged
+        let ssim = mean_ssim(&a, &b);
+        assert!(ssim > 13.0 / 16.0 - 0.01 && ssim < 1.0, "{}", ssim);
+        let (highlight, changed) = highlight_differences(&a, &b, 16);
+        assert_eq!(changed, 192);
+        assert_eq!(highlight.get_pixel(12, 12), &Rgba([255, 0, 0, 255]));
+        assert_eq!(highlight.get_pixel(0, 0), &Rgba([192, 192, 192, 255]));
+        // A slight change stays below the threshold
+        let lighter = GrayImage::from_fn(32, 32, |x, y| image::Luma([a.get_pixel(x, y)[0].max(10)]));
+        assert_eq!(highlight_differences(&a, &lighter, 16).1, 0);
+    }
+
+    #[test]
+    fn pads_with_white() {
+        let (a, b) = pad_to_same_size(GrayImage::new(4, 6), GrayImage::new(5, 3));
+        assert_eq!((a.dimensions(), b.dimensions()), ((5, 6), (5, 6)));
+        assert_eq!((a.get_pixel(4, 0)[0], b.get_pixel(0, 5)[0], b.get_pixel(0, 0)[0]), (255, 255, 0));
+    }
+}
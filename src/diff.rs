@@ -0,0 +1,429 @@
+// ========== Structural PDF diff ==========
+//
+// Compares two PDFs after resolving references, so object numbers never show
+// up in the report. Each page is compared by its tree attributes, resources,
+// fonts and the parsed content-stream operations.
+
+use crate::Result;
+use lopdf::content::{Content, Operation};
+use lopdf::{Dictionary, Document as LoDoc, Object, ObjectId};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+/// Beyond this many LCS cells the operation diff falls back to a positional comparison.
+const MAX_LCS_CELLS: usize = 4_000_000;
+/// Depth limit when following references, guards against cyclic structures.
+const MAX_DEPTH: usize = 12;
+
+#[derive(Clone, Debug)]
+pub struct DiffOptions {
+    /// Maximum number of operation lines reported per page.
+    pub max_ops: usize,
+    /// Skip the content-stream comparison.
+    pub skip_content: bool,
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        DiffOptions { max_ops: 50, skip_content: false }
+    }
+}
+
+pub struct DiffSection {
+    pub title: String,
+    pub entries: Vec<String>,
+}
+
+#[derive(Default)]
+pub struct DiffReport {
+    pub sections: Vec<DiffSection>,
+}
+
+impl DiffReport {
+    pub fn is_empty(&self) -> bool {
+        self.sections.iter().all(|s| s.entries.is_empty())
+    }
+
+    pub fn difference_count(&self) -> usize {
+        self.sections.iter().map(|s| s.entries.len()).sum()
+    }
+
+    fn push(&mut self, title: impl Into<String>, entries: Vec<String>) {
+        if !entries.is_empty() {
+            self.sections.push(DiffSection { title: title.into(), entries });
+        }
+    }
+}
+
+impl fmt::Display for DiffReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "No structural differences.");
+        }
+        for section in &self.sections {
+            writeln!(f, "== {} ==", section.title)?;
+            for entry in &section.entries {
+                writeln!(f, "  {}", entry)?;
+            }
+        }
+        writeln!(f, "{} difference(s)", self.difference_count())
+    }
+}
+
+pub fn diff_files(left: &str, right: &str, opts: &DiffOptions) -> Result<DiffReport> {
+    let a = LoDoc::load(left)?;
+    let b = LoDoc::load(right)?;
+    Ok(diff_documents(&a, &b, opts))
+}
+
+pub fn diff_documents(a: &LoDoc, b: &LoDoc, opts: &DiffOptions) -> DiffReport {
+    let mut report = DiffReport::default();
+
+    let catalog = |doc: &LoDoc| {
+        let mut map = BTreeMap::new();
+        if let Ok(catalog) = doc.catalog() {
+            flatten_dict(doc, catalog, "", &mut map, 0, &[b"Pages", b"Metadata"]);
+        }
+        map
+    };
+    report.push("Catalog", compare_maps(&catalog(a), &catalog(b)));
+
+    let pages_a: Vec<ObjectId> = a.get_pages().into_values().collect();
+    let pages_b: Vec<ObjectId> = b.get_pages().into_values().collect();
+    let mut tree = Vec::new();
+    if pages_a.len() != pages_b.len() {
+        tree.push(format!("page count: {} vs {}", pages_a.len(), pages_b.len()));
+    }
+    let (depth_a, depth_b) = (page_tree_depth(a), page_tree_depth(b));
+    if depth_a != depth_b {
+        tree.push(format!("page tree depth: {} vs {}", depth_a, depth_b));
+    }
+    report.push("Page tree", tree);
+
+    for (index, (&pa, &pb)) in pages_a.iter().zip(&pages_b).enumerate() {
+        let page = index + 1;
+        report.push(format!("Page {} attributes", page), compare_maps(&page_attributes(a, pa), &page_attributes(b, pb)));
+        report.push(format!("Page {} resources", page), compare_maps(&page_resources(a, pa), &page_resources(b, pb)));
+        report.push(format!("Page {} fonts", page), compare_sets(&page_fonts(a, pa), &page_fonts(b, pb)));
+        if !opts.skip_content {
+            let ops_a = page_operations(a, pa);
+            let ops_b = page_operations(b, pb);
+            report.push(format!("Page {} content", page), diff_lines(&ops_a, &ops_b, opts.max_ops));
+        }
+    }
+    report
+}
+
+/// Strip the six-letter subset tag (`ABCDEF+`) from a font name.
+pub fn strip_subset_tag(name: &str) -> &str {
+    match name.split_once('+') {
+        Some((tag, rest)) if tag.len() == 6 && tag.bytes().all(|c| c.is_ascii_uppercase()) => rest,
+        _ => name,
+    }
+}
+
+fn flatten_dict(doc: &LoDoc, dict: &Dictionary, prefix: &str, out: &mut BTreeMap<String, String>, depth: usize, skip: &[&[u8]]) {
+    for (key, value) in dict.iter() {
+        // Parent links are back-references; Length and filters only describe the encoding
+        if skip.contains(&key.as_slice()) || [&b"Parent"[..], b"Length", b"Filter", b"DecodeParms"].contains(&key.as_slice()) {
+            continue;
+        }
+        let path = format!("{}/{}", prefix, String::from_utf8_lossy(key));
+        flatten(doc, value, &path, out, depth + 1, skip);
+    }
+}
+
+fn flatten(doc: &LoDoc, obj: &Object, path: &str, out: &mut BTreeMap<String, String>, depth: usize, skip: &[&[u8]]) {
+    if depth > MAX_DEPTH {
+        out.insert(path.to_string(), "<too deep>".to_string());
+        return;
+    }
+    match obj {
+        Object::Reference(id) => match doc.get_object(*id) {
+            Ok(target) => flatten(doc, target, path, out, depth + 1, skip),
+            Err(_) => {
+                out.insert(path.to_string(), "<missing object>".to_string());
+            }
+        },
+        Object::Dictionary(dict) => flatten_dict(doc, dict, path, out, depth, skip),
+        Object::Stream(stream) => {
+            flatten_dict(doc, &stream.dict, path, out, depth, skip);
+            let len = stream.decompressed_content().map(|c| c.len()).unwrap_or(stream.content.len());
+            out.insert(format!("{}#stream", path), format!("{} bytes", len));
+        }
+        Object::Array(items) => {
+            out.insert(format!("{}#len", path), items.len().to_string());
+            for (i, item) in items.iter().enumerate() {
+                flatten(doc, item, &format!("{}[{}]", path, i), out, depth + 1, skip);
+            }
+        }
+        Object::Name(name) => {
+            let name = String::from_utf8_lossy(name);
+            let name = if path.ends_with("/BaseFont") || path.ends_with("/FontName") { strip_subset_tag(&name) } else { &name };
+            out.insert(path.to_string(), format!("/{}", name));
+        }
+        Object::String(bytes, _) => {
+            out.insert(path.to_string(), format!("({})", String::from_utf8_lossy(bytes)));
+        }
+        other => {
+            out.insert(path.to_string(), scalar(other));
+        }
+    }
+}
+
+fn scalar(obj: &Object) -> String {
+    match obj {
+        Object::Null => "null".to_string(),
+        Object::Boolean(b) => b.to_string(),
+        Object::Integer(i) => i.to_string(),
+        Object::Real(r) => format!("{}", (*r * 10000.0).round() / 10000.0),
+        _ => format!("{:?}", obj),
+    }
+}
+
+fn compare_maps(a: &BTreeMap<String, String>, b: &BTreeMap<String, String>) -> Vec<String> {
+    let mut entries = Vec::new();
+    let keys: BTreeSet<&String> = a.keys().chain(b.keys()).collect();
+    for key in keys {
+        match (a.get(key), b.get(key)) {
+            (Some(x), Some(y)) if x != y => entries.push(format!("~ {}: {} vs {}", key, x, y)),
+            (Some(x), None) => entries.push(format!("- {}: {}", key, x)),
+            (None, Some(y)) => entries.push(format!("+ {}: {}", key, y)),
+            _ => {}
+        }
+    }
+    entries
+}
+
+fn compare_sets(a: &BTreeSet<String>, b: &BTreeSet<String>) -> Vec<String> {
+    let mut entries: Vec<String> = a.difference(b).map(|f| format!("- {}", f)).collect();
+    entries.extend(b.difference(a).map(|f| format!("+ {}", f)));
+    entries
+}
+
+fn page_tree_depth(doc: &LoDoc) -> usize {
+    fn depth(doc: &LoDoc, id: ObjectId, level: usize) -> usize {
+        if level > MAX_DEPTH {
+            return level;
+        }
+        let kids = doc.get_dictionary(id).ok().and_then(|d| d.get(b"Kids").ok()).and_then(|k| k.as_array().ok());
+        match kids {
+            Some(kids) => kids.iter().filter_map(|k| k.as_reference().ok()).map(|k| depth(doc, k, level + 1)).max().unwrap_or(level + 1),
+            None => level,
+        }
+    }
+    doc.catalog()
+        .ok()
+        .and_then(|c| c.get(b"Pages").ok())
+        .and_then(|p| p.as_reference().ok())
+        .map(|root| depth(doc, root, 0))
+        .unwrap_or(0)
+}
+
+fn page_attributes(doc: &LoDoc, page_id: ObjectId) -> BTreeMap<String, String> {
+    let mut map = BTreeMap::new();
+    if let Ok(page) = doc.get_dictionary(page_id) {
+        flatten_dict(doc, page, "", &mut map, 0, &[b"Contents", b"Resources", b"Annots"]);
+        if let Ok(annots) = page.get(b"Annots").and_then(|a| doc.dereference(a)).and_then(|(_, a)| a.as_array()) {
+            map.insert("/Annots#len".to_string(), annots.len().to_string());
+        }
+    }
+    map
+}
+
+fn page_resources(doc: &LoDoc, page_id: ObjectId) -> BTreeMap<String, String> {
+    let mut map = BTreeMap::new();
+    if let Ok((own, inherited)) = doc.get_page_resources(page_id) {
+        let mut dicts: Vec<&Dictionary> = inherited.iter().filter_map(|id| doc.get_dictionary(*id).ok()).collect();
+        dicts.extend(own);
+        for dict in dicts {
+            // Fonts are compared separately by name, not by resource key
+            flatten_dict(doc, dict, "", &mut map, 0, &[b"Font"]);
+        }
+    }
+    map
+}
+
+fn page_fonts(doc: &LoDoc, page_id: ObjectId) -> BTreeSet<String> {
+    let mut fonts = BTreeSet::new();
+    if let Ok(map) = doc.get_page_fonts(page_id) {
+        for font in map.values() {
+            let name = |key: &[u8]| font.get(key).and_then(|n| n.as_name()).map(|n| String::from_utf8_lossy(n).into_owned()).unwrap_or_default();
+            let base = name(b"BaseFont");
+            let encoding = name(b"Encoding");
+            let embedded = font_is_embedded(doc, font);
+            fonts.insert(format!(
+                "{} ({}{}{})",
+                strip_subset_tag(&base),
+                name(b"Subtype"),
+                if encoding.is_empty() { String::new() } else { format!(", {}", encoding) },
+                if embedded { ", embedded" } else { "" }
+            ));
+        }
+    }
+    fonts
+}
+
+fn font_is_embedded(doc: &LoDoc, font: &Dictionary) -> bool {
+    let descriptor = |dict: &Dictionary| {
+        dict.get(b"FontDescriptor")
+            .and_then(|d| doc.dereference(d))
+            .and_then(|(_, d)| d.as_dict())
+            .map(|d| d.has(b"FontFile") || d.has(b"FontFile2") || d.has(b"FontFile3"))
+            .unwrap_or(false)
+    };
+    if descriptor(font) {
+        return true;
+    }
+    font.get(b"DescendantFonts")
+        .and_then(|d| doc.dereference(d))
+        .and_then(|(_, d)| d.as_array())
+        .map(|kids| {
+            kids.iter()
+                .filter_map(|k| doc.dereference(k).ok())
+                .filter_map(|(_, k)| k.as_dict().ok())
+                .any(descriptor)
+        })
+        .unwrap_or(false)
+}
+
+fn page_operations(doc: &LoDoc, page_id: ObjectId) -> Vec<String> {
+    let content = doc.get_page_content(page_id).unwrap_or_default();
+    match Content::decode(&content) {
+        Ok(content) => content.operations.iter().map(format_operation).collect(),
+        Err(e) => vec![format!("<unparsable content: {}>", e)],
+    }
+}
+
+/// Render an operation as one normalized line (numbers rounded, strings hex-encoded).
+pub fn format_operation(op: &Operation) -> String {
+    let mut parts: Vec<String> = op.operands.iter().map(format_operand).collect();
+    parts.push(op.operator.clone());
+    parts.join(" ")
+}
+
+fn format_operand(obj: &Object) -> String {
+    match obj {
+        Object::Name(name) => format!("/{}", String::from_utf8_lossy(name)),
+        Object::String(bytes, _) => format!("<{}>", bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>()),
+        Object::Array(items) => format!("[{}]", items.iter().map(format_operand).collect::<Vec<_>>().join(" ")),
+        Object::Dictionary(dict) => format!(
+            "<<{}>>",
+            dict.iter().map(|(k, v)| format!("/{} {}", String::from_utf8_lossy(k), format_operand(v))).collect::<Vec<_>>().join(" ")
+        ),
+        other => scalar(other),
+    }
+}
+
+/// Line diff based on the longest common subsequence, reported as `-`/`+` lines.
+fn diff_lines(a: &[String], b: &[String], max: usize) -> Vec<String> {
+    let mut entries = Vec::new();
+    let mut total = 0usize;
+    let mut emit = |entry: String| {
+        if total < max {
+            entries.push(entry);
+        }
+        total += 1;
+    };
+
+    if (a.len() + 1) * (b.len() + 1) > MAX_LCS_CELLS {
+        for i in 0..a.len().max(b.len()) {
+            match (a.get(i), b.get(i)) {
+                (Some(x), Some(y)) if x == y => {}
+                (x, y) => {
+                    if let Some(x) = x {
+                        emit(format!("- #{} {}", i + 1, x));
+                    }
+                    if let Some(y) = y {
+                        emit(format!("+ #{} {}", i + 1, y));
+                    }
+                }
+            }
+        }
+    } else {
+        let (n, m) = (a.len(), b.len());
+        let mut lcs = vec![0u32; (n + 1) * (m + 1)];
+        let at = |i: usize, j: usize| i * (m + 1) + j;
+        for i in (0..n).rev() {
+            for j in (0..m).rev() {
+                lcs[at(i, j)] = if a[i] == b[j] { lcs[at(i + 1, j + 1)] + 1 } else { lcs[at(i + 1, j)].max(lcs[at(i, j + 1)]) };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < n || j < m {
+            if i < n && j < m && a[i] == b[j] {
+                i += 1;
+                j += 1;
+            } else if j < m && (i == n || lcs[at(i, j + 1)] >= lcs[at(i + 1, j)]) {
+                emit(format!("+ #{} {}", j + 1, b[j]));
+                j += 1;
+            } else {
+                emit(format!("- #{} {}", i + 1, a[i]));
+                i += 1;
+            }
+        }
+    }
+
+    if total > max {
+        entries.push(format!("... {} more operation difference(s)", total - max));
+    }
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDocument;
+    use lopdf::dictionary;
+
+    /// A one-page document drawing `content`; `offset` unused objects come
+    /// first, so the same document gets different object numbers.
+    fn document(content: &str, font: &str, offset: usize) -> LoDoc {
+        let mut doc = TestDocument::new();
+        for _ in 0..offset {
+            doc.doc.add_object(Object::Null);
+        }
+        let font = doc.doc.add_object(dictionary! { "Type" => "Font", "Subtype" => "Type1", "BaseFont" => font });
+        doc.page(content, dictionary! { "Resources" => dictionary! { "Font" => dictionary! { "F1" => font } } });
+        doc.finish(dictionary! {})
+    }
+
+    fn lines(s: &str) -> Vec<String> {
+        s.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn object_numbers_and_subset_tags_do_not_count() {
+        let a = document("BT /F1 10 Tf (a) Tj ET", "ABCDEF+Serif", 0);
+        let b = document("BT /F1 10 Tf (a) Tj ET", "GHIJKL+Serif", 3);
+        assert!(diff_documents(&a, &b, &DiffOptions::default()).is_empty());
+    }
+
+    #[test]
+    fn reports_changed_operations_and_fonts() {
+        let a = document("BT /F1 10 Tf (a) Tj ET", "Serif", 0);
+        let b = document("BT /F1 12 Tf (a) Tj ET", "Sans", 0);
+        let report = diff_documents(&a, &b, &DiffOptions::default());
+        let titles: Vec<&str> = report.sections.iter().map(|s| s.title.as_str()).collect();
+        // Fonts are compared by name, not as resources
+        assert_eq!(titles, ["Page 1 fonts", "Page 1 content"]);
+        assert_eq!(report.sections[1].entries, ["+ #2 /F1 12 Tf", "- #2 /F1 10 Tf"]);
+        assert!(diff_documents(&a, &b, &DiffOptions { skip_content: true, ..Default::default() }).sections.iter().all(|s| s.title != "Page 1 content"));
+    }
+
+    #[test]
+    fn diffs_lines_along_their_longest_common_subsequence() {
+        assert_eq!(diff_lines(&lines("a b c d"), &lines("a x c d e"), 50), ["+ #2 x", "- #2 b", "+ #5 e"]);
+        assert!(diff_lines(&lines("a b"), &lines("a b"), 50).is_empty());
+        assert_eq!(diff_lines(&lines("a b c"), &lines("x y z"), 2).last().unwrap(), "... 4 more operation difference(s)");
+    }
+
+    #[test]
+    fn normalizes_operations() {
+        let op = Operation::new("TJ", vec![Object::Array(vec![Object::string_literal("Hi"), Object::Real(-0.123456)])]);
+        assert_eq!(format_operation(&op), "[<4869> -0.1235] TJ");
+        assert_eq!(strip_subset_tag("ABCDEF+NewCM10"), "NewCM10");
+        assert_eq!(strip_subset_tag("Abcdef+NewCM10"), "Abcdef+NewCM10");
+        assert_eq!(strip_subset_tag("ABC+NewCM10"), "ABC+NewCM10");
+    }
+}
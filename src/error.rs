@@ -0,0 +1,42 @@
+// ========== Errors ==========
+
+use pdfium_render::prelude::PdfiumError;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// libpdfium could not be loaded or failed on a document
+    #[error("pdfium: {0}")]
+    Pdfium(#[from] PdfiumError),
+    /// No loadable libpdfium in any of the searched locations
+    #[error("libpdfium could not be loaded, tried:\n  {}", .0.join("\n  "))]
+    PdfiumNotFound(Vec<String>),
+    /// The libpdfium found is older than the pdfium API this binary was built for
+    #[error("libpdfium at {path} is build {found}, but at least {expected} is required")]
+    PdfiumVersion { path: String, found: u32, expected: u32 },
+    /// A PDF could not be parsed, edited or written with lopdf
+    #[error("PDF: {0}")]
+    Pdf(#[from] lopdf::Error),
+    /// The output document could not be written, e.g. a font could not be embedded
+    #[error("PDF generation failed: {0}")]
+    Render(String),
+    /// The document is encrypted and the password is missing or wrong
+    #[error("{0} is encrypted: wrong or missing password")]
+    Password(String),
+    /// The written output breaks requirements of the requested PDF/A level
+    #[error("output does not conform to {}:\n{}", .0.level, .0)]
+    PdfA(crate::pdfa::PdfAReport),
+    #[error("no usable text font found")]
+    NoFont,
+    /// The page selection, after skipping blank pages, left nothing to convert
+    #[error("no pages selected")]
+    NoPages,
+    /// The batch output directory is the input directory, so outputs would replace their inputs
+    #[error("output directory {0} is the input directory")]
+    OutputIsInput(String),
+    #[error("image: {0}")]
+    Image(#[from] image::ImageError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
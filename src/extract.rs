@@ -0,0 +1,388 @@
+// ========== Extraction ==========
+//
+// An `Extractor` turns one page of a document into positioned glyphs; the
+// `PageReader` on top groups them into lines a batch of pages at a time.
+//
+// pdfium calls are serialized behind one library-wide lock, and dropping any
+// `Pdfium` handle tears the library down for every other handle. So a single
+// handle on this thread reads the glyphs page by page and hands each page to
+// the rayon pool for grouping while it continues with the next one. The
+// lopdf backend is read the same way. A tagged document's structure tree is
+// read with the glyphs and decides the lines' elements and reading order.
+// For a tagged output the figures of a page are read as well, and the lines
+// are marked with the figure or table cell they lie in.
+
+mod interpreter;
+mod pdf_font;
+mod pdfium;
+
+pub use interpreter::LopdfExtractor;
+pub use pdfium::{load_pdfium, PdfiumExtractor, PDFIUM_LIB_ENV};
+pub(crate) use pdfium::load_document;
+
+use crate::Result;
+use crate::layout::{group_lines, Glyph, Line};
+use crate::links::Link;
+use crate::metadata::Metadata;
+use crate::outline::OutlineItem;
+use crate::structure::{self, FigureRegion, StructNode};
+use lopdf::Document as LoDoc;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// A source of positioned glyphs, one page at a time.
+pub trait Extractor {
+    fn page_count(&self) -> usize;
+    /// Glyphs of page `index` (0-based) in PDF user space, y pointing up.
+    /// `y` is the bottom of the glyph box, not the baseline.
+    fn page_glyphs(&self, index: usize) -> Result<Vec<Glyph>>;
+    /// Link annotations of page `index`, destinations by input page index.
+    fn page_links(&self, index: usize) -> Result<Vec<Link>>;
+    /// Bookmarks of the document, destinations by input page index.
+    fn outline(&self) -> Result<Vec<OutlineItem>>;
+    /// Info dictionary, /Lang and XMP metadata of the document.
+    fn metadata(&self) -> Result<Metadata>;
+    /// Structure tree of page `index`, empty when the document is not tagged.
+    fn page_structure(&self, index: usize) -> Result<Vec<StructNode>>;
+    /// Images and form XObjects drawing graphics on page `index`, leaving
+    /// out the ones that cover most of the page.
+    fn page_figures(&self, index: usize) -> Result<Vec<FigureRegion>>;
+}
+
+/// Figures cover at most this share of the page; larger images are
+/// backgrounds or scans with their text laid over them.
+const MAX_FIGURE_AREA: f32 = 0.5;
+
+/// Whether `rect` (left, bottom, right, top) is small enough to be a figure
+/// on a page of `width` by `height`.
+fn figure_sized(rect: &[f32; 4], width: f32, height: f32) -> bool {
+    (rect[2] - rect[0]) * (rect[3] - rect[1]) <= width * height * MAX_FIGURE_AREA
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// libpdfium, loaded at runtime
+    #[default]
+    Pdfium,
+    /// Content-stream interpretation with lopdf, no native library needed
+    Lopdf,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "pdfium" => Ok(Backend::Pdfium),
+            "lopdf" => Ok(Backend::Lopdf),
+            _ => Err(format!("unknown backend '{}' (expected pdfium or lopdf)", s)),
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Backend::Pdfium => "pdfium",
+            Backend::Lopdf => "lopdf",
+        })
+    }
+}
+
+/// Pages to read as 1-based inclusive ranges, e.g. `1-3,7,10-`. Pages are
+/// always read in document order; numbers past the last page select nothing.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PageSelection {
+    /// First and last page of each range, `None` for an open end
+    ranges: Vec<(usize, Option<usize>)>,
+}
+
+impl PageSelection {
+    /// Only the pages between `first` and `last` (1-based, inclusive) of this selection.
+    pub fn within(mut self, first: Option<usize>, last: Option<usize>) -> Self {
+        let first = first.unwrap_or(1).max(1);
+        for (start, end) in &mut self.ranges {
+            *start = (*start).max(first);
+            *end = match (*end, last) {
+                (Some(end), Some(last)) => Some(end.min(last)),
+                (end, last) => end.or(last),
+            };
+        }
+        self.ranges.retain(|&(start, end)| end.is_none_or(|end| start <= end));
+        self
+    }
+
+    /// 0-based indices of the selected pages of a document with `count` pages.
+    pub fn indices(&self, count: usize) -> Vec<usize> {
+        (0..count).filter(|&i| self.ranges.iter().any(|&(start, end)| start <= i + 1 && end.is_none_or(|end| i < end))).collect()
+    }
+}
+
+impl Default for PageSelection {
+    /// Every page
+    fn default() -> Self {
+        PageSelection { ranges: vec![(1, None)] }
+    }
+}
+
+impl FromStr for PageSelection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let page = |n: &str| match n.trim().parse::<usize>() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err(format!("invalid page number '{}' in '{}' (pages count from 1)", n.trim(), s)),
+        };
+        let mut ranges = Vec::new();
+        for range in s.split(',') {
+            let (start, end) = match range.split_once('-') {
+                Some((start, end)) if end.trim().is_empty() => (page(start)?, None),
+                Some((start, end)) => (page(start)?, Some(page(end)?)),
+                None => (page(range)?, Some(page(range)?)),
+            };
+            if end.is_some_and(|end| end < start) {
+                return Err(format!("page range '{}' ends before it starts", range.trim()));
+            }
+            ranges.push((start, end));
+        }
+        Ok(PageSelection { ranges })
+    }
+}
+
+impl fmt::Display for PageSelection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ranges: Vec<String> = self
+            .ranges
+            .iter()
+            .map(|&(start, end)| match end {
+                Some(end) if end == start => start.to_string(),
+                Some(end) => format!("{}-{}", start, end),
+                None => format!("{}-", start),
+            })
+            .collect();
+        if ranges.is_empty() {
+            return f.write_str("none");
+        }
+        f.write_str(&ranges.join(","))
+    }
+}
+
+/// How an input document is opened.
+#[derive(Clone, Debug, Default)]
+pub struct OpenOptions {
+    pub backend: Backend,
+    /// Owner or user password of an encrypted document
+    pub password: Option<String>,
+    /// libpdfium to load instead of searching for it (see [`load_pdfium`])
+    pub pdfium_lib: Option<PathBuf>,
+    /// Pages to read (default: all); the others are never loaded
+    pub pages: Option<PageSelection>,
+    /// Leave out pages without any text
+    pub skip_blank: bool,
+}
+
+/// Open `path` and hand the extractor to `f`. The pdfium handle lives
+/// exactly as long as the call.
+pub fn with_extractor<R>(path: &str, opts: &OpenOptions, f: impl FnOnce(&dyn Extractor) -> Result<R>) -> Result<R> {
+    with_loaded_extractor(path, opts, None, f)
+}
+
+/// Like [`with_extractor`], with `doc` the document already read (and
+/// decrypted) with lopdf, so the extractor does not read it again.
+pub fn with_loaded_extractor<R>(
+    path: &str,
+    opts: &OpenOptions,
+    doc: Option<LoDoc>,
+    f: impl FnOnce(&dyn Extractor) -> Result<R>,
+) -> Result<R> {
+    let password = opts.password.as_deref();
+    match opts.backend {
+        Backend::Pdfium => {
+            let pdfium = load_pdfium(opts.pdfium_lib.as_deref())?;
+            let extractor = PdfiumExtractor::open(&pdfium, path, password)?;
+            f(&match doc {
+                Some(doc) => extractor.with_lopdf(doc),
+                None => extractor,
+            })
+        }
+        Backend::Lopdf => f(&match doc {
+            Some(doc) => LopdfExtractor::from_document(doc),
+            None => LopdfExtractor::open(path, password)?,
+        }),
+    }
+}
+
+/// Extract and group the pages of `path` that `opts` selects.
+pub fn extract_lines(path: &str, opts: &OpenOptions) -> Result<Vec<Vec<Line>>> {
+    with_extractor(path, opts, |extractor| {
+        let mut reader = PageReader::with_selection(extractor, opts.pages.as_ref(), opts.skip_blank);
+        let mut pages_out = Vec::new();
+        while let Some(batch) = reader.next_batch(usize::MAX)? {
+            pages_out.extend(batch);
+        }
+        Ok(pages_out)
+    })
+}
+
+/// Extract and group every page of an already opened document.
+pub fn read_lines(extractor: &dyn Extractor) -> Result<Vec<Vec<Line>>> {
+    let mut reader = PageReader::new(extractor);
+    let mut pages_out = Vec::new();
+    while let Some(batch) = reader.next_batch(usize::MAX)? {
+        pages_out.extend(batch);
+    }
+    Ok(pages_out)
+}
+
+/// Reads an input document a batch of pages at a time, so only the batch
+/// currently being converted is held in memory.
+pub struct PageReader<'a> {
+    extractor: &'a dyn Extractor,
+    /// Input pages to read, in order
+    selected: Vec<usize>,
+    next: usize,
+    skip_blank: bool,
+    /// Mark the figures and tables of the pages
+    regions: bool,
+    /// Input pages returned so far, one per output page
+    read: Vec<usize>,
+}
+
+impl<'a> PageReader<'a> {
+    pub fn new(extractor: &'a dyn Extractor) -> Self {
+        PageReader::with_selection(extractor, None, false)
+    }
+
+    /// Read only the `pages` selected (default: all), leaving out pages
+    /// without text when `skip_blank` is set.
+    pub fn with_selection(extractor: &'a dyn Extractor, pages: Option<&PageSelection>, skip_blank: bool) -> Self {
+        let count = extractor.page_count();
+        let selected = pages.map_or_else(|| (0..count).collect(), |pages| pages.indices(count));
+        PageReader { extractor, selected, next: 0, skip_blank, regions: false, read: Vec::new() }
+    }
+
+    /// Also mark the lines with the figure or table cell they lie in (see
+    /// [`structure::regions`]), for a tagged output.
+    pub fn with_regions(mut self) -> Self {
+        self.regions = true;
+        self
+    }
+
+    /// Number of pages selected; blank pages skipped are only known once read.
+    pub fn page_count(&self) -> usize {
+        self.selected.len()
+    }
+
+    /// Input page index of every page returned so far, in output order.
+    pub fn input_pages(&self) -> &[usize] {
+        &self.read
+    }
+
+    /// Extract and group the next `size` selected pages; `None` once all
+    /// were read. Blank pages skipped make the batch smaller.
+    pub fn next_batch(&mut self, size: usize) -> Result<Option<Vec<Vec<Line>>>> {
+        if self.next >= self.selected.len() {
+            return Ok(None);
+        }
+        let end = self.next.saturating_add(size.max(1)).min(self.selected.len());
+        let selected = &self.selected[self.next..end];
+        let mut pages_out: Vec<Vec<Line>> = selected.iter().map(|_| Vec::new()).collect();
+        let mut kept = vec![true; selected.len()];
+        let (extractor, skip_blank, regions) = (self.extractor, self.skip_blank, self.regions);
+        rayon::in_place_scope(|scope| -> Result<()> {
+            for ((&page_index, slot), kept) in selected.iter().zip(pages_out.iter_mut()).zip(kept.iter_mut()) {
+                let glyphs: Vec<Glyph> = extractor.page_glyphs(page_index)?;
+                if skip_blank && glyphs.iter().all(|g| g.ch.is_whitespace()) {
+                    log::debug!("page {} has no text, skipped", page_index + 1);
+                    *kept = false;
+                    continue;
+                }
+                let tree = extractor.page_structure(page_index)?;
+                let figures = if regions { Some(extractor.page_figures(page_index)?) } else { None };
+                log::trace!("page {}: {} glyphs, {} structure elements", page_index + 1, glyphs.len(), tree.len());
+                scope.spawn(move |_| {
+                    let lines = structure::apply(group_lines(glyphs), &tree);
+                    *slot = match figures {
+                        Some(figures) => structure::regions(lines, &figures, &tree),
+                        None => lines,
+                    };
+                });
+            }
+            Ok(())
+        })?;
+        self.read.extend(selected.iter().zip(&kept).filter(|(_, kept)| **kept).map(|(&page_index, _)| page_index));
+        let mut kept = kept.into_iter();
+        pages_out.retain(|_| kept.next() == Some(true));
+        self.next = end;
+        Ok(Some(pages_out))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDocument;
+    use lopdf::{dictionary, Object};
+
+    fn selection(s: &str) -> PageSelection {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_page_ranges() {
+        assert_eq!(selection("1-3,7,10-").indices(12), [0, 1, 2, 6, 9, 10, 11]);
+        assert_eq!(selection(" 2 - 3 , 5").indices(4), [1, 2]);
+        // Overlapping ranges select a page once, in document order
+        assert_eq!(selection("5,1-2,2").indices(6), [0, 1, 4]);
+        assert_eq!(selection("8-").indices(5), Vec::<usize>::new());
+        assert_eq!(selection("1-3,7,10-").to_string(), "1-3,7,10-");
+    }
+
+    #[test]
+    fn rejects_invalid_ranges() {
+        for s in ["", "0", "3-1", "a", "1,,2", "-2", "1-2-3"] {
+            assert!(s.parse::<PageSelection>().is_err(), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn narrows_to_first_and_last() {
+        assert_eq!(PageSelection::default().within(Some(3), Some(5)).to_string(), "3-5");
+        assert_eq!(selection("1-3,7,10-").within(Some(2), Some(8)).to_string(), "2-3,7");
+        assert_eq!(selection("1-3,10-").within(None, Some(11)).to_string(), "1-3,10-11");
+        assert_eq!(selection("1-3").within(Some(5), None).to_string(), "none");
+        assert!(selection("1-3").within(Some(5), None).indices(10).is_empty());
+    }
+
+    /// Text, baseline and glyph positions of every line, page by page.
+    fn read_all(extractor: &dyn Extractor, batch: usize) -> Vec<Vec<(String, f32, Vec<f32>)>> {
+        let mut reader = PageReader::new(extractor);
+        let mut pages = Vec::new();
+        while let Some(batch) = reader.next_batch(batch).unwrap() {
+            let line = |line: &Line| (line.glyphs.iter().map(|g| g.ch).collect(), line.y, line.glyphs.iter().map(|g| g.x).collect());
+            pages.extend(batch.iter().map(|lines| lines.iter().map(line).collect::<Vec<_>>()));
+        }
+        pages
+    }
+
+    #[test]
+    fn reads_pages_alike_on_any_number_of_threads() {
+        let mut doc = TestDocument::new();
+        let widths: Vec<Object> = vec![500.into(); 95];
+        let font = doc.doc.add_object(dictionary! {
+            "Type" => "Font", "Subtype" => "Type1", "BaseFont" => "Helvetica",
+            "Encoding" => "WinAnsiEncoding", "FirstChar" => 32, "LastChar" => 126, "Widths" => widths,
+        });
+        for p in 0..30 {
+            let lines: String = (0..p % 4 + 1).map(|l| format!("0 -14 Td (Page {} line {}) Tj ", p, l)).collect();
+            doc.page(&format!("BT /F1 12 Tf 72 760 Td {}ET", lines), dictionary! { "Resources" => dictionary! { "Font" => dictionary! { "F1" => font } } });
+        }
+        let extractor = LopdfExtractor::from_document(doc.finish(dictionary! {}));
+        let read = |threads| rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap().install(|| read_all(&extractor, 7));
+        let pages = read(1);
+        assert_eq!(pages.len(), 30);
+        assert_eq!(pages[27][3].0, "Page 27 line 3");
+        assert_eq!(pages, read(4));
+    }
+}
@@ -0,0 +1,424 @@
+// Pure-Rust extraction backend: interprets page content streams with lopdf
+// and places every glyph through the text rendering matrix, so no libpdfium
+// is needed. Form XObjects are followed, starting from the caller's
+// graphics and text state; vertical writing is laid out as
+// horizontal text. Marked content identifiers are tracked for the structure
+// tree of tagged documents. Images and the forms that paint something are
+// placed the same way for the figures of a page.
+//
+// pdfium inserts a space where the gap between two glyphs on a baseline
+// looks like a word break. TeX output has no space glyphs at all, so the
+// same is done here, otherwise words would run together.
+
+use super::Extractor;
+use super::pdf_font::PdfFont;
+use crate::{links, metadata, outline, security, structure, Result};
+use crate::layout::Glyph;
+use crate::links::Link;
+use crate::metadata::Metadata;
+use crate::outline::OutlineItem;
+use crate::structure::{FigureRegion, StructIndex, StructNode};
+use lopdf::content::{Content, Operation};
+use lopdf::{Dictionary, Document as LoDoc, Object, ObjectId};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Gap between glyphs, in em, from which a space is synthesized.
+const WORD_GAP: f32 = 0.15;
+/// Nesting limit for Form XObjects.
+const MAX_FORM_DEPTH: usize = 16;
+/// Nesting limit for the page tree.
+const MAX_TREE_DEPTH: usize = 32;
+
+type Matrix = [f32; 6];
+const IDENTITY: Matrix = [1.0, 0.0, 0.0, 1.0, 0.0, 0.0];
+
+/// `a` applied first, then `b`.
+fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    [
+        a[0] * b[0] + a[1] * b[2],
+        a[0] * b[1] + a[1] * b[3],
+        a[2] * b[0] + a[3] * b[2],
+        a[2] * b[1] + a[3] * b[3],
+        a[4] * b[0] + a[5] * b[2] + b[4],
+        a[4] * b[1] + a[5] * b[3] + b[5],
+    ]
+}
+
+pub struct LopdfExtractor {
+    doc: LoDoc,
+    pages: Vec<ObjectId>,
+    /// The structure tree by page, empty for untagged documents
+    structure: StructIndex,
+    fonts: Mutex<HashMap<ObjectId, Arc<PdfFont>>>,
+}
+
+impl LopdfExtractor {
+    pub fn open(path: &str, password: Option<&str>) -> Result<Self> {
+        Ok(Self::from_document(security::open(path, password)?.0))
+    }
+
+    pub fn from_document(doc: LoDoc) -> Self {
+        let pages = doc.get_pages().into_values().collect();
+        let structure = StructIndex::from_lopdf(&doc, &structure::role_map(&doc));
+        LopdfExtractor { doc, pages, structure, fonts: Mutex::new(HashMap::new()) }
+    }
+
+    fn font(&self, obj: &Object) -> Option<Arc<PdfFont>> {
+        match obj {
+            Object::Reference(id) => {
+                if let Some(font) = self.fonts.lock().unwrap_or_else(|e| e.into_inner()).get(id) {
+                    return Some(font.clone());
+                }
+                let font = Arc::new(PdfFont::load(&self.doc, self.doc.get_dictionary(*id).ok()?));
+                self.fonts.lock().unwrap_or_else(|e| e.into_inner()).insert(*id, font.clone());
+                Some(font)
+            }
+            Object::Dictionary(dict) => Some(Arc::new(PdfFont::load(&self.doc, dict))),
+            _ => None,
+        }
+    }
+
+    /// Interpret the content of page `index`, placing its glyphs only with `text`.
+    fn run(&self, index: usize, text: bool) -> Result<Interpreter<'_>> {
+        let page_id = self.pages[index];
+        let content = self.doc.get_and_decode_page_content(page_id)?;
+        let (own, inherited) = self.doc.get_page_resources(page_id)?;
+        let mut resources: Vec<&Dictionary> = own.into_iter().collect();
+        resources.extend(inherited.iter().filter_map(|id| self.doc.get_dictionary(*id).ok()));
+
+        let mut run = Interpreter { extractor: self, text, glyphs: Vec::new(), figures: Vec::new(), painted: false, marked: Vec::new() };
+        run.execute(&content.operations, &resources, State::default(), 0);
+        Ok(run)
+    }
+}
+
+/// The page's /MediaBox, inherited through the page tree; US Letter when
+/// there is none.
+fn media_box(doc: &LoDoc, page: ObjectId) -> [f32; 4] {
+    let mut node = doc.get_dictionary(page).ok();
+    for _ in 0..MAX_TREE_DEPTH {
+        let Some(dict) = node else { break };
+        if let Ok(rect) = dict.get(b"MediaBox").and_then(|b| doc.dereference(b)).and_then(|(_, b)| b.as_array()) {
+            let v: Vec<f32> = rect.iter().filter_map(|o| o.as_float().ok()).collect();
+            if let Ok(rect) = <[f32; 4]>::try_from(v.as_slice()) {
+                return rect;
+            }
+        }
+        node = dict.get(b"Parent").and_then(Object::as_reference).and_then(|id| doc.get_dictionary(id)).ok();
+    }
+    [0.0, 0.0, 612.0, 792.0]
+}
+
+impl Extractor for LopdfExtractor {
+    fn page_count(&self) -> usize {
+        self.pages.len()
+    }
+
+    fn page_glyphs(&self, index: usize) -> Result<Vec<Glyph>> {
+        Ok(self.run(index, true)?.glyphs)
+    }
+
+    fn page_links(&self, index: usize) -> Result<Vec<Link>> {
+        Ok(links::from_lopdf(&self.doc, &self.pages, index))
+    }
+
+    fn outline(&self) -> Result<Vec<OutlineItem>> {
+        Ok(outline::from_lopdf(&self.doc, &self.pages))
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(metadata::from_lopdf(&self.doc))
+    }
+
+    fn page_structure(&self, index: usize) -> Result<Vec<StructNode>> {
+        Ok(self.structure.page(self.pages[index]))
+    }
+
+    fn page_figures(&self, index: usize) -> Result<Vec<FigureRegion>> {
+        let figures = self.run(index, false)?.figures;
+        let [x1, y1, x2, y2] = media_box(&self.doc, self.pages[index]);
+        Ok(figures.into_iter().filter(|f| super::figure_sized(&f.rect, x2 - x1, y2 - y1)).collect())
+    }
+}
+
+#[derive(Clone)]
+struct State {
+    ctm: Matrix,
+    font: Option<Arc<PdfFont>>,
+    size: f32,
+    char_spacing: f32,
+    word_spacing: f32,
+    hscale: f32,
+    leading: f32,
+    rise: f32,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        State { ctm: IDENTITY, font: None, size: 0.0, char_spacing: 0.0, word_spacing: 0.0, hscale: 1.0, leading: 0.0, rise: 0.0 }
+    }
+}
+
+struct Interpreter<'a> {
+    extractor: &'a LopdfExtractor,
+    /// Place the glyphs; without, only the figures are collected
+    text: bool,
+    glyphs: Vec<Glyph>,
+    figures: Vec<FigureRegion>,
+    /// Whether anything but text was painted since the innermost form began
+    painted: bool,
+    /// Open marked content sequences, with their MCID if they have one
+    marked: Vec<Option<i32>>,
+}
+
+impl<'a> Interpreter<'a> {
+    fn resource(&self, resources: &[&'a Dictionary], category: &[u8], name: &[u8]) -> Option<&'a Object> {
+        let doc = &self.extractor.doc;
+        resources.iter().find_map(|res| {
+            let dict = match res.get(category).ok()? {
+                Object::Reference(id) => doc.get_dictionary(*id).ok()?,
+                Object::Dictionary(dict) => dict,
+                _ => return None,
+            };
+            dict.get(name).ok()
+        })
+    }
+
+    /// Run `ops` starting from `state`, the caller's graphics state for a form.
+    fn execute(&mut self, ops: &[Operation], resources: &[&'a Dictionary], mut state: State, depth: usize) {
+        let mut stack = Vec::new();
+        let (mut tm, mut tlm) = (IDENTITY, IDENTITY);
+        for op in ops {
+            let n: Vec<f32> = op.operands.iter().filter_map(|o| o.as_float().ok()).collect();
+            match (op.operator.as_str(), n.as_slice()) {
+                ("q", _) => stack.push(state.clone()),
+                ("Q", _) => state = stack.pop().unwrap_or(state),
+                ("cm", &[a, b, c, d, e, f]) => state.ctm = multiply(&[a, b, c, d, e, f], &state.ctm),
+                ("BT", _) => (tm, tlm) = (IDENTITY, IDENTITY),
+                ("Tf", &[size]) => {
+                    let name = op.operands.first().and_then(|o| o.as_name().ok()).unwrap_or_default();
+                    state.font = self.resource(resources, b"Font", name).and_then(|f| self.extractor.font(f));
+                    state.size = size;
+                }
+                ("Tc", &[v]) => state.char_spacing = v,
+                ("Tw", &[v]) => state.word_spacing = v,
+                ("Tz", &[v]) => state.hscale = v / 100.0,
+                ("TL", &[v]) => state.leading = v,
+                ("Ts", &[v]) => state.rise = v,
+                ("Td", &[x, y]) => {
+                    tlm = multiply(&[1.0, 0.0, 0.0, 1.0, x, y], &tlm);
+                    tm = tlm;
+                }
+                ("TD", &[x, y]) => {
+                    state.leading = -y;
+                    tlm = multiply(&[1.0, 0.0, 0.0, 1.0, x, y], &tlm);
+                    tm = tlm;
+                }
+                ("Tm", &[a, b, c, d, e, f]) => (tm, tlm) = ([a, b, c, d, e, f], [a, b, c, d, e, f]),
+                ("T*", _) => {
+                    tlm = multiply(&[1.0, 0.0, 0.0, 1.0, 0.0, -state.leading], &tlm);
+                    tm = tlm;
+                }
+                ("Tj", _) | ("'", _) | ("\"", _) => {
+                    if op.operator != "Tj" {
+                        if let &[aw, ac] = n.as_slice() {
+                            (state.word_spacing, state.char_spacing) = (aw, ac);
+                        }
+                        tlm = multiply(&[1.0, 0.0, 0.0, 1.0, 0.0, -state.leading], &tlm);
+                        tm = tlm;
+                    }
+                    if let Some(Object::String(bytes, _)) = op.operands.last() {
+                        self.show(bytes, &state, &mut tm);
+                    }
+                }
+                ("TJ", _) => {
+                    for item in op.operands.first().and_then(|o| o.as_array().ok()).into_iter().flatten() {
+                        match item {
+                            Object::String(bytes, _) => self.show(bytes, &state, &mut tm),
+                            other => {
+                                let adjust = other.as_float().unwrap_or(0.0);
+                                let tx = -adjust / 1000.0 * state.size * state.hscale;
+                                tm = multiply(&[1.0, 0.0, 0.0, 1.0, tx, 0.0], &tm);
+                            }
+                        }
+                    }
+                }
+                ("BMC", _) => self.marked.push(None),
+                ("BDC", _) => {
+                    let mcid = op.operands.get(1).and_then(|props| match props {
+                        Object::Name(name) => self.resource(resources, b"Properties", name).map(|p| self.extractor.doc.dereference(p).map_or(p, |(_, p)| p)),
+                        props => Some(props),
+                    });
+                    self.marked.push(mcid.and_then(|p| p.as_dict().ok()).and_then(|p| p.get(b"MCID").ok()).and_then(|m| m.as_i64().ok()).map(|m| m as i32));
+                }
+                ("EMC", _) => {
+                    self.marked.pop();
+                }
+                ("Do", _) if depth < MAX_FORM_DEPTH => {
+                    let name = op.operands.first().and_then(|o| o.as_name().ok()).unwrap_or_default();
+                    self.xobject(resources, name, &state, depth);
+                }
+                ("BI", _) => self.figure([0.0, 0.0, 1.0, 1.0], &state.ctm),
+                ("S" | "s" | "f" | "F" | "f*" | "B" | "B*" | "b" | "b*" | "sh", _) => self.painted = true,
+                _ => {}
+            }
+        }
+    }
+
+    /// Run a form in `state` or place an image.
+    fn xobject(&mut self, resources: &[&'a Dictionary], name: &[u8], state: &State, depth: usize) {
+        let doc: &'a LoDoc = &self.extractor.doc;
+        let Some(Ok(stream)) = self.resource(resources, b"XObject", name).map(|o| match o {
+            Object::Reference(id) => doc.get_object(*id).and_then(Object::as_stream),
+            other => other.as_stream(),
+        }) else {
+            return;
+        };
+        match stream.dict.get(b"Subtype").and_then(Object::as_name) {
+            Ok(b"Form") => {}
+            // An image fills the unit square
+            Ok(b"Image") => return self.figure([0.0, 0.0, 1.0, 1.0], &state.ctm),
+            _ => return,
+        }
+        let matrix = match stream.dict.get(b"Matrix").and_then(Object::as_array) {
+            Ok(m) => {
+                let v: Vec<f32> = m.iter().filter_map(|o| o.as_float().ok()).collect();
+                <Matrix>::try_from(v.as_slice()).unwrap_or(IDENTITY)
+            }
+            Err(_) => IDENTITY,
+        };
+        let data = stream.decompressed_content().unwrap_or_else(|_| stream.content.clone());
+        let Ok(content) = Content::decode(&data) else { return };
+        // A form without its own resources uses the ones of its caller
+        let own = match stream.dict.get(b"Resources") {
+            Ok(Object::Reference(id)) => doc.get_dictionary(*id).ok(),
+            Ok(Object::Dictionary(dict)) => Some(dict),
+            _ => None,
+        };
+        let mut inner: Vec<&Dictionary> = own.into_iter().collect();
+        inner.extend_from_slice(resources);
+        // The form starts from the caller's graphics state, text state included
+        let state = State { ctm: multiply(&matrix, &state.ctm), ..state.clone() };
+        let ctm = state.ctm;
+        let painted = std::mem::replace(&mut self.painted, false);
+        self.execute(&content.operations, &inner, state, depth + 1);
+        if self.painted {
+            let bbox: Vec<f32> = stream.dict.get(b"BBox").and_then(Object::as_array).into_iter().flatten().filter_map(|o| o.as_float().ok()).collect();
+            if let Ok(bbox) = <[f32; 4]>::try_from(bbox.as_slice()) {
+                self.figure(bbox, &ctm);
+            }
+        }
+        self.painted |= painted;
+    }
+
+    /// Add the figure covering `rect` in the space `ctm` maps to the page.
+    fn figure(&mut self, rect: [f32; 4], ctm: &Matrix) {
+        let corners = [(rect[0], rect[1]), (rect[2], rect[1]), (rect[0], rect[3]), (rect[2], rect[3])];
+        let points = corners.map(|(x, y)| (x * ctm[0] + y * ctm[2] + ctm[4], x * ctm[1] + y * ctm[3] + ctm[5]));
+        let xs = points.map(|(x, _)| x);
+        let ys = points.map(|(_, y)| y);
+        let min = |v: [f32; 4]| v.into_iter().fold(f32::INFINITY, f32::min);
+        let max = |v: [f32; 4]| v.into_iter().fold(f32::NEG_INFINITY, f32::max);
+        self.figures.push(FigureRegion { rect: [min(xs), min(ys), max(xs), max(ys)], mcid: self.mcid() });
+        self.painted = true;
+    }
+
+    /// Emit the glyphs of one string and advance the text matrix past them.
+    fn show(&mut self, bytes: &[u8], state: &State, tm: &mut Matrix) {
+        let Some(font) = state.font.as_ref().filter(|_| self.text) else { return };
+        for (code, len) in font.codes(bytes) {
+            let trm = multiply(&multiply(&[state.size * state.hscale, 0.0, 0.0, state.size, 0.0, state.rise], tm), &state.ctm);
+            let size = trm[2].hypot(trm[3]);
+            let advance = font.width(code);
+            let width = advance * trm[0].hypot(trm[1]);
+            let (x, baseline) = (trm[4], trm[5]);
+            let y = baseline + font.descent * size;
+            let text = font.unicode(code);
+            let mapped = text.is_some();
+            let chars: Vec<char> = text.map_or(vec!['?'], |t| t.chars().collect());
+
+            if !chars.first().is_some_and(|c| c.is_whitespace()) {
+                self.word_break(x, y, size, &font.name);
+            }
+            let share = width / chars.len().max(1) as f32;
+            for (i, ch) in chars.into_iter().enumerate() {
+                self.glyphs.push(Glyph { ch, x: x + share * i as f32, y, w: share, size, font: font.name.clone(), mapped, mcid: self.mcid() });
+            }
+
+            let spacing = if len == 1 && code == 32 { state.word_spacing } else { 0.0 };
+            let tx = (advance * state.size + state.char_spacing + spacing) * state.hscale;
+            *tm = multiply(&[1.0, 0.0, 0.0, 1.0, tx, 0.0], tm);
+        }
+    }
+
+    /// Insert a space when the previous glyph ends well before `x` on the same baseline.
+    fn word_break(&mut self, x: f32, y: f32, size: f32, font: &str) {
+        let Some(prev) = self.glyphs.last() else { return };
+        let end = prev.x + prev.w;
+        if prev.ch.is_whitespace() || (prev.y - y).abs() > size * 0.3 || x - end < size * WORD_GAP {
+            return;
+        }
+        self.glyphs.push(Glyph { ch: ' ', x: end, y, w: x - end, size, font: font.to_string(), mapped: true, mcid: self.mcid() });
+    }
+
+    /// MCID of the innermost marked content sequence that has one.
+    fn mcid(&self) -> Option<i32> {
+        self.marked.iter().rev().find_map(|m| *m)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDocument;
+    use lopdf::{dictionary, Stream};
+
+    /// A one-page document drawing `form` as /Fm after running `page`.
+    fn document(page: &str, form: &str) -> LoDoc {
+        let mut doc = TestDocument::new();
+        let widths: Vec<Object> = vec![500.into(); 95];
+        let font = doc.doc.add_object(dictionary! {
+            "Type" => "Font", "Subtype" => "Type1", "BaseFont" => "Helvetica",
+            "Encoding" => "WinAnsiEncoding", "FirstChar" => 32, "LastChar" => 126, "Widths" => widths,
+        });
+        let bbox: Vec<Object> = vec![0.into(), 0.into(), 100.into(), 100.into()];
+        let form = doc.doc.add_object(Stream::new(dictionary! { "Type" => "XObject", "Subtype" => "Form", "BBox" => bbox }, form.as_bytes().to_vec()));
+        let resources = dictionary! { "Font" => dictionary! { "F1" => font }, "XObject" => dictionary! { "Fm" => form } };
+        doc.page(page, dictionary! { "Resources" => resources });
+        doc.finish(dictionary! {})
+    }
+
+    #[test]
+    fn forms_inherit_the_callers_state() {
+        let doc = document("BT /F1 12 Tf 3 Tc 50 Tz ET q 2 0 0 2 0 0 cm /Fm Do Q", "BT 1 0 0 1 10 20 Tm (Hi) Tj ET");
+        let glyphs = LopdfExtractor::from_document(doc).page_glyphs(0).unwrap();
+        let placed: Vec<(char, f32, f32)> = glyphs.iter().map(|g| (g.ch, g.x, g.size)).collect();
+        // Font size 12 and the CTM scale 2; each advance (6 + Tc 3) at Tz 50, scaled by 2
+        assert_eq!(placed, [('H', 20.0, 24.0), ('i', 29.0, 24.0)]);
+    }
+
+    #[test]
+    fn form_state_does_not_leak_back() {
+        let doc = document("BT /F1 10 Tf ET /Fm Do BT 1 0 0 1 0 0 Tm (a) Tj ET", "1 0 0 1 50 50 cm BT 5 Tc ET");
+        let glyphs = LopdfExtractor::from_document(doc).page_glyphs(0).unwrap();
+        let placed: Vec<(char, f32, f32)> = glyphs.iter().map(|g| (g.ch, g.x, g.size)).collect();
+        assert_eq!(placed, [('a', 0.0, 10.0)]);
+    }
+
+    #[test]
+    fn places_images_and_painting_forms_as_figures() {
+        let mut doc = document("q 100 0 0 50 20 30 cm /Im Do Q q 1 0 0 1 300 400 cm /Fm Do Q", "0 0 m 10 10 l S");
+        let image = doc.add_object(Stream::new(dictionary! { "Type" => "XObject", "Subtype" => "Image", "Width" => 1, "Height" => 1 }, vec![0]));
+        let page = doc.page_iter().next().unwrap();
+        let resources = doc.get_dictionary_mut(page).and_then(|p| p.get_mut(b"Resources")).and_then(Object::as_dict_mut).unwrap();
+        resources.get_mut(b"XObject").and_then(Object::as_dict_mut).unwrap().set("Im", image);
+        let figures = LopdfExtractor::from_document(doc).page_figures(0).unwrap();
+        let rects: Vec<[f32; 4]> = figures.iter().map(|f| f.rect).collect();
+        assert_eq!(rects, [[20.0, 30.0, 120.0, 80.0], [300.0, 400.0, 400.0, 500.0]]);
+
+        // A form of text only is no figure
+        let doc = document("/Fm Do", "BT /F1 12 Tf (Hi) Tj ET");
+        assert!(LopdfExtractor::from_document(doc).page_figures(0).unwrap().is_empty());
+    }
+}
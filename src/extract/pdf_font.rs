@@ -0,0 +1,408 @@
+// Font decoding for the lopdf backend: splits strings into character codes
+// and maps each code to Unicode and to its advance width.
+//
+// Unicode comes from the ToUnicode CMap, then /Differences glyph names,
+// then the base encoding. Type0 fonts use their Encoding CMap (Identity-H/V
+// or an embedded one) and the descendant's /W widths. Built-in encodings of
+// embedded font programs are not read; such fonts fall back to
+// StandardEncoding unless they carry a ToUnicode CMap.
+
+use lopdf::content::Content;
+use lopdf::{Dictionary, Document as LoDoc, Object};
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+/// Entries expanded from one CMap range at most; larger ranges are clipped.
+const MAX_RANGE_ENTRIES: u32 = 0x10000;
+
+pub struct PdfFont {
+    pub name: String,
+    composite: bool,
+    code_space: Vec<(u32, u32, usize)>,
+    /// Code to CID for composite fonts with a non-identity CMap
+    cids: Option<HashMap<u32, u32>>,
+    to_unicode: HashMap<u32, String>,
+    /// Unicode from /Encoding for simple fonts, indexed by code
+    encoding: Vec<Option<char>>,
+    widths: HashMap<u32, f32>,
+    default_width: f32,
+    /// Glyph space to text space, horizontal scale (0.001 except for Type3)
+    scale: f32,
+    /// Bottom of the glyph boxes below the baseline in text space units (negative)
+    pub descent: f32,
+}
+
+impl PdfFont {
+    pub fn load(doc: &LoDoc, font: &Dictionary) -> PdfFont {
+        let name = font.get(b"BaseFont").and_then(Object::as_name).map(|n| String::from_utf8_lossy(n).into_owned()).unwrap_or_default();
+        let composite = font.get(b"Subtype").and_then(Object::as_name).is_ok_and(|s| s == b"Type0");
+        let descendant = composite
+            .then(|| deref(doc, font.get(b"DescendantFonts").ok()?).as_array().ok()?.first().and_then(|d| deref(doc, d).as_dict().ok()))
+            .flatten();
+        let scale = font
+            .get(b"FontMatrix")
+            .and_then(Object::as_array)
+            .ok()
+            .and_then(|m| m.first())
+            .and_then(|a| number(deref(doc, a)))
+            .unwrap_or(0.001);
+        let descriptor = descendant.unwrap_or(font).get(b"FontDescriptor").ok().and_then(|d| deref(doc, d).as_dict().ok());
+        // pdfium's loose glyph boxes reach down to the font bounding box, fall back to /Descent
+        let descent = descriptor
+            .and_then(|d| {
+                let bbox = d.get(b"FontBBox").and_then(Object::as_array).ok().and_then(|b| b.get(1)).and_then(number);
+                bbox.or_else(|| d.get(b"Descent").ok().and_then(number))
+            })
+            .map_or(-0.22, |d| d * 0.001);
+
+        let mut pdf_font = PdfFont {
+            name,
+            composite,
+            code_space: vec![(0, 0xFF, 1)],
+            cids: None,
+            to_unicode: HashMap::new(),
+            encoding: Vec::new(),
+            widths: HashMap::new(),
+            default_width: 0.0,
+            scale,
+            descent,
+        };
+
+        if composite {
+            pdf_font.code_space = vec![(0, 0xFFFF, 2)];
+            if let Ok(Object::Stream(stream)) = font.get(b"Encoding").map(|e| deref(doc, e)) {
+                let cmap = CMap::parse(&stream.decompressed_content().unwrap_or_else(|_| stream.content.clone()));
+                if !cmap.code_space.is_empty() {
+                    pdf_font.code_space = cmap.code_space;
+                }
+                pdf_font.cids = Some(cmap.cids);
+            }
+            if let Some(descendant) = descendant {
+                pdf_font.default_width = descendant.get(b"DW").ok().and_then(number).unwrap_or(1000.0);
+                if let Ok(w) = descendant.get(b"W").map(|w| deref(doc, w)).and_then(Object::as_array) {
+                    pdf_font.widths = cid_widths(doc, w);
+                }
+            }
+        } else {
+            pdf_font.encoding = simple_encoding(doc, font);
+            let first = font.get(b"FirstChar").ok().and_then(number).unwrap_or(0.0) as u32;
+            if let Ok(widths) = font.get(b"Widths").map(|w| deref(doc, w)).and_then(Object::as_array) {
+                for (i, w) in widths.iter().enumerate() {
+                    if let Some(w) = number(deref(doc, w)) {
+                        pdf_font.widths.insert(first + i as u32, w);
+                    }
+                }
+            }
+            // Fonts without metrics (standard 14) get a plausible average width
+            let missing = descriptor.and_then(|d| d.get(b"MissingWidth").ok()).and_then(number);
+            pdf_font.default_width = missing.unwrap_or(if pdf_font.widths.is_empty() { 500.0 } else { 0.0 });
+        }
+
+        if let Ok(Object::Stream(stream)) = font.get(b"ToUnicode").map(|t| deref(doc, t)) {
+            let cmap = CMap::parse(&stream.decompressed_content().unwrap_or_else(|_| stream.content.clone()));
+            pdf_font.to_unicode = cmap.unicode;
+        }
+        pdf_font
+    }
+
+    /// Split a string operand into (code, byte length) pairs.
+    pub fn codes(&self, bytes: &[u8]) -> Vec<(u32, usize)> {
+        let min_len = self.code_space.iter().map(|r| r.2).min().unwrap_or(1);
+        let mut out = Vec::new();
+        let mut i = 0;
+        while i < bytes.len() {
+            let len = (1..=4)
+                .filter(|&len| i + len <= bytes.len())
+                .find(|&len| {
+                    let code = be_int(&bytes[i..i + len]);
+                    self.code_space.iter().any(|&(lo, hi, l)| l == len && (lo..=hi).contains(&code))
+                })
+                .unwrap_or(min_len.min(bytes.len() - i));
+            out.push((be_int(&bytes[i..i + len]), len));
+            i += len;
+        }
+        out
+    }
+
+    /// Text for one code, `None` when the font gives no way to map it.
+    pub fn unicode(&self, code: u32) -> Option<String> {
+        if let Some(text) = self.to_unicode.get(&code) {
+            return Some(text.clone());
+        }
+        if self.composite {
+            return None;
+        }
+        self.encoding.get(code as usize).copied().flatten().map(String::from)
+    }
+
+    /// Horizontal advance of one code in text space units (em of the font size).
+    pub fn width(&self, code: u32) -> f32 {
+        let key = match &self.cids {
+            Some(cids) => cids.get(&code).copied().unwrap_or(code),
+            None => code,
+        };
+        self.widths.get(&key).copied().unwrap_or(self.default_width) * self.scale
+    }
+}
+
+fn deref<'a>(doc: &'a LoDoc, obj: &'a Object) -> &'a Object {
+    match obj {
+        Object::Reference(id) => doc.get_object(*id).unwrap_or(obj),
+        _ => obj,
+    }
+}
+
+fn number(obj: &Object) -> Option<f32> {
+    match obj {
+        Object::Integer(i) => Some(*i as f32),
+        Object::Real(r) => Some(*r),
+        _ => None,
+    }
+}
+
+fn be_int(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |acc, b| (acc << 8) | *b as u32)
+}
+
+/// `/W [c [w1 w2 ...] c_first c_last w ...]`
+fn cid_widths(doc: &LoDoc, w: &[Object]) -> HashMap<u32, f32> {
+    let mut widths = HashMap::new();
+    let mut items = w.iter().map(|o| deref(doc, o)).peekable();
+    while let Some(first) = items.next() {
+        let Some(first) = number(first) else { break };
+        match items.next() {
+            Some(Object::Array(list)) => {
+                for (i, w) in list.iter().enumerate() {
+                    if let Some(w) = number(deref(doc, w)) {
+                        widths.insert(first as u32 + i as u32, w);
+                    }
+                }
+            }
+            Some(last) => {
+                let (Some(last), Some(w)) = (number(last), items.next().and_then(number)) else { break };
+                for cid in (first as u32..=last as u32).take(MAX_RANGE_ENTRIES as usize) {
+                    widths.insert(cid, w);
+                }
+            }
+            None => break,
+        }
+    }
+    widths
+}
+
+/// Unicode per code from /Encoding: a base encoding plus /Differences.
+fn simple_encoding(doc: &LoDoc, font: &Dictionary) -> Vec<Option<char>> {
+    let encoding = font.get(b"Encoding").map(|e| deref(doc, e));
+    let base = match encoding {
+        Ok(Object::Name(name)) => name.as_slice(),
+        Ok(Object::Dictionary(dict)) => dict.get(b"BaseEncoding").and_then(Object::as_name).unwrap_or(b"StandardEncoding"),
+        _ => b"StandardEncoding",
+    };
+    let mut table = base_encoding(base);
+    if let Ok(Object::Dictionary(dict)) = encoding
+        && let Ok(differences) = dict.get(b"Differences").and_then(Object::as_array)
+    {
+        let mut code = 0usize;
+        for item in differences {
+            match item {
+                Object::Integer(i) => code = *i as usize,
+                Object::Name(name) => {
+                    if let Some(slot) = table.get_mut(code) {
+                        *slot = glyph_name_to_char(&String::from_utf8_lossy(name));
+                    }
+                    code += 1;
+                }
+                _ => {}
+            }
+        }
+    }
+    table
+}
+
+/// One of the predefined single-byte encodings. lopdf only exposes its
+/// tables through font dictionaries, so each code is decoded through one.
+fn base_encoding(name: &[u8]) -> Vec<Option<char>> {
+    let font = Dictionary::from_iter([
+        (b"Type".to_vec(), Object::Name(b"Font".to_vec())),
+        (b"Encoding".to_vec(), Object::Name(name.to_vec())),
+    ]);
+    let doc = LoDoc::new();
+    let Ok(encoding) = font.get_font_encoding(&doc) else { return vec![None; 256] };
+    (0..=255u8)
+        .map(|b| {
+            let text = encoding.bytes_to_string(&[b]).ok()?;
+            let mut chars = text.chars();
+            chars.next().filter(|_| chars.next().is_none())
+        })
+        .collect()
+}
+
+/// Adobe Glyph List lookup, with `uniXXXX`, `uXXXX[XX]`, ligature
+/// (`f_i`) and suffix (`a.sc`) forms. Ligatures map to their first char
+/// when there is no precomposed form.
+fn glyph_name_to_char(name: &str) -> Option<char> {
+    static NAMES: OnceLock<HashMap<&'static str, char>> = OnceLock::new();
+    let names = NAMES.get_or_init(|| {
+        let mut names: HashMap<&'static str, char> = glyph_names::GLYPH_NAME_PAIRS.iter().map(|(c, n)| (*n, *c)).collect();
+        names.extend([("ff", '\u{FB00}'), ("fi", '\u{FB01}'), ("fl", '\u{FB02}'), ("ffi", '\u{FB03}'), ("ffl", '\u{FB04}')]);
+        names
+    });
+    let name = name.split('.').next().unwrap_or(name);
+    if let Some(c) = names.get(name) {
+        return Some(*c);
+    }
+    if let Some(hex) = name.strip_prefix("uni").filter(|h| h.len() >= 4) {
+        // Not a char boundary for non-ASCII names, which are no hex anyway
+        return u32::from_str_radix(hex.get(..4)?, 16).ok().and_then(char::from_u32);
+    }
+    if let Some(hex) = name.strip_prefix('u').filter(|h| (4..=6).contains(&h.len())) {
+        return u32::from_str_radix(hex, 16).ok().and_then(char::from_u32);
+    }
+    let ligature = name.replace('_', "");
+    if ligature != name {
+        return names.get(ligature.as_str()).copied().or_else(|| name.split('_').next().and_then(glyph_name_to_char));
+    }
+    None
+}
+
+/// The parts of a CMap file the extractor needs. The PostScript syntax of
+/// CMaps tokenizes like a content stream, so lopdf's content parser does
+/// the work: the entries of a `begin...`/`end...` section end up as
+/// operands of the `end...` operator.
+#[derive(Default)]
+struct CMap {
+    code_space: Vec<(u32, u32, usize)>,
+    unicode: HashMap<u32, String>,
+    cids: HashMap<u32, u32>,
+}
+
+impl CMap {
+    fn parse(data: &[u8]) -> CMap {
+        let mut cmap = CMap::default();
+        let Ok(content) = Content::decode(data) else { return cmap };
+        for op in &content.operations {
+            match op.operator.as_str() {
+                "endcodespacerange" => {
+                    for pair in op.operands.chunks_exact(2) {
+                        if let (Some(lo), Some(hi)) = (string(&pair[0]), string(&pair[1])) {
+                            cmap.code_space.push((be_int(lo), be_int(hi), lo.len()));
+                        }
+                    }
+                }
+                "endbfchar" => {
+                    for pair in op.operands.chunks_exact(2) {
+                        if let (Some(src), Some(dst)) = (string(&pair[0]), unicode_target(&pair[1])) {
+                            cmap.unicode.insert(be_int(src), dst);
+                        }
+                    }
+                }
+                "endbfrange" => {
+                    for triple in op.operands.chunks_exact(3) {
+                        let (Some(lo), Some(hi)) = (string(&triple[0]).map(be_int), string(&triple[1]).map(be_int)) else { continue };
+                        for (i, code) in (lo..=hi).take(MAX_RANGE_ENTRIES as usize).enumerate() {
+                            let dst = match &triple[2] {
+                                Object::Array(list) => list.get(i).and_then(unicode_target),
+                                Object::String(start, _) => increment_utf16(start, i as u16),
+                                _ => None,
+                            };
+                            if let Some(dst) = dst {
+                                cmap.unicode.insert(code, dst);
+                            }
+                        }
+                    }
+                }
+                "endcidchar" => {
+                    for pair in op.operands.chunks_exact(2) {
+                        if let (Some(src), Some(cid)) = (string(&pair[0]), number(&pair[1])) {
+                            cmap.cids.insert(be_int(src), cid as u32);
+                        }
+                    }
+                }
+                "endcidrange" => {
+                    for triple in op.operands.chunks_exact(3) {
+                        let (Some(lo), Some(hi), Some(cid)) = (string(&triple[0]).map(be_int), string(&triple[1]).map(be_int), number(&triple[2])) else { continue };
+                        for (i, code) in (lo..=hi).take(MAX_RANGE_ENTRIES as usize).enumerate() {
+                            cmap.cids.insert(code, cid as u32 + i as u32);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        cmap
+    }
+}
+
+fn string(obj: &Object) -> Option<&[u8]> {
+    match obj {
+        Object::String(bytes, _) => Some(bytes),
+        _ => None,
+    }
+}
+
+fn unicode_target(obj: &Object) -> Option<String> {
+    match obj {
+        Object::String(bytes, _) => utf16be(bytes),
+        Object::Name(name) => glyph_name_to_char(&String::from_utf8_lossy(name)).map(String::from),
+        _ => None,
+    }
+}
+
+fn utf16be(bytes: &[u8]) -> Option<String> {
+    let units: Vec<u16> = bytes.chunks(2).map(|c| be_int(c) as u16).collect();
+    String::from_utf16(&units).ok()
+}
+
+/// bfrange destinations count up in their last UTF-16 unit.
+fn increment_utf16(start: &[u8], by: u16) -> Option<String> {
+    let mut units: Vec<u16> = start.chunks(2).map(|c| be_int(c) as u16).collect();
+    let last = units.last_mut()?;
+    *last = last.wrapping_add(by);
+    String::from_utf16(&units).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_glyph_names() {
+        assert_eq!(glyph_name_to_char("A"), Some('A'));
+        assert_eq!(glyph_name_to_char("a.sc"), Some('a'));
+        assert_eq!(glyph_name_to_char("uni00E9"), Some('é'));
+        assert_eq!(glyph_name_to_char("u1D400"), Some('𝐀'));
+        assert_eq!(glyph_name_to_char("f_i"), Some('\u{FB01}'));
+        assert_eq!(glyph_name_to_char("T_h"), Some('T'));
+        assert_eq!(glyph_name_to_char("g123"), None);
+        // Non-ASCII after the prefix must not split a character
+        assert_eq!(glyph_name_to_char("uniÄ…"), None);
+        assert_eq!(glyph_name_to_char("uÄ…"), None);
+    }
+
+    #[test]
+    fn parses_cmaps() {
+        let cmap = CMap::parse(
+            b"/CIDInit /ProcSet findresource begin 12 dict begin begincmap\n\
+              1 begincodespacerange <0000> <FFFF> endcodespacerange\n\
+              2 beginbfchar <0003> <0020> <0010> <00660069> endbfchar\n\
+              2 beginbfrange <0020> <0022> <0041> <0030> <0031> [<0078> /eacute] endbfrange\n\
+              1 begincidrange <0100> <0102> 7 endcidrange\n\
+              1 begincidchar <0200> 42 endcidchar\n\
+              endcmap CMapName currentdict /CMap defineresource pop end end",
+        );
+        assert_eq!(cmap.code_space, [(0, 0xFFFF, 2)]);
+        let unicode = |code| cmap.unicode.get(&code).map(String::as_str);
+        assert_eq!(unicode(0x03), Some(" "));
+        assert_eq!(unicode(0x10), Some("fi"));
+        assert_eq!((unicode(0x20), unicode(0x22)), (Some("A"), Some("C")));
+        assert_eq!((unicode(0x30), unicode(0x31)), (Some("x"), Some("é")));
+        assert_eq!((cmap.cids.get(&0x101), cmap.cids.get(&0x200)), (Some(&8), Some(&42)));
+    }
+
+    #[test]
+    fn splits_codes_by_code_space() {
+        let mut font = PdfFont::load(&LoDoc::new(), &Dictionary::new());
+        font.code_space = vec![(0x00, 0x80, 1), (0x8140, 0x9FFC, 2)];
+        assert_eq!(font.codes(&[0x41, 0x81, 0x40, 0x42]), [(0x41, 1), (0x8140, 2), (0x42, 1)]);
+    }
+}
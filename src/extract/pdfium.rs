@@ -0,0 +1,468 @@
+// pdfium extraction backend. Glyph boxes come from pdfium's text page,
+// which also decodes the fonts and inserts spaces between words. What
+// pdfium-render does not wrap (bookmarks' open state, the structure tree
+// and marked content) is read with the raw bindings on a second handle of
+// the document, opened when first needed; what pdfium does not read at all
+// (/Lang, the XMP packet, the role map) with lopdf, loaded once when first
+// needed.
+
+use super::Extractor;
+use crate::{metadata, security, structure, Error, Result};
+use crate::layout::Glyph;
+use crate::links::{Link, LinkTarget};
+use crate::metadata::{Date, Metadata};
+use crate::outline::{Destination, OutlineItem, View};
+use crate::structure::{FigureRegion, StructNode};
+use lopdf::Document as LoDoc;
+use pdfium_render::prelude::*;
+use std::cell::OnceCell;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+#[cfg(not(feature = "static"))]
+use std::path::PathBuf;
+
+pub struct PdfiumExtractor<'a> {
+    pdfium: &'a Pdfium,
+    doc: PdfDocument<'a>,
+    /// Second handle for the raw bindings, opened on first use; null if it
+    /// could not be opened
+    raw: OnceCell<FPDF_DOCUMENT>,
+    /// The structure tree's role map when the document is tagged
+    role_map: OnceCell<Option<HashMap<String, String>>>,
+    /// The document read with lopdf, `None` when lopdf cannot read it
+    lopdf: OnceCell<Option<LoDoc>>,
+    path: String,
+    password: Option<&'a str>,
+}
+
+/// Open `path` with pdfium, reporting a wrong or missing password as such.
+pub(crate) fn load_document<'a>(pdfium: &'a Pdfium, path: &str, password: Option<&'a str>) -> Result<PdfDocument<'a>> {
+    match pdfium.load_pdf_from_file(path, password) {
+        Ok(doc) => Ok(doc),
+        Err(PdfiumError::PdfiumLibraryInternalError(PdfiumInternalError::PasswordError)) => Err(Error::Password(path.to_string())),
+        Err(e) => Err(e.into()),
+    }
+}
+
+impl<'a> PdfiumExtractor<'a> {
+    pub fn open(pdfium: &'a Pdfium, path: &str, password: Option<&'a str>) -> Result<Self> {
+        let doc = load_document(pdfium, path, password)?;
+        Ok(PdfiumExtractor { pdfium, doc, raw: OnceCell::new(), role_map: OnceCell::new(), lopdf: OnceCell::new(), path: path.to_string(), password })
+    }
+
+    /// Use `doc`, the input already read with lopdf, instead of reading it
+    /// again when it is needed.
+    pub fn with_lopdf(mut self, doc: LoDoc) -> Self {
+        self.lopdf = OnceCell::from(Some(doc));
+        self
+    }
+
+    fn raw(&self) -> FPDF_DOCUMENT {
+        *self.raw.get_or_init(|| self.pdfium.bindings().FPDF_LoadDocument(&self.path, self.password))
+    }
+
+    /// The role map of a tagged document, `None` for untagged ones. pdfium
+    /// reports custom structure types unmapped, so the map is read with lopdf.
+    fn role_map(&self) -> Option<&HashMap<String, String>> {
+        self.role_map
+            .get_or_init(|| {
+                let raw = self.raw();
+                let tagged = !raw.is_null() && self.pdfium.bindings().FPDFCatalog_IsTagged(raw) != 0;
+                tagged.then(|| self.lopdf().map(structure::role_map).unwrap_or_default())
+            })
+            .as_ref()
+    }
+
+    /// The document read with lopdf, loaded on the first call.
+    fn lopdf(&self) -> Option<&LoDoc> {
+        self.lopdf.get_or_init(|| security::open(&self.path, self.password).ok().map(|(doc, _)| doc)).as_ref()
+    }
+
+    /// MCID of the text object of every character of page `index`.
+    fn marked_content(&self, index: usize) -> Vec<Option<i32>> {
+        let bindings = self.pdfium.bindings();
+        let page = bindings.FPDF_LoadPage(self.raw(), index as i32);
+        if page.is_null() {
+            return Vec::new();
+        }
+        let text = bindings.FPDFText_LoadPage(page);
+        let mcids = (0..bindings.FPDFText_CountChars(text))
+            .map(|i| {
+                let object = bindings.FPDFText_GetTextObject(text, i);
+                // Null for the spaces and line breaks pdfium generates
+                let mcid = if object.is_null() { -1 } else { bindings.FPDFPageObj_GetMarkedContentID(object) };
+                (mcid >= 0).then_some(mcid)
+            })
+            .collect();
+        bindings.FPDFText_ClosePage(text);
+        bindings.FPDF_ClosePage(page);
+        mcids
+    }
+
+    /// MCID of every page object of page `index`, in pdfium-render's order.
+    fn object_marked_content(&self, index: usize) -> Vec<Option<i32>> {
+        let bindings = self.pdfium.bindings();
+        let page = bindings.FPDF_LoadPage(self.raw(), index as i32);
+        if page.is_null() {
+            return Vec::new();
+        }
+        let mcids = (0..bindings.FPDFPage_CountObjects(page))
+            .map(|i| {
+                let mcid = bindings.FPDFPageObj_GetMarkedContentID(bindings.FPDFPage_GetObject(page, i));
+                (mcid >= 0).then_some(mcid)
+            })
+            .collect();
+        bindings.FPDF_ClosePage(page);
+        mcids
+    }
+}
+
+impl Drop for PdfiumExtractor<'_> {
+    fn drop(&mut self) {
+        if let Some(&raw) = self.raw.get()
+            && !raw.is_null()
+        {
+            self.pdfium.bindings().FPDF_CloseDocument(raw);
+        }
+    }
+}
+
+impl Extractor for PdfiumExtractor<'_> {
+    fn page_count(&self) -> usize {
+        self.doc.pages().len() as usize
+    }
+
+    fn page_glyphs(&self, index: usize) -> Result<Vec<Glyph>> {
+        let page = self.doc.pages().get(index as u16)?;
+        let tp = page.text()?;
+        let mcids = if self.role_map().is_some() { self.marked_content(index) } else { Vec::new() };
+        let mut glyphs = Vec::new();
+        for ch in tp.chars().iter() {
+            let c = ch.unicode_char();
+            let bbox = ch.loose_bounds()?;
+            let size = ch.scaled_font_size();
+            let font_name = ch.font_name();
+            let w = bbox.width().value;
+            glyphs.push(Glyph {
+                ch: c.unwrap_or('?'),
+                x: bbox.left().value,
+                y: bbox.bottom().value,
+                w,
+                size: size.value,
+                font: font_name,
+                mapped: c.is_some(),
+                mcid: mcids.get(ch.index()).copied().flatten(),
+            });
+        }
+        Ok(glyphs)
+    }
+
+    fn page_links(&self, index: usize) -> Result<Vec<Link>> {
+        let page = self.doc.pages().get(index as u16)?;
+        let mut links = Vec::new();
+        for link in page.links().iter() {
+            let Ok(rect) = link.rect() else { continue };
+            let target = match link.action() {
+                Some(PdfAction::Uri(action)) => action.uri().ok().map(LinkTarget::Uri),
+                Some(PdfAction::LocalDestination(action)) => action.destination().ok().as_ref().and_then(destination).map(LinkTarget::GoTo),
+                _ => link.destination().as_ref().and_then(destination).map(LinkTarget::GoTo),
+            };
+            if let Some(target) = target {
+                links.push(Link { rect: [rect.left().value, rect.bottom().value, rect.right().value, rect.top().value], target });
+            }
+        }
+        Ok(links)
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        // pdfium exposes neither /Lang nor the XMP packet; its Info tags are
+        // only used for documents lopdf cannot parse
+        if let Some(doc) = self.lopdf() {
+            return Ok(metadata::from_lopdf(doc));
+        }
+        let tags = self.doc.metadata();
+        let tag = |tag| tags.get(tag).map(|tag| tag.value().trim().to_string()).filter(|value| !value.is_empty());
+        Ok(Metadata {
+            title: tag(PdfDocumentMetadataTagType::Title),
+            author: tag(PdfDocumentMetadataTagType::Author),
+            subject: tag(PdfDocumentMetadataTagType::Subject),
+            keywords: tag(PdfDocumentMetadataTagType::Keywords),
+            creator: tag(PdfDocumentMetadataTagType::Creator),
+            producer: tag(PdfDocumentMetadataTagType::Producer),
+            creation_date: tag(PdfDocumentMetadataTagType::CreationDate).and_then(|date| Date::parse(&date)),
+            modification_date: tag(PdfDocumentMetadataTagType::ModificationDate).and_then(|date| Date::parse(&date)),
+            language: None,
+        })
+    }
+
+    fn outline(&self) -> Result<Vec<OutlineItem>> {
+        // pdfium-render hides the open state of bookmarks
+        let raw = self.raw();
+        if raw.is_null() {
+            return Ok(Vec::new());
+        }
+        Ok(read_bookmarks(self.pdfium.bindings(), raw, std::ptr::null_mut(), &mut HashSet::new()))
+    }
+
+    fn page_structure(&self, index: usize) -> Result<Vec<StructNode>> {
+        let Some(role_map) = self.role_map() else { return Ok(Vec::new()) };
+        let bindings = self.pdfium.bindings();
+        let page = bindings.FPDF_LoadPage(self.raw(), index as i32);
+        if page.is_null() {
+            return Ok(Vec::new());
+        }
+        let tree = bindings.FPDF_StructTree_GetForPage(page);
+        let mut nodes = Vec::new();
+        if !tree.is_null() {
+            for i in 0..bindings.FPDF_StructTree_CountChildren(tree) {
+                nodes.extend(struct_node(bindings, bindings.FPDF_StructTree_GetChildAtIndex(tree, i), role_map, 0));
+            }
+            bindings.FPDF_StructTree_Close(tree);
+        }
+        bindings.FPDF_ClosePage(page);
+        Ok(nodes)
+    }
+
+    fn page_figures(&self, index: usize) -> Result<Vec<FigureRegion>> {
+        let page = self.doc.pages().get(index as u16)?;
+        let mcids = if self.role_map().is_some() { self.object_marked_content(index) } else { Vec::new() };
+        let (width, height) = (page.width().value, page.height().value);
+        let mut figures = Vec::new();
+        for (i, object) in page.objects().iter().enumerate() {
+            if !is_figure(&object, 0) {
+                continue;
+            }
+            let Ok(bounds) = object.bounds() else { continue };
+            let rect = [bounds.left().value, bounds.bottom().value, bounds.right().value, bounds.top().value];
+            if super::figure_sized(&rect, width, height) {
+                figures.push(FigureRegion { rect, mcid: mcids.get(i).copied().flatten() });
+            }
+        }
+        Ok(figures)
+    }
+}
+
+/// Nesting limit for form XObjects.
+const MAX_FORM_DEPTH: usize = 16;
+
+/// Whether `object` is an image or a form painting something besides text.
+fn is_figure(object: &PdfPageObject, depth: usize) -> bool {
+    match object.object_type() {
+        PdfPageObjectType::Image => true,
+        PdfPageObjectType::XObjectForm if depth < MAX_FORM_DEPTH => object.as_x_object_form_object().is_some_and(|form| {
+            (0..form.len())
+                .filter_map(|i| form.get(i).ok())
+                .any(|inner| matches!(inner.object_type(), PdfPageObjectType::Path | PdfPageObjectType::Shading) || is_figure(&inner, depth + 1))
+        }),
+        _ => false,
+    }
+}
+
+/// Nesting limit for structure elements.
+const MAX_STRUCT_DEPTH: usize = 64;
+
+/// An element of a page's structure tree; `None` when it holds no content of the page.
+fn struct_node(bindings: &dyn PdfiumLibraryBindings, element: FPDF_STRUCTELEMENT, role_map: &HashMap<String, String>, depth: usize) -> Option<StructNode> {
+    if element.is_null() || depth > MAX_STRUCT_DEPTH {
+        return None;
+    }
+    let mcids: Vec<i32> = (0..bindings.FPDF_StructElement_GetMarkedContentIdCount(element))
+        .map(|i| bindings.FPDF_StructElement_GetMarkedContentIdAtIndex(element, i))
+        .filter(|&mcid| mcid >= 0)
+        .collect();
+    // Null children are marked content or object references
+    let children: Vec<StructNode> = (0..bindings.FPDF_StructElement_CountChildren(element))
+        .filter_map(|i| struct_node(bindings, bindings.FPDF_StructElement_GetChildAtIndex(element, i), role_map, depth + 1))
+        .collect();
+    if mcids.is_empty() && children.is_empty() {
+        return None;
+    }
+    let kind = utf16_string(|buffer, len| bindings.FPDF_StructElement_GetType(element, buffer, len));
+    let alt = Some(utf16_string(|buffer, len| bindings.FPDF_StructElement_GetAltText(element, buffer, len))).filter(|alt| !alt.trim().is_empty());
+    Some(StructNode { kind: structure::map_role(role_map, &kind), alt, mcids, children })
+}
+
+/// A UTF-16LE string pdfium writes with a terminating NUL, asked for its
+/// length in bytes first.
+fn utf16_string(read: impl Fn(*mut std::ffi::c_void, std::ffi::c_ulong) -> std::ffi::c_ulong) -> String {
+    let len = read(std::ptr::null_mut(), 0);
+    let mut buffer = vec![0u16; (len as usize).div_ceil(2)];
+    read(buffer.as_mut_ptr().cast(), len);
+    String::from_utf16_lossy(&buffer).trim_end_matches('\0').to_string()
+}
+
+/// A destination resolved by pdfium, also named ones.
+fn destination(dest: &PdfDestination) -> Option<Destination> {
+    let page = dest.page_index().ok()? as usize;
+    let view = match dest.view_settings().ok()? {
+        PdfDestinationViewSettings::SpecificCoordinatesAndZoom(left, top, zoom) => {
+            View::Xyz { left: left.map(|v| v.value), top: top.map(|v| v.value), zoom: zoom.filter(|z| *z != 0.0) }
+        }
+        PdfDestinationViewSettings::FitPageHorizontallyToWindow(top) => View::FitH { top: top.map(|v| v.value) },
+        PdfDestinationViewSettings::FitPageVerticallyToWindow(left) => View::FitV { left: left.map(|v| v.value) },
+        PdfDestinationViewSettings::FitPageToRectangle(rect) => {
+            View::FitR { left: rect.left().value, bottom: rect.bottom().value, right: rect.right().value, top: rect.top().value }
+        }
+        PdfDestinationViewSettings::FitBoundsToWindow => View::FitB,
+        PdfDestinationViewSettings::FitBoundsHorizontallyToWindow(top) => View::FitBH { top: top.map(|v| v.value) },
+        PdfDestinationViewSettings::FitBoundsVerticallyToWindow(left) => View::FitBV { left: left.map(|v| v.value) },
+        PdfDestinationViewSettings::FitPageToWindow | PdfDestinationViewSettings::Unknown => View::Fit,
+    };
+    Some(Destination { page, view })
+}
+
+/// Children of `parent` (the outline root when null). `seen` stops at loops in broken files.
+fn read_bookmarks(bindings: &dyn PdfiumLibraryBindings, doc: FPDF_DOCUMENT, parent: FPDF_BOOKMARK, seen: &mut HashSet<usize>) -> Vec<OutlineItem> {
+    let mut items = Vec::new();
+    let mut bookmark = bindings.FPDFBookmark_GetFirstChild(doc, parent);
+    while !bookmark.is_null() && seen.insert(bookmark as usize) {
+        items.push(OutlineItem {
+            title: bookmark_title(bindings, bookmark),
+            dest: bookmark_destination(bindings, doc, bookmark),
+            // Negative counts mark closed items
+            open: bindings.FPDFBookmark_GetCount(bookmark) > 0,
+            children: read_bookmarks(bindings, doc, bookmark, seen),
+        });
+        bookmark = bindings.FPDFBookmark_GetNextSibling(doc, bookmark);
+    }
+    items
+}
+
+fn bookmark_title(bindings: &dyn PdfiumLibraryBindings, bookmark: FPDF_BOOKMARK) -> String {
+    utf16_string(|buffer, len| bindings.FPDFBookmark_GetTitle(bookmark, buffer, len))
+}
+
+/// /Dest of the bookmark or the destination of its GoTo action.
+fn bookmark_destination(bindings: &dyn PdfiumLibraryBindings, doc: FPDF_DOCUMENT, bookmark: FPDF_BOOKMARK) -> Option<Destination> {
+    let dest = bindings.FPDFBookmark_GetDest(doc, bookmark);
+    if dest.is_null() {
+        return None;
+    }
+    let page = usize::try_from(bindings.FPDFDest_GetDestPageIndex(doc, dest)).ok()?;
+    let mut count = 0;
+    let mut params = [0.0f32; 4];
+    let mode = bindings.FPDFDest_GetView(dest, &mut count, params.as_mut_ptr());
+    let param = |i: usize| (i < count as usize).then_some(params[i]);
+    // PDFDEST_VIEW_* of fpdf_doc.h
+    let view = match mode {
+        1 => {
+            let (mut has_x, mut has_y, mut has_zoom) = (0, 0, 0);
+            let (mut x, mut y, mut zoom) = (0.0, 0.0, 0.0);
+            bindings.FPDFDest_GetLocationInPage(dest, &mut has_x, &mut has_y, &mut has_zoom, &mut x, &mut y, &mut zoom);
+            View::Xyz {
+                left: (has_x != 0).then_some(x),
+                top: (has_y != 0).then_some(y),
+                zoom: (has_zoom != 0 && zoom != 0.0).then_some(zoom),
+            }
+        }
+        3 => View::FitH { top: param(0) },
+        4 => View::FitV { left: param(0) },
+        5 => View::FitR { left: params[0], bottom: params[1], right: params[2], top: params[3] },
+        6 => View::FitB,
+        7 => View::FitBH { top: param(0) },
+        8 => View::FitBV { left: param(0) },
+        _ => View::Fit,
+    };
+    Some(Destination { page, view })
+}
+
+// ========== Library discovery ==========
+//
+// libpdfium is looked for in order: the path given by the caller (`--pdfium-lib`),
+// `PDFIUM_LIB_PATH`, the directory of the executable, the working directory,
+// `lib/` and finally the system loader path. Each of the first may name the
+// library file or the directory holding it. An explicit path is not fallen
+// back from. With the `static` feature the library is linked in instead.
+
+/// Environment variable naming libpdfium or its directory.
+pub const PDFIUM_LIB_ENV: &str = "PDFIUM_LIB_PATH";
+
+/// Bind to libpdfium, see the search order above. The error lists every
+/// location tried and why it failed.
+#[cfg(not(feature = "static"))]
+pub fn load_pdfium(explicit: Option<&Path>) -> Result<Pdfium> {
+    let mut tried = Vec::new();
+    let candidates: Vec<PathBuf> = match explicit {
+        Some(path) => vec![library_path(path)],
+        None => {
+            let mut paths: Vec<PathBuf> = std::env::var_os(PDFIUM_LIB_ENV).map(|p| library_path(Path::new(&p))).into_iter().collect();
+            let mut dirs: Vec<PathBuf> = std::env::current_exe().ok().and_then(|exe| exe.parent().map(Path::to_path_buf)).into_iter().collect();
+            dirs.extend([PathBuf::from("."), PathBuf::from("lib")]);
+            paths.extend(dirs.iter().map(Pdfium::pdfium_platform_library_name_at_path));
+            paths
+        }
+    };
+    for path in candidates {
+        if !path.is_file() {
+            tried.push(format!("{}: not found", path.display()));
+            continue;
+        }
+        match Pdfium::bind_to_library(&path) {
+            Ok(bindings) => return checked(Pdfium::new(bindings), Some(&path)),
+            Err(e) => tried.push(format!("{}: {}", path.display(), e)),
+        }
+    }
+    if explicit.is_none() {
+        match Pdfium::bind_to_system_library() {
+            Ok(bindings) => return checked(Pdfium::new(bindings), None),
+            Err(e) => tried.push(format!("system library {}: {}", Pdfium::pdfium_platform_library_name().to_string_lossy(), e)),
+        }
+    }
+    Err(Error::PdfiumNotFound(tried))
+}
+
+/// Use the libpdfium linked into the executable.
+#[cfg(feature = "static")]
+pub fn load_pdfium(_explicit: Option<&Path>) -> Result<Pdfium> {
+    Ok(Pdfium::new(Pdfium::bind_to_statically_linked_library()?))
+}
+
+/// `path` itself if it names a file, else the platform library name inside it.
+#[cfg(not(feature = "static"))]
+fn library_path(path: &Path) -> PathBuf {
+    if path.is_dir() { Pdfium::pdfium_platform_library_name_at_path(path) } else { path.to_path_buf() }
+}
+
+/// Reject a library older than the pdfium API this crate was built against.
+/// The build number comes from the `VERSION` file pdfium-binaries ships next
+/// to `lib/`; without one (e.g. a system library) the check is skipped.
+#[cfg(not(feature = "static"))]
+fn checked(pdfium: Pdfium, path: Option<&Path>) -> Result<Pdfium> {
+    let (Some(expected), Some(path)) = (api_build(pdfium.bindings().version()), path) else { return Ok(pdfium) };
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let version = [dir.join("VERSION"), dir.join("../VERSION")].into_iter().find_map(|file| std::fs::read_to_string(file).ok());
+    let found = version.as_deref().and_then(|v| v.lines().find_map(|line| line.strip_prefix("BUILD=")?.trim().parse::<u32>().ok()));
+    match found {
+        Some(found) if found < expected => Err(Error::PdfiumVersion { path: path.display().to_string(), found, expected }),
+        _ => Ok(pdfium),
+    }
+}
+
+/// pdfium build number of an API version; `None` for changes not released yet.
+#[cfg(not(feature = "static"))]
+fn api_build(version: version::PdfiumApiVersion) -> Option<u32> {
+    use version::PdfiumApiVersion::*;
+    Some(match version {
+        Future => return None,
+        V7215 => 7215,
+        V7123 => 7123,
+        V6996 => 6996,
+        V6721 => 6721,
+        V6666 => 6666,
+        V6611 => 6611,
+        V6569 => 6569,
+        V6555 => 6555,
+        V6490 => 6490,
+        V6406 => 6406,
+        V6337 => 6337,
+        V6295 => 6295,
+        V6259 => 6259,
+        V6164 => 6164,
+        V6124 => 6124,
+        V6110 => 6110,
+        V6084 => 6084,
+        V6043 => 6043,
+        V6015 => 6015,
+        V5961 => 5961,
+    })
+}
@@ -0,0 +1,202 @@
+// ========== Text fidelity check ==========
+//
+// Compares the text extracted from the input with the text re-extracted from
+// the output through the same `extract_lines` path, page by page.
+
+use crate::Line;
+use crate::fonts::FontRegistry;
+use rustybuzz::Face;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Plain text of one page, captured before the lines are handed to the renderer.
+pub struct PageText {
+    pub lines: Vec<String>,
+    /// Glyphs pdfium could not map to Unicode (rendered as '?')
+    pub unmapped: usize,
+}
+
+pub fn page_texts(pages: &[Vec<Line>]) -> Vec<PageText> {
+    pages
+        .iter()
+        .map(|lines| PageText {
+            lines: lines.iter().map(|l| normalize_ws(&l.glyphs.iter().map(|g| g.ch).collect::<String>())).collect(),
+            unmapped: lines.iter().flat_map(|l| &l.glyphs).filter(|g| !g.mapped).count(),
+        })
+        .collect()
+}
+
+pub struct PageFidelity {
+    pub page: usize,
+    pub input_chars: usize,
+    pub edit_distance: usize,
+    pub missing: CharCounts,
+    pub extra: CharCounts,
+    pub reordered_lines: usize,
+    pub unmapped: usize,
+    /// Input characters no font of the substitute chain has a glyph for
+    pub not_in_font: Vec<char>,
+}
+
+impl PageFidelity {
+    pub fn is_clean(&self) -> bool {
+        self.edit_distance == 0 && self.unmapped == 0 && self.not_in_font.is_empty()
+    }
+}
+
+pub struct FidelityReport {
+    pub pages: Vec<PageFidelity>,
+    pub page_counts: (usize, usize),
+}
+
+impl FidelityReport {
+    pub fn is_clean(&self) -> bool {
+        self.page_counts.0 == self.page_counts.1 && self.pages.iter().all(PageFidelity::is_clean)
+    }
+}
+
+impl fmt::Display for FidelityReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.page_counts.0 != self.page_counts.1 {
+            writeln!(f, "page count differs: {} in input, {} in output", self.page_counts.0, self.page_counts.1)?;
+        }
+        for p in &self.pages {
+            let accuracy = 100.0 * (1.0 - p.edit_distance as f64 / p.input_chars.max(1) as f64);
+            writeln!(
+                f,
+                "page {:>3}  edit distance {:>4} / {:>5} chars ({:.2}%)  reordered lines {}  unmapped {}",
+                p.page, p.edit_distance, p.input_chars, accuracy.max(0.0), p.reordered_lines, p.unmapped
+            )?;
+            if !p.missing.is_empty() {
+                writeln!(f, "          missing: {}", format_counts(&p.missing))?;
+            }
+            if !p.extra.is_empty() {
+                writeln!(f, "          extra:   {}", format_counts(&p.extra))?;
+            }
+            if !p.not_in_font.is_empty() {
+                writeln!(f, "          not in font: {}", p.not_in_font.iter().map(|c| format!("{:?} U+{:04X}", c, *c as u32)).collect::<Vec<_>>().join(", "))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Characters with how often they occur.
+type CharCounts = Vec<(char, usize)>;
+
+fn format_counts(counts: &[(char, usize)]) -> String {
+    counts.iter().map(|(c, n)| format!("{:?}x{}", c, n)).collect::<Vec<_>>().join(" ")
+}
+
+pub fn check(expected: &[PageText], actual: &[PageText], fonts: &FontRegistry) -> FidelityReport {
+    let faces: Vec<Face> = fonts.fonts().iter().filter_map(|f| Face::from_slice(&f.data, 0)).collect();
+    let mut pages = Vec::new();
+    for (index, (exp, act)) in expected.iter().zip(actual).enumerate() {
+        let exp_text = exp.lines.join("\n");
+        let act_text = act.lines.join("\n");
+        let (missing, extra) = char_balance(&exp_text, &act_text);
+        let mut not_in_font: Vec<char> = exp_text
+            .chars()
+            .filter(|c| !c.is_whitespace() && !c.is_control())
+            .filter(|c| faces.iter().all(|face| face.glyph_index(*c).is_none()))
+            .collect();
+        not_in_font.sort_unstable();
+        not_in_font.dedup();
+        pages.push(PageFidelity {
+            page: index + 1,
+            input_chars: exp_text.chars().count(),
+            edit_distance: levenshtein(&exp_text, &act_text),
+            missing,
+            extra,
+            reordered_lines: reordered_lines(&exp.lines, &act.lines),
+            unmapped: exp.unmapped,
+            not_in_font,
+        });
+    }
+    FidelityReport { pages, page_counts: (expected.len(), actual.len()) }
+}
+
+fn normalize_ws(s: &str) -> String {
+    s.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Character-level edit distance, two-row dynamic programming.
+fn levenshtein(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    let mut cur = vec![0; b.len() + 1];
+    for (i, ca) in a.iter().enumerate() {
+        cur[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let subst = prev[j] + usize::from(ca != cb);
+            cur[j + 1] = subst.min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        std::mem::swap(&mut prev, &mut cur);
+    }
+    prev[b.len()]
+}
+
+/// Per-character count differences, ignoring whitespace.
+fn char_balance(expected: &str, actual: &str) -> (CharCounts, CharCounts) {
+    let mut counts: BTreeMap<char, isize> = BTreeMap::new();
+    for c in expected.chars().filter(|c| !c.is_whitespace()) {
+        *counts.entry(c).or_default() += 1;
+    }
+    for c in actual.chars().filter(|c| !c.is_whitespace()) {
+        *counts.entry(c).or_default() -= 1;
+    }
+    let missing = counts.iter().filter(|(_, n)| **n > 0).map(|(c, n)| (*c, *n as usize)).collect();
+    let extra = counts.iter().filter(|(_, n)| **n < 0).map(|(c, n)| (*c, n.unsigned_abs())).collect();
+    (missing, extra)
+}
+
+/// Lines present on both sides but outside their longest common subsequence.
+fn reordered_lines(a: &[String], b: &[String]) -> usize {
+    let (n, m) = (a.len(), b.len());
+    let mut lcs = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if a[i] == b[j] { lcs[i + 1][j + 1] + 1 } else { lcs[i + 1][j].max(lcs[i][j + 1]) };
+        }
+    }
+    let common = a.iter().filter(|l| b.contains(l)).count();
+    common.saturating_sub(lcs[0][0])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(s: &str) -> Vec<String> {
+        s.split(',').map(String::from).collect()
+    }
+
+    #[test]
+    fn counts_edits_per_character() {
+        assert_eq!(levenshtein("kitten", "sitting"), 3);
+        assert_eq!(levenshtein("", "abc"), 3);
+        assert_eq!(levenshtein("abc", ""), 3);
+        assert_eq!(levenshtein("same", "same"), 0);
+        // Characters, not bytes
+        assert_eq!(levenshtein("façade", "facade"), 1);
+        assert_eq!(levenshtein("∑x", "x"), 1);
+    }
+
+    #[test]
+    fn balances_characters_ignoring_whitespace() {
+        let (missing, extra) = char_balance("a b\nbc", "ab  cx");
+        assert_eq!(missing, [('b', 1)]);
+        assert_eq!(extra, [('x', 1)]);
+        assert_eq!(normalize_ws("  a \t b\n"), "a b");
+    }
+
+    #[test]
+    fn counts_lines_out_of_order() {
+        assert_eq!(reordered_lines(&lines("a,b,c,d"), &lines("a,b,c,d")), 0);
+        assert_eq!(reordered_lines(&lines("a,b,c,d"), &lines("c,a,b,d")), 1);
+        assert_eq!(reordered_lines(&lines("a,b,c,d"), &lines("d,c,b,a")), 3);
+        // Lines missing on one side are not reordered
+        assert_eq!(reordered_lines(&lines("a,b,c"), &lines("a,x,c")), 0);
+    }
+}
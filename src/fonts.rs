@@ -0,0 +1,478 @@
+// ========== Font registry and cached shaping ==========
+//
+// Font files are read and parsed once per run. The registry owns the bytes,
+// a `Shaper` borrows them to build each rustybuzz face once and keeps an LRU
+// cache of shaped runs, so repeated lines (headers, footers, page numbers,
+// common words) are only shaped the first time. Pages are shaped in parallel
+// with one `Shaper` (and cache) per worker thread, kept by `Shapers` for the
+// whole run so the caches carry over from one batch to the next.
+
+use crate::{Error, Line, Result};
+use krilla::text::{Font, GlyphId, KrillaGlyph};
+use lru::LruCache;
+use rayon::prelude::*;
+use rustybuzz::{Face, Feature, UnicodeBuffer};
+use std::collections::BTreeSet;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex, PoisonError};
+use unicode_properties::emoji::{is_regional_indicator, is_tag_character, is_zwj};
+use unicode_properties::{GeneralCategoryGroup, UnicodeGeneralCategory};
+
+/// Candidate files for the primary text font, the first existing one is used.
+const PRIMARY_FONT_PATHS: [&str; 6] = [
+    "NewCM10-Regular.otf",
+    "/usr/share/fonts/truetype/ubuntu/Ubuntu-R.ttf",
+    "/usr/share/fonts/truetype/dejavu/DejaVuSerif.ttf",
+    "/System/Library/Fonts/Times.ttc",
+    "C:/Windows/Fonts/times.ttf",
+    "/usr/share/fonts/truetype/liberation/LiberationSerif-Regular.ttf",
+];
+
+/// Fallback fonts by category, tried in this order when the primary font has
+/// no glyph for a character. The first existing file of each category is used.
+const FALLBACK_FONT_PATHS: [(&str, &[&str]); 4] = [
+    ("math", &[
+        "NewCMMath-Regular.otf",
+        "/usr/share/fonts/opentype/stix/STIXTwoMath-Regular.otf",
+        "/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf",
+        "/System/Library/Fonts/Supplemental/STIXTwoMath.otf",
+        "C:/Windows/Fonts/cambria.ttc",
+    ]),
+    ("symbols", &[
+        "/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf",
+        "/usr/share/fonts/truetype/freefont/FreeSerif.ttf",
+        "/System/Library/Fonts/Apple Symbols.ttf",
+        "C:/Windows/Fonts/seguisym.ttf",
+    ]),
+    ("CJK", &[
+        "/usr/share/fonts/opentype/noto/NotoSerifCJK-Regular.ttc",
+        "/usr/share/fonts/opentype/noto/NotoSansCJK-Regular.ttc",
+        "/usr/share/fonts/truetype/droid/DroidSansFallbackFull.ttf",
+        "/System/Library/Fonts/PingFang.ttc",
+        "C:/Windows/Fonts/msyh.ttc",
+    ]),
+    ("emoji", &[
+        "/usr/share/fonts/truetype/noto/NotoColorEmoji.ttf",
+        "/System/Library/Fonts/Apple Color Emoji.ttc",
+        "C:/Windows/Fonts/seguiemj.ttf",
+    ]),
+];
+
+/// Number of shaped runs kept by default.
+pub const DEFAULT_SHAPE_CACHE_SIZE: usize = 4096;
+
+#[derive(Clone, Debug)]
+pub struct ShapeOptions {
+    /// OpenType features applied to every run, e.g. `-liga` or `onum`
+    pub features: Vec<Feature>,
+    /// Shaped runs cached per worker thread
+    pub cache_size: usize,
+}
+
+impl Default for ShapeOptions {
+    fn default() -> Self {
+        ShapeOptions { features: Vec::new(), cache_size: DEFAULT_SHAPE_CACHE_SIZE }
+    }
+}
+
+pub struct RegisteredFont {
+    /// "primary" or the fallback category
+    pub role: &'static str,
+    pub path: String,
+    pub font: Font,
+    /// The file's bytes, shared with `font`
+    pub data: Arc<Vec<u8>>,
+}
+
+/// Primary font followed by the fallback fonts that were found.
+pub struct FontRegistry {
+    fonts: Vec<RegisteredFont>,
+}
+
+impl FontRegistry {
+    /// Fails with `Error::NoFont` when none of the primary candidates exists.
+    pub fn load() -> Result<Self> {
+        let primary = PRIMARY_FONT_PATHS.iter().find_map(|path| Self::read(path, "primary")).ok_or(Error::NoFont)?;
+        let mut fonts = vec![primary];
+        for (role, paths) in FALLBACK_FONT_PATHS {
+            let found = paths
+                .iter()
+                .filter(|path| fonts.iter().all(|f| f.path != **path))
+                .find_map(|path| Self::read(path, role));
+            fonts.extend(found);
+        }
+        for font in &fonts {
+            log::debug!("{} font: {}", font.role, font.path);
+        }
+        Ok(FontRegistry { fonts })
+    }
+
+    fn read(path: &str, role: &'static str) -> Option<RegisteredFont> {
+        let data = Arc::new(std::fs::read(path).ok()?);
+        let font = Font::new(data.clone().into(), 0)?;
+        Some(RegisteredFont { role, path: path.to_string(), font, data })
+    }
+
+    pub fn primary(&self) -> &Font {
+        &self.fonts[0].font
+    }
+
+    pub fn fonts(&self) -> &[RegisteredFont] {
+        &self.fonts
+    }
+
+    pub fn font(&self, index: usize) -> &Font {
+        &self.fonts[index].font
+    }
+}
+
+/// Result of shaping one run of text with one font.
+pub struct ShapedText {
+    pub glyphs: Vec<KrillaGlyph>,
+    /// Characters of the clusters that produced glyph 0 (.notdef): the ones
+    /// the font lacks, or the whole cluster when it has them all
+    pub notdef: Vec<char>,
+    /// Total advance in em
+    pub width: f32,
+}
+
+/// A piece of a line shaped with one font of the registry.
+pub struct ShapedRun {
+    pub font: usize,
+    pub text: String,
+    pub shaped: Arc<ShapedText>,
+    /// Horizontal start of the run relative to the line, in em
+    pub offset: f32,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ShapeCacheStats {
+    pub hits: usize,
+    pub misses: usize,
+}
+
+impl ShapeCacheStats {
+    fn since(self, earlier: ShapeCacheStats) -> ShapeCacheStats {
+        ShapeCacheStats { hits: self.hits - earlier.hits, misses: self.misses - earlier.misses }
+    }
+
+    fn add(mut self, other: ShapeCacheStats) -> ShapeCacheStats {
+        self.hits += other.hits;
+        self.misses += other.misses;
+        self
+    }
+}
+
+/// Runs of every line, page by page, in input order.
+pub struct ShapedPages {
+    pub pages: Vec<Vec<Vec<ShapedRun>>>,
+    /// Characters no font could render
+    pub missing: BTreeSet<char>,
+    pub stats: ShapeCacheStats,
+}
+
+/// One `Shaper` per worker thread of the rayon pool it was created on.
+pub struct Shapers<'a> {
+    shapers: Vec<Mutex<Shaper<'a>>>,
+}
+
+impl<'a> Shapers<'a> {
+    pub fn new(fonts: &'a FontRegistry, opts: &ShapeOptions) -> Self {
+        let shapers = (0..rayon::current_num_threads()).map(|_| Mutex::new(Shaper::new(fonts, opts.features.clone(), opts.cache_size))).collect();
+        Shapers { shapers }
+    }
+
+    /// Shape all pages on the current rayon pool. Output order follows the input.
+    pub fn shape_pages(&self, pages: &[Vec<Line>]) -> ShapedPages {
+        let shaped: Vec<_> = pages
+            .par_iter()
+            .map(|lines| {
+                // Each worker only locks its own shaper, so the lock is never contended
+                let index = rayon::current_thread_index().unwrap_or(0) % self.shapers.len();
+                let mut shaper = self.shapers[index].lock().unwrap_or_else(PoisonError::into_inner);
+                let before = shaper.stats();
+                let mut missing = BTreeSet::new();
+                let runs: Vec<Vec<ShapedRun>> = lines.iter().map(|line| shaper.shape_line_runs(line, &mut missing)).collect();
+                (runs, missing, shaper.stats().since(before))
+            })
+            .collect();
+        let mut out = ShapedPages { pages: Vec::with_capacity(shaped.len()), missing: BTreeSet::new(), stats: ShapeCacheStats::default() };
+        for (runs, missing, stats) in shaped {
+            out.pages.push(runs);
+            out.missing.extend(missing);
+            out.stats = out.stats.add(stats);
+        }
+        out
+    }
+}
+
+/// A shaper's features never change, so the font and text identify a run.
+#[derive(Hash, PartialEq, Eq)]
+struct RunKey {
+    font: usize,
+    text: String,
+}
+
+pub struct Shaper<'a> {
+    faces: Vec<Option<Face<'a>>>,
+    features: Vec<Feature>,
+    cache: LruCache<RunKey, Arc<ShapedText>>,
+    stats: ShapeCacheStats,
+}
+
+impl<'a> Shaper<'a> {
+    pub fn new(registry: &'a FontRegistry, features: Vec<Feature>, cache_size: usize) -> Self {
+        Shaper {
+            faces: registry.fonts.iter().map(|f| Face::from_slice(&f.data, 0)).collect(),
+            features,
+            cache: LruCache::new(NonZeroUsize::new(cache_size).unwrap_or(NonZeroUsize::MIN)),
+            stats: ShapeCacheStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> ShapeCacheStats {
+        self.stats
+    }
+
+    fn covers(&self, font: usize, c: char) -> bool {
+        self.faces[font].as_ref().is_some_and(|f| f.glyph_index(c).is_some())
+    }
+
+    /// Index of the first font with a glyph for `c`, the one it is shaped with.
+    pub fn font_for(&self, c: char) -> Option<usize> {
+        (0..self.faces.len()).find(|&i| self.covers(i, c))
+    }
+
+    /// Shape `text` with one font, answering from the cache when possible.
+    pub fn shape(&mut self, font: usize, text: &str) -> Option<Arc<ShapedText>> {
+        let key = RunKey { font, text: text.to_string() };
+        if let Some(hit) = self.cache.get(&key) {
+            self.stats.hits += 1;
+            return Some(hit.clone());
+        }
+        self.stats.misses += 1;
+        let shaped = Arc::new(shape_with_rustybuzz(self.faces[font].as_ref()?, &self.features, text));
+        self.cache.put(key, shaped.clone());
+        Some(shaped)
+    }
+
+    /// Index of the first font from `first` on with a glyph for every
+    /// character of `cluster`; joiners and selectors need none.
+    fn font_for_cluster(&self, cluster: &str, first: usize) -> Option<usize> {
+        (first..self.faces.len()).find(|&i| cluster.chars().filter(|&c| !is_invisible(c)).all(|c| self.covers(i, c)))
+    }
+
+    /// Font of every cluster: the first from `first[i]` on covering all of
+    /// it. Whitespace and clusters no font covers stay with the run before.
+    fn cluster_fonts(&self, clusters: &[&str], first: &[usize]) -> Vec<usize> {
+        let mut fonts: Vec<usize> = Vec::with_capacity(clusters.len());
+        for (cluster, &from) in clusters.iter().zip(first) {
+            let blank = cluster.chars().all(char::is_whitespace);
+            let font = if blank { None } else { self.font_for_cluster(cluster, from) };
+            fonts.push(font.or(fonts.last().copied()).unwrap_or(0));
+        }
+        fonts
+    }
+
+    /// Split a line into grapheme clusters, give each the first font that
+    /// covers the whole cluster and shape the runs of clusters sharing a
+    /// font. Clusters that still come out as .notdef are tried again with the
+    /// fonts after theirs; characters no font renders are added to `missing`.
+    pub fn shape_line_runs(&mut self, line: &Line, missing: &mut BTreeSet<char>) -> Vec<ShapedRun> {
+        let text: String = line.glyphs.iter().map(|g| g.ch).collect();
+        let clusters = clusters(&text);
+        let mut first = vec![0; clusters.len()];
+        loop {
+            let fonts = self.cluster_fonts(&clusters, &first);
+            let mut runs = Vec::new();
+            let mut notdef = BTreeSet::new();
+            let mut retry = false;
+            let mut offset = 0.0;
+            let mut start = 0;
+            while start < clusters.len() {
+                let font = fonts[start];
+                let end = (start..clusters.len()).find(|&i| fonts[i] != font).unwrap_or(clusters.len());
+                let text = clusters[start..end].concat();
+                let run = start..end;
+                start = end;
+                let Some(shaped) = self.shape(font, &text) else {
+                    notdef.extend(text.chars().filter(|c| !c.is_whitespace()));
+                    continue;
+                };
+                for i in run {
+                    let failed = clusters[i].chars().any(|c| shaped.notdef.contains(&c));
+                    if failed && self.font_for_cluster(clusters[i], font + 1).is_some() {
+                        first[i] = font + 1;
+                        retry = true;
+                    }
+                }
+                notdef.extend(shaped.notdef.iter().copied());
+                let width = shaped.width;
+                runs.push(ShapedRun { font, text, shaped, offset });
+                offset += width;
+            }
+            // Every retry moves a cluster to a later font, so this ends
+            if !retry {
+                missing.extend(notdef);
+                return runs;
+            }
+        }
+    }
+}
+
+/// Joiners, variation selectors and tags, drawn without a glyph of their own.
+fn is_invisible(c: char) -> bool {
+    is_zwj(c) || is_tag_character(c) || matches!(c, '\u{FE00}'..='\u{FE0F}' | '\u{E0100}'..='\u{E01EF}')
+}
+
+/// Split `text` into grapheme clusters: a base character with the marks,
+/// selectors, emoji modifiers and joined characters that follow it, or a
+/// pair of regional indicators.
+fn clusters(text: &str) -> Vec<&str> {
+    let mut starts = Vec::new();
+    let mut prev: Option<char> = None;
+    // A regional indicator waiting for the second of its pair
+    let mut open_flag = false;
+    for (i, c) in text.char_indices() {
+        let pairs_flag = open_flag && is_regional_indicator(c);
+        let extends = prev.is_some()
+            && (pairs_flag
+                || prev.is_some_and(is_zwj)
+                || is_invisible(c)
+                || c.general_category_group() == GeneralCategoryGroup::Mark
+                || matches!(c, '\u{1F3FB}'..='\u{1F3FF}'));
+        if !extends {
+            starts.push(i);
+        }
+        open_flag = is_regional_indicator(c) && !pairs_flag;
+        prev = Some(c);
+    }
+    starts.push(text.len());
+    starts.windows(2).map(|w| &text[w[0]..w[1]]).collect()
+}
+
+/// Shape one run; also records the characters whose cluster produced glyph 0.
+fn shape_with_rustybuzz(face: &Face, features: &[Feature], text: &str) -> ShapedText {
+    let upem = face.units_per_em() as f32;
+    let mut buffer = UnicodeBuffer::new();
+    buffer.push_str(text);
+    let output = rustybuzz::shape(face, features, buffer);
+    // Clusters are byte offsets; a cluster ends where the next one starts
+    let mut boundaries: Vec<usize> = output.glyph_infos().iter().map(|i| i.cluster as usize).collect();
+    boundaries.push(text.len());
+    boundaries.sort_unstable();
+    boundaries.dedup();
+    let mut glyphs = Vec::new();
+    let mut notdef = Vec::new();
+    let mut width = 0.0;
+    for (info, pos) in output.glyph_infos().iter().zip(output.glyph_positions()) {
+        let gid = GlyphId::new(info.glyph_id);
+        let adv = pos.x_advance as f32 / upem;
+        let dx = pos.x_offset as f32 / upem;
+        let start = info.cluster as usize;
+        let end = boundaries.iter().copied().find(|&b| b > start).unwrap_or(text.len());
+        if info.glyph_id == 0 {
+            // The characters of the cluster the font lacks, or all of them
+            let chars = text[start..end].chars().filter(|c| !c.is_whitespace() && !is_invisible(*c));
+            let lacking: Vec<char> = chars.clone().filter(|&c| face.glyph_index(c).is_none()).collect();
+            notdef.extend(if lacking.is_empty() { chars.collect() } else { lacking });
+        }
+        width += adv;
+        glyphs.push(KrillaGlyph::new(gid, adv, dx, 0.0, 0.0, start..end, None));
+    }
+    ShapedText { glyphs, notdef, width }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::Glyph;
+
+    /// A registry of `paths`, the first one primary; fails when one is missing.
+    fn registry(paths: &[&str]) -> FontRegistry {
+        let fonts = paths.iter().map(|path| FontRegistry::read(path, "test").unwrap_or_else(|| panic!("cannot read font {}", path))).collect();
+        FontRegistry { fonts }
+    }
+
+    fn line(text: &str) -> Line {
+        let glyph = |ch| Glyph { ch, x: 0.0, y: 0.0, w: 5.0, size: 10.0, font: "F".into(), mapped: true, mcid: None };
+        Line { glyphs: text.chars().map(glyph).collect(), y: 0.0, font: "F".into(), size: 10.0, tag: None, region: None }
+    }
+
+    #[test]
+    fn splits_text_into_grapheme_clusters() {
+        let text = "e\u{301}x \u{1F1E9}\u{1F1EA}\u{1F1EB}\u{1F44D}\u{1F3FD}a\u{200D}b\u{2764}\u{FE0F}";
+        let expected = ["e\u{301}", "x", " ", "\u{1F1E9}\u{1F1EA}", "\u{1F1EB}", "\u{1F44D}\u{1F3FD}", "a\u{200D}b", "\u{2764}\u{FE0F}"];
+        assert_eq!(clusters(text), expected);
+    }
+
+    #[test]
+    fn keeps_marks_in_the_font_of_their_base() {
+        // NewCM has no combining right arrow above (U+20D7) or ≠, DejaVu Sans has both
+        let registry = registry(&["NewCM10-Regular.otf", "/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf"]);
+        let mut shaper = Shaper::new(&registry, Vec::new(), 8);
+        let mut missing = BTreeSet::new();
+        let runs = shaper.shape_line_runs(&line("v x\u{20D7} \u{2260} w"), &mut missing);
+        let split: Vec<(usize, &str)> = runs.iter().map(|run| (run.font, run.text.as_str())).collect();
+        assert_eq!(split, [(0, "v "), (1, "x\u{20D7} \u{2260} "), (0, "w")]);
+        assert!(missing.is_empty());
+        assert!(runs.iter().all(|run| run.shaped.notdef.is_empty()));
+        assert_eq!(runs[1].offset, runs[0].shaped.width);
+    }
+
+    #[test]
+    fn reports_characters_no_font_renders() {
+        // Neither font has the combining enclosing circle (U+20DD)
+        let registry = registry(&["NewCM10-Regular.otf", "/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf"]);
+        let mut shaper = Shaper::new(&registry, Vec::new(), 8);
+        let mut missing = BTreeSet::new();
+        let runs = shaper.shape_line_runs(&line("a\u{20DD}b \u{2260}"), &mut missing);
+        let split: Vec<(usize, &str)> = runs.iter().map(|run| (run.font, run.text.as_str())).collect();
+        assert_eq!(split, [(0, "a\u{20DD}b "), (1, "\u{2260}")]);
+        assert_eq!(missing, BTreeSet::from(['\u{20DD}']));
+    }
+
+    #[test]
+    fn shares_the_font_bytes_with_krilla() {
+        let registry = registry(&["NewCM10-Regular.otf"]);
+        // One reference in the registry, the others held by the krilla font
+        assert!(Arc::strong_count(&registry.fonts()[0].data) > 1);
+    }
+
+    #[test]
+    fn repeated_runs_are_answered_from_the_cache() {
+        let registry = registry(&["NewCM10-Regular.otf"]);
+        let mut shaper = Shaper::new(&registry, Vec::new(), 2);
+        let first = shaper.shape(0, "Page 1").unwrap();
+        let again = shaper.shape(0, "Page 1").unwrap();
+        assert!(Arc::ptr_eq(&first, &again));
+        assert_eq!((shaper.stats().hits, shaper.stats().misses), (1, 1));
+
+        // The least recently used run is evicted once the cache is full
+        shaper.shape(0, "Page 2");
+        shaper.shape(0, "Page 3");
+        shaper.shape(0, "Page 1");
+        assert_eq!((shaper.stats().hits, shaper.stats().misses), (1, 4));
+    }
+
+    type RunDescription = (usize, String, f32, Vec<u32>);
+
+    /// Font, text, offset and glyphs of every run, line by line and page by page.
+    fn describe(shaped: &ShapedPages) -> Vec<Vec<Vec<RunDescription>>> {
+        let run = |run: &ShapedRun| (run.font, run.text.clone(), run.offset, run.shaped.glyphs.iter().map(|g| g.glyph_id.to_u32()).collect());
+        shaped.pages.iter().map(|lines| lines.iter().map(|runs| runs.iter().map(run).collect()).collect()).collect()
+    }
+
+    #[test]
+    fn shapes_pages_alike_on_any_number_of_threads() {
+        let registry = registry(&["NewCM10-Regular.otf", "/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf"]);
+        let page = |p: usize| (0..p % 5 + 1).map(|l| line(&format!("Page {} line {} \u{2260} {}", p, l, p * l))).collect();
+        let pages: Vec<Vec<Line>> = (0..24).map(page).collect();
+        let shape = |threads| {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+            let shaped = pool.install(|| Shapers::new(&registry, &ShapeOptions::default()).shape_pages(&pages));
+            (describe(&shaped), shaped.missing, shaped.stats.hits + shaped.stats.misses)
+        };
+        assert_eq!(shape(1), shape(4));
+    }
+}
@@ -0,0 +1,99 @@
+// ========== Document inspection ==========
+//
+// What a document holds, without converting it: page count, version,
+// encryption, whether it is tagged, its metadata, outline and links. No
+// glyphs are extracted; the extractor only reads the document-level data
+// and the page annotations.
+
+use crate::extract::{with_loaded_extractor, OpenOptions};
+use crate::metadata::Metadata;
+use crate::outline::OutlineItem;
+use crate::security::{self, Security};
+use crate::{Error, Result};
+use std::fmt;
+
+pub struct DocumentInfo {
+    pub pages: usize,
+    /// Header version, `None` when lopdf cannot parse the file
+    pub version: Option<String>,
+    pub security: Option<Security>,
+    /// Whether the catalog has a structure tree
+    pub tagged: bool,
+    pub metadata: Metadata,
+    /// Bookmarks at any depth
+    pub bookmarks: usize,
+    /// Link annotations on all pages
+    pub links: usize,
+}
+
+/// Inspect `path`, opened as `opts` says; the page selection is ignored.
+pub fn inspect(path: &str, opts: &OpenOptions) -> Result<DocumentInfo> {
+    // Read once; the extractor gets the document for metadata and role map
+    let (doc, version, security, tagged) = match security::open(path, opts.password.as_deref()) {
+        Ok((doc, security)) => {
+            let tagged = doc.catalog().is_ok_and(|catalog| catalog.has(b"StructTreeRoot"));
+            let version = doc.version.clone();
+            (Some(doc), Some(version), security, tagged)
+        }
+        Err(e @ Error::Password(_)) => return Err(e),
+        // Damaged files may still open with pdfium
+        Err(_) => (None, None, None, false),
+    };
+    with_loaded_extractor(path, opts, doc, |extractor| {
+        let pages = extractor.page_count();
+        let mut links = 0;
+        for page in 0..pages {
+            links += extractor.page_links(page)?.len();
+        }
+        Ok(DocumentInfo {
+            pages,
+            version,
+            security,
+            tagged,
+            metadata: extractor.metadata()?,
+            bookmarks: count_items(&extractor.outline()?),
+            links,
+        })
+    })
+}
+
+fn count_items(items: &[OutlineItem]) -> usize {
+    items.iter().map(|item| 1 + count_items(&item.children)).sum()
+}
+
+impl fmt::Display for DocumentInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "pages        {}", self.pages)?;
+        if let Some(version) = &self.version {
+            writeln!(f, "version      {}", version)?;
+        }
+        match &self.security {
+            Some(security) => writeln!(f, "encryption   {}", security)?,
+            None => writeln!(f, "encryption   none")?,
+        }
+        writeln!(f, "tagged       {}", if self.tagged { "yes" } else { "no" })?;
+        let m = &self.metadata;
+        let fields = [
+            ("title", &m.title),
+            ("author", &m.author),
+            ("subject", &m.subject),
+            ("keywords", &m.keywords),
+            ("creator", &m.creator),
+            ("producer", &m.producer),
+            ("language", &m.language),
+        ];
+        for (name, value) in fields {
+            if let Some(value) = value {
+                writeln!(f, "{:<12} {}", name, value)?;
+            }
+        }
+        if let Some(date) = m.creation_date {
+            writeln!(f, "created      {}", date)?;
+        }
+        if let Some(date) = m.modification_date {
+            writeln!(f, "modified     {}", date)?;
+        }
+        writeln!(f, "bookmarks    {}", self.bookmarks)?;
+        writeln!(f, "links        {}", self.links)
+    }
+}
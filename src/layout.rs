@@ -0,0 +1,54 @@
+// ========== Layout analysis ==========
+//
+// The document model shared by every stage: glyphs as the extractor reports
+// them and the lines they are grouped into.
+
+use crate::structure::{Region, SourceTag};
+
+#[derive(Clone)]
+pub struct Glyph {
+    pub ch: char,
+    pub x: f32,
+    pub y: f32,
+    pub w: f32,
+    pub size: f32,
+    pub font: String,
+    /// False when pdfium had no Unicode value and `ch` is the '?' placeholder
+    pub mapped: bool,
+    /// Marked content identifier of the page content the glyph is drawn in
+    pub mcid: Option<i32>,
+}
+
+/// Never empty: `group_lines` only returns lines with at least one glyph.
+pub struct Line {
+    pub glyphs: Vec<Glyph>,
+    pub y: f32,
+    pub font: String,
+    pub size: f32,
+    /// The structure element of a tagged input the line belongs to
+    pub tag: Option<SourceTag>,
+    /// The figure or table cell the line lies in, for a tagged output
+    pub region: Option<Region>,
+}
+
+/// Bucket the glyphs of one page into lines, top to bottom, each sorted left
+/// to right. Lines made only of control characters are dropped.
+pub fn group_lines(mut glyphs: Vec<Glyph>) -> Vec<Line> {
+    glyphs.sort_by(|a, b| b.y.total_cmp(&a.y));
+    let mut lines: Vec<Vec<Glyph>> = Vec::new();
+    for g in glyphs {
+        if let Some(bucket) = lines.iter_mut().find(|bucket| (bucket[0].y - g.y).abs() < g.size * 0.4) {
+            bucket.push(g);
+        } else {
+            lines.push(vec![g]);
+        }
+    }
+    lines.into_iter().filter_map(|mut gs| {
+        gs.sort_by(|a, b| a.x.total_cmp(&b.x));
+        let font = gs[0].font.clone();
+        let size = gs[0].size;
+        let y = gs[0].y + gs[0].size * 0.22;
+        let glyphs: Vec<Glyph> = gs.into_iter().filter(|g| !g.ch.is_control()).collect();
+        (!glyphs.is_empty()).then_some(Line { glyphs, y, font, size, tag: None, region: None })
+    }).collect()
+}
@@ -0,0 +1,147 @@
+//! Re-typesets PDF documents the way Typst writes them.
+//!
+//! The conversion runs in three stages that can also be used on their own:
+//! [`extract`] reads positioned glyphs (pdfium or lopdf backend), [`layout`] groups them
+//! into lines, [`render`] writes the lines with pdf-writer (in Typst's content
+//! stream structure by default). [`convert`] chains them.
+
+pub mod batch;
+pub mod compare;
+pub mod diff;
+pub mod error;
+pub mod extract;
+pub mod fidelity;
+pub mod fonts;
+pub mod inspect;
+pub mod layout;
+pub mod links;
+pub mod metadata;
+pub mod optimize;
+pub mod outline;
+pub mod pdfa;
+pub mod render;
+pub mod security;
+pub mod structure;
+#[cfg(test)]
+mod testing;
+
+pub use error::{Error, Result};
+pub use extract::{extract_lines, load_pdfium, read_lines, with_extractor, with_loaded_extractor, Backend, Extractor, LopdfExtractor, OpenOptions, PageReader, PageSelection, PdfiumExtractor, PDFIUM_LIB_ENV};
+pub use fonts::{FontRegistry, ShapeOptions};
+pub use layout::{group_lines, Glyph, Line};
+pub use metadata::{Metadata, MetadataOptions};
+pub use optimize::OptimizeOptions;
+pub use pdfa::{PdfA, PdfAReport};
+pub use render::{create_renderer, render_like_typst, PassthroughRenderer, PdfWriterRenderer, RenderStats, Renderer, RendererKind, TypstWriter};
+pub use security::{Encryption, Security};
+pub use structure::Tagging;
+
+#[derive(Clone, Debug, Default)]
+pub struct ConvertOptions {
+    /// Backend, password and pdfium library for reading the input
+    pub open: OpenOptions,
+    pub renderer: RendererKind,
+    pub optimize: OptimizeOptions,
+    /// OpenType features and cache size for shaping the text
+    pub shaping: ShapeOptions,
+    /// Pages extracted and drawn per batch; 0 means 4 per rayon worker
+    pub batch_pages: usize,
+    /// Encrypt the output like this: the input's [`Security::encryption`] or
+    /// a new [`Encryption::aes256`]
+    pub encryption: Option<Encryption>,
+    /// Overrides of the metadata carried over from the input
+    pub metadata: MetadataOptions,
+    /// Write a PDF/A file of this level and check the result
+    pub pdfa: Option<PdfA>,
+    /// Write a structure tree inferred from the lines (not with the
+    /// passthrough renderer, which keeps the input's)
+    pub tagging: Option<Tagging>,
+}
+
+/// Convert `input` into `output`.
+pub fn convert(input: &str, output: &str, fonts: &FontRegistry, opts: &ConvertOptions) -> Result<RenderStats> {
+    convert_with(input, output, fonts, opts, |_, _| {})
+}
+
+/// Like [`convert`], calling `on_batch` with the input page indices and the
+/// lines of every batch before it is drawn and dropped.
+pub fn convert_with(
+    input: &str,
+    output: &str,
+    fonts: &FontRegistry,
+    opts: &ConvertOptions,
+    on_batch: impl FnMut(&[usize], &[Vec<Line>]),
+) -> Result<RenderStats> {
+    convert_loaded(input, output, fonts, opts, None, on_batch)
+}
+
+/// Like [`convert_with`], with `doc` the input already read (and decrypted)
+/// with lopdf, e.g. to report its encryption, so it is not read again.
+pub fn convert_loaded(
+    input: &str,
+    output: &str,
+    fonts: &FontRegistry,
+    opts: &ConvertOptions,
+    doc: Option<lopdf::Document>,
+    mut on_batch: impl FnMut(&[usize], &[Vec<Line>]),
+) -> Result<RenderStats> {
+    if opts.tagging.is_some() && opts.renderer == RendererKind::Passthrough {
+        return Err(Error::Render("the passthrough renderer keeps the input's structure and cannot tag the output".to_string()));
+    }
+    let mut renderer = create_renderer(opts.renderer, fonts, input, opts.open.password.as_deref(), output, &opts.optimize, &opts.shaping);
+    if let Some(level) = opts.pdfa {
+        renderer.set_pdfa(level);
+    }
+    if let Some(encryption) = &opts.encryption {
+        renderer.set_encryption(encryption.clone());
+    }
+    if let Some(tagging) = opts.tagging {
+        renderer.set_tagging(tagging);
+    }
+    let batch_size = if opts.batch_pages == 0 { rayon::current_num_threads() * 4 } else { opts.batch_pages };
+    log::debug!("{} renderer, {} backend, {} pages per batch", opts.renderer, opts.open.backend, batch_size);
+    with_loaded_extractor(input, &opts.open, doc, |extractor| {
+        let mut reader = PageReader::with_selection(extractor, opts.open.pages.as_ref(), opts.open.skip_blank);
+        if opts.tagging.is_some() {
+            reader = reader.with_regions();
+        }
+        // Pages flow through in batches; each batch is dropped once it has been drawn
+        while let Some(pages) = reader.next_batch(batch_size)? {
+            let read = reader.input_pages();
+            log::debug!("drawing {} pages ({} of {} selected read)", pages.len(), read.len(), reader.page_count());
+            on_batch(&read[read.len() - pages.len()..], &pages);
+            renderer.add_pages(&pages)?;
+        }
+        // Destinations are renumbered to output pages once every page left out is known
+        let input_pages = reader.input_pages();
+        if input_pages.is_empty() {
+            return Err(Error::NoPages);
+        }
+        for (page, &input_page) in input_pages.iter().enumerate() {
+            renderer.add_links(page, links::renumber(extractor.page_links(input_page)?, input_pages));
+        }
+        let mut outline = extractor.outline()?;
+        outline::renumber(&mut outline, input_pages);
+        renderer.set_outline(outline);
+        renderer.select_pages(input_pages);
+        let mut metadata = extractor.metadata()?;
+        opts.metadata.apply(&mut metadata);
+        if opts.tagging == Some(Tagging::PdfUa) && metadata.title.is_none() {
+            return Err(Error::Render("PDF/UA requires a document title and the input has none".to_string()));
+        }
+        renderer.set_metadata(metadata);
+        Ok(())
+    })?;
+    let stats = renderer.finish()?;
+    if let Some(level) = opts.pdfa {
+        // Checked on the file as written
+        let mut report = pdfa::check(output, level)?;
+        for violation in &stats.validation {
+            report.add(None, violation.clone());
+        }
+        if !report.is_clean() {
+            return Err(Error::PdfA(report));
+        }
+    }
+    Ok(stats)
+}
@@ -0,0 +1,128 @@
+// ========== Links ==========
+//
+// Link annotations as the extractors read them from an input page and the
+// renderers write them to the output page: the clickable rectangle and a
+// URI or a place in the document. Destinations may be explicit, named or
+// the target of a GoTo action, which is how LaTeX hyperref writes citations
+// and cross-references.
+
+use crate::outline::{item_destination, Destination};
+use lopdf::{Document as LoDoc, Object, ObjectId};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Link {
+    /// Left, bottom, right, top in the input page's user space
+    pub rect: [f32; 4],
+    pub target: LinkTarget,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LinkTarget {
+    Uri(String),
+    /// Destination by input page index
+    GoTo(Destination),
+}
+
+/// Point in-document links at output pages, `pages` being the input page of
+/// each output page; links into pages left out are dropped.
+pub fn renumber(links: Vec<Link>, pages: &[usize]) -> Vec<Link> {
+    links
+        .into_iter()
+        .filter_map(|link| match link.target {
+            LinkTarget::GoTo(dest) => Some(Link { target: LinkTarget::GoTo(dest.renumber(pages)?), ..link }),
+            LinkTarget::Uri(_) => Some(link),
+        })
+        .collect()
+}
+
+/// Link annotations of page `index` of a (decrypted) lopdf document;
+/// `pages` are the page object ids in page order.
+pub fn from_lopdf(doc: &LoDoc, pages: &[ObjectId], index: usize) -> Vec<Link> {
+    let resolve = |obj: &Object| match obj {
+        Object::Reference(id) => doc.get_object(*id).ok().cloned(),
+        obj => Some(obj.clone()),
+    };
+    let Some(annots) = doc.get_dictionary(pages[index]).ok().and_then(|page| page.get(b"Annots").ok()).and_then(resolve) else {
+        return Vec::new();
+    };
+    let Ok(annots) = annots.as_array() else { return Vec::new() };
+    let mut links = Vec::new();
+    for annot in annots.iter().filter_map(resolve) {
+        let Ok(dict) = annot.as_dict() else { continue };
+        if dict.get(b"Subtype").and_then(Object::as_name).ok() != Some(b"Link") {
+            continue;
+        }
+        let Some(rect) = dict.get(b"Rect").ok().and_then(resolve).and_then(|r| normalized_rect(r.as_array().ok()?)) else { continue };
+        let uri = dict
+            .get(b"A")
+            .ok()
+            .and_then(resolve)
+            .and_then(|action| action.as_dict().ok()?.get(b"URI").ok()?.as_str().ok().map(|uri| String::from_utf8_lossy(uri).into_owned()));
+        let target = match uri {
+            Some(uri) => Some(LinkTarget::Uri(uri)),
+            None => item_destination(doc, pages, dict).map(LinkTarget::GoTo),
+        };
+        if let Some(target) = target {
+            links.push(Link { rect, target });
+        }
+    }
+    links
+}
+
+/// /Rect corners may come in any order.
+fn normalized_rect(rect: &[Object]) -> Option<[f32; 4]> {
+    let [x0, y0, x1, y1] = [0, 1, 2, 3].map(|i| rect.get(i).and_then(|v| v.as_float().ok()));
+    let (x0, y0, x1, y1) = (x0?, y0?, x1?, y1?);
+    Some([x0.min(x1), y0.min(y1), x0.max(x1), y0.max(y1)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outline::View;
+    use crate::testing::TestDocument;
+    use lopdf::{dictionary, StringFormat};
+
+    fn goto(page: usize) -> Link {
+        Link { rect: [0.0, 0.0, 10.0, 10.0], target: LinkTarget::GoTo(Destination { page, view: View::Fit }) }
+    }
+
+    #[test]
+    fn renumbers_and_drops_links_into_pages_left_out() {
+        let uri = Link { rect: [0.0, 0.0, 10.0, 10.0], target: LinkTarget::Uri("https://example.org".to_string()) };
+        let links = vec![goto(3), goto(1), uri.clone(), goto(5)];
+        assert_eq!(renumber(links, &[1, 5]), [goto(0), uri, goto(1)]);
+        assert_eq!(renumber(vec![goto(0)], &[]), []);
+    }
+
+    #[test]
+    fn reads_link_annotations() {
+        let mut pages = TestDocument::new();
+        let (first, second) = (pages.doc.new_object_id(), pages.doc.new_object_id());
+        let rect = |r: [i64; 4]| -> Object { r.iter().map(|&v| v.into()).collect::<Vec<Object>>().into() };
+        let uri = dictionary! { "S" => "URI", "URI" => Object::String(b"https://example.org".to_vec(), StringFormat::Literal) };
+        let dest: Vec<Object> = vec![second.into(), "Fit".into()];
+        let gone: Vec<Object> = vec![pages.pages.into(), "Fit".into()];
+        let annots: Vec<Object> = vec![
+            // Corners in any order
+            pages.doc.add_object(dictionary! { "Subtype" => "Link", "Rect" => rect([100, 20, 10, 5]), "A" => uri }).into(),
+            dictionary! { "Subtype" => "Link", "Rect" => rect([0, 0, 5, 5]), "Dest" => dest }.into(),
+            dictionary! { "Subtype" => "Text", "Rect" => rect([0, 0, 5, 5]) }.into(),
+            // Neither a page nor a URI
+            dictionary! { "Subtype" => "Link", "Rect" => rect([0, 0, 5, 5]), "Dest" => gone }.into(),
+        ];
+        pages.page_at(first, "", dictionary! { "Annots" => annots });
+        pages.page_at(second, "", dictionary! {});
+        let doc = pages.finish(dictionary! {});
+
+        let links = from_lopdf(&doc, &[first, second], 0);
+        assert_eq!(
+            links,
+            [
+                Link { rect: [10.0, 5.0, 100.0, 20.0], target: LinkTarget::Uri("https://example.org".to_string()) },
+                Link { rect: [0.0, 0.0, 5.0, 5.0], target: LinkTarget::GoTo(Destination { page: 1, view: View::Fit }) },
+            ]
+        );
+        assert!(from_lopdf(&doc, &[first, second], 1).is_empty());
+    }
+}
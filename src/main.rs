@@ -1,539 +1,669 @@
-// ========== Part 0: Dependencies ==========
-use pdfium_render::prelude::*;
-use krilla::{
-    Document, 
-    text::{Font, KrillaGlyph, GlyphId}, 
-    page::PageSettings, 
-    geom::{Point, Transform}, 
-    paint::Fill, 
-    color::rgb, 
-    num::NormalizedF32, 
-    surface::Surface
-};
-use anyhow::Result;
-use clap::Parser;
-use rustybuzz::{Face, UnicodeBuffer};
-use lopdf::{Document as LoDoc, Dictionary, Object};
-use std::string::String;
-use regex::Regex;
+// Thin command line front end over the `pdf_convert` library, one
+// subcommand per stage so each can be run without the whole pipeline.
+use anyhow::{anyhow, Result};
+use clap::{ArgAction, Args, Parser, Subcommand};
+use log::{debug, error, info, warn, Level, LevelFilter, Log, Metadata, Record};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use pdf_convert::batch::{self, BatchOptions, FileStatus};
+use pdf_convert::compare::{self, CompareOptions};
+use pdf_convert::diff::{self, DiffOptions};
+use pdf_convert::fonts::{Shaper, DEFAULT_SHAPE_CACHE_SIZE};
+use pdf_convert::inspect;
+use pdf_convert::security::{self, Encryption, Permission};
+use pdf_convert::{convert_loaded, extract_lines, fidelity, with_extractor, Backend, ConvertOptions, Error, MetadataOptions, OpenOptions, PageReader, PageSelection, PdfA, RenderStats, RendererKind, FontRegistry, OptimizeOptions, ShapeOptions, Tagging};
+use rustybuzz::Feature;
 
-// ========== Part 1: Inject D65 Gray Color Space ==========
-fn inject_d65gray(obj: &mut LoDoc) -> lopdf::Result<()> {
-    // 1) CalGray parameters dictionary
-    let calgray_dict = Dictionary::from_iter([
-        (b"WhitePoint".to_vec(), Object::Array(vec![
-            Object::Real(0.95047),
-            Object::Real(1.0),
-            Object::Real(1.08883),
-        ])),
-        (b"Gamma".to_vec(), Object::Real(2.2)),
-    ]);
-
-    // 2) Color space object must be an array: [/CalGray <<...>>]
-    let cs_obj = Object::Array(vec![
-        Object::Name(b"CalGray".to_vec()),
-        Object::Dictionary(calgray_dict),
-    ]);
-
-    // 3) Insert into object table and get id
-    let cs_id = obj.new_object_id();
-    obj.objects.insert(cs_id, cs_obj);
-
-    // 4) Add /d65gray reference to each page's /Resources
-    for (_, page_id) in obj.get_pages() {
-        let page = obj.get_object_mut(page_id)?.as_dict_mut()?;
-        
-        // Get or create Resources dictionary
-        let resources = if let Ok(res) = page.get_mut(b"Resources") {
-            res.as_dict_mut()?
-        } else {
-            let new_res = Dictionary::new();
-            page.set(b"Resources", Object::Dictionary(new_res));
-            page.get_mut(b"Resources")?.as_dict_mut()?
-        };
-        
-        // Get or create ColorSpace dictionary
-        let colors = if let Ok(cs) = resources.get_mut(b"ColorSpace") {
-            cs.as_dict_mut()?
-        } else {
-            let new_cs = Dictionary::new();
-            resources.set(b"ColorSpace", Object::Dictionary(new_cs));
-            resources.get_mut(b"ColorSpace")?.as_dict_mut()?
-        };
+// ========== Command line entry ==========
+#[derive(Parser)]
+struct Opt {
+    #[command(subcommand)]
+    command: Command,
+    /// Worker threads for extraction, shaping and content rewriting (0 = all cores)
+    #[arg(long, short = 'j', default_value_t = 0, global = true)]
+    jobs: usize,
+    /// libpdfium file or directory to load (default: search PDFIUM_LIB_PATH, next to the executable, ./, lib/, system)
+    #[arg(long, global = true)]
+    pdfium_lib: Option<PathBuf>,
+    /// Log more: -v adds debug, -vv trace messages
+    #[arg(long, short = 'v', action = ArgAction::Count, global = true, conflicts_with = "quiet")]
+    verbose: u8,
+    /// Log less: -q only warnings and errors, -qq only errors
+    #[arg(long, short = 'q', action = ArgAction::Count, global = true)]
+    quiet: u8,
+    /// Log as text (default) or as one JSON object per line, always on stderr
+    #[arg(long, default_value_t = LogFormat::Text, global = true)]
+    log_format: LogFormat,
+}
 
-        colors.set(b"d65gray".to_vec(), Object::Reference(cs_id)); // Key: must be Reference
-    }
-    Ok(())
+/// How an input is opened.
+#[derive(Args)]
+struct OpenArgs {
+    /// Extraction backend: pdfium (default) or lopdf (no native library)
+    #[arg(long, default_value_t = Backend::Pdfium)]
+    backend: Backend,
+    /// Password of an encrypted input (owner or user password)
+    #[arg(long, conflicts_with = "password_file")]
+    password: Option<String>,
+    /// Read the input password from the first line of this file
+    #[arg(long)]
+    password_file: Option<PathBuf>,
 }
 
-// ========== Part 2: Define Glyph, Line and clustering functions ==========
-#[derive(Clone)]
-pub struct Glyph {
-    pub ch: char,
-    pub x: f32,
-    pub y: f32,
-    pub w: f32,
-    pub size: f32,
-    pub font: String,
+/// Which pages of an input are read.
+#[derive(Args)]
+struct PageArgs {
+    /// Pages to read, e.g. 1-3,7,10- (default: all)
+    #[arg(long)]
+    pages: Option<PageSelection>,
+    /// First page to read
+    #[arg(long)]
+    first: Option<usize>,
+    /// Last page to read
+    #[arg(long)]
+    last: Option<usize>,
+    /// Leave out pages without any text
+    #[arg(long)]
+    skip_blank: bool,
 }
 
-pub struct Line {
-    pub glyphs: Vec<Glyph>,
-    pub y: f32,
-    pub font: String,
-    pub size: f32,
+/// How each input is converted, shared by `convert` and `batch`.
+#[derive(Args)]
+struct ConvertArgs {
+    #[command(flatten)]
+    open: OpenArgs,
+    #[command(flatten)]
+    pages: PageArgs,
+    /// Decimal places kept for numbers in rewritten content streams
+    #[arg(long, default_value_t = 5)]
+    precision: usize,
+    /// Output renderer: typst (default), pdf-writer or passthrough (holds the
+    /// whole input document in memory)
+    #[arg(long, default_value_t = RendererKind::Typst)]
+    renderer: RendererKind,
+    /// OpenType features for shaping, comma separated, e.g. -liga,onum
+    #[arg(long, value_delimiter = ',', allow_hyphen_values = true)]
+    font_features: Vec<Feature>,
+    /// Shaped runs cached per worker thread
+    #[arg(long, default_value_t = DEFAULT_SHAPE_CACHE_SIZE)]
+    shape_cache: usize,
+    /// Encrypt the output like the input instead of writing it unencrypted
+    #[arg(long, conflicts_with = "owner_password")]
+    keep_encryption: bool,
+    /// Encrypt the output with AES-256; this password grants full access
+    #[arg(long)]
+    owner_password: Option<String>,
+    /// Password needed to open the encrypted output (default: none)
+    #[arg(long, requires = "owner_password", default_value = "")]
+    user_password: String,
+    /// What the user password allows, comma separated: print, copy, modify, annotate
+    #[arg(long, requires = "owner_password", value_delimiter = ',')]
+    allow: Vec<Permission>,
+    /// Title of the output (default: the input's)
+    #[arg(long)]
+    title: Option<String>,
+    /// Author of the output (default: the input's)
+    #[arg(long)]
+    author: Option<String>,
+    /// Language of the output's text, e.g. en-US (default: the input's)
+    #[arg(long)]
+    lang: Option<String>,
+    /// Name this program as the producer instead of keeping the input's
+    #[arg(long)]
+    update_producer: bool,
+    /// Write PDF/A (2b or 3b) and fail with a report when the output does not conform
+    #[arg(long, conflicts_with_all = ["owner_password", "keep_encryption"])]
+    pdfa: Option<PdfA>,
+    /// Tag the output: headings, paragraphs, lists and links in a structure tree
+    #[arg(long)]
+    tagged: bool,
+    /// Tag the output and identify it as PDF/UA-1 (needs a title, see --title)
+    #[arg(long)]
+    pdfua: bool,
+    /// Pages extracted and drawn per batch (default: 4 per worker thread)
+    #[arg(long)]
+    batch_pages: Option<usize>,
 }
 
-fn group_lines(mut glyphs: Vec<Glyph>, _font: &Font) -> Vec<Line> {
-    glyphs.sort_by(|a, b| b.y.partial_cmp(&a.y).unwrap());
-    let mut lines: Vec<Vec<Glyph>> = Vec::new();
-    for g in glyphs {
-        if let Some(bucket) = lines.iter_mut().find(|bucket| (bucket[0].y - g.y).abs() < g.size * 0.4) {
-            bucket.push(g);
-        } else {
-            lines.push(vec![g]);
-        }
-    }
-    lines.into_iter().map(|mut gs| {
-        gs.sort_by(|a, b| a.x.partial_cmp(&b.x).unwrap());
-        let font = gs[0].font.clone();
-        let size = gs[0].size;
-        let y = gs[0].y + gs[0].size * 0.22;
-        let glyphs = gs.into_iter().filter(|g| !g.ch.is_control()).collect();
-        Line { glyphs, y, font, size }
-    }).collect()
+#[derive(Subcommand)]
+enum Command {
+    /// Convert a PDF: extract its text and write it anew
+    Convert {
+        input: String,
+        /// Output file, `-` for stdout
+        output: String,
+        #[command(flatten)]
+        convert: Box<ConvertArgs>,
+        /// Re-extract the output and compare its text with the input per page
+        #[arg(long)]
+        check_text: bool,
+        /// Log every extracted line with its page, position and size
+        #[arg(long)]
+        dump_lines: bool,
+    },
+    /// Convert every PDF under a directory into the same layout under another
+    Batch {
+        input_dir: PathBuf,
+        output_dir: PathBuf,
+        /// Inputs to convert: `*` and `?` within a name, `**` across directories
+        #[arg(long, default_value = "*.pdf")]
+        glob: String,
+        /// Also convert the PDFs in subdirectories
+        #[arg(long, short = 'r')]
+        recursive: bool,
+        /// Convert even when the output is newer than the input
+        #[arg(long)]
+        force: bool,
+        /// JSON report to write (default: batch-report.json in the output directory)
+        #[arg(long)]
+        report: Option<PathBuf>,
+        #[command(flatten)]
+        convert: Box<ConvertArgs>,
+    },
+    /// Print the text lines extracted from a PDF
+    Extract {
+        input: String,
+        #[command(flatten)]
+        open: OpenArgs,
+        #[command(flatten)]
+        pages: PageArgs,
+        /// text (default) prints the lines, lines adds position and size
+        #[arg(long, default_value_t = ExtractFormat::Text)]
+        format: ExtractFormat,
+    },
+    /// Print page count, encryption, tagging, metadata, bookmarks and links of a PDF
+    Inspect {
+        input: String,
+        #[command(flatten)]
+        open: OpenArgs,
+    },
+    /// List the fonts text is set in; with an input or --text, which font renders which characters
+    Fonts {
+        #[arg(conflicts_with = "text")]
+        input: Option<String>,
+        /// Characters to check instead of an input's
+        #[arg(long)]
+        text: Option<String>,
+        #[command(flatten)]
+        open: OpenArgs,
+        #[command(flatten)]
+        pages: PageArgs,
+    },
+    /// Structurally compare two PDFs (e.g. our output against cmp_typst.pdf)
+    Diff {
+        left: String,
+        right: String,
+        /// Maximum number of content-stream differences shown per page
+        #[arg(long, default_value_t = 50)]
+        max_ops: usize,
+        /// Only compare page trees, resources and fonts
+        #[arg(long)]
+        skip_content: bool,
+    },
+    /// Render two PDFs with pdfium and compare them pixel by pixel
+    Compare {
+        left: String,
+        right: String,
+        /// Rendering resolution
+        #[arg(long, default_value_t = 96.0)]
+        dpi: f32,
+        /// Gray-level difference above which a pixel counts as changed
+        #[arg(long, default_value_t = 16)]
+        threshold: u8,
+        /// Write highlighted diff PNGs for changed pages into this directory
+        #[arg(long)]
+        diff_dir: Option<PathBuf>,
+        /// Fail when a page's PSNR (dB) is below this value
+        #[arg(long)]
+        min_psnr: Option<f64>,
+        /// Fail when a page's SSIM is below this value
+        #[arg(long)]
+        min_ssim: Option<f64>,
+        /// Password of encrypted inputs (owner or user password), tried on both
+        #[arg(long, conflicts_with = "password_file")]
+        password: Option<String>,
+        /// Read the password from the first line of this file
+        #[arg(long)]
+        password_file: Option<PathBuf>,
+    },
 }
 
-// ========== Part 3: Extract lines ==========
-pub fn extract_lines(path: &str, font: &Font) -> Result<Vec<Vec<Line>>> {
-    let pdfium = Pdfium::default();
-    let doc = pdfium.load_pdf_from_file(path, None)?;
-    let mut pages_out = Vec::new();
-    for page_index in 0..doc.pages().len() {
-        let page = doc.pages().get(page_index)?;
-        let tp = page.text()?;
-        let mut glyphs = Vec::new();
-        for ch in tp.chars().iter() {
-            let c = ch.unicode_char();
-            let bbox = ch.loose_bounds()?;
-            let size = ch.scaled_font_size();
-            let font_name = ch.font_name();
-            let w = bbox.width().value as f32;
-            glyphs.push(Glyph {
-                ch: c.unwrap_or('?'),
-                x: bbox.left().value as f32,
-                y: bbox.bottom().value as f32,
-                w,
-                size: size.value as f32,
-                font: font_name,
-            });
-        }
-        let lines = group_lines(glyphs, font);
-        pages_out.push(lines);
-    }
-    Ok(pages_out)
+/// How `extract` prints the lines.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ExtractFormat {
+    /// The text of each line
+    Text,
+    /// Page, position and size of the first glyph, then the text
+    Lines,
 }
 
-// ========== Part 4: Write PDF using krilla with Typst-like style ==========
+impl FromStr for ExtractFormat {
+    type Err = String;
 
-fn load_font_and_bytes() -> (Font, Vec<u8>) {
-    let font_paths = [
-        "NewCM10-Regular.otf",
-        "/usr/share/fonts/truetype/ubuntu/Ubuntu-R.ttf",
-        "/usr/share/fonts/truetype/dejavu/DejaVuSerif.ttf",
-        "/System/Library/Fonts/Times.ttc",
-        "C:/Windows/Fonts/times.ttf",
-        "/usr/share/fonts/truetype/liberation/LiberationSerif-Regular.ttf",
-    ];
-    let mut font_data = None;
-    let mut font_bytes = None;
-    for path in &font_paths {
-        if let Ok(data) = std::fs::read(path) {
-            font_data = Some(data.clone());
-            font_bytes = Some(data);
-            println!("✅ Using font: {}", path);
-            break;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "text" => Ok(ExtractFormat::Text),
+            "lines" => Ok(ExtractFormat::Lines),
+            _ => Err(format!("unknown format '{}' (expected text or lines)", s)),
         }
     }
-    let font = if let Some(data) = &font_data {
-        Font::new(data.clone().into(), 0).unwrap()
-    } else {
-        println!("⚠️  No fonts found, using fallback");
-        Font::new(vec![].into(), 0).unwrap()
-    };
-    (font, font_bytes.unwrap_or_else(|| vec![]))
 }
 
-fn shape_line_with_rustybuzz(font_bytes: &[u8], line: &Line) -> (String, Vec<KrillaGlyph>) {
-    let face = Face::from_slice(font_bytes, 0).unwrap();
-    let upem = face.units_per_em() as f32;
-    let mut buffer = UnicodeBuffer::new();
-    let text: String = line.glyphs.iter().map(|g| g.ch).collect();
-    buffer.push_str(&text);
-    let output = rustybuzz::shape(&face, &[], buffer);
-    let mut kglyphs = Vec::new();
-    let mut cluster_to_range = Vec::new();
-    for (i, _) in text.char_indices() {
-        cluster_to_range.push(i);
-    }
-    cluster_to_range.push(text.len());
-    for (info, pos) in output.glyph_infos().iter().zip(output.glyph_positions()) {
-        let gid = GlyphId::new(info.glyph_id);
-        let adv = pos.x_advance as f32 / upem;
-        let dx  = pos.x_offset  as f32 / upem;
-        let start = cluster_to_range.get(info.cluster as usize).copied().unwrap_or(0);
-        let end = cluster_to_range.get(info.cluster as usize + 1).copied().unwrap_or(text.len());
-        kglyphs.push(KrillaGlyph::new(
-            gid, adv, dx, 0.0, 0.0, start..end, None,
-        ));
+impl fmt::Display for ExtractFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ExtractFormat::Text => "text",
+            ExtractFormat::Lines => "lines",
+        })
     }
-    (text, kglyphs)
 }
 
-fn draw_one_line<'a>(
-    surface: &mut Surface<'a>,
-    font: &Font,
-    font_bytes: &[u8],
-    line: &Line,
-) {
-    let (plain, kglyphs) = shape_line_with_rustybuzz(font_bytes, line);
-    let start_x = line.glyphs[0].x;
-    let baseline_y = 841.89 - line.y;
-    surface.draw_glyphs(
-        Point::from_xy(start_x, baseline_y),
-        &kglyphs,
-        font.clone(),
-        &plain,
-        line.size,
-        false,
-    );
+fn main() {
+    let opt = Opt::parse();
+    let level = match (opt.verbose, opt.quiet) {
+        (0, 0) => LevelFilter::Info,
+        (1, _) => LevelFilter::Debug,
+        (_, 0) => LevelFilter::Trace,
+        (_, 1) => LevelFilter::Warn,
+        _ => LevelFilter::Error,
+    };
+    // Lives for the whole run
+    let logger = Box::leak(Box::new(Logger { level, format: opt.log_format }));
+    log::set_logger(logger).expect("the logger is set once");
+    log::set_max_level(level);
+    if let Err(e) = run(opt) {
+        error!("{}", e);
+        std::process::exit(1);
+    }
 }
 
-/// Extract all q ... Q blocks (assume each paragraph/line is wrapped by q ... Q)
-fn extract_q_blocks(content: &str) -> Vec<String> {
-    let mut blocks = Vec::new();
-    let mut start = None;
-    let mut depth = 0;
-    let lines: Vec<&str> = content.lines().collect();
-    for (i, line) in lines.iter().enumerate() {
-        if line.trim_start().starts_with("q") {
-            if depth == 0 {
-                start = Some(i);
-            }
-            depth += 1;
+fn run(opt: Opt) -> Result<()> {
+    rayon::ThreadPoolBuilder::new().num_threads(opt.jobs).build_global()?;
+    match opt.command {
+        Command::Convert { input, output, convert, check_text, dump_lines } => {
+            run_convert(&input, &output, &convert, check_text, dump_lines, opt.pdfium_lib)
         }
-        if line.trim_start().starts_with("Q") {
-            depth -= 1;
-            if depth == 0 {
-                if let Some(s) = start {
-                    let block = lines[s..=i].join("\n");
-                    blocks.push(block);
+        Command::Batch { input_dir, output_dir, glob, recursive, force, report, convert } => {
+            let opts = BatchOptions { pattern: glob, recursive, force };
+            run_batch(&input_dir, &output_dir, &opts, report, &convert, opt.pdfium_lib)
+        }
+        Command::Extract { input, open, pages, format } => run_extract(&input, &open_options(&open, Some(&pages), opt.pdfium_lib)?, format),
+        Command::Inspect { input, open } => {
+            print!("{}", inspect::inspect(&input, &open_options(&open, None, opt.pdfium_lib)?)?);
+            Ok(())
+        }
+        Command::Fonts { input, text, open, pages } => {
+            let chars: Option<BTreeSet<char>> = match (input, text) {
+                (Some(input), _) => {
+                    let pages = extract_lines(&input, &open_options(&open, Some(&pages), opt.pdfium_lib)?)?;
+                    Some(pages.iter().flatten().flat_map(|line| &line.glyphs).map(|g| g.ch).collect())
                 }
-                start = None;
+                (None, Some(text)) => Some(text.chars().collect()),
+                (None, None) => None,
+            };
+            run_fonts(chars)
+        }
+        Command::Diff { left, right, max_ops, skip_content } => {
+            let report = diff::diff_files(&left, &right, &DiffOptions { max_ops, skip_content })?;
+            print!("{}", report);
+            if !report.is_empty() {
+                std::process::exit(1);
             }
+            Ok(())
         }
-    }
-    blocks
-}
-
-// Extract 1 0 0 1 x y cm inside a block
-fn extract_cm(lines: &[&str]) -> Option<(f32, f32, usize)> {
-    for (i, line) in lines.iter().enumerate() {
-        let parts: Vec<&str> = line.trim().split_whitespace().collect();
-        if parts.len() == 7 && parts[0] == "1" && parts[1] == "0" && parts[2] == "0" && parts[3] == "1" && parts[6] == "cm" {
-            let x = parts[4].parse().ok()?;
-            let y = parts[5].parse().ok()?;
-            return Some((x, y, i));
+        Command::Compare { left, right, dpi, threshold, diff_dir, min_psnr, min_ssim, password, password_file } => {
+            let open = OpenOptions { password: read_password(password, password_file.as_deref())?, pdfium_lib: opt.pdfium_lib, ..Default::default() };
+            let opts = CompareOptions { dpi, threshold, diff_dir, min_psnr, min_ssim, open };
+            let report = compare::compare_files(&left, &right, &opts)?;
+            print!("{}", report);
+            if !report.passed() {
+                std::process::exit(1);
+            }
+            Ok(())
         }
     }
-    None
 }
 
-// Extract 1 0 0 -1 tx ty Tm inside a block
-fn extract_tm(lines: &[&str]) -> Option<(f32, f32, usize)> {
-    for (i, line) in lines.iter().enumerate() {
-        let parts: Vec<&str> = line.trim().split_whitespace().collect();
-        if parts.len() == 7 && parts[0] == "1" && parts[1] == "0" && parts[2] == "0" && parts[3] == "-1" && parts[6] == "Tm" {
-            let tx = parts[4].parse().ok()?;
-            let ty = parts[5].parse().ok()?;
-            return Some((tx, ty, i));
-        }
-    }
-    None
+/// Backend, password and, when given, page selection of an input.
+fn open_options(args: &OpenArgs, pages: Option<&PageArgs>, pdfium_lib: Option<PathBuf>) -> Result<OpenOptions> {
+    let password = read_password(args.password.clone(), args.password_file.as_deref())?;
+    let (selection, skip_blank) = match pages {
+        Some(PageArgs { pages: None, first: None, last: None, skip_blank }) => (None, *skip_blank),
+        Some(PageArgs { pages, first, last, skip_blank }) => (Some(pages.clone().unwrap_or_default().within(*first, *last)), *skip_blank),
+        None => (None, false),
+    };
+    Ok(OpenOptions { backend: args.backend, password, pdfium_lib, pages: selection, skip_blank })
 }
 
-// Compose new Tm (outer cm + block cm + block Tm)
-fn combine_cm_tm(outer_cm: (f32, f32), block_cm: (f32, f32), block_tm: (f32, f32)) -> (f32, f32) {
-    // Note: y direction flip is handled by outer cm in typst, so we can directly add
-    (outer_cm.0 + block_cm.0 + block_tm.0, outer_cm.1 + block_cm.1 + block_tm.1)
+/// `password`, or the first line of `file`.
+fn read_password(password: Option<String>, file: Option<&Path>) -> Result<Option<String>> {
+    Ok(match (password, file) {
+        (Some(password), _) => Some(password),
+        (None, Some(file)) => Some(std::fs::read_to_string(file)?.lines().next().unwrap_or_default().to_string()),
+        (None, None) => None,
+    })
 }
 
-/// Remove q...Q and cm, keep only content, and compose new Tm
-fn strip_q_block_with_outer_cm(block: &str, outer_cm: (f32, f32), ignore_block_cm: bool) -> String {
-    let mut lines: Vec<&str> = block.lines().collect();
-    // Remove the first line q and cm
-    if lines.len() > 2 && lines[0].trim_start().starts_with("q") && lines[2].trim_start().ends_with("cm") {
-        lines.drain(0..3);
-    } else if lines.len() > 1 && lines[0].trim_start().starts_with("q") {
-        lines.remove(0);
+/// Options for converting `input`, reporting its encryption and the output's.
+/// The input is read with lopdf for that; the document comes along for the
+/// conversion to reuse.
+fn convert_options(args: &ConvertArgs, input: &str, pdfium_lib: Option<PathBuf>) -> Result<(ConvertOptions, Option<lopdf::Document>)> {
+    let open = open_options(&args.open, Some(&args.pages), pdfium_lib)?;
+    // Report the encryption up front; documents lopdf cannot parse are left to the backend
+    let (doc, security) = match security::open(input, open.password.as_deref()) {
+        Ok((doc, security)) => (Some(doc), security),
+        Err(e @ Error::Password(_)) => return Err(e.into()),
+        Err(_) => (None, None),
+    };
+    if let Some(security) = &security {
+        info!("Encrypted input: {}", security);
     }
-    // Remove the last Q
-    if let Some(last) = lines.last() {
-        if last.trim_start().starts_with("Q") {
-            lines.pop();
+    let encryption = match &args.owner_password {
+        Some(owner) => {
+            let encryption = Encryption::aes256(owner, &args.user_password, &args.allow)?;
+            let allowed = security::permission_names(encryption.permissions()).join(", ");
+            info!("Output is encrypted with AES-256; user password allows: {}", allowed);
+            Some(encryption)
+        }
+        None if args.keep_encryption && security.is_some() => {
+            info!("Output keeps the input's encryption");
+            security.map(|s| s.encryption)
         }
+        None if security.is_some() => {
+            info!("Output is written without encryption");
+            None
+        }
+        None => None,
+    };
+    let opts = ConvertOptions {
+        open,
+        renderer: args.renderer,
+        optimize: OptimizeOptions { precision: args.precision },
+        shaping: ShapeOptions { features: args.font_features.clone(), cache_size: args.shape_cache },
+        batch_pages: args.batch_pages.unwrap_or(0),
+        encryption,
+        metadata: MetadataOptions {
+            title: args.title.clone(),
+            author: args.author.clone(),
+            language: args.lang.clone(),
+            update_producer: args.update_producer,
+        },
+        pdfa: args.pdfa,
+        tagging: match (args.pdfua, args.tagged) {
+            (true, _) => Some(Tagging::PdfUa),
+            (false, true) => Some(Tagging::Tagged),
+            (false, false) => None,
+        },
+    };
+    Ok((opts, doc))
+}
+
+// ========== Convert ==========
+fn run_convert(input: &str, output: &str, args: &ConvertArgs, check_text: bool, dump_lines: bool, pdfium_lib: Option<PathBuf>) -> Result<()> {
+    let fonts = FontRegistry::load()?;
+    let (opts, doc) = convert_options(args, input, pdfium_lib)?;
+    if let Some(pages) = &opts.open.pages {
+        info!("Converting pages {}", pages);
     }
-    // Extract block cm and Tm
-    let block_cm = extract_cm(&lines).unwrap_or((0.0, 0.0, usize::MAX));
-    let block_tm = extract_tm(&lines).unwrap_or((0.0, 0.0, usize::MAX));
-    // Compose new Tm
-    let new_tm = if ignore_block_cm {
-        // Reverse engineer new Tm so that outer cm + new Tm = block_cm + block_Tm
-        (block_cm.0 + block_tm.0 - outer_cm.0, block_cm.1 + block_tm.1 - outer_cm.1)
-    } else {
-        combine_cm_tm(outer_cm, (block_cm.0, block_cm.1), (block_tm.0, block_tm.1))
+    // The output is post-processed in place, so stdout gets a finished temporary file
+    let temp = (output == "-").then(TempFile::create).transpose()?;
+    let path = match &temp {
+        Some(temp) => temp.path.to_str().ok_or_else(|| anyhow!("temporary path {} is not valid UTF-8", temp.path.display()))?,
+        None => output,
     };
-    // Filter out all 1 0 0 1 ... cm and 1 0 0 -1 ... Tm lines
-    let mut filtered: Vec<String> = lines
-        .into_iter()
-        .enumerate()
-        .filter(|(i, l)| {
-            let t = l.trim_start();
-            !(t.starts_with("1 0 0 1") && t.ends_with("cm")) && !(t.starts_with("1 0 0 -1") && t.ends_with("Tm")) && *i != block_tm.2 && *i != block_cm.2
-        })
-        .map(|(_, l)| l.to_string())
-        .collect();
-    // Extract /f0 ... Tf line
-    let mut font_line = None;
-    filtered.retain(|l| {
-        if l.trim_start().starts_with("/f0") && l.trim_end().ends_with("Tf") {
-            font_line = Some(l.clone());
-            false
-        } else {
-            true
+    let mut expected = Vec::new();
+    let result = convert_loaded(input, path, &fonts, &opts, doc, |input_pages, pages| {
+        if dump_lines {
+            for (page, lines) in input_pages.iter().zip(pages) {
+                for line in lines {
+                    let (first, text): (_, String) = (&line.glyphs[0], line.glyphs.iter().map(|g| g.ch).collect());
+                    dump_line(format_args!("page {:>2}  {:3.0} {:3.0}  size {:>4.1}  '{}'", page + 1, first.x, first.y, first.size, text));
+                }
+            }
         }
-    });
-    // Extract /d65gray cs and 0 scn lines (for body)
-    let mut color_lines = Vec::new();
-    filtered.retain(|l| {
-        let t = l.trim_start();
-        if t == "/d65gray cs" || t == "0 scn" {
-            color_lines.push(l.clone());
-            false
-        } else {
-            true
+        if check_text {
+            expected.extend(fidelity::page_texts(pages));
         }
     });
-    // Insert new Tm line (after BT), and move font line before BT
-    let mut result = Vec::new();
-    let mut bt_found = false;
-    for l in filtered {
-        if l.trim_start() == "BT" && !bt_found {
-            if let Some(font) = &font_line {
-                result.push(font.clone());
-            }
-            result.push(l);
-            result.push(format!("    1 0 0 -1 {:.5} {:.5} Tm", new_tm.0, new_tm.1));
-            bt_found = true;
-        } else {
-            result.push(l);
+    let stats = result?;
+    if opts.open.skip_blank || opts.open.pages.is_some() {
+        info!("{} pages converted", stats.pages);
+    }
+    debug!("Shaping cache: {} hits, {} misses", stats.shaping.hits, stats.shaping.misses);
+    if !stats.missing.is_empty() {
+        warn!("No font can render: {}", batch::missing_chars(&stats.missing));
+    }
+    info!("Content streams: {}", stats.content);
+    if let Some(level) = opts.pdfa {
+        info!("Output conforms to {}", level);
+    }
+    if let Some(tagging) = opts.tagging {
+        info!("Output is {}", tagging);
+    }
+    if check_text {
+        // A newly encrypted output opens with its owner password, a kept encryption with the input's;
+        // it holds only the selected pages, so all of them are compared
+        let password = args.owner_password.clone().or(opts.open.password.clone());
+        let reopen = OpenOptions { password, pages: None, skip_blank: false, ..opts.open.clone() };
+        let actual = fidelity::page_texts(&extract_lines(path, &reopen)?);
+        let report = fidelity::check(&expected, &actual, &fonts);
+        for line in report.to_string().lines() {
+            info!("{}", line);
         }
+        if !report.is_clean() {
+            warn!("Output text differs from input");
+        }
+    }
+    if let Some(temp) = temp {
+        let data = std::fs::read(&temp.path)?;
+        drop(temp);
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(&data)?;
+        stdout.flush()?;
+        info!("Done: written to stdout");
+    } else {
+        info!("Done: {}", output);
     }
-    result.join("\n")
+    Ok(())
 }
 
-fn dedup_font_and_color(content: &str) -> String {
-    let mut result = Vec::new();
-    let mut last_font: Option<String> = None;
-    let mut last_color: Option<(String, String)> = None;
-    let mut font_stack = Vec::new();
-    let mut color_stack = Vec::new();
-    let mut pending_color: Option<String> = None;
-    for line in content.lines() {
-        let l = line.trim_start();
-        if l == "q" {
-            font_stack.push(last_font.clone());
-            color_stack.push(last_color.clone());
-            result.push(line.to_string());
-        } else if l == "Q" {
-            last_font = font_stack.pop().unwrap_or(None);
-            last_color = color_stack.pop().unwrap_or(None);
-            result.push(line.to_string());
-        } else if l.starts_with("/f0") && l.ends_with("Tf") {
-            if let Some(ref last) = last_font {
-                if last == l {
-                    continue; // Skip duplicate font
-                }
-            }
-            last_font = Some(l.to_string());
-            result.push(line.to_string());
-        } else if l == "/d65gray cs" || l == "0 scn" {
-            if let Some((ref last_cs, ref last_scn)) = last_color {
-                if (l == "/d65gray cs" && last_cs == l) || (l == "0 scn" && last_scn == l) {
-                    continue; // Skip duplicate color
-                }
-            }
-            // Record color pair
-            if l == "/d65gray cs" {
-                pending_color = Some(l.to_string());
-            } else if l == "0 scn" {
-                let cs = pending_color.take().unwrap_or_else(|| "/d65gray cs".to_string());
-                last_color = Some((cs, l.to_string()));
+/// A file of our own in the temporary directory, removed when dropped, so
+/// every way out of the conversion cleans it up.
+struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    /// Create a new empty file under an unpredictable name, never opening
+    /// one that exists (or a symlink planted in its place).
+    fn create() -> std::io::Result<TempFile> {
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        loop {
+            let path = std::env::temp_dir().join(format!("pdf_convert-{:016x}.pdf", rand::random::<u64>()));
+            match options.open(&path) {
+                Ok(_) => return Ok(TempFile { path }),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
             }
-            result.push(line.to_string());
-        } else {
-            result.push(line.to_string());
         }
     }
-    result.join("\n")
 }
 
-fn rewrite_content_streams(obj: &mut LoDoc) -> lopdf::Result<()> {
-    use lopdf::Object::*;
-    for (page_idx, (_, page_id)) in obj.get_pages().into_iter().enumerate() {
-        let page = obj.get_object(page_id)?.as_dict()?;
-        if let Ok(contents) = page.get(b"Contents") {
-            let content_ids = match contents {
-                Reference(id) => vec![*id],
-                Array(arr) => arr.iter().filter_map(|o| o.as_reference().ok()).collect(),
-                _ => continue,
-            };
-            for cid in content_ids {
-                let stream = obj.get_object_mut(cid)?.as_stream_mut()?;
-                let decoded = stream.decompressed_content()?;
-                let content_str = std::string::String::from_utf8_lossy(&decoded);
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
 
-                let blocks = extract_q_blocks(&content_str);
-                let mut final_content = std::string::String::new();
+/// Log one line of `--dump-lines`. It was asked for, so it bypasses the
+/// level filter and `-q` does not hide it.
+fn dump_line(args: fmt::Arguments) {
+    log::logger().log(&Record::builder().level(Level::Info).target(LINES_TARGET).args(args).build());
+}
 
-                if page_idx == 0 && blocks.len() >= 3 {
-                    // typst first page structure
-                    final_content.push_str("1 0 0 -1 0 841.8898 cm\nq\n    1 0 0 1 70.86614 85.03937 cm\n    q\n        1 0 0 1 137.37465 60 cm\n");
-                    final_content.push_str(&strip_q_block_with_outer_cm(&blocks[0], (70.86614+137.37465, 85.03937+60.0), true));
-                    final_content.push_str("\n    Q\n");
-                    final_content.push_str(&strip_q_block_with_outer_cm(&blocks[1], (70.86614, 85.03937), true));
-                    final_content.push_str("\n");
-                    final_content.push_str("    q\n        1 0 0 1 188.56316 110.807 cm\n");
-                    final_content.push_str(&strip_q_block_with_outer_cm(&blocks[2], (70.86614+188.56316, 85.03937+110.807), true));
-                    final_content.push_str("\n    Q\nQ\n");
-                    // Body part: only insert color and font once at the beginning
-                    for block in &blocks[3..] {
-                        let body = strip_q_block_with_outer_cm(block, (0.0, 0.0), false);
-                        final_content.push_str(&body);
-                        final_content.push_str("\n");
-                    }
-                    // Replace color
-                    final_content = final_content
-                        .replace("0 0 0 rg", "/d65gray cs\n0 scn")
-                        .replace("0 0 0 RG", "/d65gray CS\n0 SCN")
-                        .replace("0 Tr\n", "");
-                } else {
-                    // Other page body: only insert color and font once at the beginning
-                    let page_transform = "1 0 0 -1 0 841.89 cm\n";
-                    let mut page_body = std::string::String::new();
-                    let mut first = true;
-                    for block in &blocks {
-                        let block_str = strip_q_block_with_outer_cm(block, (0.0, 0.0), false);
-                        if first {
-                            page_body.push_str("/d65gray cs\n0 scn\n/F0 10 Tf\n");
-                            first = false;
+// ========== Batch ==========
+fn run_batch(input_dir: &Path, output_dir: &Path, opts: &BatchOptions, report_path: Option<PathBuf>, args: &ConvertArgs, pdfium_lib: Option<PathBuf>) -> Result<()> {
+    let fonts = FontRegistry::load()?;
+    info!("Converting {} in {} into {}", opts.pattern, input_dir.display(), output_dir.display());
+    let convert_file = |input: &str, output: &str| -> Result<RenderStats> {
+        let (opts, doc) = convert_options(args, input, pdfium_lib.clone())?;
+        Ok(convert_loaded(input, output, &fonts, &opts, doc, |_, _| {})?)
+    };
+    let report = batch::convert_dir(input_dir, output_dir, opts, convert_file, |file| match file.status {
+        FileStatus::Converted if file.warnings.is_empty() => info!("{}", file),
+        FileStatus::Converted => warn!("{}", file),
+        FileStatus::UpToDate => info!("{}", file),
+        FileStatus::Failed(_) => error!("{}", file),
+    })?;
+    let report_path = report_path.unwrap_or_else(|| output_dir.join("batch-report.json"));
+    if let Some(dir) = report_path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(&report_path, report.to_json())?;
+    info!("{}", report.summary());
+    info!("Report: {}", report_path.display());
+    if report.failed() > 0 {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+// ========== Extract ==========
+fn run_extract(input: &str, opts: &OpenOptions, format: ExtractFormat) -> Result<()> {
+    with_extractor(input, opts, |extractor| {
+        let mut reader = PageReader::with_selection(extractor, opts.pages.as_ref(), opts.skip_blank);
+        // Printed a batch at a time, so long documents start printing right away
+        while let Some(pages) = reader.next_batch(rayon::current_num_threads())? {
+            let read = reader.input_pages();
+            for (page, lines) in read[read.len() - pages.len()..].iter().zip(&pages) {
+                if format == ExtractFormat::Text {
+                    println!("── page {} ──", page + 1);
+                }
+                for line in lines {
+                    let text: String = line.glyphs.iter().map(|g| g.ch).collect();
+                    match format {
+                        ExtractFormat::Text => println!("{}", text),
+                        ExtractFormat::Lines => {
+                            let first = &line.glyphs[0];
+                            println!("page {:>2}  {:3.0} {:3.0}  size {:>4.1}  '{}'", page + 1, first.x, first.y, first.size, text);
                         }
-                        page_body.push_str(&block_str);
-                        page_body.push_str("\n");
                     }
-                    final_content = format!("{}{}", page_transform, page_body);
                 }
+            }
+        }
+        Ok(())
+    })?;
+    Ok(())
+}
 
-                // Global deduplication of font and color
-                let final_content = dedup_font_and_color(&final_content);
-
-                stream.set_content(final_content.as_bytes().to_vec());
-                stream.dict.remove(b"Filter");
-                stream.dict.remove(b"DecodeParms");
+// ========== Fonts ==========
+fn run_fonts(chars: Option<BTreeSet<char>>) -> Result<()> {
+    let fonts = FontRegistry::load()?;
+    let Some(chars) = chars else {
+        for font in fonts.fonts() {
+            println!("{:<8} {}", font.role, font.path);
+        }
+        return Ok(());
+    };
+    let shaper = Shaper::new(&fonts, Vec::new(), DEFAULT_SHAPE_CACHE_SIZE);
+    let mut by_font: Vec<Vec<char>> = vec![Vec::new(); fonts.fonts().len()];
+    let mut missing = BTreeSet::new();
+    for c in chars.into_iter().filter(|c| !c.is_whitespace()) {
+        match shaper.font_for(c) {
+            Some(font) => by_font[font].push(c),
+            None => {
+                missing.insert(c);
             }
         }
     }
+    for (font, chars) in fonts.fonts().iter().zip(&by_font) {
+        println!("{:<8} {}  {} characters", font.role, font.path, chars.len());
+        // The primary font's share is mostly plain text; the fallbacks' is worth listing
+        if font.role != "primary" && !chars.is_empty() {
+            println!("         {}", chars.iter().collect::<String>());
+        }
+    }
+    if !missing.is_empty() {
+        println!("none     {}", batch::missing_chars(&missing));
+    }
     Ok(())
 }
 
-pub fn render_like_typst(pages: Vec<Vec<Line>>, out: &str) -> Result<()> {
-    let (font, font_bytes) = load_font_and_bytes();
-    let mut document = Document::new();
-    
-    for (_page_num, lines) in pages.into_iter().enumerate() {
-        let mut page = document.start_page_with(PageSettings::new(595.28, 841.89));
-        let mut surface = page.surface();
-
-        // Set color for the whole block
-        surface.set_fill(Some(Fill {
-            paint: rgb::Color::new(0, 0, 0).into(),
-            opacity: NormalizedF32::ONE,
-            rule: Default::default(),
-        }));
+// ========== Logging ==========
+/// How log records are written to stderr.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum LogFormat {
+    /// The message, prefixed with its level unless it is info
+    Text,
+    /// One object per line with time, level, target and message
+    Json,
+}
 
-        // Apply page-level transform to flip coordinate system (like Typst does)
-        // This puts the origin at top-left and flips Y-axis - should come first
-        surface.push_transform(&krilla::geom::Transform::from_row(1.0, 0.0, 0.0, -1.0, 0.0, 841.89));
+impl FromStr for LogFormat {
+    type Err = String;
 
-        // Draw all lines with proper positioning
-        for line in &lines {
-            // Create a nested transform for each line (like Typst does)
-            // Use the line's x position for the transform, and y position for text matrix
-            surface.push_transform(&krilla::geom::Transform::from_row(1.0, 0.0, 0.0, 1.0, line.glyphs[0].x, 0.0));
-            
-            let (plain, kglyphs) = shape_line_with_rustybuzz(&font_bytes, line);
-            surface.draw_glyphs(
-                Point::from_xy(0.0, 841.89 - line.y),
-                &kglyphs,
-                font.clone(),
-                &plain,
-                line.size,
-                false,
-            );
-            
-            surface.pop(); // Pop the line transform
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format '{}' (expected text or json)", s)),
         }
+    }
+}
 
-        surface.pop(); // Pop the page transform
-        surface.finish();
-        page.finish();
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+        })
     }
-    
-    // Generate krilla PDF
-    let bytes = document.finish().map_err(|e| anyhow::anyhow!("PDF generation failed: {:?}", e))?;
-    
-    // Process with lopdf for color space injection and content stream rewriting
-    let mut lo = LoDoc::load_mem(&bytes)?;
-    inject_d65gray(&mut lo)?;
-    rewrite_content_streams(&mut lo)?;
-    
-    // Let lopdf rewrite the PDF with proper xref
-    let mut output = Vec::new();
-    lo.save_to(&mut output)?;
-    
-    std::fs::write(out, output)?;
-    Ok(())
 }
 
-// ========== Part 5: Command line entry ==========
-#[derive(Parser)]
-struct Opt {
-    input: String,
-    output: String,
+/// Target of the `--dump-lines` records, which are logged at any level.
+const LINES_TARGET: &str = "pdf_convert::lines";
+
+/// Writes records to stderr, keeping stdout for the command's output.
+struct Logger {
+    level: LevelFilter,
+    format: LogFormat,
 }
 
-fn main() -> Result<()> {
-    let opt = Opt::parse();
-    let (font, _font_bytes) = load_font_and_bytes();
-    let pages = extract_lines(&opt.input, &font)?;
-    // Print extracted text for debugging
-    for (p, lines) in pages.iter().enumerate() {
-        for line in lines {
-            println!("page {:>2}  {:3.0} {:3.0}  size {:>4.1}  '{}'", 
-                     p + 1, line.glyphs[0].x, line.glyphs[0].y, line.glyphs[0].size, line.glyphs[0].ch);
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // Debug output of the dependencies is left out
+        metadata.target() == LINES_TARGET
+            || (metadata.level() <= self.level && (metadata.level() <= Level::Warn || metadata.target().starts_with("pdf_convert")))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
         }
+        let level = record.level().as_str().to_ascii_lowercase();
+        let line = match (self.format, record.level()) {
+            (LogFormat::Text, Level::Info) => record.args().to_string(),
+            (LogFormat::Text, _) => format!("{}: {}", level, record.args()),
+            (LogFormat::Json, _) => {
+                let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+                format!(
+                    "{{\"time\": {:.3}, \"level\": \"{}\", \"target\": {}, \"message\": {}}}",
+                    time,
+                    level,
+                    batch::json_string(record.target()),
+                    batch::json_string(&record.args().to_string())
+                )
+            }
+        };
+        let _ = writeln!(std::io::stderr().lock(), "{}", line);
     }
-    render_like_typst(pages, &opt.output)?;
-    println!("✅ Done: {}", opt.output);
-    Ok(())
-}
\ No newline at end of file
+
+    fn flush(&self) {}
+}
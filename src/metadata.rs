@@ -0,0 +1,511 @@
+// ========== Document metadata ==========
+//
+// Title, author, dates and language as the extractors read them from the
+// input's Info dictionary, with the XMP packet filling what Info lacks, and
+// the renderers write them to the output: the Info dictionary, /Lang and an
+// XMP packet generated from the same values so both always agree.
+
+use crate::pdfa::PdfA;
+use lopdf::{decode_text_string, Document as LoDoc, Object};
+use regex::Regex;
+use std::fmt;
+use xmp_writer::{LangId, Timezone, XmpWriter};
+
+/// Producer written with `MetadataOptions::update_producer`.
+pub const PRODUCER: &str = concat!("pdf_convert ", env!("CARGO_PKG_VERSION"));
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metadata {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub subject: Option<String>,
+    pub keywords: Option<String>,
+    /// Application that created the original document
+    pub creator: Option<String>,
+    /// Application that wrote the PDF
+    pub producer: Option<String>,
+    pub creation_date: Option<Date>,
+    pub modification_date: Option<Date>,
+    /// Natural language of the text, e.g. `en-US` (the catalog's /Lang)
+    pub language: Option<String>,
+}
+
+/// Overrides applied on top of the input's metadata.
+#[derive(Clone, Debug, Default)]
+pub struct MetadataOptions {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub language: Option<String>,
+    /// Replace the input's producer by [`PRODUCER`]
+    pub update_producer: bool,
+}
+
+impl MetadataOptions {
+    pub fn apply(&self, metadata: &mut Metadata) {
+        if let Some(title) = &self.title {
+            metadata.title = Some(title.clone());
+        }
+        if let Some(author) = &self.author {
+            metadata.author = Some(author.clone());
+        }
+        if let Some(language) = &self.language {
+            metadata.language = Some(language.clone());
+        }
+        if self.update_producer {
+            metadata.producer = Some(PRODUCER.to_string());
+        }
+    }
+}
+
+/// A date as PDF and XMP write it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Date {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    /// Offset from UTC in minutes, `None` when unknown
+    pub offset: Option<i16>,
+}
+
+impl Date {
+    /// Parse a PDF date (`D:YYYYMMDDHHmmSSOHH'mm'`) or an XMP date
+    /// (`YYYY-MM-DDThh:mm:ss+hh:mm`). Missing fields take their defaults.
+    pub fn parse(s: &str) -> Option<Date> {
+        let s = s.trim();
+        let s = s.strip_prefix("D:").unwrap_or(s);
+        // A '-' is the sign of an offset after XMP's 'T' or PDF's plain digits, otherwise a date separator
+        let zone_start = s.find(['Z', 'z', '+']).or_else(|| s.rfind('-').filter(|&i| s[..i].contains('T') || !s[..i].contains('-')));
+        let (stamp, zone) = match zone_start {
+            Some(i) => (&s[..i], Some(&s[i..])),
+            None => (s, None),
+        };
+        let stamp: String = stamp.chars().filter(char::is_ascii_digit).collect();
+        let field = |range: std::ops::Range<usize>, default: u8| stamp.get(range).and_then(|v| v.parse().ok()).unwrap_or(default);
+        let year = stamp.get(0..4)?.parse().ok()?;
+        let offset = zone.and_then(|zone| {
+            let sign = match zone.chars().next()? {
+                'Z' | 'z' => return Some(0),
+                '-' => -1,
+                _ => 1,
+            };
+            let zone: String = zone.chars().filter(char::is_ascii_digit).collect();
+            let hours: i16 = zone.get(0..2)?.parse().ok()?;
+            let minutes: i16 = zone.get(2..4).and_then(|m| m.parse().ok()).unwrap_or(0);
+            Some(sign * (hours * 60 + minutes))
+        });
+        Some(Date {
+            year,
+            month: field(4..6, 1),
+            day: field(6..8, 1),
+            hour: field(8..10, 0),
+            minute: field(10..12, 0),
+            second: field(12..14, 0),
+            offset,
+        })
+    }
+
+    pub fn to_pdf_writer(self) -> pdf_writer::Date {
+        let date = self.writable();
+        let pdf_date = pdf_writer::Date::new(date.year).month(date.month).day(date.day).hour(date.hour).minute(date.minute).second(date.second);
+        match date.offset {
+            Some(offset) => {
+                let (hour, minute) = split_offset(offset);
+                pdf_date.utc_offset_hour(hour).utc_offset_minute(minute)
+            }
+            None => pdf_date,
+        }
+    }
+
+    pub fn to_xmp(self) -> xmp_writer::DateTime {
+        let date = self.writable();
+        let timezone = date.offset.map(|offset| match split_offset(offset) {
+            (0, 0) => Timezone::Utc,
+            (hour, minute) => Timezone::Local { hour, minute: minute as i8 },
+        });
+        xmp_writer::DateTime {
+            year: date.year,
+            month: Some(date.month),
+            day: Some(date.day),
+            hour: Some(date.hour),
+            minute: Some(date.minute),
+            second: Some(date.second),
+            timezone,
+        }
+    }
+
+    /// `self`, or the same time in UTC when its offset is under an hour
+    /// behind UTC: pdf-writer and xmp-writer carry the offset's sign on the
+    /// hours and would write it as ahead.
+    fn writable(self) -> Date {
+        match self.offset {
+            Some(offset @ -59..=-1) => {
+                let mut date = Date { offset: Some(0), ..self };
+                let minutes = u16::from(self.hour) * 60 + u16::from(self.minute) + offset.unsigned_abs();
+                date.hour = (minutes / 60 % 24) as u8;
+                date.minute = (minutes % 60) as u8;
+                if minutes >= 24 * 60 {
+                    date.next_day();
+                }
+                date
+            }
+            _ => self,
+        }
+    }
+
+    fn next_day(&mut self) {
+        let leap = self.year.is_multiple_of(4) && (!self.year.is_multiple_of(100) || self.year.is_multiple_of(400));
+        let days = match self.month {
+            2 if leap => 29,
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            _ => 31,
+        };
+        if self.day < days {
+            self.day += 1;
+        } else if self.month < 12 {
+            (self.day, self.month) = (1, self.month + 1);
+        } else {
+            (self.day, self.month, self.year) = (1, 1, self.year + 1);
+        }
+    }
+}
+
+/// Hours with the sign of an offset in minutes, and the minutes past them.
+fn split_offset(offset: i16) -> (i8, u8) {
+    let abs = offset.unsigned_abs();
+    (offset.signum() as i8 * (abs / 60) as i8, (abs % 60) as u8)
+}
+
+/// The PDF date string, e.g. `D:20240131120000+01'00'`.
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "D:{:04}{:02}{:02}{:02}{:02}{:02}", self.year, self.month, self.day, self.hour, self.minute, self.second)?;
+        match self.offset {
+            Some(0) => f.write_str("Z"),
+            Some(offset) => {
+                let sign = if offset < 0 { '-' } else { '+' };
+                write!(f, "{}{:02}'{:02}'", sign, offset.unsigned_abs() / 60, offset.unsigned_abs() % 60)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl Metadata {
+    pub fn is_empty(&self) -> bool {
+        *self == Metadata::default()
+    }
+
+    /// Fill the fields `self` lacks from `other`.
+    fn or(mut self, other: Metadata) -> Metadata {
+        self.title = self.title.or(other.title);
+        self.author = self.author.or(other.author);
+        self.subject = self.subject.or(other.subject);
+        self.keywords = self.keywords.or(other.keywords);
+        self.creator = self.creator.or(other.creator);
+        self.producer = self.producer.or(other.producer);
+        self.creation_date = self.creation_date.or(other.creation_date);
+        self.modification_date = self.modification_date.or(other.modification_date);
+        self.language = self.language.or(other.language);
+        self
+    }
+
+    /// An XMP packet with the same values as the Info dictionary, and the
+    /// PDF/A and PDF/UA identifications when the output is written for them.
+    pub fn to_xmp(&self, pdfa: Option<PdfA>, pdfua: bool) -> String {
+        let mut xmp = XmpWriter::new();
+        xmp.format("application/pdf");
+        if let Some(level) = pdfa {
+            xmp.pdfa_part(level.part());
+            xmp.pdfa_conformance(level.conformance());
+        }
+        if pdfua {
+            xmp.pdfua_part(1);
+        }
+        if let Some(title) = &self.title {
+            xmp.title([(Some(LangId::default()), title.as_str())]);
+        }
+        if let Some(author) = &self.author {
+            xmp.creator([author.as_str()]);
+        }
+        if let Some(subject) = &self.subject {
+            xmp.description([(Some(LangId::default()), subject.as_str())]);
+        }
+        if let Some(keywords) = &self.keywords {
+            xmp.pdf_keywords(keywords);
+        }
+        if let Some(language) = &self.language {
+            xmp.language([LangId(language)]);
+        }
+        if let Some(creator) = &self.creator {
+            xmp.creator_tool(creator);
+        }
+        if let Some(producer) = &self.producer {
+            xmp.producer(producer);
+        }
+        if let Some(date) = self.creation_date {
+            xmp.create_date(date.to_xmp());
+        }
+        if let Some(date) = self.modification_date {
+            xmp.modify_date(date.to_xmp());
+            xmp.metadata_date(date.to_xmp());
+        }
+        xmp.finish(None)
+    }
+}
+
+// ========== Reading with lopdf ==========
+
+/// Metadata of a (decrypted) lopdf document: the Info dictionary, then the
+/// catalog's XMP packet for what Info lacks.
+pub fn from_lopdf(doc: &LoDoc) -> Metadata {
+    let info = from_info(doc);
+    match xmp_packet(doc) {
+        Some(xmp) => info.or(from_xmp(&xmp)),
+        None => info,
+    }
+}
+
+/// The Info dictionary and /Lang alone.
+pub(crate) fn from_info(doc: &LoDoc) -> Metadata {
+    let info = doc.trailer.get(b"Info").ok().and_then(|info| match info {
+        Object::Reference(id) => doc.get_dictionary(*id).ok(),
+        info => info.as_dict().ok(),
+    });
+    let text = |key: &[u8]| {
+        let value = info?.get(key).ok()?;
+        let value = match value {
+            Object::Reference(id) => doc.get_object(*id).ok()?,
+            value => value,
+        };
+        decode_text_string(value).ok().map(|v| v.trim().to_string()).filter(|v| !v.is_empty())
+    };
+    Metadata {
+        title: text(b"Title"),
+        author: text(b"Author"),
+        subject: text(b"Subject"),
+        keywords: text(b"Keywords"),
+        creator: text(b"Creator"),
+        producer: text(b"Producer"),
+        creation_date: text(b"CreationDate").and_then(|d| Date::parse(&d)),
+        modification_date: text(b"ModDate").and_then(|d| Date::parse(&d)),
+        language: doc.catalog().and_then(|c| c.get(b"Lang")).ok().and_then(|lang| decode_text_string(lang).ok()).filter(|l| !l.is_empty()),
+    }
+}
+
+/// The catalog's XMP packet.
+pub(crate) fn xmp_packet(doc: &LoDoc) -> Option<String> {
+    let id = doc.catalog().and_then(|c| c.get(b"Metadata")).and_then(Object::as_reference).ok()?;
+    let stream = doc.get_object(id).and_then(Object::as_stream).ok()?;
+    let xmp = stream.decompressed_content().unwrap_or_else(|_| stream.content.clone());
+    Some(String::from_utf8_lossy(&xmp).into_owned())
+}
+
+/// The properties we write, read from an XMP packet.
+pub(crate) fn from_xmp(xmp: &str) -> Metadata {
+    let property = |name| xmp_property(xmp, name);
+    Metadata {
+        title: property("dc:title"),
+        author: property("dc:creator"),
+        subject: property("dc:description"),
+        keywords: property("pdf:Keywords"),
+        creator: property("xmp:CreatorTool"),
+        producer: property("pdf:Producer"),
+        creation_date: property("xmp:CreateDate").and_then(|d| Date::parse(&d)),
+        modification_date: property("xmp:ModifyDate").and_then(|d| Date::parse(&d)),
+        language: property("dc:language"),
+    }
+}
+
+/// Property `name` of an XMP packet. Values may be element content (plain
+/// or an rdf:Alt/Seq/Bag, whose items are joined) or attributes of
+/// rdf:Description.
+pub(crate) fn xmp_property(xmp: &str, name: &str) -> Option<String> {
+    let element = Regex::new(&format!(r"(?s)<{0}(?:\s[^>]*)?>(.*?)</{0}>", regex::escape(name))).ok()?;
+    let value = match element.captures(xmp) {
+        Some(caps) => {
+            let content = caps.get(1)?.as_str();
+            let item = Regex::new(r"(?s)<rdf:li(?:\s[^>]*)?>(.*?)</rdf:li>").ok()?;
+            let items: Vec<&str> = item.captures_iter(content).filter_map(|c| c.get(1)).map(|m| m.as_str()).collect();
+            if items.is_empty() { content.to_string() } else { items.join(", ") }
+        }
+        None => Regex::new(&format!(r#"\s{}\s*=\s*"([^"]*)""#, regex::escape(name))).ok()?.captures(xmp)?.get(1)?.as_str().to_string(),
+    };
+    Some(unescape(value.trim())).filter(|v| !v.is_empty())
+}
+
+/// The properties `to_xmp` writes for the fields of `Metadata`, with their
+/// namespaces.
+const PROPERTIES: [(&str, &str); 10] = [
+    ("dc:title", DC),
+    ("dc:creator", DC),
+    ("dc:description", DC),
+    ("dc:language", DC),
+    ("pdf:Keywords", PDF),
+    ("pdf:Producer", PDF),
+    ("xmp:CreatorTool", XMP),
+    ("xmp:CreateDate", XMP),
+    ("xmp:ModifyDate", XMP),
+    ("xmp:MetadataDate", XMP),
+];
+const DC: &str = "http://purl.org/dc/elements/1.1/";
+const PDF: &str = "http://ns.adobe.com/pdf/1.3/";
+const XMP: &str = "http://ns.adobe.com/xap/1.0/";
+
+/// `xmp` with the properties of the fields `changes` sets replaced by the
+/// values `to_xmp` writes for them; all other properties, also of other
+/// schemas, are kept. Properties the packet lacks, or holds as attributes,
+/// go into a description of their own.
+pub(crate) fn update_xmp(xmp: &str, changes: &Metadata) -> String {
+    let generated = changes.to_xmp(None, false);
+    let mut xmp = xmp.to_string();
+    let mut added = String::new();
+    let mut namespaces = Vec::new();
+    for (property, namespace) in PROPERTIES {
+        let name = regex::escape(property);
+        let element = Regex::new(&format!(r"(?s)<{0}(?:\s[^>]*)?>.*?</{0}>", name)).unwrap();
+        let Some(value) = element.find(&generated) else { continue };
+        if let Some(old) = element.find(&xmp) {
+            xmp.replace_range(old.range(), value.as_str());
+            continue;
+        }
+        let attribute = Regex::new(&format!(r#"\s{}\s*=\s*"[^"]*""#, name)).unwrap();
+        xmp = attribute.replace(&xmp, "").into_owned();
+        added.push_str(value.as_str());
+        let prefix = property.split(':').next().unwrap_or_default();
+        if !namespaces.iter().any(|&(p, _)| p == prefix) {
+            namespaces.push((prefix, namespace));
+        }
+    }
+    if let (false, Some(end)) = (added.is_empty(), xmp.find("</rdf:RDF>")) {
+        let declarations: String = namespaces.iter().map(|(prefix, url)| format!(r#" xmlns:{}="{}""#, prefix, url)).collect();
+        xmp.insert_str(end, &format!(r#"<rdf:Description rdf:about=""{}>{}</rdf:Description>"#, declarations, added));
+    }
+    xmp
+}
+
+fn unescape(s: &str) -> String {
+    let entity = Regex::new(r"&(#x[0-9a-fA-F]+|#[0-9]+|amp|lt|gt|quot|apos);").unwrap();
+    entity
+        .replace_all(s, |caps: &regex::Captures| match &caps[1] {
+            "amp" => "&".to_string(),
+            "lt" => "<".to_string(),
+            "gt" => ">".to_string(),
+            "quot" => "\"".to_string(),
+            "apos" => "'".to_string(),
+            code => {
+                let n = match code.strip_prefix("#x") {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                    None => code[1..].parse().ok(),
+                };
+                n.and_then(char::from_u32).map(String::from).unwrap_or_default()
+            }
+        })
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pdf_writer::{Chunk, Ref};
+
+    fn date(offset: Option<i16>) -> Date {
+        Date { year: 2024, month: 1, day: 31, hour: 23, minute: 45, second: 10, offset }
+    }
+
+    /// The date as pdf-writer writes it into the Info dictionary.
+    fn info_string(date: Date) -> String {
+        let mut chunk = Chunk::new();
+        chunk.indirect(Ref::new(1)).primitive(date.to_pdf_writer());
+        let bytes = String::from_utf8(chunk.as_bytes().to_vec()).unwrap();
+        bytes[bytes.find('(').unwrap() + 1..bytes.find(')').unwrap()].to_string()
+    }
+
+    /// The creation date as the XMP packet holds it.
+    fn xmp_string(date: Date) -> String {
+        let metadata = Metadata { creation_date: Some(date), ..Metadata::default() };
+        xmp_property(&metadata.to_xmp(None, false), "xmp:CreateDate").unwrap()
+    }
+
+    #[test]
+    fn parses_pdf_dates() {
+        assert_eq!(Date::parse("D:20240131234510+01'00'"), Some(date(Some(60))));
+        assert_eq!(Date::parse("D:20240131234510-03'30'"), Some(date(Some(-210))));
+        assert_eq!(Date::parse("D:20240131234510-00'30'"), Some(date(Some(-30))));
+        assert_eq!(Date::parse("D:20240131234510Z"), Some(date(Some(0))));
+        assert_eq!(Date::parse("D:20240131234510"), Some(date(None)));
+        assert_eq!(Date::parse("D:2024"), Some(Date { year: 2024, month: 1, day: 1, ..Date::default() }));
+        assert_eq!(Date::parse("D:20"), None);
+    }
+
+    #[test]
+    fn parses_xmp_dates() {
+        assert_eq!(Date::parse("2024-01-31T23:45:10+05:30"), Some(date(Some(330))));
+        assert_eq!(Date::parse("2024-01-31T23:45:10-03:30"), Some(date(Some(-210))));
+        assert_eq!(Date::parse("2024-01-31T23:45:10-00:30"), Some(date(Some(-30))));
+        assert_eq!(Date::parse("2024-01-31T23:45:10Z"), Some(date(Some(0))));
+        assert_eq!(Date::parse("2024-01-31"), Some(Date { year: 2024, month: 1, day: 31, ..Date::default() }));
+    }
+
+    #[test]
+    fn displays_offsets_with_their_sign() {
+        assert_eq!(date(Some(-210)).to_string(), "D:20240131234510-03'30'");
+        assert_eq!(date(Some(-30)).to_string(), "D:20240131234510-00'30'");
+        assert_eq!(date(Some(330)).to_string(), "D:20240131234510+05'30'");
+        assert_eq!(date(Some(0)).to_string(), "D:20240131234510Z");
+        assert_eq!(date(None).to_string(), "D:20240131234510");
+    }
+
+    #[test]
+    fn writes_negative_offsets() {
+        assert_eq!(info_string(date(Some(-210))), "D:20240131234510-03'30");
+        assert_eq!(xmp_string(date(Some(-210))), "2024-01-31T23:45:10-03:30");
+        assert_eq!(info_string(date(Some(330))), "D:20240131234510+05'30");
+        assert_eq!(xmp_string(date(Some(330))), "2024-01-31T23:45:10+05:30");
+    }
+
+    #[test]
+    fn writes_offsets_under_an_hour_behind_utc_in_utc() {
+        // 23:45 at -00:30 is 00:15 UTC on the next day, here the next year
+        let late = Date { month: 12, day: 31, ..date(Some(-30)) };
+        assert_eq!(info_string(late), "D:20250101001510Z");
+        assert_eq!(xmp_string(late), "2025-01-01T00:15:10Z");
+        let leap = Date { month: 2, day: 28, ..date(Some(-30)) };
+        assert_eq!(info_string(leap), "D:20240229001510Z");
+    }
+
+    #[test]
+    fn round_trips() {
+        for offset in [None, Some(0), Some(60), Some(330), Some(-210), Some(-600), Some(-30)] {
+            let date = date(offset);
+            assert_eq!(Date::parse(&date.to_string()), Some(date), "{}", date);
+            let written = date.writable();
+            assert_eq!(Date::parse(&info_string(date)), Some(written), "{}", date);
+            assert_eq!(Date::parse(&xmp_string(date)), Some(written), "{}", date);
+        }
+    }
+
+    #[test]
+    fn updates_only_the_changed_xmp_properties() {
+        let xmp = concat!(
+            r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">"#,
+            r#"<rdf:Description rdf:about="" xmlns:pdfaid="http://www.aiim.org/pdfa/ns/id/" pdfaid:part="2" pdfaid:conformance="B"/>"#,
+            r#"<rdf:Description rdf:about="" xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:xmpRights="http://ns.adobe.com/xap/1.0/rights/" xmlns:pdf="http://ns.adobe.com/pdf/1.3/" pdf:Producer="TeX">"#,
+            r#"<dc:title><rdf:Alt><rdf:li xml:lang="x-default">Old</rdf:li></rdf:Alt></dc:title>"#,
+            r#"<dc:rights><rdf:Alt><rdf:li xml:lang="x-default">CC-BY</rdf:li></rdf:Alt></dc:rights>"#,
+            r#"<xmpRights:Marked>True</xmpRights:Marked>"#,
+            r#"</rdf:Description></rdf:RDF></x:xmpmeta>"#,
+        );
+        let changes = Metadata { title: Some("New & improved".into()), producer: Some(PRODUCER.into()), ..Metadata::default() };
+        let updated = update_xmp(xmp, &changes);
+        assert_eq!(from_xmp(&updated), Metadata { title: changes.title.clone(), producer: changes.producer.clone(), ..Metadata::default() });
+        assert_eq!(xmp_property(&updated, "pdfaid:part").as_deref(), Some("2"));
+        assert_eq!(xmp_property(&updated, "dc:rights").as_deref(), Some("CC-BY"));
+        assert_eq!(xmp_property(&updated, "xmpRights:Marked").as_deref(), Some("True"));
+        assert_eq!(updated.matches("pdf:Producer").count(), 2, "{}", updated);
+        assert_eq!(update_xmp(xmp, &Metadata::default()), xmp);
+    }
+}
@@ -0,0 +1,380 @@
+// ========== Content-stream optimizer ==========
+//
+// Works on parsed operations instead of text lines, so it does not depend on
+// how the previous rewriting step laid out the stream.
+
+use lopdf::Object;
+use lopdf::content::{Content, Operation};
+use std::collections::BTreeMap;
+use std::fmt;
+
+#[derive(Clone, Debug)]
+pub struct OptimizeOptions {
+    /// Number of decimal places kept for real operands.
+    pub precision: usize,
+}
+
+impl Default for OptimizeOptions {
+    fn default() -> Self {
+        OptimizeOptions { precision: 5 }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OptimizeStats {
+    pub bytes_before: usize,
+    pub bytes_after: usize,
+    pub ops_before: usize,
+    pub ops_after: usize,
+}
+
+impl OptimizeStats {
+    pub fn add(&mut self, other: OptimizeStats) {
+        self.bytes_before += other.bytes_before;
+        self.bytes_after += other.bytes_after;
+        self.ops_before += other.ops_before;
+        self.ops_after += other.ops_after;
+    }
+}
+
+impl fmt::Display for OptimizeStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let saved = self.bytes_before as f64 - self.bytes_after as f64;
+        let pct = if self.bytes_before > 0 { saved * 100.0 / self.bytes_before as f64 } else { 0.0 };
+        write!(
+            f,
+            "{} -> {} bytes ({:.1}% saved), {} -> {} operations",
+            self.bytes_before, self.bytes_after, pct, self.ops_before, self.ops_after
+        )
+    }
+}
+
+/// Parse, optimize and re-encode one content stream.
+pub fn optimize_content(data: &[u8], opts: &OptimizeOptions) -> lopdf::Result<(Vec<u8>, OptimizeStats)> {
+    let content = Content::decode(data)?;
+    let ops_before = content.operations.len();
+    let ops = optimize_operations(content.operations, opts);
+    let ops_after = ops.len();
+    let encoded = encode_indented(&ops)?;
+    let stats = OptimizeStats {
+        bytes_before: data.len(),
+        bytes_after: encoded.len(),
+        ops_before,
+        ops_after,
+    };
+    Ok((encoded, stats))
+}
+
+pub fn optimize_operations(mut ops: Vec<Operation>, opts: &OptimizeOptions) -> Vec<Operation> {
+    for op in &mut ops {
+        for operand in &mut op.operands {
+            round_object(operand, opts.precision);
+        }
+    }
+    // Each pass can expose work for another one (e.g. dropping a redundant
+    // font inside q ... Q leaves an empty pair), so run until nothing changes.
+    loop {
+        let len = ops.len();
+        ops = drop_redundant_state(ops);
+        ops = collapse_td(ops);
+        ops = merge_text_objects(ops);
+        ops = remove_empty_groups(ops);
+        if ops.len() == len {
+            return ops;
+        }
+    }
+}
+
+/// Encode operations one per line, indenting nested q/Q and BT/ET like Typst.
+pub fn encode_indented(ops: &[Operation]) -> lopdf::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut depth = 0usize;
+    for (i, op) in ops.iter().enumerate() {
+        if op.operator == "Q" || op.operator == "ET" {
+            depth = depth.saturating_sub(1);
+        }
+        if i > 0 {
+            out.push(b'\n');
+        }
+        out.extend(std::iter::repeat_n(b' ', depth * 4));
+        let single = Content { operations: std::slice::from_ref(op) };
+        out.extend(single.encode()?);
+        if op.operator == "q" || op.operator == "BT" {
+            depth += 1;
+        }
+    }
+    Ok(out)
+}
+
+fn round_object(obj: &mut Object, precision: usize) {
+    match obj {
+        Object::Real(v) => {
+            let scale = 10f64.powi(precision as i32);
+            let rounded = (*v as f64 * scale).round() / scale;
+            if rounded.fract() == 0.0 && rounded.abs() < i32::MAX as f64 {
+                *obj = Object::Integer(rounded as i64);
+            } else {
+                *v = rounded as f32;
+            }
+        }
+        Object::Array(items) => {
+            for item in items {
+                round_object(item, precision);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn operands_key(op: &Operation) -> String {
+    let single = Content { operations: vec![Operation::new("", op.operands.clone())] };
+    String::from_utf8_lossy(&single.encode().unwrap_or_default()).into_owned()
+}
+
+/// Operators that only change the graphics or text state and never paint.
+fn is_state_op(op: &str) -> bool {
+    matches!(
+        op,
+        "cm" | "w" | "J" | "j" | "M" | "d" | "ri" | "i" | "gs"
+            | "cs" | "CS" | "sc" | "scn" | "SC" | "SCN" | "g" | "G" | "rg" | "RG" | "k" | "K"
+            | "Tc" | "Tw" | "Tz" | "TL" | "Tf" | "Tr" | "Ts"
+    )
+}
+
+/// Drop color, font, line width and text state operators that set the value
+/// already in effect. The state is saved and restored across q/Q; `gs` may
+/// set the font and line width, so both are unknown after it.
+fn drop_redundant_state(ops: Vec<Operation>) -> Vec<Operation> {
+    const INITIAL: &str = "<initial>";
+    let mut state: BTreeMap<&'static str, String> = BTreeMap::new();
+    let mut stack = Vec::new();
+    let mut out = Vec::with_capacity(ops.len());
+    let mut iter = ops.into_iter().peekable();
+
+    while let Some(op) = iter.next() {
+        let (space_slot, color_slot) = match op.operator.as_str() {
+            "cs" | "sc" | "scn" | "g" | "rg" | "k" => ("fill_space", "fill_color"),
+            "CS" | "SC" | "SCN" | "G" | "RG" | "K" => ("stroke_space", "stroke_color"),
+            _ => ("", ""),
+        };
+        match op.operator.as_str() {
+            "q" => {
+                stack.push(state.clone());
+                out.push(op);
+            }
+            "Q" => {
+                state = stack.pop().unwrap_or_default();
+                out.push(op);
+            }
+            "cs" | "CS" => {
+                let space = operands_key(&op);
+                let color_op = if op.operator == "cs" { ["sc", "scn"] } else { ["SC", "SCN"] };
+                let follows = iter.peek().is_some_and(|next| color_op.contains(&next.operator.as_str()));
+                let color = if follows { operands_key(iter.peek().unwrap()) } else { INITIAL.to_string() };
+                let same = state.get(space_slot) == Some(&space) && state.get(color_slot) == Some(&color);
+                if same {
+                    if follows {
+                        iter.next();
+                    }
+                    continue;
+                }
+                state.insert(space_slot, space);
+                state.insert(color_slot, color);
+                out.push(op);
+                if follows {
+                    out.push(iter.next().unwrap());
+                }
+            }
+            "sc" | "scn" | "SC" | "SCN" => {
+                let color = operands_key(&op);
+                if state.get(color_slot) != Some(&color) {
+                    state.insert(color_slot, color);
+                    out.push(op);
+                }
+            }
+            "g" | "rg" | "k" | "G" | "RG" | "K" => {
+                let space = match op.operator.to_ascii_lowercase().as_str() {
+                    "g" => "/DeviceGray",
+                    "rg" => "/DeviceRGB",
+                    _ => "/DeviceCMYK",
+                }
+                .to_string();
+                let color = operands_key(&op);
+                if state.get(space_slot) != Some(&space) || state.get(color_slot) != Some(&color) {
+                    state.insert(space_slot, space);
+                    state.insert(color_slot, color);
+                    out.push(op);
+                }
+            }
+            "gs" => {
+                // The graphics state parameter dictionary can set /Font and /LW
+                state.remove("Tf");
+                state.remove("w");
+                out.push(op);
+            }
+            "Tf" | "w" | "Ts" | "Tc" | "Tw" | "Tz" | "TL" | "Tr" => {
+                let slot: &'static str = match op.operator.as_str() {
+                    "Tf" => "Tf",
+                    "w" => "w",
+                    "Ts" => "Ts",
+                    "Tc" => "Tc",
+                    "Tw" => "Tw",
+                    "Tz" => "Tz",
+                    "TL" => "TL",
+                    _ => "Tr",
+                };
+                let value = operands_key(&op);
+                if state.get(slot) != Some(&value) {
+                    state.insert(slot, value);
+                    out.push(op);
+                }
+            }
+            _ => out.push(op),
+        }
+    }
+    out
+}
+
+fn number(obj: &Object) -> Option<f32> {
+    match obj {
+        Object::Integer(i) => Some(*i as f32),
+        Object::Real(r) => Some(*r),
+        _ => None,
+    }
+}
+
+/// `a b Td c d Td` moves the line matrix by `(a + c, b + d)`.
+fn collapse_td(ops: Vec<Operation>) -> Vec<Operation> {
+    let mut out: Vec<Operation> = Vec::with_capacity(ops.len());
+    for op in ops {
+        if op.operator == "Td"
+            && let Some(prev) = out.last_mut().filter(|p| p.operator == "Td")
+        {
+            let sum = prev.operands.iter().zip(&op.operands).map(|(a, b)| Some(number(a)? + number(b)?)).collect::<Option<Vec<_>>>();
+            if let Some(sum) = sum.filter(|s| s.len() == 2) {
+                prev.operands = sum.into_iter().map(Object::Real).collect();
+                continue;
+            }
+        }
+        out.push(op);
+    }
+    out
+}
+
+/// Merge `ET [state] BT` when the following text object starts with its own
+/// `Tm`: BT only resets the text matrices, so a leading Tm makes the reset
+/// moot. State operators in between are allowed inside a text object, except
+/// `cm`, which blocks the merge.
+fn merge_text_objects(ops: Vec<Operation>) -> Vec<Operation> {
+    let mut out: Vec<Operation> = Vec::with_capacity(ops.len());
+    let mut skip_bt = None;
+    for (i, op) in ops.iter().enumerate() {
+        if Some(i) == skip_bt {
+            skip_bt = None;
+            continue;
+        }
+        if op.operator == "ET" {
+            let bt = ops[i + 1..].iter().position(|o| o.operator == "cm" || !is_state_op(&o.operator)).map(|p| i + 1 + p);
+            if let Some(bt) = bt.filter(|&bt| ops[bt].operator == "BT") {
+                let first_positioning = ops[bt + 1..].iter().find(|o| !is_state_op(&o.operator));
+                if first_positioning.is_some_and(|o| o.operator == "Tm") {
+                    skip_bt = Some(bt);
+                    continue;
+                }
+            }
+        }
+        out.push(op.clone());
+    }
+    out
+}
+
+/// Remove `q ... Q` groups (and `BT ET` pairs) that contain nothing but state
+/// changes, since everything they set is discarded again.
+fn remove_empty_groups(ops: Vec<Operation>) -> Vec<Operation> {
+    let mut out: Vec<Operation> = Vec::with_capacity(ops.len());
+    let mut opens: Vec<usize> = Vec::new();
+    for op in ops {
+        match op.operator.as_str() {
+            "q" => {
+                opens.push(out.len());
+                out.push(op);
+            }
+            "Q" => {
+                if let Some(start) = opens.pop()
+                    && out[start + 1..].iter().all(|o| is_state_op(&o.operator))
+                {
+                    out.truncate(start);
+                    continue;
+                }
+                out.push(op);
+            }
+            "ET" if out.last().is_some_and(|o| o.operator == "BT") => {
+                out.pop();
+            }
+            _ => out.push(op),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run `pass` over `content` and give the operations back one per line.
+    fn run(pass: fn(Vec<Operation>) -> Vec<Operation>, content: &str) -> String {
+        let ops = pass(Content::decode(content.as_bytes()).unwrap().operations);
+        String::from_utf8(encode_indented(&ops).unwrap()).unwrap().lines().map(str::trim).collect::<Vec<_>>().join("\n")
+    }
+
+    #[test]
+    fn drops_state_already_in_effect() {
+        let out = run(drop_redundant_state, "BT /F1 10 Tf 0 g (a) Tj /F1 10 Tf 0 g 1 Tc (b) Tj /F2 10 Tf 1 Tc (c) Tj ET");
+        assert_eq!(out, "BT\n/F1 10 Tf\n0 g\n(a) Tj\n1 Tc\n(b) Tj\n/F2 10 Tf\n(c) Tj\nET");
+    }
+
+    #[test]
+    fn keeps_state_restored_by_q() {
+        // After Q the font is /F1 again, so /F2 must be set anew
+        let out = run(drop_redundant_state, "/F1 10 Tf q /F2 10 Tf (a) Tj Q /F2 10 Tf (b) Tj q /F2 10 Tf (c) Tj Q");
+        assert_eq!(out, "/F1 10 Tf\nq\n/F2 10 Tf\n(a) Tj\nQ\n/F2 10 Tf\n(b) Tj\nq\n(c) Tj\nQ");
+    }
+
+    #[test]
+    fn forgets_font_and_line_width_set_by_gs() {
+        let out = run(drop_redundant_state, "/F1 12 Tf 2 w 0 g /GS0 gs /F1 12 Tf 2 w 0 g");
+        assert_eq!(out, "/F1 12 Tf\n2 w\n0 g\n/GS0 gs\n/F1 12 Tf\n2 w");
+    }
+
+    #[test]
+    fn tracks_color_spaces_with_their_color() {
+        // g sets DeviceGray, so the same gray after rg is not redundant; a
+        // space is only dropped together with the color following it
+        let out = run(drop_redundant_state, "0 g 0 0 0 rg 0 g /CS0 cs 1 sc /CS0 cs 1 sc /CS0 cs 0.5 sc 0.5 sc");
+        assert_eq!(out, "0 g\n0 0 0 rg\n0 g\n/CS0 cs\n1 sc\n/CS0 cs\n0.5 sc");
+    }
+
+    #[test]
+    fn merges_text_objects_repositioned_by_tm() {
+        let out = run(merge_text_objects, "BT 1 0 0 1 10 10 Tm (a) Tj ET 0 g BT /F1 10 Tf 1 0 0 1 10 20 Tm (b) Tj ET");
+        assert_eq!(out, "BT\n1 0 0 1 10 10 Tm\n(a) Tj\n0 g\n/F1 10 Tf\n1 0 0 1 10 20 Tm\n(b) Tj\nET");
+    }
+
+    #[test]
+    fn keeps_text_objects_apart_without_tm_or_across_cm() {
+        let td = "BT 1 0 0 1 10 10 Tm (a) Tj ET BT 10 20 Td (b) Tj ET";
+        assert_eq!(run(merge_text_objects, td), run(|ops| ops, td));
+        let cm = "BT 1 0 0 1 10 10 Tm (a) Tj ET 2 0 0 2 0 0 cm BT 1 0 0 1 10 20 Tm (b) Tj ET";
+        assert_eq!(run(merge_text_objects, cm), run(|ops| ops, cm));
+        let path = "BT 1 0 0 1 10 10 Tm (a) Tj ET 0 0 m 1 1 l S BT 1 0 0 1 10 20 Tm (b) Tj ET";
+        assert_eq!(run(merge_text_objects, path), run(|ops| ops, path));
+    }
+
+    #[test]
+    fn optimizes_until_nothing_changes() {
+        // Dropping the repeated font empties the q/Q group, which then goes
+        let ops = Content::decode(b"BT /F1 10 Tf 1 0 0 1 0 0 Tm (a) Tj ET q BT /F1 10 Tf ET Q 1.234567 0 Td").unwrap().operations;
+        let out = optimize_operations(ops, &OptimizeOptions { precision: 2 });
+        assert_eq!(String::from_utf8(encode_indented(&out).unwrap()).unwrap(), "BT\n    /F1 10 Tf\n    1 0 0 1 0 0 Tm\n    (a) Tj\nET\n1.23 0 Td");
+    }
+}
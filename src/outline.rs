@@ -0,0 +1,244 @@
+// ========== Document outline ==========
+//
+// Bookmarks as the extractors read them from the input and the renderers
+// write them to the output. Destinations keep the input's view and are
+// renumbered to output pages when only some pages are converted; each
+// renderer maps them onto its own pages.
+
+use lopdf::{decode_text_string, Dictionary, Document as LoDoc, Object, ObjectId};
+use std::collections::HashSet;
+
+/// One bookmark and the ones nested under it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutlineItem {
+    pub title: String,
+    /// `None` for bookmarks that do not jump within the document (e.g. URI actions)
+    pub dest: Option<Destination>,
+    /// Whether the children are shown expanded
+    pub open: bool,
+    pub children: Vec<OutlineItem>,
+}
+
+/// A place in the input document.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Destination {
+    /// 0-based page index
+    pub page: usize,
+    pub view: View,
+}
+
+/// How the target page is shown, coordinates in PDF user space. `None`
+/// keeps the viewer's current value.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum View {
+    Xyz { left: Option<f32>, top: Option<f32>, zoom: Option<f32> },
+    Fit,
+    FitH { top: Option<f32> },
+    FitV { left: Option<f32> },
+    FitR { left: f32, bottom: f32, right: f32, top: f32 },
+    FitB,
+    FitBH { top: Option<f32> },
+    FitBV { left: Option<f32> },
+}
+
+/// Number of items shown below `items` with the open state they have.
+pub fn visible_count(items: &[OutlineItem]) -> usize {
+    items.iter().map(|item| 1 + if item.open { visible_count(&item.children) } else { 0 }).sum()
+}
+
+/// Point the destinations of `items` at output pages, `pages` being the input
+/// page of each output page. Bookmarks into pages left out keep their title
+/// but no longer jump.
+pub fn renumber(items: &mut [OutlineItem], pages: &[usize]) {
+    for item in items {
+        item.dest = item.dest.and_then(|dest| dest.renumber(pages));
+        renumber(&mut item.children, pages);
+    }
+}
+
+impl Destination {
+    /// The same place on the output page showing input page `self.page`, if any.
+    pub fn renumber(self, pages: &[usize]) -> Option<Destination> {
+        let page = pages.iter().position(|&p| p == self.page)?;
+        Some(Destination { page, ..self })
+    }
+}
+
+// ========== Reading with lopdf ==========
+
+/// Read the outline of a (decrypted) lopdf document; `pages` are the page
+/// object ids in page order.
+pub fn from_lopdf(doc: &LoDoc, pages: &[ObjectId]) -> Vec<OutlineItem> {
+    let first = doc.catalog().and_then(|catalog| doc.get_dict_in_dict(catalog, b"Outlines")).and_then(|root| root.get(b"First"));
+    let mut seen = HashSet::new();
+    first.map(|first| read_items(doc, pages, first, &mut seen)).unwrap_or_default()
+}
+
+/// The items linked from `first` by /Next, with their children. `seen`
+/// stops at loops in broken files.
+fn read_items(doc: &LoDoc, pages: &[ObjectId], first: &Object, seen: &mut HashSet<ObjectId>) -> Vec<OutlineItem> {
+    let mut items = Vec::new();
+    let mut next = first.as_reference().ok();
+    while let Some(id) = next.filter(|id| seen.insert(*id)) {
+        let Ok(dict) = doc.get_dictionary(id) else { break };
+        let title = dict.get(b"Title").ok().map(|t| resolve(doc, t)).and_then(|t| decode_text_string(t).ok()).unwrap_or_default();
+        let children = dict.get(b"First").map(|first| read_items(doc, pages, first, seen)).unwrap_or_default();
+        items.push(OutlineItem {
+            title,
+            dest: item_destination(doc, pages, dict),
+            open: dict.get(b"Count").and_then(Object::as_i64).is_ok_and(|count| count > 0),
+            children,
+        });
+        next = dict.get(b"Next").and_then(Object::as_reference).ok();
+    }
+    items
+}
+
+/// Destination of an outline item or link annotation: /Dest, or /D of a GoTo action.
+pub fn item_destination(doc: &LoDoc, pages: &[ObjectId], dict: &Dictionary) -> Option<Destination> {
+    if let Ok(dest) = dict.get(b"Dest") {
+        return destination(doc, pages, dest);
+    }
+    let action = resolve(doc, dict.get(b"A").ok()?).as_dict().ok()?;
+    if action.get(b"S").and_then(Object::as_name).ok()? != b"GoTo" {
+        return None;
+    }
+    destination(doc, pages, action.get(b"D").ok()?)
+}
+
+/// Resolve an explicit destination array or a named one.
+fn destination(doc: &LoDoc, pages: &[ObjectId], dest: &Object) -> Option<Destination> {
+    let dest = match resolve(doc, dest) {
+        Object::Name(name) | Object::String(name, _) => named_destination(doc, name)?,
+        dest => dest,
+    };
+    // Named destinations may be a dictionary holding the array in /D
+    let array = match dest {
+        Object::Dictionary(dict) => resolve(doc, dict.get(b"D").ok()?).as_array().ok()?,
+        dest => dest.as_array().ok()?,
+    };
+    let page = match array.first()? {
+        Object::Reference(id) => pages.iter().position(|p| p == id)?,
+        // Remote-style destinations give the page number directly
+        page => page.as_i64().ok().and_then(|n| usize::try_from(n).ok())?,
+    };
+    let num = |i: usize| array.get(i).and_then(|v| v.as_float().ok());
+    let view = match array.get(1).and_then(|v| v.as_name().ok()).unwrap_or(b"Fit") {
+        b"XYZ" => View::Xyz { left: num(2), top: num(3), zoom: num(4).filter(|z| *z != 0.0) },
+        b"FitH" => View::FitH { top: num(2) },
+        b"FitV" => View::FitV { left: num(2) },
+        b"FitR" => View::FitR { left: num(2)?, bottom: num(3)?, right: num(4)?, top: num(5)? },
+        b"FitB" => View::FitB,
+        b"FitBH" => View::FitBH { top: num(2) },
+        b"FitBV" => View::FitBV { left: num(2) },
+        _ => View::Fit,
+    };
+    Some(Destination { page, view })
+}
+
+/// Look up a named destination in the catalog's /Dests dictionary (PDF 1.1)
+/// or the /Dests name tree.
+fn named_destination<'a>(doc: &'a LoDoc, name: &[u8]) -> Option<&'a Object> {
+    let catalog = doc.catalog().ok()?;
+    if let Ok(dest) = doc.get_dict_in_dict(catalog, b"Dests").and_then(|dests| dests.get(name)) {
+        return Some(resolve(doc, dest));
+    }
+    let tree = doc.get_dict_in_dict(catalog, b"Names").and_then(|names| doc.get_dict_in_dict(names, b"Dests")).ok()?;
+    find_in_name_tree(doc, tree, name, 0)
+}
+
+fn find_in_name_tree<'a>(doc: &'a LoDoc, node: &'a Dictionary, name: &[u8], depth: usize) -> Option<&'a Object> {
+    if let Ok(names) = node.get(b"Names").and_then(Object::as_array) {
+        return names
+            .chunks_exact(2)
+            .find(|pair| resolve(doc, &pair[0]).as_str().is_ok_and(|key| key == name))
+            .map(|pair| resolve(doc, &pair[1]));
+    }
+    if depth > 32 {
+        return None;
+    }
+    let kids = node.get(b"Kids").and_then(Object::as_array).ok()?;
+    kids.iter().filter_map(|kid| resolve(doc, kid).as_dict().ok()).find_map(|kid| {
+        let in_range = kid.get(b"Limits").and_then(Object::as_array).ok().is_none_or(|limits| {
+            let limit = |i: usize| limits.get(i).and_then(|l| l.as_str().ok());
+            limit(0).is_none_or(|low| low <= name) && limit(1).is_none_or(|high| name <= high)
+        });
+        if in_range { find_in_name_tree(doc, kid, name, depth + 1) } else { None }
+    })
+}
+
+fn resolve<'a>(doc: &'a LoDoc, obj: &'a Object) -> &'a Object {
+    match obj {
+        Object::Reference(id) => doc.get_object(*id).unwrap_or(obj),
+        obj => obj,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDocument;
+    use lopdf::{dictionary, StringFormat};
+
+    fn item(title: &str, page: Option<usize>, children: Vec<OutlineItem>) -> OutlineItem {
+        let dest = page.map(|page| Destination { page, view: View::Fit });
+        OutlineItem { title: title.to_string(), dest, open: true, children }
+    }
+
+    #[test]
+    fn renumbers_to_output_pages() {
+        let mut items = vec![item("a", Some(4), vec![item("a.1", Some(2), vec![]), item("a.2", Some(6), vec![])]), item("b", None, vec![])];
+        // Output pages show input pages 2, 4 and 6 (0-based)
+        renumber(&mut items, &[2, 4, 6]);
+        assert_eq!(items, [item("a", Some(1), vec![item("a.1", Some(0), vec![]), item("a.2", Some(2), vec![])]), item("b", None, vec![])]);
+        // Bookmarks into pages left out stay, without a destination
+        renumber(&mut items, &[1]);
+        assert_eq!(items, [item("a", Some(0), vec![item("a.1", None, vec![]), item("a.2", None, vec![])]), item("b", None, vec![])]);
+    }
+
+    /// Two pages and an outline: an open item with an explicit XYZ
+    /// destination and a child jumping through a GoTo action to a name in
+    /// the /Dests name tree, an item with a name from the catalog's /Dests,
+    /// and a URI item whose /Next loops back to the first.
+    fn outlined_document() -> LoDoc {
+        let mut pages = TestDocument::new();
+        let page_ids: Vec<ObjectId> = (0..2).map(|_| pages.page("", dictionary! {})).collect();
+        let doc = &mut pages.doc;
+        let text = |s: &str| Object::String(s.as_bytes().to_vec(), StringFormat::Literal);
+
+        let (root, first, child, second, last) = (doc.new_object_id(), doc.new_object_id(), doc.new_object_id(), doc.new_object_id(), doc.new_object_id());
+        let xyz: Vec<Object> = vec![page_ids[1].into(), "XYZ".into(), 10.into(), 700.into(), 0.into()];
+        doc.objects.insert(first, dictionary! { "Title" => text("Intro"), "Parent" => root, "Next" => second, "First" => child, "Last" => child, "Count" => 1, "Dest" => xyz }.into());
+        let goto = dictionary! { "S" => "GoTo", "D" => text("sec") };
+        doc.objects.insert(child, dictionary! { "Title" => text("Section"), "Parent" => first, "A" => goto }.into());
+        doc.objects.insert(second, dictionary! { "Title" => text("Old style"), "Parent" => root, "Next" => last, "Dest" => "old" }.into());
+        let uri = dictionary! { "S" => "URI", "URI" => text("https://example.org") };
+        doc.objects.insert(last, dictionary! { "Title" => text("Web"), "Parent" => root, "A" => uri, "Next" => first }.into());
+        doc.objects.insert(root, dictionary! { "Type" => "Outlines", "First" => first, "Last" => last, "Count" => 4 }.into());
+
+        let fit_h: Vec<Object> = vec![page_ids[0].into(), "FitH".into(), 500.into()];
+        let leaf = dictionary! { "Limits" => vec![text("a"), text("z")], "Names" => vec![text("sec"), dictionary! { "D" => fit_h }.into()] };
+        let fit: Vec<Object> = vec![page_ids[0].into(), "Fit".into()];
+        pages.finish(dictionary! {
+            "Outlines" => root,
+            "Names" => dictionary! { "Dests" => dictionary! { "Kids" => vec![leaf.into()] } },
+            "Dests" => dictionary! { "old" => fit },
+        })
+    }
+
+    #[test]
+    fn reads_the_outline_with_its_destinations() {
+        let doc = outlined_document();
+        let pages: Vec<ObjectId> = doc.get_pages().into_values().collect();
+        let items = from_lopdf(&doc, &pages);
+        let dest = |page, view| Some(Destination { page, view });
+        let section = OutlineItem { title: "Section".into(), dest: dest(0, View::FitH { top: Some(500.0) }), open: false, children: vec![] };
+        let expected = [
+            OutlineItem { title: "Intro".into(), dest: dest(1, View::Xyz { left: Some(10.0), top: Some(700.0), zoom: None }), open: true, children: vec![section] },
+            OutlineItem { title: "Old style".into(), dest: dest(0, View::Fit), open: false, children: vec![] },
+            OutlineItem { title: "Web".into(), dest: None, open: false, children: vec![] },
+        ];
+        assert_eq!(items, expected);
+        assert_eq!(visible_count(&items), 4);
+    }
+}
@@ -0,0 +1,454 @@
+// ========== PDF/A ==========
+//
+// Archival output. With a conformance level the renderers add what PDF/A-2b
+// and PDF/A-3b ask for beyond the usual output: an sRGB output intent, the
+// PDF/A identification in the XMP packet, a file ID and printable
+// annotations. The fonts are embedded anyway. Since the passthrough renderer
+// and encryption rewrite the file with lopdf, [`check`] verifies the file
+// that was actually written.
+//
+// The check is our own, written against the file-level rules of ISO 19005-2
+// and -3; krilla validates only documents it writes itself, so the renderers
+// run its validator on the text they draw (see `render::pdfa`). It leaves out
+// everything that needs the content streams interpreted (colour spaces used
+// without a matching output intent, transparency and blend modes, image
+// interpolation, operators outside the allowed set), the consistency of
+// font programs with their widths and CIDSet, the XMP schemas beyond the
+// identification, the PDF/A conformance of embedded files and the
+// implementation limits.
+
+use crate::metadata::{from_info, from_xmp, xmp_packet, xmp_property, Metadata};
+use crate::{security, Error, Result};
+use lopdf::{Dictionary, Document as LoDoc, Object, ObjectId};
+use std::fmt;
+use std::io::Read;
+use std::str::FromStr;
+
+/// sRGB IEC61966-2.1 profile of the output intent (from Compact-ICC-Profiles, CC0)
+pub(crate) const SRGB_ICC: &[u8] = include_bytes!("../icc/sRGB-v4.icc");
+
+/// Output condition identifier of the output intent.
+pub(crate) const OUTPUT_CONDITION: &str = "sRGB IEC61966-2.1";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PdfA {
+    /// PDF/A-2b: visual appearance preserved
+    A2b,
+    /// PDF/A-3b: like PDF/A-2b, arbitrary embedded files allowed
+    A3b,
+}
+
+impl PdfA {
+    /// `pdfaid:part`
+    pub fn part(self) -> i32 {
+        match self {
+            PdfA::A2b => 2,
+            PdfA::A3b => 3,
+        }
+    }
+
+    /// `pdfaid:conformance`
+    pub fn conformance(self) -> &'static str {
+        "B"
+    }
+}
+
+impl FromStr for PdfA {
+    type Err = String;
+
+    /// Accepts `2b`, `3b` and spellings like `PDF/A-2b` or `A2-B`.
+    fn from_str(s: &str) -> Result<Self, String> {
+        let level: String = s.to_ascii_lowercase().replace("pdf/a", "").chars().filter(char::is_ascii_alphanumeric).collect();
+        match level.trim_start_matches('a') {
+            "2b" => Ok(PdfA::A2b),
+            "3b" => Ok(PdfA::A3b),
+            _ => Err(format!("unknown PDF/A level '{}' (expected 2b or 3b)", s)),
+        }
+    }
+}
+
+impl fmt::Display for PdfA {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PdfA::A2b => f.write_str("PDF/A2-B"),
+            PdfA::A3b => f.write_str("PDF/A3-B"),
+        }
+    }
+}
+
+// ========== Conformance check ==========
+
+/// One requirement the file breaks, with the object it was found in.
+#[derive(Clone, Debug)]
+pub struct Violation {
+    pub object: Option<ObjectId>,
+    pub message: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct PdfAReport {
+    pub level: PdfA,
+    pub violations: Vec<Violation>,
+}
+
+impl PdfAReport {
+    pub fn is_clean(&self) -> bool {
+        self.violations.is_empty()
+    }
+
+    pub fn add(&mut self, object: Option<ObjectId>, message: impl Into<String>) {
+        self.violations.push(Violation { object, message: message.into() });
+    }
+}
+
+impl fmt::Display for PdfAReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for violation in &self.violations {
+            match violation.object {
+                Some((num, generation)) => writeln!(f, "  object {} {}: {}", num, generation, violation.message)?,
+                None => writeln!(f, "  {}", violation.message)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Annotation subtypes PDF/A-2 and -3 do not allow.
+const FORBIDDEN_ANNOTATIONS: &[&[u8]] = &[b"3D", b"Sound", b"Screen", b"Movie", b"RichMedia"];
+
+/// Action types PDF/A-2 and -3 do not allow.
+const FORBIDDEN_ACTIONS: &[&[u8]] =
+    &[b"Launch", b"Sound", b"Movie", b"ResetForm", b"ImportData", b"Hide", b"SetOCGState", b"Rendition", b"Trans", b"GoTo3DView", b"JavaScript"];
+
+/// Annotation flags: Invisible, Hidden, Print, NoView, ToggleNoView.
+const INVISIBLE: i64 = 1;
+const HIDDEN: i64 = 2;
+const PRINT: i64 = 4;
+const NO_VIEW: i64 = 32;
+const TOGGLE_NO_VIEW: i64 = 256;
+
+/// Check the file at `path` against the file-level requirements of `level`:
+/// header, trailer, encryption, metadata, output intent, font embedding,
+/// annotations, actions and stream filters. Content streams are not
+/// interpreted.
+pub fn check(path: &str, level: PdfA) -> Result<PdfAReport> {
+    let mut report = PdfAReport { level, violations: Vec::new() };
+    check_header(path, &mut report)?;
+    let doc = match security::open(path, None) {
+        Ok((doc, security)) => {
+            if security.is_some() {
+                report.add(None, "the file is encrypted");
+            }
+            doc
+        }
+        Err(Error::Password(_)) => {
+            report.add(None, "the file is encrypted");
+            return Ok(report);
+        }
+        Err(e) => return Err(e),
+    };
+    match doc.trailer.get(b"ID").and_then(Object::as_array) {
+        Ok(id) if id.len() == 2 => {}
+        _ => report.add(None, "the trailer has no file /ID"),
+    }
+    check_catalog(&doc, level, &mut report);
+    for (&id, object) in &doc.objects {
+        let dict = match object {
+            Object::Dictionary(dict) => dict,
+            Object::Stream(stream) => {
+                check_stream(id, &stream.dict, &mut report);
+                &stream.dict
+            }
+            _ => continue,
+        };
+        if dict.get(b"Type").and_then(Object::as_name).is_ok_and(|t| t == b"Font") {
+            check_font(&doc, id, dict, &mut report);
+        }
+        // /Type is optional for annotations, /Rect with /Subtype marks them
+        if dict.get(b"Type").and_then(Object::as_name).is_ok_and(|t| t == b"Annot") || (dict.has(b"Rect") && dict.has(b"Subtype")) {
+            check_annotation(id, dict, &mut report);
+        }
+        if let Some(action) = dict.get(b"S").and_then(Object::as_name).ok().filter(|s| FORBIDDEN_ACTIONS.contains(s)) {
+            report.add(Some(id), format!("{} action", String::from_utf8_lossy(action)));
+        }
+        if dict.has(b"AA") && dict.get(b"Type").and_then(Object::as_name).is_ok_and(|t| t == b"Catalog" || t == b"Page") {
+            report.add(Some(id), "additional actions (/AA)");
+        }
+    }
+    Ok(report)
+}
+
+/// `%PDF-1.n` with n at most 7, then a comment of at least four bytes above 127.
+fn check_header(path: &str, report: &mut PdfAReport) -> Result<()> {
+    let mut head = [0u8; 64];
+    let len = std::fs::File::open(path)?.read(&mut head)?;
+    let head = &head[..len];
+    let version_ok = head.starts_with(b"%PDF-1.") && head.get(7).is_some_and(|n| (b'0'..=b'7').contains(n));
+    if !version_ok {
+        report.add(None, "the header is not %PDF-1.0 to %PDF-1.7");
+    }
+    let second = head.iter().position(|&b| b == b'\n' || b == b'\r').map(|eol| {
+        let rest = &head[eol..];
+        let start = rest.iter().position(|&b| b != b'\n' && b != b'\r').unwrap_or(rest.len());
+        &rest[start..]
+    });
+    let binary_ok = second.is_some_and(|line| line.first() == Some(&b'%') && line[1..].iter().take_while(|&&b| b > 127).count() >= 4);
+    if !binary_ok {
+        report.add(None, "the header is not followed by a binary comment");
+    }
+    Ok(())
+}
+
+fn check_catalog(doc: &LoDoc, level: PdfA, report: &mut PdfAReport) {
+    let Ok(catalog) = doc.catalog() else {
+        report.add(None, "the document has no catalog");
+        return;
+    };
+    let catalog_id = doc.trailer.get(b"Root").and_then(Object::as_reference).ok();
+
+    // Output intent with an embedded profile
+    let intents = resolve(doc, catalog.get(b"OutputIntents").ok()).and_then(|i| i.as_array().ok());
+    let pdfa_intent = intents.into_iter().flatten().filter_map(|i| resolve(doc, Some(i))?.as_dict().ok()).find(|intent| {
+        intent.get(b"S").and_then(Object::as_name).is_ok_and(|s| s == b"GTS_PDFA1")
+    });
+    match pdfa_intent {
+        None => report.add(catalog_id, "no GTS_PDFA1 output intent"),
+        Some(intent) if resolve(doc, intent.get(b"DestOutputProfile").ok()).and_then(|p| p.as_stream().ok()).is_none() => {
+            report.add(catalog_id, "the output intent has no embedded ICC profile (/DestOutputProfile)")
+        }
+        Some(_) => {}
+    }
+
+    // XMP packet identifying the level and agreeing with the Info dictionary
+    let metadata_id = catalog.get(b"Metadata").and_then(Object::as_reference).ok();
+    let Some(xmp) = xmp_packet(doc) else {
+        report.add(catalog_id, "no XMP metadata stream");
+        return;
+    };
+    if metadata_id.and_then(|id| doc.get_object(id).ok()).and_then(|s| s.as_stream().ok()).is_some_and(|s| s.dict.has(b"Filter")) {
+        report.add(metadata_id, "the XMP metadata stream is compressed");
+    }
+    let part = xmp_property(&xmp, "pdfaid:part");
+    if part.as_deref() != Some(level.part().to_string().as_str()) {
+        report.add(metadata_id, format!("XMP pdfaid:part is {}, expected {}", part.as_deref().unwrap_or("missing"), level.part()));
+    }
+    let conformance = xmp_property(&xmp, "pdfaid:conformance");
+    if !conformance.as_deref().is_some_and(|c| c.eq_ignore_ascii_case(level.conformance())) {
+        report.add(metadata_id, format!("XMP pdfaid:conformance is {}, expected {}", conformance.as_deref().unwrap_or("missing"), level.conformance()));
+    }
+    let info_id = doc.trailer.get(b"Info").and_then(Object::as_reference).ok();
+    for mismatch in mismatches(&from_info(doc), &from_xmp(&xmp)) {
+        report.add(info_id, mismatch);
+    }
+
+    let names = resolve(doc, catalog.get(b"Names").ok()).and_then(|n| n.as_dict().ok());
+    if level == PdfA::A2b && names.is_some_and(|names| names.has(b"EmbeddedFiles")) {
+        report.add(catalog_id, "embedded files, which PDF/A-2 only allows when they conform themselves (not verified)");
+    }
+    if names.is_some_and(|names| names.has(b"JavaScript")) {
+        report.add(catalog_id, "document-level JavaScript");
+    }
+}
+
+/// Info entries whose XMP counterpart is missing or differs.
+fn mismatches(info: &Metadata, xmp: &Metadata) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut compare = |key: &str, property: &str, info: Option<String>, xmp: Option<String>| {
+        if let Some(info) = info {
+            match xmp {
+                Some(xmp) if xmp == info => {}
+                Some(xmp) => out.push(format!("Info /{} {:?} differs from XMP {} {:?}", key, info, property, xmp)),
+                None => out.push(format!("Info /{} {:?} is missing from XMP {}", key, info, property)),
+            }
+        }
+    };
+    compare("Title", "dc:title", info.title.clone(), xmp.title.clone());
+    compare("Author", "dc:creator", info.author.clone(), xmp.author.clone());
+    compare("Subject", "dc:description", info.subject.clone(), xmp.subject.clone());
+    compare("Keywords", "pdf:Keywords", info.keywords.clone(), xmp.keywords.clone());
+    compare("Creator", "xmp:CreatorTool", info.creator.clone(), xmp.creator.clone());
+    compare("Producer", "pdf:Producer", info.producer.clone(), xmp.producer.clone());
+    compare("CreationDate", "xmp:CreateDate", info.creation_date.map(|d| d.to_string()), xmp.creation_date.map(|d| d.to_string()));
+    compare("ModDate", "xmp:ModifyDate", info.modification_date.map(|d| d.to_string()), xmp.modification_date.map(|d| d.to_string()));
+    out
+}
+
+fn check_stream(id: ObjectId, dict: &Dictionary, report: &mut PdfAReport) {
+    let filters = match dict.get(b"Filter") {
+        Ok(Object::Name(name)) => vec![name.as_slice()],
+        Ok(Object::Array(names)) => names.iter().filter_map(|n| n.as_name().ok()).collect(),
+        _ => Vec::new(),
+    };
+    if filters.contains(&b"LZWDecode".as_slice()) {
+        report.add(Some(id), "LZW compressed stream");
+    }
+    if dict.has(b"F") || dict.has(b"FFilter") || dict.has(b"FDecodeParms") {
+        report.add(Some(id), "stream data in an external file");
+    }
+}
+
+/// Every font but Type0 (checked through its descendant) and Type3 needs
+/// an embedded font program.
+fn check_font(doc: &LoDoc, id: ObjectId, font: &Dictionary, report: &mut PdfAReport) {
+    let subtype = font.get(b"Subtype").and_then(Object::as_name).unwrap_or(b"");
+    if subtype == b"Type0" || subtype == b"Type3" {
+        return;
+    }
+    let descriptor = resolve(doc, font.get(b"FontDescriptor").ok()).and_then(|d| d.as_dict().ok());
+    let embedded = descriptor.is_some_and(|d| d.has(b"FontFile") || d.has(b"FontFile2") || d.has(b"FontFile3"));
+    if !embedded {
+        let name = font.get(b"BaseFont").and_then(Object::as_name).map(String::from_utf8_lossy).unwrap_or_default();
+        report.add(Some(id), format!("font {} is not embedded", name));
+    }
+}
+
+fn check_annotation(id: ObjectId, annot: &Dictionary, report: &mut PdfAReport) {
+    let subtype = annot.get(b"Subtype").and_then(Object::as_name).unwrap_or(b"");
+    if FORBIDDEN_ANNOTATIONS.contains(&subtype) {
+        report.add(Some(id), format!("{} annotation", String::from_utf8_lossy(subtype)));
+    }
+    if subtype == b"Popup" {
+        return;
+    }
+    let flags = annot.get(b"F").and_then(Object::as_i64).unwrap_or(0);
+    if flags & PRINT == 0 || flags & (INVISIBLE | HIDDEN | NO_VIEW | TOGGLE_NO_VIEW) != 0 {
+        report.add(Some(id), format!("{} annotation is not printable and visible (/F {})", String::from_utf8_lossy(subtype), flags));
+    }
+}
+
+fn resolve<'a>(doc: &'a LoDoc, obj: Option<&'a Object>) -> Option<&'a Object> {
+    match obj? {
+        Object::Reference(id) => doc.get_object(*id).ok(),
+        obj => Some(obj),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fonts::{FontRegistry, ShapeOptions};
+    use crate::layout::{Glyph, Line};
+    use crate::optimize::OptimizeOptions;
+    use crate::render::{create_renderer, RendererKind};
+    use crate::security::Encryption;
+    use crate::testing::TestDocument;
+    use lopdf::{dictionary, Stream};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A new name in the temporary directory.
+    fn temp_path() -> String {
+        static USED: AtomicUsize = AtomicUsize::new(0);
+        let name = format!("pdf_convert-pdfa-{}-{}.pdf", std::process::id(), USED.fetch_add(1, Ordering::Relaxed));
+        std::env::temp_dir().join(name).to_str().unwrap().to_string()
+    }
+
+    fn line(text: &str, y: f32) -> Line {
+        let glyph = |(i, ch)| Glyph { ch, x: 72.0 + 6.0 * i as f32, y, w: 6.0, size: 12.0, font: "F".into(), mapped: true, mcid: None };
+        Line { glyphs: text.chars().enumerate().map(glyph).collect(), y, font: "F".into(), size: 12.0, tag: None, region: None }
+    }
+
+    /// A one-page document with a line drawn, no fonts and no metadata.
+    fn input() -> String {
+        let mut doc = TestDocument::new();
+        doc.page("0 0 m 10 10 l S", dictionary! {});
+        let path = temp_path();
+        doc.finish(dictionary! {}).save(&path).unwrap();
+        path
+    }
+
+    /// Write `input` again as `level` with `kind` and return the output.
+    fn convert(kind: RendererKind, input: &str, level: PdfA, fonts: &FontRegistry) -> (String, Vec<String>) {
+        let out = temp_path();
+        let mut renderer = create_renderer(kind, fonts, input, None, &out, &OptimizeOptions::default(), &ShapeOptions::default());
+        renderer.set_pdfa(level);
+        renderer.add_pages(&[vec![line("Archived text", 700.0), line("x \u{2260} y", 680.0)]]).unwrap();
+        renderer.select_pages(&[0]);
+        renderer.set_metadata(Metadata { title: Some("Archive".into()), ..Metadata::default() });
+        let stats = renderer.finish().unwrap();
+        (out, stats.validation)
+    }
+
+    /// A PDF/A document made with lopdf: output intent, uncompressed XMP
+    /// identification and file ID.
+    fn conforming(level: PdfA) -> LoDoc {
+        let mut doc = TestDocument::new();
+        doc.page("0 0 m 10 10 l S", dictionary! {});
+        let profile = doc.doc.add_object(Stream::new(dictionary! { "N" => 3 }, SRGB_ICC.to_vec()));
+        let xmp = Metadata::default().to_xmp(Some(level), false);
+        let xmp = doc.doc.add_object(Stream::new(dictionary! { "Type" => "Metadata", "Subtype" => "XML" }, xmp.into_bytes()));
+        let intent = dictionary! {
+            "Type" => "OutputIntent", "S" => "GTS_PDFA1", "DestOutputProfile" => profile,
+            "OutputConditionIdentifier" => Object::string_literal(OUTPUT_CONDITION),
+        };
+        let mut doc = doc.finish(dictionary! { "OutputIntents" => vec![intent.into()], "Metadata" => xmp });
+        let id = Object::string_literal(vec![7u8; 16]);
+        doc.trailer.set("ID", vec![id.clone(), id]);
+        doc
+    }
+
+    fn violations(mut doc: LoDoc, level: PdfA) -> Vec<String> {
+        let path = temp_path();
+        doc.save(&path).unwrap();
+        check(&path, level).unwrap().violations.into_iter().map(|v| v.message).collect()
+    }
+
+    #[test]
+    fn parses_and_names_levels() {
+        for (s, level) in [("2b", PdfA::A2b), ("PDF/A-3b", PdfA::A3b), ("A2-B", PdfA::A2b)] {
+            assert_eq!(s.parse::<PdfA>(), Ok(level), "{}", s);
+        }
+        assert!("1b".parse::<PdfA>().is_err());
+        assert_eq!(PdfA::A3b.to_string().parse::<PdfA>(), Ok(PdfA::A3b));
+    }
+
+    #[test]
+    fn pdfa_output_of_every_renderer_passes_the_check() {
+        let fonts = FontRegistry::load().unwrap();
+        let input = input();
+        for kind in [RendererKind::Typst, RendererKind::PdfWriter, RendererKind::Passthrough] {
+            for level in [PdfA::A2b, PdfA::A3b] {
+                let (out, validation) = convert(kind, &input, level, &fonts);
+                let report = check(&out, level).unwrap();
+                assert!(report.is_clean(), "{} {}:\n{}", kind, level, report);
+                assert!(validation.is_empty(), "{} {}: {:?}", kind, level, validation);
+            }
+        }
+    }
+
+    #[test]
+    fn krilla_reports_notdef_glyphs() {
+        // No font of the registry has the combining enclosing circle
+        let fonts = FontRegistry::load().unwrap();
+        let out = temp_path();
+        let mut renderer = create_renderer(RendererKind::Typst, &fonts, "", None, &out, &OptimizeOptions::default(), &ShapeOptions::default());
+        renderer.set_pdfa(PdfA::A2b);
+        renderer.add_pages(&[vec![line("a\u{20DD}b", 700.0)]]).unwrap();
+        let validation = renderer.finish().unwrap().validation;
+        assert_eq!(validation.len(), 1, "{:?}", validation);
+        assert!(validation[0].starts_with(".notdef glyph of NewCM10-Regular.otf"), "{}", validation[0]);
+    }
+
+    #[test]
+    fn reports_each_violation() {
+        let level = PdfA::A2b;
+        assert_eq!(violations(conforming(level), level), Vec::<String>::new());
+
+        let mut doc = conforming(level);
+        doc.catalog_mut().unwrap().remove(b"OutputIntents");
+        assert_eq!(violations(doc, level), ["no GTS_PDFA1 output intent"]);
+
+        let mut doc = conforming(level);
+        doc.add_object(dictionary! { "Type" => "Font", "Subtype" => "Type1", "BaseFont" => "Helvetica" });
+        assert_eq!(violations(doc, level), ["font Helvetica is not embedded"]);
+
+        let mut doc = conforming(level);
+        let xmp = doc.catalog().unwrap().get(b"Metadata").and_then(Object::as_reference).unwrap();
+        let without_id = Metadata::default().to_xmp(None, false);
+        doc.get_object_mut(xmp).and_then(Object::as_stream_mut).unwrap().set_content(without_id.into_bytes());
+        assert_eq!(violations(doc, level), ["XMP pdfaid:part is missing, expected 2", "XMP pdfaid:conformance is missing, expected B"]);
+
+        let mut doc = conforming(level);
+        security::encrypt_document(&mut doc, &Encryption::aes256("owner", "", &[]).unwrap()).unwrap();
+        assert!(violations(doc, level).contains(&"the file is encrypted".to_string()));
+    }
+}
@@ -0,0 +1,140 @@
+// ========== Rendering ==========
+//
+// A `Renderer` receives the grouped lines batch by batch, in page order, and
+// writes the output document when finished. Shaping goes through the font
+// registry; every backend reports the same `RenderStats`.
+
+mod direct;
+mod document;
+mod embed;
+mod file;
+mod links;
+mod metadata;
+mod outline;
+mod passthrough;
+mod pdfa;
+mod structure;
+mod typst;
+
+pub use direct::PdfWriterRenderer;
+pub use passthrough::PassthroughRenderer;
+pub use typst::{render_like_typst, TypstWriter};
+
+use crate::Result;
+use crate::fonts::{FontRegistry, ShapeCacheStats, ShapeOptions, ShapedPages};
+use crate::layout::Line;
+use crate::links::Link;
+use crate::metadata::Metadata;
+use crate::optimize::{OptimizeOptions, OptimizeStats};
+use crate::pdfa::PdfA;
+use crate::security::Encryption;
+use crate::outline::OutlineItem;
+use crate::structure::Tagging;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+
+/// A4 in points, the page size every drawn page gets.
+pub(crate) const PAGE_WIDTH: f32 = 595.2756;
+pub(crate) const PAGE_HEIGHT: f32 = 841.8898;
+
+pub trait Renderer {
+    /// Draw the next batch of pages.
+    fn add_pages(&mut self, pages: &[Vec<Line>]) -> Result<()>;
+    /// Bookmarks to write, destinations by output page index. Renderers that
+    /// copy the input document keep its own outline.
+    fn set_outline(&mut self, _outline: Vec<OutlineItem>) {}
+    /// Link annotations of output page `page`, destinations by output page
+    /// index. Renderers that copy the input document keep its own annotations.
+    fn add_links(&mut self, _page: usize, _links: Vec<Link>) {}
+    /// Input page index of every output page, once all were added. Renderers
+    /// that draw the pages themselves only got these; the ones that copy the
+    /// input document drop the others.
+    fn select_pages(&mut self, _pages: &[usize]) {}
+    /// Document metadata to write in place of the input's.
+    fn set_metadata(&mut self, metadata: Metadata);
+    /// Write the output for this PDF/A level.
+    fn set_pdfa(&mut self, level: PdfA);
+    /// Encrypt the output like this; called before the first batch.
+    fn set_encryption(&mut self, encryption: Encryption);
+    /// Write a structure tree; called before the first batch. Renderers that
+    /// copy the input document keep its own structure.
+    fn set_tagging(&mut self, _tagging: Tagging) {}
+    /// Finish the output document.
+    fn finish(self: Box<Self>) -> Result<RenderStats>;
+}
+
+/// What happened while rendering, for the caller to report.
+#[derive(Debug, Default)]
+pub struct RenderStats {
+    pub pages: usize,
+    pub shaping: ShapeCacheStats,
+    /// Characters no font of the registry could render
+    pub missing: BTreeSet<char>,
+    pub content: OptimizeStats,
+    /// PDF/A violations krilla's validator found in the drawn text
+    pub validation: Vec<String>,
+}
+
+impl RenderStats {
+    pub(crate) fn record(&mut self, pages: usize, shaped: &ShapedPages) {
+        self.pages += pages;
+        self.shaping.hits += shaped.stats.hits;
+        self.shaping.misses += shaped.stats.misses;
+        self.missing.extend(shaped.missing.iter().copied());
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RendererKind {
+    /// Content streams written in Typst's structure
+    #[default]
+    Typst,
+    /// Text objects written directly with pdf-writer
+    PdfWriter,
+    /// The input document with only its content streams optimized
+    Passthrough,
+}
+
+impl FromStr for RendererKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "typst" => Ok(RendererKind::Typst),
+            "pdf-writer" => Ok(RendererKind::PdfWriter),
+            "passthrough" => Ok(RendererKind::Passthrough),
+            _ => Err(format!("unknown renderer '{}' (expected typst, pdf-writer or passthrough)", s)),
+        }
+    }
+}
+
+impl fmt::Display for RendererKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RendererKind::Typst => "typst",
+            RendererKind::PdfWriter => "pdf-writer",
+            RendererKind::Passthrough => "passthrough",
+        })
+    }
+}
+
+/// Create the renderer of `kind` writing `output`, with the text shaped as
+/// `shaping` says and the content streams optimized as `opts` says. `input`
+/// and its `password` are only read by the passthrough renderer, which does
+/// not shape.
+pub fn create_renderer<'a>(
+    kind: RendererKind,
+    fonts: &'a FontRegistry,
+    input: &str,
+    password: Option<&str>,
+    output: &str,
+    opts: &OptimizeOptions,
+    shaping: &ShapeOptions,
+) -> Box<dyn Renderer + 'a> {
+    match kind {
+        RendererKind::Typst => Box::new(TypstWriter::new(fonts, output, opts, shaping)),
+        RendererKind::PdfWriter => Box::new(PdfWriterRenderer::new(fonts, output, opts, shaping)),
+        RendererKind::Passthrough => Box::new(PassthroughRenderer::new(input, password, output, opts)),
+    }
+}
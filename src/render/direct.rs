@@ -0,0 +1,157 @@
+// pdf-writer renderer: writes the shaped runs straight into text objects,
+// one Type0 font per registry font with a subset of the glyphs used, and no
+// post-processing beyond the content stream optimizer. A tagged output marks
+// each element as one marked content sequence inside the page's text object.
+
+use super::document::DocumentWriter;
+use super::embed::FontEmbedder;
+use super::pdfa::TextValidator;
+use super::structure::{begin_tag, Tag};
+use super::{RenderStats, Renderer};
+use crate::Result;
+use crate::fonts::{FontRegistry, ShapeOptions, ShapedRun, Shapers};
+use crate::layout::Line;
+use crate::links::Link;
+use crate::metadata::Metadata;
+use crate::optimize::{optimize_content, OptimizeOptions};
+use crate::outline::OutlineItem;
+use crate::pdfa::PdfA;
+use crate::security::Encryption;
+use crate::structure::{self, Tagging};
+use pdf_writer::{Chunk, Content, Finish, Name, Ref};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Pages are written to the output as their batch is drawn; the page
+/// dictionaries, fonts and document-level objects follow in `finish`.
+pub struct PdfWriterRenderer<'a> {
+    fonts: &'a FontRegistry,
+    shapers: Shapers<'a>,
+    embedder: FontEmbedder<'a>,
+    /// Set with a PDF/A level
+    validator: Option<TextValidator<'a>>,
+    optimize: OptimizeOptions,
+    document: DocumentWriter,
+    /// Registry fonts of every page written
+    page_fonts: Vec<BTreeSet<usize>>,
+    stats: RenderStats,
+}
+
+impl<'a> PdfWriterRenderer<'a> {
+    /// Renderer writing `out`, with the text shaped as `shaping` says and the
+    /// content streams optimized as `opts` says.
+    pub fn new(fonts: &'a FontRegistry, out: &str, opts: &OptimizeOptions, shaping: &ShapeOptions) -> Self {
+        PdfWriterRenderer {
+            fonts,
+            shapers: Shapers::new(fonts, shaping),
+            embedder: FontEmbedder::new(fonts),
+            validator: None,
+            optimize: opts.clone(),
+            document: DocumentWriter::new(out),
+            page_fonts: Vec::new(),
+            stats: RenderStats::default(),
+        }
+    }
+
+    /// The content stream of one page and the registry fonts it uses.
+    fn draw_page(&mut self, lines: &[Line], runs: Vec<Vec<ShapedRun>>) -> (Vec<u8>, BTreeSet<usize>) {
+        let mut content = Content::new();
+        let mut fonts = BTreeSet::new();
+        content.begin_text();
+        let mut current: Option<(usize, f32)> = None;
+        let elements = if self.document.tagging().is_some() { structure::elements(lines) } else { Vec::new() };
+        let mut element = 0;
+        for (i, (line, line_runs)) in lines.iter().zip(runs).enumerate() {
+            if elements.get(element).is_some_and(|e| e.lines.start == i) {
+                begin_tag(&mut content, elements[element].role, element);
+            }
+            for run in line_runs {
+                if !self.embedder.can_draw(run.font) {
+                    continue;
+                }
+                if current != Some((run.font, line.size)) {
+                    content.set_font(Name(format!("F{}", run.font).as_bytes()), line.size);
+                    current = Some((run.font, line.size));
+                }
+                fonts.insert(run.font);
+                let x = line.glyphs[0].x + run.offset * line.size;
+                content.set_text_matrix([1.0, 0.0, 0.0, 1.0, x, line.y]);
+                self.embedder.show_run(&mut content, &run);
+            }
+            if elements.get(element).is_some_and(|e| e.lines.end == i + 1) {
+                content.end_marked_content();
+                element += 1;
+            }
+        }
+        content.end_text();
+        if self.document.tagging().is_some() {
+            self.document.add_tags(elements.iter().map(|e| Tag::new(e, lines)).collect());
+        }
+        (content.finish().to_vec(), fonts)
+    }
+}
+
+impl Renderer for PdfWriterRenderer<'_> {
+    fn add_pages(&mut self, pages: &[Vec<Line>]) -> Result<()> {
+        let shaped = self.shapers.shape_pages(pages);
+        self.stats.record(pages.len(), &shaped);
+        if let Some(validator) = &mut self.validator {
+            validator.check(pages, &shaped.pages, |run| self.embedder.can_draw(run.font));
+        }
+        let mut chunk = Chunk::new();
+        for (lines, runs) in pages.iter().zip(shaped.pages) {
+            let (content, fonts) = self.draw_page(lines, runs);
+            let (content, content_stats) = optimize_content(&content, &self.optimize)?;
+            self.stats.content.add(content_stats);
+            self.document.add_page(&mut chunk, &content);
+            self.page_fonts.push(fonts);
+        }
+        self.document.write(&chunk)
+    }
+
+    fn set_outline(&mut self, outline: Vec<OutlineItem>) {
+        self.document.set_outline(outline);
+    }
+
+    fn add_links(&mut self, page: usize, links: Vec<Link>) {
+        self.document.add_links(page, links);
+    }
+
+    fn set_metadata(&mut self, metadata: Metadata) {
+        self.document.set_metadata(metadata);
+    }
+
+    fn set_pdfa(&mut self, level: PdfA) {
+        self.validator = Some(TextValidator::new(self.fonts, level));
+        self.document.set_pdfa(level);
+    }
+
+    fn set_encryption(&mut self, encryption: Encryption) {
+        self.document.set_encryption(encryption);
+    }
+
+    fn set_tagging(&mut self, tagging: Tagging) {
+        self.document.set_tagging(tagging);
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<RenderStats> {
+        let mut chunk = Chunk::new();
+        let used: BTreeSet<usize> = self.page_fonts.iter().flatten().copied().collect();
+        let font_refs: BTreeMap<usize, Ref> = used.iter().map(|&f| (f, self.document.alloc().bump())).collect();
+        for (&font, &type0_ref) in &font_refs {
+            self.embedder.write_font(&mut chunk, font, type0_ref, self.document.alloc())?;
+        }
+
+        let PdfWriterRenderer { document, page_fonts, mut stats, validator, .. } = *self;
+        stats.validation = validator.map(TextValidator::violations).unwrap_or_default();
+        document.finish(chunk, |i, page| {
+            let mut resources = page.resources();
+            let mut fonts = resources.fonts();
+            for font in &page_fonts[i] {
+                fonts.pair(Name(format!("F{}", font).as_bytes()), font_refs[font]);
+            }
+            fonts.finish();
+            resources.finish();
+        })?;
+        Ok(stats)
+    }
+}
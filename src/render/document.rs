@@ -0,0 +1,186 @@
+// Document writer shared by the renderers that draw the pages. It appends
+// their content streams to the output file batch by batch and keeps what the
+// document level needs; once the renderer has written its fonts and
+// resources, `finish` adds the catalog, page tree, pages, outline, links,
+// metadata, PDF/A output intent and structure tree.
+
+use super::file::PdfFile;
+use super::links::write_links;
+use super::metadata::write_metadata;
+use super::outline::write_outline;
+use super::pdfa::{file_id, output_intent, write_profile};
+use super::structure::{tag_catalog, write_structure, Tag};
+use super::{PAGE_HEIGHT, PAGE_WIDTH};
+use crate::Result;
+use crate::links::Link;
+use crate::metadata::Metadata;
+use crate::outline::OutlineItem;
+use crate::pdfa::PdfA;
+use crate::security::Encryption;
+use crate::structure::Tagging;
+use md5::{Digest, Md5};
+use pdf_writer::types::TabOrder;
+use pdf_writer::writers::{Catalog, Page};
+use pdf_writer::{Chunk, Finish, Rect, Ref, TextStr};
+
+pub(super) struct DocumentWriter {
+    out: String,
+    /// Created with the first batch
+    file: Option<PdfFile>,
+    alloc: Ref,
+    /// Content stream of every page written
+    pages: Vec<Ref>,
+    /// Hash of the content streams for the PDF/A file ID
+    contents: Md5,
+    outline: Vec<OutlineItem>,
+    /// Link annotations by page
+    links: Vec<Vec<Link>>,
+    metadata: Metadata,
+    pdfa: Option<PdfA>,
+    encryption: Option<Encryption>,
+    tagging: Option<Tagging>,
+    /// Marked content sequences by page, in MCID order
+    tags: Vec<Vec<Tag>>,
+}
+
+impl DocumentWriter {
+    /// Writer of `out`; the file is created with the first batch.
+    pub fn new(out: &str) -> Self {
+        DocumentWriter {
+            out: out.to_string(),
+            file: None,
+            alloc: Ref::new(1),
+            pages: Vec::new(),
+            contents: Md5::new(),
+            outline: Vec::new(),
+            links: Vec::new(),
+            metadata: Metadata::default(),
+            pdfa: None,
+            encryption: None,
+            tagging: None,
+            tags: Vec::new(),
+        }
+    }
+
+    /// Allocator of the ids of the renderer's objects.
+    pub fn alloc(&mut self) -> &mut Ref {
+        &mut self.alloc
+    }
+
+    pub fn tagging(&self) -> Option<Tagging> {
+        self.tagging
+    }
+
+    /// Add `content` to `chunk` as the content stream of the next page.
+    pub fn add_page(&mut self, chunk: &mut Chunk, content: &[u8]) {
+        self.contents.update(content);
+        let content_ref = self.alloc.bump();
+        chunk.stream(content_ref, content);
+        self.pages.push(content_ref);
+    }
+
+    /// Marked content sequences of the next page of a tagged output.
+    pub fn add_tags(&mut self, tags: Vec<Tag>) {
+        self.tags.push(tags);
+    }
+
+    /// Append `chunk` to the output file.
+    pub fn write(&mut self, chunk: &Chunk) -> Result<()> {
+        if self.file.is_none() {
+            self.file = Some(PdfFile::create(&self.out, self.encryption.take())?);
+        }
+        self.file.as_mut().expect("created above").write(chunk)
+    }
+
+    pub fn set_outline(&mut self, outline: Vec<OutlineItem>) {
+        self.outline = outline;
+    }
+
+    pub fn add_links(&mut self, page: usize, links: Vec<Link>) {
+        if self.links.len() <= page {
+            self.links.resize_with(page + 1, Vec::new);
+        }
+        self.links[page] = links;
+    }
+
+    pub fn set_metadata(&mut self, metadata: Metadata) {
+        self.metadata = metadata;
+    }
+
+    pub fn set_pdfa(&mut self, level: PdfA) {
+        self.pdfa = Some(level);
+    }
+
+    pub fn set_encryption(&mut self, encryption: Encryption) {
+        self.encryption = Some(encryption);
+    }
+
+    pub fn set_tagging(&mut self, tagging: Tagging) {
+        self.tagging = Some(tagging);
+    }
+
+    /// Write the catalog, the page tree, the pages and the document-level
+    /// objects into `chunk`, which holds the renderer's fonts, then finish
+    /// the output file. `resources` writes the resources of the page with
+    /// the given index.
+    pub fn finish(mut self, mut chunk: Chunk, mut resources: impl FnMut(usize, &mut Page<'_>)) -> Result<()> {
+        let mut alloc = self.alloc;
+        let catalog_ref = alloc.bump();
+        let tree_ref = alloc.bump();
+        let page_ids: Vec<Ref> = self.pages.iter().map(|_| alloc.bump()).collect();
+        let outline_ref = write_outline(&mut chunk, &mut alloc, &self.outline, &page_ids);
+        let pdfua = self.tagging == Some(Tagging::PdfUa);
+        let metadata_refs = write_metadata(&mut chunk, &mut alloc, &self.metadata, self.pdfa, pdfua);
+        let profile_ref = self.pdfa.map(|_| alloc.bump());
+        let struct_ref = self.tagging.map(|_| alloc.bump());
+
+        let mut catalog = chunk.indirect(catalog_ref).start::<Catalog>();
+        catalog.pages(tree_ref);
+        if let Some(outline_ref) = outline_ref {
+            catalog.outlines(outline_ref);
+        }
+        if let Some((_, xmp_ref)) = metadata_refs {
+            catalog.metadata(xmp_ref);
+        }
+        if let Some(language) = &self.metadata.language {
+            catalog.lang(TextStr(language));
+        }
+        if let Some(profile_ref) = profile_ref {
+            output_intent(&mut catalog, profile_ref);
+        }
+        if let (Some(struct_ref), Some(tagging)) = (struct_ref, self.tagging) {
+            tag_catalog(&mut catalog, struct_ref, tagging);
+        }
+        catalog.finish();
+        if let Some(profile_ref) = profile_ref {
+            write_profile(&mut chunk, profile_ref);
+        }
+        chunk.pages(tree_ref).kids(page_ids.iter().copied()).count(page_ids.len() as i32);
+
+        // Annotation keys of the parent tree follow the pages'
+        let mut struct_parent = self.pages.len() as i32;
+        let mut page_links = Vec::new();
+        for (i, (&page_ref, &content_ref)) in page_ids.iter().zip(&self.pages).enumerate() {
+            let annots = write_links(&mut chunk, &mut alloc, self.links.get(i).map_or(&[], Vec::as_slice), &page_ids, self.tagging.map(|_| &mut struct_parent));
+            let mut writer = chunk.page(page_ref);
+            writer.parent(tree_ref).media_box(Rect::new(0.0, 0.0, PAGE_WIDTH, PAGE_HEIGHT)).contents(content_ref);
+            if !annots.is_empty() {
+                writer.annotations(annots.iter().map(|(annot, _)| *annot));
+            }
+            if self.tagging.is_some() {
+                writer.struct_parents(i as i32).tab_order(TabOrder::StructureOrder);
+            }
+            resources(i, &mut writer);
+            writer.finish();
+            page_links.push(annots);
+        }
+        if let Some(struct_ref) = struct_ref {
+            write_structure(&mut chunk, &mut alloc, struct_ref, &page_ids, &self.tags, &page_links);
+        }
+
+        let id = self.pdfa.map(|_| file_id(std::mem::take(&mut self.contents)));
+        self.write(&chunk)?;
+        let file = self.file.take().expect("created above");
+        file.finish(catalog_ref, metadata_refs.map(|(info_ref, _)| info_ref), id.as_deref())
+    }
+}
@@ -0,0 +1,202 @@
+// Font embedding shared by the pdf-writer based renderers: shaped runs are
+// written as 2-byte glyph ids of a per-font subset, and every font used ends
+// up as one Type0 font with its subsetted program and a ToUnicode CMap.
+
+use crate::fonts::{FontRegistry, ShapedRun};
+use crate::{Error, Result};
+use flate2::Compression;
+use flate2::write::ZlibEncoder;
+use pdf_writer::types::{CidFontType, FontFlags, SystemInfo, UnicodeCmap};
+use pdf_writer::{Chunk, Content, Filter, Finish, Name, Rect, Ref, Str, TextStr};
+use rustybuzz::ttf_parser::{name_id, GlyphId, RawFace, Tag};
+use rustybuzz::Face;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use subsetter::GlyphRemapper;
+
+const SYSTEM_INFO: SystemInfo = SystemInfo { registry: Str(b"Adobe"), ordering: Str(b"Identity"), supplement: 0 };
+
+/// Glyphs of one registry font used so far, by subset glyph id.
+#[derive(Default)]
+struct FontSubset {
+    remapper: GlyphRemapper,
+    /// Advance in font units per subset glyph id
+    advances: BTreeMap<u16, u16>,
+    /// Text of the cluster a subset glyph starts
+    text: BTreeMap<u16, String>,
+}
+
+pub(super) struct FontEmbedder<'a> {
+    fonts: &'a FontRegistry,
+    faces: Vec<Option<Face<'a>>>,
+    subsets: Vec<FontSubset>,
+}
+
+impl<'a> FontEmbedder<'a> {
+    pub fn new(fonts: &'a FontRegistry) -> Self {
+        let faces: Vec<_> = fonts.fonts().iter().map(|f| Face::from_slice(&f.data, 0)).collect();
+        let subsets = faces.iter().map(|_| FontSubset::default()).collect();
+        FontEmbedder { fonts, faces, subsets }
+    }
+
+    /// Whether the registry font at `index` could be parsed, runs in other fonts are skipped.
+    pub fn can_draw(&self, index: usize) -> bool {
+        self.faces[index].is_some()
+    }
+
+    /// Show the glyphs of `run` in the current text object. Glyphs are written
+    /// as 2-byte subset ids; TJ adjustments carry kerning and offsets where the
+    /// shaped advance differs from /W. A glyph whose cluster text differs from
+    /// the ToUnicode entry it already has (e.g. the en dash ligature for `--`)
+    /// is shown in its own `/Span` with an ActualText, like krilla does.
+    pub fn show_run(&mut self, content: &mut Content, run: &ShapedRun) {
+        let Some(face) = &self.faces[run.font] else { return };
+        let upem = face.units_per_em() as f32;
+        let subset = &mut self.subsets[run.font];
+
+        // (ActualText, adjustment before the glyph, subset glyph id) per glyph
+        let mut glyphs = Vec::with_capacity(run.shaped.glyphs.len());
+        let mut pending = 0.0;
+        for glyph in &run.shaped.glyphs {
+            let gid = glyph.glyph_id.to_u32() as u16;
+            let new_gid = subset.remapper.remap(gid);
+            let advance = face.glyph_hor_advance(GlyphId(gid)).unwrap_or(0);
+            subset.advances.insert(new_gid, advance);
+            let mut actual = None;
+            if let Some(text) = run.text.get(glyph.text_range.clone()).filter(|t| !t.is_empty()) {
+                let mapped = subset.text.entry(new_gid).or_insert_with(|| text.to_string());
+                if mapped != text {
+                    actual = Some(text);
+                }
+            }
+            glyphs.push((actual, pending - glyph.x_offset * 1000.0, new_gid));
+            pending = (advance as f32 / upem - glyph.x_advance + glyph.x_offset) * 1000.0;
+        }
+
+        for segment in glyphs.chunk_by(|a, b| a.0.is_none() && b.0.is_none()) {
+            if let Some(text) = segment[0].0 {
+                content.begin_marked_content_with_properties(Name(b"Span")).properties().actual_text(TextStr(text));
+            }
+            let mut shown = content.show_positioned();
+            let mut items = shown.items();
+            for &(_, adjust, gid) in segment {
+                if adjust.abs() > 1e-3 {
+                    items.adjust(adjust);
+                }
+                items.show(Str(&gid.to_be_bytes()));
+            }
+            items.finish();
+            shown.finish();
+            if segment[0].0.is_some() {
+                content.end_marked_content();
+            }
+        }
+    }
+
+    /// Write the Type0 font, CID font, descriptor, ToUnicode CMap and subset
+    /// program of one registry font.
+    pub fn write_font(&self, chunk: &mut Chunk, index: usize, type0_ref: Ref, alloc: &mut Ref) -> Result<()> {
+        let registered = &self.fonts.fonts()[index];
+        let fail = |reason: String| Error::Render(format!("cannot embed {}: {}", registered.path, reason));
+        let (Some(face), subset) = (&self.faces[index], &self.subsets[index]) else { return Err(fail("unreadable font".into())) };
+        let subsetted = subsetter::subset(&registered.data, 0, &subset.remapper).map_err(|e| fail(e.to_string()))?;
+        // CFF fonts embed only the bare CFF program
+        let cff = RawFace::parse(&subsetted, 0).ok().and_then(|raw| raw.table(Tag::from_bytes(b"CFF ")).map(<[u8]>::to_vec));
+        let is_cff = cff.is_some();
+        let program = cff.unwrap_or(subsetted);
+
+        let (cid_ref, descriptor_ref, cmap_ref, file_ref) = (alloc.bump(), alloc.bump(), alloc.bump(), alloc.bump());
+        let postscript = face
+            .names()
+            .into_iter()
+            .find(|n| n.name_id == name_id::POST_SCRIPT_NAME)
+            .map(|n| {
+                // PostScript names are ASCII, stored as UTF-16BE in Unicode records
+                let bytes = if n.is_unicode() { n.name.iter().skip(1).step_by(2).copied().collect() } else { n.name.to_vec() };
+                String::from_utf8_lossy(&bytes).into_owned()
+            })
+            .unwrap_or_else(|| "unknown".to_string());
+        let base_font = format!("{}+{}", subset_tag(&subset.advances), postscript);
+        let to_pdf = |v: f32| v / face.units_per_em() as f32 * 1000.0;
+
+        chunk.type0_font(type0_ref)
+            .base_font(Name(base_font.as_bytes()))
+            .encoding_predefined(Name(b"Identity-H"))
+            .descendant_font(cid_ref)
+            .to_unicode(cmap_ref);
+
+        let mut cid = chunk.cid_font(cid_ref);
+        cid.subtype(if is_cff { CidFontType::Type0 } else { CidFontType::Type2 });
+        cid.base_font(Name(base_font.as_bytes()));
+        cid.system_info(SYSTEM_INFO);
+        cid.font_descriptor(descriptor_ref);
+        cid.default_width(0.0);
+        if !is_cff {
+            cid.cid_to_gid_map_predefined(Name(b"Identity"));
+        }
+        let mut widths = cid.widths();
+        for (gid, advance) in &subset.advances {
+            widths.same(*gid, *gid, to_pdf(*advance as f32));
+        }
+        widths.finish();
+        cid.finish();
+
+        let bbox = face.global_bounding_box();
+        let mut flags = FontFlags::SYMBOLIC;
+        flags.set(FontFlags::FIXED_PITCH, face.is_monospaced());
+        flags.set(FontFlags::ITALIC, face.is_italic());
+        let mut descriptor = chunk.font_descriptor(descriptor_ref);
+        descriptor
+            .name(Name(base_font.as_bytes()))
+            .flags(flags)
+            .bbox(Rect::new(to_pdf(bbox.x_min as f32), to_pdf(bbox.y_min as f32), to_pdf(bbox.x_max as f32), to_pdf(bbox.y_max as f32)))
+            .italic_angle(face.italic_angle().unwrap_or(0.0))
+            .ascent(to_pdf(face.ascender() as f32))
+            .descent(to_pdf(face.descender() as f32))
+            .cap_height(to_pdf(face.capital_height().unwrap_or(face.ascender()) as f32))
+            .stem_v(10.0 + 0.244 * (face.weight().to_number() as f32 - 50.0));
+        if is_cff {
+            descriptor.font_file3(file_ref);
+        } else {
+            descriptor.font_file2(file_ref);
+        }
+        descriptor.finish();
+
+        let mut cmap = UnicodeCmap::new(Name(b"Custom"), SYSTEM_INFO);
+        for (gid, text) in &subset.text {
+            cmap.pair_with_multiple(*gid, text.chars());
+        }
+        chunk.cmap(cmap_ref, &cmap.finish()).finish();
+
+        let compressed = deflate(&program)?;
+        let mut stream = chunk.stream(file_ref, &compressed);
+        stream.filter(Filter::FlateDecode);
+        if is_cff {
+            stream.pair(Name(b"Subtype"), Name(b"CIDFontType0C"));
+        }
+        stream.finish();
+        Ok(())
+    }
+}
+
+fn deflate(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// Six-letter subset tag derived from the glyph set, stable across runs.
+fn subset_tag<T: Hash>(data: &T) -> String {
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    let mut hash = Hasher::finish(&hasher);
+    (0..6)
+        .map(|_| {
+            let letter = (b'A' + (hash % 26) as u8) as char;
+            hash /= 26;
+            letter
+        })
+        .collect()
+}
@@ -0,0 +1,250 @@
+// Output file for the pdf-writer based renderers. Objects are appended chunk
+// by chunk as the renderer finishes them, so page contents leave memory with
+// their batch; `finish` writes the cross-reference table and trailer.
+// pdf-writer keeps object offsets to itself, so the offsets within a chunk
+// are read from the table it writes for the chunk alone. Encrypted outputs
+// pass each chunk through lopdf, which encrypts its strings and streams and
+// writes it again, so nothing but the current chunk is held.
+
+use crate::security::{self, Encryption};
+use crate::Result;
+use lopdf::xref::XrefType;
+use lopdf::Document as LoDoc;
+use pdf_writer::{Chunk, Pdf, Ref};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+
+/// Length of one cross-reference entry, line end included.
+const XREF_ENTRY: usize = 20;
+
+pub(super) struct PdfFile {
+    path: String,
+    file: BufWriter<File>,
+    /// Bytes written so far
+    position: usize,
+    /// Offset of every object written, by id
+    offsets: Vec<(i32, usize)>,
+    encryption: Option<Encryption>,
+    finished: bool,
+}
+
+impl PdfFile {
+    /// Create `path` and write the header. Objects are encrypted like
+    /// `encryption` says.
+    pub fn create(path: &str, encryption: Option<Encryption>) -> Result<Self> {
+        let header = Pdf::new();
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(header.as_bytes())?;
+        Ok(PdfFile { path: path.to_string(), file, position: header.len(), offsets: Vec::new(), encryption, finished: false })
+    }
+
+    /// Append the objects of `chunk`.
+    pub fn write(&mut self, chunk: &Chunk) -> Result<()> {
+        let Some(encryption) = &self.encryption else {
+            let offsets = chunk_offsets(chunk);
+            return self.append(chunk.as_bytes(), offsets);
+        };
+        let mut pdf = Pdf::new();
+        pdf.extend(chunk);
+        let mut doc = LoDoc::load_mem(&pdf.finish())?;
+        for (&id, object) in doc.objects.iter_mut() {
+            encryption.encrypt_object(id, object)?;
+        }
+        self.append_lopdf(doc)
+    }
+
+    fn append(&mut self, bytes: &[u8], offsets: Vec<(i32, usize)>) -> Result<()> {
+        for (id, offset) in offsets {
+            self.offsets.push((id, self.position + offset));
+        }
+        self.file.write_all(bytes)?;
+        self.position += bytes.len();
+        Ok(())
+    }
+
+    /// Append the objects of `doc` as lopdf writes them.
+    fn append_lopdf(&mut self, mut doc: LoDoc) -> Result<()> {
+        doc.reference_table.cross_reference_type = XrefType::CrossReferenceTable;
+        let mut bytes = Vec::new();
+        doc.save_to(&mut bytes)?;
+        // The header and binary mark take the first two lines
+        let header = bytes.iter().enumerate().filter(|&(_, &b)| b == b'\n').nth(1).map_or(0, |(i, _)| i + 1);
+        let table = bytes.windows(6).rposition(|w| w == b"\nxref\n").map_or(bytes.len(), |i| i + 1);
+        let offsets = table_offsets(&bytes[table..], header);
+        self.append(&bytes[header..table], offsets)
+    }
+
+    /// Write the cross-reference table and the trailer. `id` becomes both
+    /// halves of the file ID; encrypted outputs get the one of their
+    /// encryption or a random one if there is neither.
+    pub fn finish(mut self, root: Ref, info: Option<Ref>, id: Option<&[u8]>) -> Result<()> {
+        let mut id = id.map(|id| [id.to_vec(), id.to_vec()]);
+        let mut encrypt = None;
+        if let Some(encryption) = self.encryption.take() {
+            // Keys below revision 5 derive from the ID of the document encrypted first
+            if let Some(halves) = encryption.id().and_then(|id| id.as_array().ok()) {
+                let halves: Vec<Vec<u8>> = halves.iter().filter_map(|half| half.as_str().ok()).map(<[u8]>::to_vec).collect();
+                id = halves.try_into().ok();
+            }
+            id.get_or_insert_with(|| {
+                let random = security::random_id().to_vec();
+                [random.clone(), random]
+            });
+            let dict_id = self.offsets.iter().map(|&(id, _)| id).max().unwrap_or(0) + 1;
+            let mut doc = LoDoc::new();
+            doc.objects.insert((dict_id as u32, 0), encryption.dictionary()?.into());
+            doc.max_id = dict_id as u32;
+            self.append_lopdf(doc)?;
+            encrypt = Some(dict_id);
+        }
+        self.offsets.sort();
+        let size = self.offsets.last().map_or(0, |&(id, _)| id) + 1;
+        let mut used = self.offsets.iter().peekable();
+        let free: Vec<i32> = (0..size).filter(|&id| used.next_if(|&&(used, _)| used == id).is_none()).collect();
+
+        let mut xref = format!("xref\n0 {}\n", size);
+        let mut objects = self.offsets.iter().peekable();
+        let mut next_free = free.iter().skip(1);
+        for id in 0..size {
+            match objects.next_if(|&&(used, _)| used == id) {
+                Some(&(_, offset)) => xref.push_str(&format!("{:010} 00000 n\r\n", offset)),
+                // Free entries are linked, the last one back to 0
+                None => {
+                    let generation = if id == 0 { 65535 } else { 0 };
+                    xref.push_str(&format!("{:010} {:05} f\r\n", next_free.next().copied().unwrap_or(0), generation));
+                }
+            }
+        }
+
+        xref.push_str(&format!("trailer\n<<\n  /Size {}\n  /Root {} 0 R\n", size, root.get()));
+        if let Some(info) = info {
+            xref.push_str(&format!("  /Info {} 0 R\n", info.get()));
+        }
+        if let Some(encrypt) = encrypt {
+            xref.push_str(&format!("  /Encrypt {} 0 R\n", encrypt));
+        }
+        if let Some([first, second]) = id {
+            let hex = |id: &[u8]| id.iter().map(|b| format!("{:02X}", b)).collect::<String>();
+            xref.push_str(&format!("  /ID [<{}> <{}>]\n", hex(&first), hex(&second)));
+        }
+        xref.push_str(&format!(">>\nstartxref\n{}\n%%EOF", self.position));
+        self.file.write_all(xref.as_bytes())?;
+        self.file.flush()?;
+        self.finished = true;
+        Ok(())
+    }
+}
+
+impl Drop for PdfFile {
+    /// An unfinished file has no cross-reference table and is removed.
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Id and offset within `chunk` of each of its objects.
+fn chunk_offsets(chunk: &Chunk) -> Vec<(i32, usize)> {
+    let mut pdf = Pdf::new();
+    let header = pdf.len();
+    pdf.extend(chunk);
+    let bytes = pdf.finish();
+    table_offsets(&bytes[header + chunk.len()..], header)
+}
+
+/// Id and offset of each object in the cross-reference `table`, less the
+/// `header` before the objects.
+fn table_offsets(table: &[u8], header: usize) -> Vec<(i32, usize)> {
+    let mut offsets = Vec::new();
+    // "xref", then subsections of "{first} {count}" and one entry per id
+    let mut rest = table.splitn(2, |&b| b == b'\n').nth(1).unwrap_or_default();
+    while let Some(end) = rest.iter().position(|&b| b == b'\n') {
+        let Some((first, count)) = std::str::from_utf8(&rest[..end]).ok().and_then(|line| line.split_once(' ')) else { break };
+        let (Ok(first), Ok(count)) = (first.parse::<usize>(), count.parse::<usize>()) else { break };
+        let entries = rest.get(end + 1..end + 1 + count * XREF_ENTRY).unwrap_or_default();
+        for (id, entry) in entries.chunks_exact(XREF_ENTRY).enumerate().filter(|(_, entry)| entry[17] == b'n') {
+            if let Some(offset) = std::str::from_utf8(&entry[..10]).ok().and_then(|offset| offset.parse::<usize>().ok()) {
+                offsets.push(((first + id) as i32, offset - header));
+            }
+        }
+        rest = &rest[(end + 1 + entries.len()).min(rest.len())..];
+    }
+    offsets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pdf_writer::writers::Catalog;
+    use pdf_writer::{Name, TextStr};
+
+    #[test]
+    fn offsets_point_at_the_objects() {
+        let path = std::env::temp_dir().join(format!("pdf_convert-file-{}.pdf", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut file = PdfFile::create(path, None).unwrap();
+        let mut first = Chunk::new();
+        first.indirect(Ref::new(1)).primitive(Name(b"First"));
+        first.indirect(Ref::new(2)).primitive(Name(b"Second"));
+        file.write(&first).unwrap();
+        // Object 3 is never written and becomes a free entry
+        let mut second = Chunk::new();
+        second.indirect(Ref::new(4)).primitive(Name(b"Later"));
+        file.write(&second).unwrap();
+        file.finish(Ref::new(1), None, Some(b"id")).unwrap();
+
+        let bytes = fs::read(path).unwrap();
+        fs::remove_file(path).unwrap();
+        // Everything after the binary comment of the header is ASCII
+        let xref = bytes.windows(6).rposition(|w| w == b"\nxref\n").unwrap() + 1;
+        let text = std::str::from_utf8(&bytes[xref..]).unwrap();
+        let entries: Vec<&str> = text.lines().skip(2).take(5).collect();
+        assert_eq!(entries[0], "0000000003 65535 f");
+        assert_eq!(entries[3], "0000000000 00000 f");
+        assert_eq!(entries.iter().filter(|e| e.ends_with(" n")).count(), 3);
+        for (id, entry) in entries.iter().enumerate().filter(|(_, e)| e.ends_with(" n")) {
+            let offset: usize = entry[..10].parse().unwrap();
+            assert!(bytes[offset..].starts_with(format!("{} 0 obj", id).as_bytes()), "object {}", id);
+        }
+        let startxref: usize = text.lines().rev().nth(1).unwrap().parse().unwrap();
+        assert_eq!(startxref, xref);
+        assert!(text.contains("/Size 5"));
+        assert!(text.contains("/ID [<6964> <6964>]"));
+    }
+
+    #[test]
+    fn encrypts_every_chunk() {
+        let path = std::env::temp_dir().join(format!("pdf_convert-encrypted-{}.pdf", std::process::id()));
+        let path = path.to_str().unwrap();
+        let encryption = Encryption::aes256("owner", "user", &[]).unwrap();
+        let mut file = PdfFile::create(path, Some(encryption)).unwrap();
+        let mut first = Chunk::new();
+        first.pages(Ref::new(2)).kids([Ref::new(3)]).count(1);
+        file.write(&first).unwrap();
+        let mut second = Chunk::new();
+        second.page(Ref::new(3)).parent(Ref::new(2)).contents(Ref::new(4));
+        second.stream(Ref::new(4), b"BT (secret) Tj ET");
+        second.indirect(Ref::new(1)).start::<Catalog>().pages(Ref::new(2)).lang(TextStr("secret"));
+        file.write(&second).unwrap();
+        file.finish(Ref::new(1), None, None).unwrap();
+
+        let bytes = fs::read(path).unwrap();
+        let opened = security::open(path, Some("user"));
+        fs::remove_file(path).unwrap();
+        assert!(!bytes.windows(6).any(|w| w == b"secret"));
+        let (doc, security) = opened.unwrap();
+        assert_eq!(security.unwrap().encryption.revision(), 6);
+        assert_eq!(doc.get_page_content(doc.page_iter().next().unwrap()).unwrap(), b"BT (secret) Tj ET");
+        assert_eq!(doc.catalog().unwrap().get(b"Lang").unwrap().as_str().unwrap(), b"secret");
+    }
+
+    #[test]
+    fn unfinished_files_are_removed() {
+        let path = std::env::temp_dir().join(format!("pdf_convert-unfinished-{}.pdf", std::process::id()));
+        let file = PdfFile::create(path.to_str().unwrap(), None).unwrap();
+        assert!(path.exists());
+        drop(file);
+        assert!(!path.exists());
+    }
+}
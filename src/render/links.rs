@@ -0,0 +1,49 @@
+// Link annotations for the pdf-writer based renderers. Text keeps the
+// input's coordinates on the A4 output page, so the clickable rectangles do
+// too and are only clamped to the page.
+
+use super::outline::write_destination;
+use super::structure::link_description;
+use super::{PAGE_HEIGHT, PAGE_WIDTH};
+use crate::links::{Link, LinkTarget};
+use pdf_writer::types::{ActionType, AnnotationFlags, AnnotationType};
+use pdf_writer::{Chunk, Rect, Ref, Str, TextStr};
+
+/// Write the links of one page and return the refs for its /Annots with
+/// their rectangles. `pages` are the output pages by input page index; links
+/// to other pages and rectangles entirely off the page are dropped. A tagged
+/// output passes the next /StructParent key and gets a description of each
+/// link in /Contents.
+pub(super) fn write_links(chunk: &mut Chunk, alloc: &mut Ref, links: &[Link], pages: &[Ref], mut struct_parent: Option<&mut i32>) -> Vec<(Ref, Rect)> {
+    let mut refs = Vec::new();
+    for link in links {
+        if matches!(link.target, LinkTarget::GoTo(dest) if dest.page >= pages.len()) {
+            continue;
+        }
+        let [left, bottom, right, top] = link.rect;
+        let rect = Rect::new(left.clamp(0.0, PAGE_WIDTH), bottom.clamp(0.0, PAGE_HEIGHT), right.clamp(0.0, PAGE_WIDTH), top.clamp(0.0, PAGE_HEIGHT));
+        if rect.x1 >= rect.x2 || rect.y1 >= rect.y2 {
+            continue;
+        }
+        let annot_ref = alloc.bump();
+        let mut annot = chunk.annotation(annot_ref);
+        // No border: the input's link boxes are not part of the text. Printable, as PDF/A requires
+        annot.subtype(AnnotationType::Link).rect(rect).border(0.0, 0.0, 0.0, None).flags(AnnotationFlags::PRINT);
+        if let Some(key) = struct_parent.as_deref_mut() {
+            annot.struct_parent(*key).contents(TextStr(&link_description(link)));
+            *key += 1;
+        }
+        let mut action = annot.action();
+        match &link.target {
+            LinkTarget::Uri(uri) => {
+                action.action_type(ActionType::Uri).uri(Str(uri.as_bytes()));
+            }
+            LinkTarget::GoTo(dest) => {
+                action.action_type(ActionType::GoTo);
+                write_destination(action.destination(), *dest, pages);
+            }
+        }
+        refs.push((annot_ref, rect));
+    }
+    refs
+}
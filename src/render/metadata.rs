@@ -0,0 +1,49 @@
+// Document metadata for the pdf-writer based renderers: the Info dictionary
+// and an XMP packet with the same values. /Lang goes into the catalog.
+
+use crate::metadata::Metadata;
+use crate::pdfa::PdfA;
+use pdf_writer::writers::DocumentInfo;
+use pdf_writer::{Chunk, Finish, Ref, TextStr};
+
+/// Write the Info dictionary and the XMP stream; returns the dictionary for
+/// the trailer's /Info and the stream for the catalog's /Metadata, `None`
+/// when there is nothing to write. PDF/A and PDF/UA always need the XMP
+/// stream.
+pub(super) fn write_metadata(chunk: &mut Chunk, alloc: &mut Ref, metadata: &Metadata, pdfa: Option<PdfA>, pdfua: bool) -> Option<(Ref, Ref)> {
+    if metadata.is_empty() && pdfa.is_none() && !pdfua {
+        return None;
+    }
+    let info_ref = alloc.bump();
+    let mut info = chunk.indirect(info_ref).start::<DocumentInfo>();
+    if let Some(title) = &metadata.title {
+        info.title(TextStr(title));
+    }
+    if let Some(author) = &metadata.author {
+        info.author(TextStr(author));
+    }
+    if let Some(subject) = &metadata.subject {
+        info.subject(TextStr(subject));
+    }
+    if let Some(keywords) = &metadata.keywords {
+        info.keywords(TextStr(keywords));
+    }
+    if let Some(creator) = &metadata.creator {
+        info.creator(TextStr(creator));
+    }
+    if let Some(producer) = &metadata.producer {
+        info.producer(TextStr(producer));
+    }
+    if let Some(date) = metadata.creation_date {
+        info.creation_date(date.to_pdf_writer());
+    }
+    if let Some(date) = metadata.modification_date {
+        info.modified_date(date.to_pdf_writer());
+    }
+    info.finish();
+
+    let xmp_ref = alloc.bump();
+    let xmp = metadata.to_xmp(pdfa, pdfua);
+    chunk.metadata(xmp_ref, xmp.as_bytes());
+    Some((info_ref, xmp_ref))
+}
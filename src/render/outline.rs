@@ -0,0 +1,66 @@
+// Outline writing shared by the pdf-writer based renderers. Their pages put
+// the text at the input's coordinates on an A4 page, so destinations keep
+// their position and are only clamped to the output page.
+
+use super::{PAGE_HEIGHT, PAGE_WIDTH};
+use crate::outline::{visible_count, Destination, OutlineItem, View};
+use pdf_writer::writers::Destination as DestinationWriter;
+use pdf_writer::{Chunk, Rect, Ref, TextStr};
+
+/// Write the outline tree and return its root for the catalog's /Outlines.
+/// `pages` are the output pages by input page index; destinations on other
+/// pages are dropped, their bookmarks kept.
+pub(super) fn write_outline(chunk: &mut Chunk, alloc: &mut Ref, items: &[OutlineItem], pages: &[Ref]) -> Option<Ref> {
+    if items.is_empty() {
+        return None;
+    }
+    let root = alloc.bump();
+    let (first, last) = write_items(chunk, alloc, items, root, pages);
+    chunk.outline(root).first(first).last(last).count(visible_count(items) as i32);
+    Some(root)
+}
+
+/// Write `items` as siblings under `parent`; returns the first and last one.
+fn write_items(chunk: &mut Chunk, alloc: &mut Ref, items: &[OutlineItem], parent: Ref, pages: &[Ref]) -> (Ref, Ref) {
+    let refs: Vec<Ref> = items.iter().map(|_| alloc.bump()).collect();
+    for (i, item) in items.iter().enumerate() {
+        let children = (!item.children.is_empty()).then(|| write_items(chunk, alloc, &item.children, refs[i], pages));
+        let mut writer = chunk.outline_item(refs[i]);
+        writer.title(TextStr(&item.title)).parent(parent);
+        if i > 0 {
+            writer.prev(refs[i - 1]);
+        }
+        if let Some(&next) = refs.get(i + 1) {
+            writer.next(next);
+        }
+        if let Some((first, last)) = children {
+            // Negative for closed items: what opening them would show
+            let descendants = visible_count(&item.children) as i32;
+            writer.first(first).last(last).count(if item.open { descendants } else { -descendants });
+        }
+        if let Some(dest) = item.dest.filter(|dest| dest.page < pages.len()) {
+            write_destination(writer.dest(), dest, pages);
+        }
+    }
+    (refs[0], refs[refs.len() - 1])
+}
+
+/// Write `dest` on the output page of its input page. Coordinates the input
+/// leaves unset become the top left corner, pdf-writer cannot write null.
+pub(super) fn write_destination(writer: DestinationWriter<'_>, dest: Destination, pages: &[Ref]) {
+    let x = |v: Option<f32>| v.unwrap_or(0.0).clamp(0.0, PAGE_WIDTH);
+    let y = |v: Option<f32>| v.unwrap_or(PAGE_HEIGHT).clamp(0.0, PAGE_HEIGHT);
+    let writer = writer.page(pages[dest.page]);
+    match dest.view {
+        View::Xyz { left, top, zoom } => writer.xyz(x(left), y(top), zoom),
+        View::Fit => writer.fit(),
+        View::FitH { top } => writer.fit_horizontal(y(top)),
+        View::FitV { left } => writer.fit_vertical(x(left)),
+        View::FitR { left, bottom, right, top } => {
+            writer.fit_rect(Rect::new(x(Some(left)), y(Some(bottom)), x(Some(right)), y(Some(top))))
+        }
+        View::FitB => writer.fit_bounding_box(),
+        View::FitBH { top } => writer.fit_bounding_box_horizontal(y(top)),
+        View::FitBV { left } => writer.fit_bounding_box_vertical(x(left)),
+    }
+}
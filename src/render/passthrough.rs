@@ -0,0 +1,324 @@
+// Passthrough renderer: keeps the input document and only runs its page
+// content streams through the optimizer. The extracted lines are counted
+// but not drawn; pages that were not selected are deleted. Streams that
+// fail to parse, or that the optimizer would not shrink, are left untouched.
+
+use super::{RenderStats, Renderer};
+use crate::security::{self, Encryption};
+use crate::Result;
+use crate::layout::Line;
+use crate::metadata::{self, Metadata};
+use crate::optimize::{optimize_content, OptimizeOptions};
+use crate::pdfa::{PdfA, OUTPUT_CONDITION, SRGB_ICC};
+use md5::{Digest, Md5};
+use lopdf::{dictionary, text_string, Document as LoDoc, Object, ObjectId, Stream};
+use std::collections::BTreeSet;
+
+pub struct PassthroughRenderer {
+    input: String,
+    password: Option<String>,
+    out: String,
+    optimize: OptimizeOptions,
+    metadata: Option<Metadata>,
+    pdfa: Option<PdfA>,
+    encryption: Option<Encryption>,
+    /// Input pages kept, `None` for all
+    pages: Option<Vec<usize>>,
+    stats: RenderStats,
+}
+
+impl PassthroughRenderer {
+    /// Renderer copying `input` into `out`, with the content streams optimized as `opts` says.
+    pub fn new(input: &str, password: Option<&str>, out: &str, opts: &OptimizeOptions) -> Self {
+        PassthroughRenderer {
+            input: input.to_string(),
+            password: password.map(str::to_string),
+            out: out.to_string(),
+            optimize: opts.clone(),
+            metadata: None,
+            pdfa: None,
+            encryption: None,
+            pages: None,
+            stats: RenderStats::default(),
+        }
+    }
+}
+
+impl Renderer for PassthroughRenderer {
+    fn add_pages(&mut self, pages: &[Vec<Line>]) -> Result<()> {
+        self.stats.pages += pages.len();
+        Ok(())
+    }
+
+    fn set_metadata(&mut self, metadata: Metadata) {
+        self.metadata = Some(metadata);
+    }
+
+    fn set_pdfa(&mut self, level: PdfA) {
+        self.pdfa = Some(level);
+    }
+
+    fn set_encryption(&mut self, encryption: Encryption) {
+        self.encryption = Some(encryption);
+    }
+
+    fn select_pages(&mut self, pages: &[usize]) {
+        self.pages = Some(pages.to_vec());
+    }
+
+    fn finish(self: Box<Self>) -> Result<RenderStats> {
+        // Decrypted on load, encrypted again, if asked to, before saving
+        let (mut doc, _) = security::open(&self.input, self.password.as_deref())?;
+        let mut stats = self.stats;
+        if let Some(pages) = &self.pages {
+            keep_pages(&mut doc, pages);
+        }
+        // Pages may share content streams, each one is optimized once
+        let streams: BTreeSet<_> = doc.get_pages().into_values().flat_map(|page| doc.get_page_contents(page)).collect();
+        for id in streams {
+            let Ok(Object::Stream(stream)) = doc.get_object_mut(id) else { continue };
+            let compressed = stream.dict.has(b"Filter");
+            let data = if compressed { stream.decompressed_content() } else { Ok(stream.content.clone()) };
+            let Ok(data) = data else { continue };
+            let Ok((optimized, content)) = optimize_content(&data, &self.optimize) else { continue };
+            if optimized.len() >= data.len() {
+                continue;
+            }
+            stats.content.add(content);
+            stream.dict.remove(b"Filter");
+            stream.dict.remove(b"DecodeParms");
+            stream.set_content(optimized);
+            if compressed {
+                stream.compress()?;
+            }
+        }
+        if let Some(metadata) = &self.metadata {
+            update_metadata(&mut doc, metadata, self.pdfa)?;
+        }
+        if self.pdfa.is_some() {
+            // Everything else PDF/A asks for has to come with the input
+            add_output_intent(&mut doc)?;
+            print_links(&mut doc);
+            if !doc.trailer.has(b"ID") {
+                let id = Object::string_literal(Md5::digest(std::fs::read(&self.input)?).to_vec());
+                doc.trailer.set("ID", vec![id.clone(), id]);
+            }
+        }
+        if let Some(encryption) = &self.encryption {
+            security::encrypt_document(&mut doc, encryption)?;
+        }
+        doc.save(&self.out)?;
+        Ok(stats)
+    }
+}
+
+/// Write the fields of `metadata` that differ from the input's into the
+/// Info dictionary, /Lang and the XMP packet, keeping all other entries and
+/// properties. The packet is only written anew when PDF/A needs an
+/// identification the input's lacks.
+fn update_metadata(doc: &mut LoDoc, metadata: &Metadata, pdfa: Option<PdfA>) -> Result<()> {
+    let changes = changes(&metadata::from_lopdf(doc), metadata);
+    let info_id = match doc.trailer.get(b"Info").and_then(Object::as_reference) {
+        Ok(id) => id,
+        Err(_) => doc.add_object(lopdf::Dictionary::new()),
+    };
+    doc.trailer.set("Info", info_id);
+    let info = doc.get_object_mut(info_id).and_then(Object::as_dict_mut)?;
+    let fields = [
+        (b"Title".as_slice(), &changes.title),
+        (b"Author", &changes.author),
+        (b"Subject", &changes.subject),
+        (b"Keywords", &changes.keywords),
+        (b"Creator", &changes.creator),
+        (b"Producer", &changes.producer),
+    ];
+    for (key, value) in fields {
+        if let Some(value) = value {
+            info.set(key, text_string(value));
+        }
+    }
+    if let Some(date) = changes.creation_date {
+        info.set("CreationDate", Object::string_literal(date.to_string()));
+    }
+    if let Some(date) = changes.modification_date {
+        info.set("ModDate", Object::string_literal(date.to_string()));
+    }
+    if let Some(language) = &changes.language {
+        doc.catalog_mut()?.set("Lang", text_string(language));
+    }
+
+    let packet = metadata::xmp_packet(doc);
+    let identified = |xmp: &str, level: PdfA| {
+        metadata::xmp_property(xmp, "pdfaid:part") == Some(level.part().to_string())
+            && metadata::xmp_property(xmp, "pdfaid:conformance").is_some_and(|c| c.eq_ignore_ascii_case(level.conformance()))
+    };
+    let xmp = match (packet, pdfa) {
+        (Some(xmp), Some(level)) if identified(&xmp, level) => metadata::update_xmp(&xmp, &changes),
+        (_, Some(level)) => metadata.to_xmp(Some(level), false),
+        (Some(xmp), None) if !changes.is_empty() => metadata::update_xmp(&xmp, &changes),
+        (_, None) => return Ok(()),
+    };
+    let stream = Stream::new(dictionary! { "Type" => "Metadata", "Subtype" => "XML" }, xmp.into_bytes());
+    let xmp_id = doc.catalog()?.get(b"Metadata").and_then(Object::as_reference).ok();
+    let xmp_id = replace_object(doc, xmp_id, stream.into());
+    doc.catalog_mut()?.set("Metadata", xmp_id);
+    Ok(())
+}
+
+/// The fields of `metadata` that differ from the input's `old` ones.
+fn changes(old: &Metadata, metadata: &Metadata) -> Metadata {
+    fn changed<T: Clone + PartialEq>(old: &Option<T>, new: &Option<T>) -> Option<T> {
+        new.clone().filter(|_| new != old)
+    }
+    Metadata {
+        title: changed(&old.title, &metadata.title),
+        author: changed(&old.author, &metadata.author),
+        subject: changed(&old.subject, &metadata.subject),
+        keywords: changed(&old.keywords, &metadata.keywords),
+        creator: changed(&old.creator, &metadata.creator),
+        producer: changed(&old.producer, &metadata.producer),
+        creation_date: changed(&old.creation_date, &metadata.creation_date),
+        modification_date: changed(&old.modification_date, &metadata.modification_date),
+        language: changed(&old.language, &metadata.language),
+    }
+}
+
+/// Add the sRGB output intent unless the input has a PDF/A one.
+fn add_output_intent(doc: &mut LoDoc) -> Result<()> {
+    let intents = match doc.catalog()?.get(b"OutputIntents") {
+        Ok(Object::Reference(id)) => doc.get_object(*id)?.as_array()?.clone(),
+        Ok(intents) => intents.as_array()?.clone(),
+        Err(_) => Vec::new(),
+    };
+    let is_pdfa = |intent: &Object| {
+        let intent = match intent {
+            Object::Reference(id) => doc.get_dictionary(*id).ok(),
+            intent => intent.as_dict().ok(),
+        };
+        intent.and_then(|i| i.get(b"S").and_then(Object::as_name).ok()) == Some(b"GTS_PDFA1".as_slice())
+    };
+    if intents.iter().any(is_pdfa) {
+        return Ok(());
+    }
+    let profile = doc.add_object(Stream::new(dictionary! { "N" => 3 }, SRGB_ICC.to_vec()));
+    let intent = doc.add_object(dictionary! {
+        "Type" => "OutputIntent",
+        "S" => "GTS_PDFA1",
+        "OutputConditionIdentifier" => text_string(OUTPUT_CONDITION),
+        "Info" => text_string(OUTPUT_CONDITION),
+        "DestOutputProfile" => profile,
+    });
+    let mut intents = intents;
+    intents.push(intent.into());
+    doc.catalog_mut()?.set("OutputIntents", intents);
+    Ok(())
+}
+
+/// Set the print flag of link annotations; TeX writes them without /F.
+fn print_links(doc: &mut LoDoc) {
+    for object in doc.objects.values_mut() {
+        let Ok(dict) = object.as_dict_mut() else { continue };
+        if dict.get(b"Subtype").and_then(Object::as_name).is_ok_and(|s| s == b"Link") && dict.has(b"Rect") {
+            let flags = dict.get(b"F").and_then(Object::as_i64).unwrap_or(0);
+            dict.set("F", flags | 4);
+        }
+    }
+}
+
+/// Put `object` in place of `id`, or add it when there is none.
+fn replace_object(doc: &mut LoDoc, id: Option<ObjectId>, object: Object) -> ObjectId {
+    match id {
+        Some(id) => {
+            doc.objects.insert(id, object);
+            id
+        }
+        None => doc.add_object(object),
+    }
+}
+
+/// Delete every page but the `keep` input page indices and the objects only
+/// they used. References into deleted pages, e.g. from outline entries and
+/// links, are left dangling, which readers take as null.
+fn keep_pages(doc: &mut LoDoc, keep: &[usize]) {
+    let keep: BTreeSet<usize> = keep.iter().copied().collect();
+    let dropped: Vec<ObjectId> = doc.page_iter().enumerate().filter(|(index, _)| !keep.contains(index)).map(|(_, id)| id).collect();
+    for id in dropped {
+        let Some(mut parent) = doc.get_dictionary(id).and_then(|page| page.get(b"Parent")).and_then(Object::as_reference).ok() else { continue };
+        if let Ok(Object::Array(kids)) = doc.get_dictionary_mut(parent).and_then(|node| node.get_mut(b"Kids")) {
+            kids.retain(|kid| kid.as_reference().ok() != Some(id));
+        }
+        // Every page tree node up to the root counts the page
+        let mut depth = 0;
+        while let Ok(node) = doc.get_dictionary_mut(parent) {
+            if let Ok(count) = node.get(b"Count").and_then(Object::as_i64) {
+                node.set("Count", count - 1);
+            }
+            match node.get(b"Parent").and_then(Object::as_reference) {
+                Ok(next) if depth < 64 => parent = next,
+                _ => break,
+            }
+            depth += 1;
+        }
+        doc.objects.remove(&id);
+    }
+    let pruned = doc.prune_objects();
+    log::debug!("kept {} pages, removed {} objects only other pages used", keep.len(), pruned.len());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDocument;
+
+    /// A document with an Info dictionary and an XMP packet that hold more
+    /// than the fields of `Metadata`.
+    fn document() -> LoDoc {
+        let mut doc = TestDocument::new();
+        doc.page("", dictionary! {});
+        let xmp = concat!(
+            r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">"#,
+            r#"<rdf:Description rdf:about="" xmlns:dc="http://purl.org/dc/elements/1.1/">"#,
+            r#"<dc:title><rdf:Alt><rdf:li xml:lang="x-default">Old</rdf:li></rdf:Alt></dc:title>"#,
+            r#"<dc:rights><rdf:Alt><rdf:li xml:lang="x-default">CC-BY</rdf:li></rdf:Alt></dc:rights>"#,
+            r#"</rdf:Description></rdf:RDF></x:xmpmeta>"#,
+        );
+        let xmp = doc.doc.add_object(Stream::new(dictionary! { "Type" => "Metadata", "Subtype" => "XML" }, xmp.as_bytes().to_vec()));
+        let info = doc.doc.add_object(dictionary! { "Title" => text_string("Old"), "Trapped" => "False" });
+        let mut doc = doc.finish(dictionary! { "Metadata" => xmp, "Lang" => text_string("de") });
+        doc.trailer.set("Info", info);
+        doc
+    }
+
+    fn info(doc: &LoDoc) -> &lopdf::Dictionary {
+        doc.get_dictionary(doc.trailer.get(b"Info").unwrap().as_reference().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn updates_only_the_changed_metadata() {
+        let mut doc = document();
+        let metadata = Metadata { title: Some("New".into()), ..metadata::from_lopdf(&doc) };
+        update_metadata(&mut doc, &metadata, None).unwrap();
+        let xmp = metadata::xmp_packet(&doc).unwrap();
+        assert_eq!(metadata::from_lopdf(&doc), metadata);
+        assert_eq!(info(&doc).get(b"Trapped").unwrap().as_name().unwrap(), b"False");
+        assert_eq!(metadata::xmp_property(&xmp, "dc:rights").as_deref(), Some("CC-BY"));
+        assert_eq!(metadata::xmp_property(&xmp, "dc:title").as_deref(), Some("New"));
+    }
+
+    #[test]
+    fn writes_a_new_packet_only_for_pdfa() {
+        let mut doc = document();
+        let metadata = metadata::from_lopdf(&doc);
+        let before = metadata::xmp_packet(&doc);
+        update_metadata(&mut doc, &metadata, None).unwrap();
+        assert_eq!(metadata::xmp_packet(&doc), before);
+
+        update_metadata(&mut doc, &metadata, Some(PdfA::A2b)).unwrap();
+        let xmp = metadata::xmp_packet(&doc).unwrap();
+        assert_eq!(metadata::xmp_property(&xmp, "pdfaid:part").as_deref(), Some("2"));
+        assert_eq!(metadata::from_lopdf(&doc), metadata);
+        // An input identified as the requested level keeps its packet
+        update_metadata(&mut doc, &metadata, Some(PdfA::A2b)).unwrap();
+        assert_eq!(metadata::xmp_packet(&doc).unwrap(), xmp);
+    }
+}
@@ -0,0 +1,109 @@
+// PDF/A additions for the pdf-writer based renderers: the output intent and
+// the file ID. The XMP identification is written with the metadata. The text
+// they draw is also checked by krilla's validator, which only runs on the
+// documents krilla writes: every batch is drawn once more into a krilla
+// document that is serialized and dropped.
+
+use super::{PAGE_HEIGHT, PAGE_WIDTH};
+use crate::fonts::{FontRegistry, ShapedRun};
+use crate::layout::Line;
+use crate::pdfa::{PdfA, OUTPUT_CONDITION, SRGB_ICC};
+use krilla::configure::{Configuration, ValidationError, Validator};
+use krilla::error::KrillaError;
+use krilla::geom::Point;
+use krilla::metadata::{DateTime, Metadata};
+use krilla::page::PageSettings;
+use krilla::{Document, SerializeSettings};
+use md5::{Digest, Md5};
+use std::collections::BTreeSet;
+use pdf_writer::types::OutputIntentSubtype;
+use pdf_writer::writers::Catalog;
+use pdf_writer::{Chunk, Ref, TextStr};
+
+/// Add the sRGB output intent to the catalog; `profile` is written by
+/// [`write_profile`].
+pub(super) fn output_intent(catalog: &mut Catalog<'_>, profile: Ref) {
+    catalog
+        .output_intents()
+        .push()
+        .subtype(OutputIntentSubtype::PDFA)
+        .output_condition_identifier(TextStr(OUTPUT_CONDITION))
+        .info(TextStr(OUTPUT_CONDITION))
+        .dest_output_profile(profile);
+}
+
+pub(super) fn write_profile(chunk: &mut Chunk, profile: Ref) {
+    chunk.icc_profile(profile, SRGB_ICC).n(3).range([0.0, 1.0, 0.0, 1.0, 0.0, 1.0]);
+}
+
+/// The file ID from the hash of the page contents, so the same input gives
+/// the same file.
+pub(super) fn file_id(contents: Md5) -> Vec<u8> {
+    contents.finalize().to_vec()
+}
+
+/// Violations krilla's validator finds in the text of the batches checked.
+pub(super) struct TextValidator<'a> {
+    fonts: &'a FontRegistry,
+    validator: Validator,
+    violations: BTreeSet<String>,
+}
+
+impl<'a> TextValidator<'a> {
+    pub fn new(fonts: &'a FontRegistry, level: PdfA) -> Self {
+        let validator = match level {
+            PdfA::A2b => Validator::A2_B,
+            PdfA::A3b => Validator::A3_B,
+        };
+        TextValidator { fonts, validator, violations: BTreeSet::new() }
+    }
+
+    /// Draw the runs shaped for `pages` that `drawn` accepts into a krilla
+    /// document and keep what its validator rejects.
+    pub fn check(&mut self, pages: &[Vec<Line>], runs: &[Vec<Vec<ShapedRun>>], drawn: impl Fn(&ShapedRun) -> bool) {
+        let settings = SerializeSettings { configuration: Configuration::new_with_validator(self.validator), ..SerializeSettings::default() };
+        let mut document = Document::new_with(settings);
+        // Only the text is checked; a date keeps the validator from asking for one
+        document.set_metadata(Metadata::new().creation_date(DateTime::new(2000)));
+        for (lines, line_runs) in pages.iter().zip(runs) {
+            let mut page = document.start_page_with(PageSettings::new(PAGE_WIDTH, PAGE_HEIGHT));
+            let mut surface = page.surface();
+            for (line, runs) in lines.iter().zip(line_runs) {
+                for run in runs.iter().filter(|run| drawn(run)) {
+                    let start = Point::from_xy(line.glyphs[0].x + run.offset * line.size, PAGE_HEIGHT - line.y);
+                    surface.draw_glyphs(start, &run.shaped.glyphs, self.fonts.font(run.font).clone(), &run.text, line.size, false);
+                }
+            }
+            surface.finish();
+            page.finish();
+        }
+        match document.finish() {
+            Ok(_) => {}
+            Err(KrillaError::Validation(errors)) => {
+                self.violations.extend(errors.iter().map(|error| describe(self.fonts, error)));
+            }
+            Err(e) => {
+                self.violations.insert(format!("krilla cannot write the text: {:?}", e));
+            }
+        }
+    }
+
+    pub fn violations(self) -> Vec<String> {
+        self.violations.into_iter().collect()
+    }
+}
+
+/// The validation error with the font named by its file.
+fn describe(fonts: &FontRegistry, error: &ValidationError) -> String {
+    let path = |font| fonts.fonts().iter().find(|f| &f.font == font).map_or("?", |f| f.path.as_str());
+    match error {
+        ValidationError::ContainsNotDefGlyph(font, _, text) => format!(".notdef glyph of {} drawn for {:?}", path(font), text),
+        ValidationError::InvalidCodepointMapping(font, glyph, c, _) => {
+            format!("glyph {} of {} is mapped to {:?}", glyph.to_u32(), path(font), c)
+        }
+        ValidationError::UnicodePrivateArea(font, glyph, c, _) => {
+            format!("glyph {} of {} is mapped to the private use character {:?}", glyph.to_u32(), path(font), c)
+        }
+        error => format!("krilla: {:?}", error),
+    }
+}
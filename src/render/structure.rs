@@ -0,0 +1,244 @@
+// Structure tree for the pdf-writer based renderers. Every element of a page
+// is one marked content sequence, numbered in drawing order; the tree is
+// Document → H1..H6 / P / Figure / L → LI → LBody / Table → TR → TD, with
+// each link annotation in a Link element under the element its rectangle
+// lies in. Artifacts are marked as such and left out of the tree. The marked
+// content is written with pdf-writer as the page is drawn, not by krilla's
+// tagging, since neither renderer builds its pages with krilla.
+
+use crate::layout::Line;
+use crate::links::{Link, LinkTarget};
+use crate::structure::{Element, Role, Tagging};
+use pdf_writer::types::StructRole;
+use pdf_writer::writers::{Catalog, StructTreeRoot};
+use pdf_writer::{Chunk, Content, Finish, Name, Rect, Ref, TextStr};
+
+/// Role and bounding box of one marked content sequence; its MCID is its
+/// index on the page.
+pub(super) struct Tag {
+    role: Role,
+    bbox: Rect,
+    alt: Option<String>,
+}
+
+impl Tag {
+    pub(super) fn new(element: &Element, lines: &[Line]) -> Tag {
+        let lines = &lines[element.lines.clone()];
+        let mut bbox = Rect::new(f32::INFINITY, f32::INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY);
+        for line in lines {
+            let last = &line.glyphs[line.glyphs.len() - 1];
+            bbox.x1 = bbox.x1.min(line.glyphs[0].x);
+            bbox.x2 = bbox.x2.max(last.x + last.w);
+            bbox.y1 = bbox.y1.min(line.y - line.size * 0.25);
+            bbox.y2 = bbox.y2.max(line.y + line.size);
+        }
+        Tag { role: element.role, bbox, alt: element.alt.clone() }
+    }
+}
+
+/// Open the marked content sequence of element `mcid`; close it with
+/// `end_marked_content`. Artifacts get no MCID.
+pub(super) fn begin_tag(content: &mut Content, role: Role, mcid: usize) {
+    match role {
+        Role::Artifact => {
+            content.begin_marked_content(tag_name(role));
+        }
+        role => {
+            content.begin_marked_content_with_properties(tag_name(role)).properties().identify(mcid as i32);
+        }
+    }
+}
+
+fn tag_name(role: Role) -> Name<'static> {
+    match role {
+        Role::Heading(1) => Name(b"H1"),
+        Role::Heading(2) => Name(b"H2"),
+        Role::Heading(3) => Name(b"H3"),
+        Role::Heading(4) => Name(b"H4"),
+        Role::Heading(5) => Name(b"H5"),
+        Role::Heading(_) => Name(b"H6"),
+        Role::Paragraph => Name(b"P"),
+        Role::ListItem => Name(b"LBody"),
+        Role::Figure => Name(b"Figure"),
+        Role::Cell { .. } => Name(b"TD"),
+        Role::Artifact => Name(b"Artifact"),
+    }
+}
+
+/// Structure type of an element; artifacts are not structure elements.
+fn struct_role(role: Role) -> Option<StructRole> {
+    Some(match role {
+        Role::Heading(1) => StructRole::H1,
+        Role::Heading(2) => StructRole::H2,
+        Role::Heading(3) => StructRole::H3,
+        Role::Heading(4) => StructRole::H4,
+        Role::Heading(5) => StructRole::H5,
+        Role::Heading(_) => StructRole::H6,
+        Role::Paragraph => StructRole::P,
+        Role::ListItem => StructRole::LBody,
+        Role::Figure => StructRole::Figure,
+        Role::Cell { .. } => StructRole::TD,
+        Role::Artifact => return None,
+    })
+}
+
+/// Point the catalog to the structure tree at `root_ref` and mark the document tagged.
+pub(super) fn tag_catalog(catalog: &mut Catalog, root_ref: Ref, tagging: Tagging) {
+    catalog.pair(Name(b"StructTreeRoot"), root_ref);
+    catalog.mark_info().marked(true);
+    if tagging == Tagging::PdfUa {
+        // PDF/UA viewers show the title, not the file name
+        catalog.viewer_preferences().display_doc_title(true);
+    }
+}
+
+/// Alternate description of a link annotation, its /Contents.
+pub(super) fn link_description(link: &Link) -> String {
+    match &link.target {
+        LinkTarget::Uri(uri) => uri.clone(),
+        LinkTarget::GoTo(dest) => format!("Link to page {}", dest.page + 1),
+    }
+}
+
+/// Write the structure tree. `tags` and `links` are by output page, the
+/// links as annotation and rectangle; a page's /StructParents is its index
+/// and the annotations' /StructParent keys follow the pages in order.
+pub(super) fn write_structure(chunk: &mut Chunk, alloc: &mut Ref, root_ref: Ref, pages: &[Ref], tags: &[Vec<Tag>], links: &[Vec<(Ref, Rect)>]) {
+    let doc_ref = alloc.bump();
+    let mut top: Vec<Ref> = Vec::new();
+    // Element holding each MCID (none for artifacts), by page, and each link's element in key order
+    let mut parents: Vec<Vec<Option<Ref>>> = Vec::new();
+    let mut link_parents: Vec<Ref> = Vec::new();
+
+    for (p, page_tags) in tags.iter().enumerate() {
+        let page_ref = pages[p];
+        let refs: Vec<Option<Ref>> = page_tags.iter().map(|t| struct_role(t.role).map(|_| alloc.bump())).collect();
+        let page_links = links.get(p).map_or(&[][..], Vec::as_slice);
+        // Element each link lies in, by its center; Document when none
+        let owners = page_links
+            .iter()
+            .map(|(_, rect)| {
+                let (x, y) = ((rect.x1 + rect.x2) / 2.0, (rect.y1 + rect.y2) / 2.0);
+                page_tags.iter().position(|t| t.role != Role::Artifact && t.bbox.x1 <= x && x <= t.bbox.x2 && t.bbox.y1 <= y && y <= t.bbox.y2)
+            });
+        let link_refs: Vec<(Ref, Option<usize>)> = owners.map(|owner| (alloc.bump(), owner)).collect();
+
+        let mut i = 0;
+        while i < page_tags.len() {
+            if let Role::Cell { table, .. } = page_tags[i].role {
+                // Consecutive cells of a table form it, the ones of a row its rows
+                let end = (i..page_tags.len()).find(|&j| !matches!(page_tags[j].role, Role::Cell { table: other, .. } if other == table)).unwrap_or(page_tags.len());
+                let mut rows: Vec<std::ops::Range<usize>> = Vec::new();
+                for j in i..end {
+                    match rows.last_mut() {
+                        Some(row) if page_tags[row.start].role == page_tags[j].role => row.end = j + 1,
+                        _ => rows.push(j..j + 1),
+                    }
+                }
+                let table_ref = alloc.bump();
+                top.push(table_ref);
+                let row_refs: Vec<Ref> = rows.iter().map(|_| alloc.bump()).collect();
+                let mut element = chunk.struct_element(table_ref);
+                element.kind(StructRole::Table).parent(doc_ref).page(page_ref);
+                element.children().items(row_refs.iter().copied());
+                element.finish();
+                for (cells, &row_ref) in rows.into_iter().zip(&row_refs) {
+                    let mut row = chunk.struct_element(row_ref);
+                    row.kind(StructRole::TR).parent(table_ref).page(page_ref);
+                    row.children().items(cells.clone().filter_map(|mcid| refs[mcid]));
+                    row.finish();
+                    for mcid in cells {
+                        if let Some(cell_ref) = refs[mcid] {
+                            write_element(chunk, cell_ref, row_ref, page_ref, &page_tags[mcid], mcid, &link_refs);
+                        }
+                    }
+                }
+                i = end;
+                continue;
+            }
+            if page_tags[i].role != Role::ListItem {
+                if let Some(elem_ref) = refs[i] {
+                    top.push(elem_ref);
+                    write_element(chunk, elem_ref, doc_ref, page_ref, &page_tags[i], i, &link_refs);
+                }
+                i += 1;
+                continue;
+            }
+            // Consecutive list items form one list
+            let list_ref = alloc.bump();
+            top.push(list_ref);
+            let end = (i..page_tags.len()).find(|&j| page_tags[j].role != Role::ListItem).unwrap_or(page_tags.len());
+            let items: Vec<Ref> = (i..end).map(|_| alloc.bump()).collect();
+            let mut list = chunk.struct_element(list_ref);
+            list.kind(StructRole::L).parent(doc_ref).page(page_ref);
+            list.children().items(items.iter().copied());
+            list.finish();
+            for (mcid, &item_ref) in (i..end).zip(&items) {
+                let Some(body_ref) = refs[mcid] else { continue };
+                let mut item = chunk.struct_element(item_ref);
+                item.kind(StructRole::LI).parent(list_ref).page(page_ref).child(body_ref);
+                item.finish();
+                write_element(chunk, body_ref, item_ref, page_ref, &page_tags[mcid], mcid, &link_refs);
+            }
+            i = end;
+        }
+
+        for ((annot_ref, _), &(link_ref, owner)) in page_links.iter().zip(&link_refs) {
+            let parent = owner.and_then(|o| refs[o]).unwrap_or(doc_ref);
+            if owner.is_none() {
+                top.push(link_ref);
+            }
+            let mut link = chunk.struct_element(link_ref);
+            link.kind(StructRole::Link).parent(parent).page(page_ref);
+            link.children().object_ref().page(page_ref).object(*annot_ref);
+            link.finish();
+            link_parents.push(link_ref);
+        }
+        parents.push(refs);
+    }
+
+    let mut document = chunk.struct_element(doc_ref);
+    document.kind(StructRole::Document).parent(root_ref);
+    document.children().items(top);
+    document.finish();
+
+    let mut root = chunk.indirect(root_ref).start::<StructTreeRoot>();
+    root.child(doc_ref);
+    let mut tree = root.insert(Name(b"ParentTree")).dict();
+    let mut nums = tree.insert(Name(b"Nums")).array();
+    for (key, refs) in parents.iter().enumerate() {
+        nums.item(key as i32);
+        let mut array = nums.push().array();
+        for elem_ref in refs {
+            match elem_ref {
+                Some(elem_ref) => array.item(*elem_ref),
+                None => array.item(pdf_writer::Null),
+            };
+        }
+    }
+    for (n, &link_ref) in link_parents.iter().enumerate() {
+        nums.item((parents.len() + n) as i32);
+        nums.item(link_ref);
+    }
+    nums.finish();
+    tree.finish();
+    root.parent_tree_next_key((parents.len() + link_parents.len()) as i32);
+}
+
+/// A heading, paragraph, figure, list body or table cell holding one MCID
+/// and the links in it.
+fn write_element(chunk: &mut Chunk, elem_ref: Ref, parent: Ref, page: Ref, tag: &Tag, mcid: usize, links: &[(Ref, Option<usize>)]) {
+    let Some(kind) = struct_role(tag.role) else { return };
+    let mut element = chunk.struct_element(elem_ref);
+    element.kind(kind).parent(parent).page(page);
+    if let Some(alt) = &tag.alt {
+        element.alt(TextStr(alt));
+    }
+    let mut children = element.children();
+    children.marked_content_id(mcid as i32);
+    for &(link_ref, owner) in links {
+        if owner == Some(mcid) {
+            children.struct_element(link_ref);
+        }
+    }
+}
@@ -0,0 +1,224 @@
+// Typst-like renderer: writes the content streams in Typst's structure
+// directly with pdf-writer. The page gets one flipping transform, every block
+// of lines its own `q`/`cm` group with the text matrices relative to the
+// block, and fill color and font are only set where the graphics state does
+// not already hold them. A tagged output marks each element of a block as
+// one marked content sequence. The output is deterministic.
+
+use super::document::DocumentWriter;
+use super::embed::FontEmbedder;
+use super::pdfa::TextValidator;
+use super::structure::{begin_tag, Tag};
+use super::{RenderStats, Renderer, PAGE_HEIGHT};
+use crate::Result;
+use crate::fonts::{FontRegistry, ShapeOptions, ShapedRun, Shapers};
+use crate::layout::Line;
+use crate::links::Link;
+use crate::metadata::Metadata;
+use crate::optimize::{optimize_content, OptimizeOptions};
+use crate::outline::OutlineItem;
+use crate::pdfa::PdfA;
+use crate::security::Encryption;
+use crate::structure::{self, blocks, Tagging};
+use pdf_writer::writers::Resources;
+use pdf_writer::types::ColorSpaceOperand;
+use pdf_writer::{Chunk, Content, Finish, Name, Ref};
+use std::collections::HashMap;
+
+/// D65 white point of the `/d65gray` CalGray color space.
+const D65_WHITE_POINT: [f32; 3] = [0.95047, 1.0, 1.08883];
+
+/// Render all pages at once and write the document to `out`.
+pub fn render_like_typst(pages: Vec<Vec<Line>>, out: &str, fonts: &FontRegistry, opts: &OptimizeOptions) -> Result<RenderStats> {
+    let mut writer = TypstWriter::new(fonts, out, opts, &ShapeOptions::default());
+    writer.add_pages(&pages)?;
+    writer.finish()
+}
+
+/// Fill color and font the current graphics state holds.
+#[derive(Clone, Copy, Default)]
+struct GraphicsState {
+    fill: bool,
+    font: Option<(usize, f32)>,
+}
+
+/// Incremental Typst-like writer: pages are shaped, drawn and written to the
+/// output as they arrive, and the caller can drop their lines right after
+/// `add_pages`. Only the glyph subsets and the page references are kept until
+/// `finish`.
+pub struct TypstWriter<'a> {
+    fonts: &'a FontRegistry,
+    shapers: Shapers<'a>,
+    embedder: FontEmbedder<'a>,
+    /// Set with a PDF/A level
+    validator: Option<TextValidator<'a>>,
+    /// Registry fonts in order of first use; `/F{n}` is the n-th one
+    used: Vec<usize>,
+    resource: HashMap<usize, usize>,
+    optimize: OptimizeOptions,
+    document: DocumentWriter,
+    stats: RenderStats,
+}
+
+impl<'a> TypstWriter<'a> {
+    /// Writer of `out`, with the text shaped as `shaping` says and the content
+    /// streams optimized as `opts` says.
+    pub fn new(fonts: &'a FontRegistry, out: &str, opts: &OptimizeOptions, shaping: &ShapeOptions) -> Self {
+        TypstWriter {
+            fonts,
+            shapers: Shapers::new(fonts, shaping),
+            embedder: FontEmbedder::new(fonts),
+            validator: None,
+            used: Vec::new(),
+            resource: HashMap::new(),
+            optimize: opts.clone(),
+            document: DocumentWriter::new(out),
+            stats: RenderStats::default(),
+        }
+    }
+
+    /// Draw `pages` and write their content streams to the output.
+    pub fn add_pages(&mut self, pages: &[Vec<Line>]) -> Result<()> {
+        // Shaping runs in parallel; drawing has to follow page order
+        let shaped = self.shapers.shape_pages(pages);
+        self.stats.record(pages.len(), &shaped);
+        if let Some(validator) = &mut self.validator {
+            validator.check(pages, &shaped.pages, |run| self.embedder.can_draw(run.font));
+        }
+        let mut chunk = Chunk::new();
+        for (lines, runs) in pages.iter().zip(shaped.pages) {
+            let page = self.draw_page(lines, runs);
+            let (content, content_stats) = optimize_content(&page, &self.optimize)?;
+            self.stats.content.add(content_stats);
+            self.document.add_page(&mut chunk, &content);
+        }
+        self.document.write(&chunk)
+    }
+
+    fn draw_page(&mut self, lines: &[Line], mut runs: Vec<Vec<ShapedRun>>) -> Vec<u8> {
+        let mut content = Content::new();
+        // Origin at the top left, y pointing down
+        content.transform([1.0, 0.0, 0.0, -1.0, 0.0, PAGE_HEIGHT]);
+        let mut state = GraphicsState::default();
+        let elements = if self.document.tagging().is_some() { structure::elements(lines) } else { Vec::new() };
+        let mut element = 0;
+        for block in blocks(lines) {
+            let block_lines = &lines[block.clone()];
+            let left = block_lines.iter().map(|l| l.glyphs[0].x).fold(f32::INFINITY, f32::min);
+            let top = PAGE_HEIGHT - block_lines[0].y - block_lines[0].size;
+
+            content.save_state();
+            let saved = state;
+            content.transform([1.0, 0.0, 0.0, 1.0, left, top]);
+            for i in block.clone() {
+                let line = &lines[i];
+                if elements.get(element).is_some_and(|e| e.lines.start == i) {
+                    begin_tag(&mut content, elements[element].role, element);
+                }
+                for run in runs[i].drain(..) {
+                    if !self.embedder.can_draw(run.font) {
+                        continue;
+                    }
+                    if !state.fill {
+                        content.set_fill_color_space(ColorSpaceOperand::Named(Name(b"d65gray")));
+                        content.set_fill_color([0.0]);
+                        state.fill = true;
+                    }
+                    if state.font != Some((run.font, line.size)) {
+                        let name = format!("F{}", self.resource_index(run.font));
+                        content.set_font(Name(name.as_bytes()), line.size);
+                        state.font = Some((run.font, line.size));
+                    }
+                    let x = line.glyphs[0].x + run.offset * line.size - left;
+                    content.begin_text();
+                    content.set_text_matrix([1.0, 0.0, 0.0, -1.0, x, PAGE_HEIGHT - line.y - top]);
+                    self.embedder.show_run(&mut content, &run);
+                    content.end_text();
+                }
+                if elements.get(element).is_some_and(|e| e.lines.end == i + 1) {
+                    content.end_marked_content();
+                    element += 1;
+                }
+            }
+            content.restore_state();
+            state = saved;
+        }
+        if self.document.tagging().is_some() {
+            self.document.add_tags(elements.iter().map(|e| Tag::new(e, lines)).collect());
+        }
+        content.finish().to_vec()
+    }
+
+    /// Number of the `/F{n}` resource of a registry font, assigned on first use.
+    fn resource_index(&mut self, font: usize) -> usize {
+        *self.resource.entry(font).or_insert_with(|| {
+            self.used.push(font);
+            self.used.len() - 1
+        })
+    }
+
+    /// Write the font subsets and the resources all pages share, then the
+    /// rest of the document.
+    pub fn finish(mut self) -> Result<RenderStats> {
+        let mut chunk = Chunk::new();
+        let alloc = self.document.alloc();
+        let resources_ref = alloc.bump();
+        let gray_ref = alloc.bump();
+        let font_refs: Vec<Ref> = self.used.iter().map(|_| alloc.bump()).collect();
+
+        // All pages share one resource dictionary, like Typst writes it
+        let mut resources = chunk.indirect(resources_ref).start::<Resources>();
+        resources.color_spaces().pair(Name(b"d65gray"), gray_ref);
+        let mut fonts = resources.fonts();
+        for (n, font_ref) in font_refs.iter().enumerate() {
+            fonts.pair(Name(format!("F{}", n).as_bytes()), *font_ref);
+        }
+        fonts.finish();
+        resources.finish();
+        chunk.color_space(gray_ref).cal_gray(D65_WHITE_POINT, None, Some(2.2));
+        for (&font, &type0_ref) in self.used.iter().zip(&font_refs) {
+            self.embedder.write_font(&mut chunk, font, type0_ref, self.document.alloc())?;
+        }
+
+        self.document.finish(chunk, |_, page| {
+            page.pair(Name(b"Resources"), resources_ref);
+        })?;
+        self.stats.validation = self.validator.map(TextValidator::violations).unwrap_or_default();
+        Ok(self.stats)
+    }
+}
+
+impl Renderer for TypstWriter<'_> {
+    fn add_pages(&mut self, pages: &[Vec<Line>]) -> Result<()> {
+        TypstWriter::add_pages(self, pages)
+    }
+
+    fn set_outline(&mut self, outline: Vec<OutlineItem>) {
+        self.document.set_outline(outline);
+    }
+
+    fn add_links(&mut self, page: usize, links: Vec<Link>) {
+        self.document.add_links(page, links);
+    }
+
+    fn set_metadata(&mut self, metadata: Metadata) {
+        self.document.set_metadata(metadata);
+    }
+
+    fn set_pdfa(&mut self, level: PdfA) {
+        self.validator = Some(TextValidator::new(self.fonts, level));
+        self.document.set_pdfa(level);
+    }
+
+    fn set_encryption(&mut self, encryption: Encryption) {
+        self.document.set_encryption(encryption);
+    }
+
+    fn set_tagging(&mut self, tagging: Tagging) {
+        self.document.set_tagging(tagging);
+    }
+
+    fn finish(self: Box<Self>) -> Result<RenderStats> {
+        TypstWriter::finish(*self)
+    }
+}
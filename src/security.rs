@@ -0,0 +1,472 @@
+// ========== Encrypted documents ==========
+//
+// Inputs encrypted with the standard security handler are opened with the
+// given password, which may be the owner or the user password; without one
+// only documents with an empty user password open. The output can carry the
+// input's encryption, opening with the same passwords and granting the same
+// permissions, or get a new AES-256 encryption with its own passwords.
+
+use crate::{Error, Result};
+use aes::cipher::{BlockEncrypt, KeyInit};
+use aes::Aes256;
+use lopdf::encryption::crypt_filters::{Aes256CryptFilter, CryptFilter, Rc4CryptFilter};
+use lopdf::encryption::PasswordAlgorithm;
+use lopdf::{Dictionary, Document as LoDoc, EncryptionState, EncryptionVersion, Object, ObjectId, Permissions};
+use md5::{Digest, Md5};
+use rand::RngCore;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Which password opened a document.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    /// Full access regardless of the permissions
+    Owner,
+    /// Access restricted to the permissions
+    User,
+}
+
+/// How an input document is encrypted and what it allows.
+#[derive(Clone, Debug)]
+pub struct Security {
+    pub access: Access,
+    pub permissions: Permissions,
+    pub encryption: Encryption,
+}
+
+/// Encryption of a document: security handler state, file key and, for the
+/// ID-dependent revisions below 5, the document ID. Applying it to another
+/// file makes that file open with the same passwords.
+#[derive(Clone, Debug)]
+pub struct Encryption {
+    state: EncryptionState,
+    id: Option<Object>,
+}
+
+/// What the user password of a newly encrypted output allows. The owner
+/// password always grants everything.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Permission {
+    /// Print, also in high quality
+    Print,
+    /// Copy text and graphics
+    Copy,
+    /// Change the content and insert, rotate or delete pages
+    Modify,
+    /// Add annotations and fill in forms
+    Annotate,
+}
+
+impl Permission {
+    fn flags(self) -> Permissions {
+        match self {
+            Permission::Print => Permissions::PRINTABLE | Permissions::PRINTABLE_IN_HIGH_QUALITY,
+            Permission::Copy => Permissions::COPYABLE,
+            Permission::Modify => Permissions::MODIFIABLE | Permissions::ASSEMBLABLE,
+            Permission::Annotate => Permissions::ANNOTABLE | Permissions::FILLABLE,
+        }
+    }
+}
+
+impl FromStr for Permission {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "print" => Ok(Permission::Print),
+            "copy" => Ok(Permission::Copy),
+            "modify" => Ok(Permission::Modify),
+            "annotate" => Ok(Permission::Annotate),
+            _ => Err(format!("unknown permission '{}' (expected print, copy, modify or annotate)", s)),
+        }
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Permission::Print => "print",
+            Permission::Copy => "copy",
+            Permission::Modify => "modify",
+            Permission::Annotate => "annotate",
+        })
+    }
+}
+
+impl Encryption {
+    /// New AES-256 (revision 6) encryption with a random file key. An empty
+    /// user password lets anyone open the document with `allow`ed rights only;
+    /// extraction for accessibility is always allowed, as PDF 2.0 requires.
+    pub fn aes256(owner_password: &str, user_password: &str, allow: &[Permission]) -> Result<Encryption> {
+        let mut key = [0u8; 32];
+        rand::rng().fill_bytes(&mut key);
+        let permissions = allow.iter().fold(Permissions::COPYABLE_FOR_ACCESSIBILITY, |acc, p| acc | p.flags());
+        let mut crypt_filters: BTreeMap<Vec<u8>, Arc<dyn CryptFilter>> = BTreeMap::new();
+        crypt_filters.insert(b"StdCF".to_vec(), Arc::new(Aes256CryptFilter));
+        let state = EncryptionState::try_from(EncryptionVersion::V5 {
+            encrypt_metadata: true,
+            crypt_filters,
+            file_encryption_key: &key,
+            stream_filter: b"StdCF".to_vec(),
+            string_filter: b"StdCF".to_vec(),
+            owner_password,
+            user_password,
+            permissions,
+        })?;
+        Ok(Encryption { state, id: None })
+    }
+
+    pub fn permissions(&self) -> Permissions {
+        self.state.permissions()
+    }
+
+    /// Security handler revision: 2-4 for RC4 and AES-128, 6 for AES-256.
+    pub fn revision(&self) -> i64 {
+        self.state.revision()
+    }
+
+    pub fn cipher(&self) -> String {
+        let aes = |method: &[u8]| {
+            self.state.crypt_filters().get(self.state.default_stream_filter()).is_some_and(|f| f.method() == method)
+        };
+        match self.state.revision() {
+            2 => "RC4 40-bit".to_string(),
+            3 => format!("RC4 {}-bit", self.state.key_length().unwrap_or(40)),
+            4 if aes(b"AESV2") => "AES-128".to_string(),
+            4 => "RC4 128-bit".to_string(),
+            _ => "AES-256".to_string(),
+        }
+    }
+
+    /// Encrypt the strings and streams of object `id`, for writers that
+    /// encrypt each object as they write it.
+    pub(crate) fn encrypt_object(&self, id: ObjectId, object: &mut Object) -> Result<()> {
+        lopdf::encryption::encrypt_object(&self.state, id, object).map_err(lopdf::Error::from)?;
+        Ok(())
+    }
+
+    /// The /Encrypt dictionary to write next to the encrypted objects.
+    pub(crate) fn dictionary(&self) -> Result<Dictionary> {
+        let mut dict = self.state.encode().map_err(lopdf::Error::from)?;
+        self.encrypt_perms(&mut dict)?;
+        Ok(dict)
+    }
+
+    /// The ID the file key of revisions below 5 depends on, which the output
+    /// has to carry.
+    pub(crate) fn id(&self) -> Option<&Object> {
+        self.id.as_ref()
+    }
+
+    /// lopdf writes the /Perms block of revision 6 unencrypted, encrypt it
+    /// with the file key (AES-256 in ECB mode) unless it already is.
+    fn encrypt_perms(&self, dict: &mut Dictionary) -> Result<()> {
+        let Ok(perms) = dict.get(b"Perms").and_then(Object::as_str) else { return Ok(()) };
+        if self.revision() < 5 || perms.len() != 16 || &perms[9..12] != b"adb" {
+            return Ok(());
+        }
+        let key = self.state.file_encryption_key();
+        let cipher = Aes256::new_from_slice(key).map_err(|_| Error::Render("invalid AES-256 file key".into()))?;
+        let mut block = aes::Block::clone_from_slice(perms);
+        cipher.encrypt_block(&mut block);
+        dict.set("Perms", Object::string_literal(block.to_vec()));
+        Ok(())
+    }
+}
+
+/// Load `path` with lopdf and decrypt it if needed. `None` for unencrypted documents.
+pub fn open(path: &str, password: Option<&str>) -> Result<(LoDoc, Option<Security>)> {
+    let mut doc = LoDoc::load_filtered(path, expect_plain_perms)?;
+    // lopdf already decrypts documents with an empty user password on load
+    if doc.is_encrypted() {
+        let password = password.ok_or_else(|| Error::Password(path.to_string()))?;
+        let decrypted = match user_password_from_owner(&doc, password) {
+            Some(user) => doc.decrypt_raw(user),
+            None => doc.decrypt(password),
+        };
+        decrypted.map_err(|e| match e {
+            lopdf::Error::Decryption(lopdf::encryption::DecryptionError::IncorrectPassword) => Error::Password(path.to_string()),
+            e => e.into(),
+        })?;
+    }
+    let Some(state) = doc.encryption_state.clone() else { return Ok((doc, None)) };
+    let id = doc.trailer.get(b"ID").ok().filter(|_| state.revision() < 5).cloned();
+    let encryption = Encryption { state, id };
+    let access = if opens_as_owner(&encryption, password.unwrap_or("")) { Access::Owner } else { Access::User };
+    let permissions = encryption.state.permissions();
+    Ok((doc, Some(Security { access, permissions, encryption })))
+}
+
+/// lopdf derives the RC4/AES-128 file key from whatever password it gets as
+/// if it were the user password. For an owner password of revisions 2-4 the
+/// user password is recovered from /O first (ISO 32000-1, algorithm 7).
+fn user_password_from_owner(doc: &LoDoc, password: &str) -> Option<Vec<u8>> {
+    const PAD: [u8; 32] = [
+        0x28, 0xbf, 0x4e, 0x5e, 0x4e, 0x75, 0x8a, 0x41, 0x64, 0x00, 0x4e, 0x56, 0xff, 0xfa, 0x01, 0x08,
+        0x2e, 0x2e, 0x00, 0xb6, 0xd0, 0x68, 0x3e, 0x80, 0x2f, 0x0c, 0xa9, 0xfe, 0x64, 0x53, 0x69, 0x7a,
+    ];
+    let dict = doc.get_encrypted().ok()?;
+    let revision = dict.get(b"R").and_then(Object::as_i64).ok()?;
+    if !(2..=4).contains(&revision) || doc.authenticate_owner_password(password).is_err() {
+        return None;
+    }
+    let owner_value = dict.get(b"O").and_then(Object::as_str).ok()?;
+    let password = PasswordAlgorithm::try_from(doc).ok()?.sanitize_password(password).ok()?;
+
+    let len = password.len().min(32);
+    let mut hash = Md5::new().chain_update(&password[..len]).chain_update(&PAD[..32 - len]).finalize();
+    let n = if revision >= 3 {
+        for _ in 0..50 {
+            hash = Md5::digest(hash);
+        }
+        dict.get(b"Length").and_then(Object::as_i64).map_or(5, |bits| (bits / 8).clamp(5, 16) as usize)
+    } else {
+        5
+    };
+    let key = &hash[..n];
+    let rc4 = |key: &[u8], data: &[u8]| Rc4CryptFilter.decrypt(key, data).ok();
+    let mut padded = owner_value.to_vec();
+    if revision == 2 {
+        padded = rc4(key, &padded)?;
+    } else {
+        for i in (0..20u8).rev() {
+            let round_key: Vec<u8> = key.iter().map(|b| b ^ i).collect();
+            padded = rc4(&round_key, &padded)?;
+        }
+    }
+    // The user password is followed by the start of the padding string
+    let end = (0..=32).find(|&k| padded.get(k..).is_some_and(|rest| PAD.starts_with(rest)))?;
+    Some(padded[..end].to_vec())
+}
+
+/// lopdf checks /Perms of revision 6 without decrypting it, so files that
+/// encrypt it as the standard requires fail with the user password, also the
+/// empty one it tries on load. While parsing, replace it by the plain block
+/// lopdf expects, built from /P and /EncryptMetadata; `encrypt_perms` puts
+/// the encrypted one back on output.
+fn expect_plain_perms(id: (u32, u16), object: &mut Object) -> Option<((u32, u16), Object)> {
+    if let Ok(dict) = object.as_dict_mut() {
+        let revision = dict.get(b"R").and_then(Object::as_i64).unwrap_or(0);
+        if dict.has(b"Perms") && revision >= 5 && dict.get(b"Filter").and_then(Object::as_name).is_ok_and(|f| f == b"Standard") {
+            let p = dict.get(b"P").and_then(Object::as_i64).unwrap_or(-1);
+            let encrypt_metadata = dict.get(b"EncryptMetadata").and_then(Object::as_bool).unwrap_or(true);
+            let mut plain = [0u8; 16];
+            plain[..8].copy_from_slice(&(p as u64 | 0xffff_ffff_0000_0000).to_le_bytes());
+            plain[8] = if encrypt_metadata { b'T' } else { b'F' };
+            plain[9..12].copy_from_slice(b"adb");
+            dict.set("Perms", Object::string_literal(plain.to_vec()));
+        }
+    }
+    // lopdf keeps top-level objects as changed in place and members of object
+    // streams, which are never streams themselves, as returned
+    let kept = if matches!(object, Object::Stream(_)) { Object::Null } else { object.clone() };
+    Some((id, kept))
+}
+
+/// Check `password` against the owner entries on a stand-in document, the
+/// decrypted one no longer has its encryption dictionary.
+fn opens_as_owner(encryption: &Encryption, password: &str) -> bool {
+    let Ok(dict) = encryption.state.encode() else { return false };
+    let mut probe = LoDoc::new();
+    if let Some(id) = &encryption.id {
+        probe.trailer.set("ID", id.clone());
+    }
+    let dict_id = probe.add_object(dict);
+    probe.trailer.set("Encrypt", Object::Reference(dict_id));
+    probe.authenticate_owner_password(password).is_ok()
+}
+
+/// Encrypt `doc` in memory, for writers that hold the whole document.
+pub fn encrypt_document(doc: &mut LoDoc, encryption: &Encryption) -> Result<()> {
+    if let Some(id) = &encryption.id {
+        doc.trailer.set("ID", id.clone());
+    } else if !doc.trailer.has(b"ID") {
+        let id = Object::string_literal(random_id().to_vec());
+        doc.trailer.set("ID", vec![id.clone(), id]);
+    }
+    doc.encrypt(&encryption.state)?;
+    let dict_id = doc.trailer.get(b"Encrypt").and_then(Object::as_reference)?;
+    let dict = doc.get_object_mut(dict_id).and_then(Object::as_dict_mut)?;
+    encryption.encrypt_perms(dict)?;
+    Ok(())
+}
+
+/// A random file ID; encrypted documents need one, revision 6 does not
+/// derive keys from it.
+pub(crate) fn random_id() -> [u8; 16] {
+    let mut id = [0u8; 16];
+    rand::rng().fill_bytes(&mut id);
+    id
+}
+
+/// Readable names of the granted permissions.
+pub fn permission_names(permissions: Permissions) -> Vec<&'static str> {
+    [
+        (Permissions::PRINTABLE, "print"),
+        (Permissions::PRINTABLE_IN_HIGH_QUALITY, "print in high quality"),
+        (Permissions::MODIFIABLE, "modify"),
+        (Permissions::COPYABLE, "copy"),
+        (Permissions::COPYABLE_FOR_ACCESSIBILITY, "copy for accessibility"),
+        (Permissions::ANNOTABLE, "annotate"),
+        (Permissions::FILLABLE, "fill forms"),
+        (Permissions::ASSEMBLABLE, "assemble"),
+    ]
+    .into_iter()
+    .filter(|(flag, _)| permissions.contains(*flag))
+    .map(|(_, name)| name)
+    .collect()
+}
+
+impl fmt::Display for Security {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let access = match self.access {
+            Access::Owner => "owner",
+            Access::User => "user",
+        };
+        let names = permission_names(self.permissions);
+        write!(
+            f,
+            "{} (R{}), opened with the {} password; permissions: {}",
+            self.encryption.cipher(),
+            self.encryption.revision(),
+            access,
+            if names.is_empty() { "none".to_string() } else { names.join(", ") }
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDocument;
+    use lopdf::dictionary;
+    use lopdf::encryption::crypt_filters::Aes128CryptFilter;
+    use lopdf::EncryptionVersion;
+    use std::collections::BTreeMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// A one-page document saved under a new name in the temporary directory.
+    fn saved_document() -> String {
+        let mut doc = TestDocument::new();
+        doc.page("BT /F1 12 Tf (secret) Tj ET", dictionary! {});
+        let mut doc = doc.finish(dictionary! {});
+        static SAVED: AtomicUsize = AtomicUsize::new(0);
+        let name = format!("pdf_convert-security-{}-{}.pdf", std::process::id(), SAVED.fetch_add(1, Ordering::Relaxed));
+        let path = std::env::temp_dir().join(name);
+        doc.save(&path).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    /// Encrypt the document at `path` in place.
+    fn encrypt_file(path: &str, encryption: &Encryption) {
+        let mut doc = LoDoc::load(path).unwrap();
+        encrypt_document(&mut doc, encryption).unwrap();
+        doc.save(path).unwrap();
+    }
+
+    fn content(doc: &LoDoc) -> Vec<u8> {
+        doc.get_page_content(doc.page_iter().next().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn encrypts_with_aes256() {
+        let path = saved_document();
+        encrypt_file(&path, &Encryption::aes256("owner", "user", &[Permission::Print]).unwrap());
+        let opened = |password| open(&path, password);
+        let user = opened(Some("user"));
+        let owner = opened(Some("owner"));
+        let (wrong, missing) = (opened(Some("other")), opened(None));
+        std::fs::remove_file(&path).unwrap();
+
+        let (doc, security) = user.unwrap();
+        let security = security.unwrap();
+        assert_eq!(content(&doc), b"BT /F1 12 Tf (secret) Tj ET");
+        assert_eq!((security.access, security.encryption.cipher(), security.encryption.revision()), (Access::User, "AES-256".to_string(), 6));
+        assert_eq!(permission_names(security.permissions), ["print", "print in high quality", "copy for accessibility"]);
+        assert_eq!(owner.unwrap().1.unwrap().access, Access::Owner);
+        assert!(matches!(wrong, Err(Error::Password(_))));
+        assert!(matches!(missing, Err(Error::Password(_))));
+    }
+
+    #[test]
+    fn opens_without_a_password_when_the_user_password_is_empty() {
+        let path = saved_document();
+        encrypt_file(&path, &Encryption::aes256("owner", "", &[]).unwrap());
+        let opened = open(&path, None);
+        std::fs::remove_file(&path).unwrap();
+        let (doc, security) = opened.unwrap();
+        assert_eq!(content(&doc), b"BT /F1 12 Tf (secret) Tj ET");
+        assert_eq!(permission_names(security.unwrap().permissions), ["copy for accessibility"]);
+    }
+
+    #[test]
+    fn parses_permissions() {
+        for permission in [Permission::Print, Permission::Copy, Permission::Modify, Permission::Annotate] {
+            assert_eq!(permission.to_string().parse::<Permission>(), Ok(permission));
+        }
+        assert!("everything".parse::<Permission>().is_err());
+    }
+
+    /// Encrypt the document at `path` with AES-128 (revision 4, keyed by the
+    /// document ID) the way other writers do.
+    fn encrypt_aes128(path: &str, owner_password: &str, user_password: &str, permissions: Permissions) {
+        let mut doc = LoDoc::load(path).unwrap();
+        let id = Object::string_literal(b"0123456789abcdef".to_vec());
+        doc.trailer.set("ID", vec![id.clone(), id]);
+        let mut crypt_filters: BTreeMap<Vec<u8>, Arc<dyn CryptFilter>> = BTreeMap::new();
+        crypt_filters.insert(b"StdCF".to_vec(), Arc::new(Aes128CryptFilter));
+        let state = EncryptionState::try_from(EncryptionVersion::V4 {
+            document: &doc,
+            encrypt_metadata: true,
+            crypt_filters,
+            stream_filter: b"StdCF".to_vec(),
+            string_filter: b"StdCF".to_vec(),
+            owner_password,
+            user_password,
+            permissions,
+        })
+        .unwrap();
+        doc.encrypt(&state).unwrap();
+        doc.save(path).unwrap();
+    }
+
+    #[test]
+    fn opens_with_the_owner_or_the_user_password() {
+        let path = saved_document();
+        encrypt_aes128(&path, "owner", "user", Permissions::PRINTABLE);
+        let (owner, user, wrong) = (open(&path, Some("owner")), open(&path, Some("user")), open(&path, Some("other")));
+        std::fs::remove_file(&path).unwrap();
+
+        let (doc, security) = owner.unwrap();
+        let security = security.unwrap();
+        assert_eq!(content(&doc), b"BT /F1 12 Tf (secret) Tj ET");
+        assert_eq!((security.access, security.encryption.cipher(), security.encryption.revision()), (Access::Owner, "AES-128".to_string(), 4));
+        let (doc, security) = user.unwrap();
+        assert_eq!(content(&doc), b"BT /F1 12 Tf (secret) Tj ET");
+        assert_eq!(security.unwrap().access, Access::User);
+        assert!(matches!(wrong, Err(Error::Password(_))));
+    }
+
+    #[test]
+    fn keeps_the_input_encryption() {
+        let (input, output) = (saved_document(), saved_document());
+        encrypt_aes128(&input, "owner", "user", Permissions::PRINTABLE | Permissions::COPYABLE);
+        let (_, security) = open(&input, Some("user")).unwrap();
+        encrypt_file(&output, &security.unwrap().encryption);
+        let (user, owner) = (open(&output, Some("user")), open(&output, Some("owner")));
+        for path in [&input, &output] {
+            std::fs::remove_file(path).unwrap();
+        }
+
+        let (doc, security) = user.unwrap();
+        assert_eq!(content(&doc), b"BT /F1 12 Tf (secret) Tj ET");
+        let security = security.unwrap();
+        assert_eq!(security.access, Access::User);
+        assert!(security.permissions.contains(Permissions::PRINTABLE | Permissions::COPYABLE));
+        assert!(!security.permissions.contains(Permissions::MODIFIABLE));
+        assert_eq!(owner.unwrap().1.unwrap().access, Access::Owner);
+    }
+}
@@ -0,0 +1,823 @@
+// ========== Logical structure ==========
+//
+// What the lines of a page are, for the structure tree of a tagged output.
+// A tagged input's own structure tree is authoritative: the extractors read
+// it per page and `apply` maps it onto the lines, which then carry their
+// element and are put in its reading order. Otherwise headings, paragraphs
+// and list items are inferred from the blocks the lines form, their font
+// size relative to the page's body text and list markers.
+//
+// `regions` marks what the lines lie in besides running text. Text drawn
+// over an image or a form XObject with graphics is a figure, described by
+// the /Alt of the element the input's tree marks the figure with, or by its
+// own text; only text is drawn, so a figure without any is lost. Runs of
+// lines whose cells (text separated by wide gaps) line up in columns are a
+// table; its rows are split into one line per cell. A cell spans one line.
+
+use crate::layout::Line;
+use lopdf::{decode_text_string, Dictionary, Document as LoDoc, Object, ObjectId};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ops::Range;
+
+/// Consecutive lines of the same size whose baselines are at most this many
+/// font sizes apart belong to one block.
+const BLOCK_LEADING: f32 = 2.0;
+
+/// Gap between glyphs, in font sizes, that separates two table cells.
+const CELL_GAP: f32 = 1.0;
+
+/// Cells of one column line up at their left, right or center within this
+/// many font sizes.
+const COLUMN_TOLERANCE: f32 = 0.5;
+
+/// Baselines of consecutive table rows are at most this many font sizes apart.
+const ROW_LEADING: f32 = 3.0;
+
+/// A table has a column whose cells are all narrower than this many font
+/// sizes; wider ones may be columns of running text.
+const MAX_CELL_WIDTH: f32 = 15.0;
+
+/// Blocks with more lines are never headings.
+const MAX_HEADING_LINES: usize = 3;
+
+/// Minimum ratio to the body size per heading level, H1 first.
+const HEADING_RATIOS: [f32; 4] = [1.9, 1.6, 1.35, 1.15];
+
+/// How tagged the output is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tagging {
+    /// A structure tree with marked content
+    Tagged,
+    /// Tagged and identified as PDF/UA-1, which requires a title
+    PdfUa,
+}
+
+impl fmt::Display for Tagging {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Tagging::Tagged => "tagged",
+            Tagging::PdfUa => "PDF/UA-1",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// Heading of level 1 to 6
+    Heading(u8),
+    Paragraph,
+    /// One item of a list; consecutive items form the list
+    ListItem,
+    /// Text of a figure or formula, described by its alternate text
+    Figure,
+    /// One cell of a table; consecutive cells of a table form it, the ones
+    /// of a row its rows
+    Cell { table: usize, row: usize },
+    /// Not part of the content, e.g. running headers of a tagged input
+    Artifact,
+}
+
+/// A run of lines with one role. Elements never span blocks.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Element {
+    pub role: Role,
+    pub lines: Range<usize>,
+    /// Alternate description from the input's structure tree
+    pub alt: Option<String>,
+}
+
+/// An element of a tagged input's structure tree, limited to one page.
+#[derive(Clone, Debug, Default)]
+pub struct StructNode {
+    /// Standard structure type after role mapping, e.g. `P` or `H1`
+    pub kind: String,
+    /// Alternate description (/Alt)
+    pub alt: Option<String>,
+    /// Marked content of the page held directly by this element
+    pub mcids: Vec<i32>,
+    pub children: Vec<StructNode>,
+}
+
+/// What a line is in the input's structure tree.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceTag {
+    /// Index of the element on its page, in reading order
+    pub element: usize,
+    pub role: Role,
+    pub alt: Option<String>,
+}
+
+/// An image, or a form XObject drawing graphics, on a page.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FigureRegion {
+    /// Bounding box in PDF user space: left, bottom, right, top
+    pub rect: [f32; 4],
+    /// Marked content identifier of the page content it is drawn in
+    pub mcid: Option<i32>,
+}
+
+/// What a line lies in besides running text, found by [`regions`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum Region {
+    /// Over the figure with this index on the page; `alt` is the alternate
+    /// description of the element the input's tree marks the figure with
+    Figure { index: usize, alt: Option<String> },
+    /// One cell of a table of the page, rows and columns counted from the top left
+    Cell { table: usize, row: usize, column: usize },
+}
+
+/// Split the lines of a page into blocks: runs of consecutive lines with the
+/// same size and regular spacing, like the paragraphs Typst groups in frames.
+pub fn blocks(lines: &[Line]) -> Vec<Range<usize>> {
+    let mut blocks = Vec::new();
+    let mut start = 0;
+    for i in 1..=lines.len() {
+        let split = i == lines.len() || {
+            let (prev, line) = (&lines[i - 1], &lines[i]);
+            let gap = prev.y - line.y;
+            (prev.size - line.size).abs() > 0.01 || gap < 0.0 || gap > line.size * BLOCK_LEADING
+        };
+        if split {
+            blocks.push(start..i);
+            start = i;
+        }
+    }
+    blocks
+}
+
+/// The elements of a page in reading order, covering every line: the
+/// input's own when its lines carry them, inferred otherwise.
+pub fn elements(lines: &[Line]) -> Vec<Element> {
+    if lines.iter().any(|l| l.tag.is_some()) {
+        return source_elements(lines);
+    }
+    let body = body_size(lines);
+    let mut elements = Vec::new();
+    for block in blocks(lines) {
+        // Figures and table cells split the block, the text between them is inferred
+        let mut i = block.start;
+        while i < block.end {
+            i = match &lines[i].region {
+                Some(Region::Cell { table, row, .. }) => {
+                    elements.push(Element { role: Role::Cell { table: *table, row: *row }, lines: i..i + 1, alt: None });
+                    i + 1
+                }
+                Some(Region::Figure { index, alt }) => {
+                    let same = |line: &Line| matches!(&line.region, Some(Region::Figure { index: other, .. }) if other == index);
+                    let end = (i..block.end).find(|&j| !same(&lines[j])).unwrap_or(block.end);
+                    // Without a description of its own a figure is described by its text
+                    let alt = alt.clone().unwrap_or_else(|| text(&lines[i..end]));
+                    elements.push(Element { role: Role::Figure, lines: i..end, alt: Some(alt) });
+                    end
+                }
+                None => {
+                    let end = (i..block.end).find(|&j| lines[j].region.is_some()).unwrap_or(block.end);
+                    text_elements(lines, i..end, body, &mut elements);
+                    end
+                }
+            };
+        }
+    }
+    elements
+}
+
+/// Headings, list items and paragraphs of `run`, lines of one block.
+fn text_elements(lines: &[Line], run: Range<usize>, body: f32, elements: &mut Vec<Element>) {
+    let size = lines[run.start].size;
+    if let Some(level) = heading_level(size / body).filter(|_| run.len() <= MAX_HEADING_LINES) {
+        elements.push(Element { role: Role::Heading(level), lines: run, alt: None });
+        return;
+    }
+    // A marker line starts a list item that runs until the next marker;
+    // lines before the first marker are a paragraph
+    let mut start = run.start;
+    let mut role = Role::Paragraph;
+    for i in run.clone() {
+        if i > start && starts_list_item(&lines[i]) {
+            elements.push(Element { role, lines: start..i, alt: None });
+            start = i;
+        }
+        if i == start && starts_list_item(&lines[i]) {
+            role = Role::ListItem;
+        }
+    }
+    elements.push(Element { role, lines: start..run.end, alt: None });
+}
+
+/// The text of `lines`, one space between them.
+fn text(lines: &[Line]) -> String {
+    let lines: Vec<String> = lines.iter().map(|line| line.glyphs.iter().map(|g| g.ch).collect::<String>().trim().to_string()).collect();
+    lines.join(" ")
+}
+
+/// Runs of lines of one source element within a block. Untagged lines are
+/// artifacts, unless they lie over a figure the tree describes.
+fn source_elements(lines: &[Line]) -> Vec<Element> {
+    let mut elements = Vec::new();
+    for block in blocks(lines) {
+        let mut start = block.start;
+        for i in block.start + 1..=block.end {
+            let element = |line: &Line| match (&line.tag, &line.region) {
+                (Some(tag), _) => (Some(tag.element), None),
+                (None, Some(Region::Figure { index, alt: Some(_) })) => (None, Some(*index)),
+                _ => (None, None),
+            };
+            if i < block.end && element(&lines[i]) == element(&lines[start]) {
+                continue;
+            }
+            let (role, alt) = match (&lines[start].tag, &lines[start].region) {
+                (Some(tag), _) => (tag.role, tag.alt.clone()),
+                (None, Some(Region::Figure { alt: Some(alt), .. })) => (Role::Figure, Some(alt.clone())),
+                _ => (Role::Artifact, None),
+            };
+            elements.push(Element { role, lines: start..i, alt });
+            start = i;
+        }
+    }
+    elements
+}
+
+/// The most common font size of the page, weighted by glyphs.
+fn body_size(lines: &[Line]) -> f32 {
+    let mut counts: HashMap<u32, usize> = HashMap::new();
+    for line in lines {
+        *counts.entry((line.size * 10.0).round() as u32).or_default() += line.glyphs.len();
+    }
+    counts.into_iter().max_by_key(|&(size, count)| (count, std::cmp::Reverse(size))).map_or(1.0, |(size, _)| size as f32 / 10.0)
+}
+
+fn heading_level(ratio: f32) -> Option<u8> {
+    HEADING_RATIOS.iter().position(|&min| ratio >= min).map(|level| level as u8 + 1)
+}
+
+/// Whether the line begins with a bullet or an enumerator like `1.`, `(a)` or
+/// `iv)`, followed by more text.
+fn starts_list_item(line: &Line) -> bool {
+    let glyphs = &line.glyphs;
+    // The marker ends at a space or a gap, as extractors may not report spaces
+    let end = (1..glyphs.len())
+        .find(|&i| glyphs[i].ch.is_whitespace() || glyphs[i].x - (glyphs[i - 1].x + glyphs[i - 1].w) > line.size * 0.2)
+        .unwrap_or(glyphs.len());
+    if glyphs[end..].iter().all(|g| g.ch.is_whitespace()) {
+        return false;
+    }
+    let marker: String = glyphs[..end].iter().map(|g| g.ch).collect();
+    if matches!(marker.as_str(), "•" | "◦" | "▪" | "‣" | "–" | "-" | "*" | "·") {
+        return true;
+    }
+    let inner = marker.strip_prefix('(').unwrap_or(&marker);
+    let Some(label) = inner.strip_suffix(['.', ')']) else { return false };
+    !label.is_empty()
+        && label.len() <= 4
+        && (label.chars().all(|c| c.is_ascii_digit())
+            || (label.len() == 1 && label.chars().all(|c| c.is_ascii_alphabetic()))
+            || label.chars().all(|c| matches!(c, 'i' | 'v' | 'x')))
+}
+
+// ========== Figures and tables ==========
+
+/// Mark the lines of a page lying over one of its `figures` and split the
+/// rows of its tables into one line per cell. A figure is described by the
+/// /Alt of the figure or formula of `tree` holding its marked content.
+/// Tables are only looked for on pages without structure, a tree tags its
+/// own.
+pub fn regions(mut lines: Vec<Line>, figures: &[FigureRegion], tree: &[StructNode]) -> Vec<Line> {
+    let mut alts = HashMap::new();
+    for node in tree {
+        figure_alts(node, None, &mut alts);
+    }
+    for line in &mut lines {
+        let last = &line.glyphs[line.glyphs.len() - 1];
+        let (x, y) = ((line.glyphs[0].x + last.x + last.w) / 2.0, line.y + line.size * 0.3);
+        if let Some(index) = figures.iter().position(|f| f.rect[0] <= x && x <= f.rect[2] && f.rect[1] <= y && y <= f.rect[3]) {
+            let alt = figures[index].mcid.and_then(|mcid| alts.get(&mcid)).cloned();
+            line.region = Some(Region::Figure { index, alt });
+        }
+    }
+    if lines.iter().any(|l| l.tag.is_some()) {
+        return lines;
+    }
+    split_tables(lines)
+}
+
+/// The alternate description of every MCID inside a figure or formula.
+fn figure_alts<'a>(node: &'a StructNode, alt: Option<&'a str>, alts: &mut HashMap<i32, String>) {
+    let alt = match source_role(&node.kind) {
+        Some(Role::Figure) => node.alt.as_deref().or(alt),
+        _ => alt,
+    };
+    if let Some(alt) = alt {
+        for &mcid in &node.mcids {
+            alts.entry(mcid).or_insert_with(|| alt.to_string());
+        }
+    }
+    for child in &node.children {
+        figure_alts(child, alt, alts);
+    }
+}
+
+/// Put each cell of the tables among `lines` on a line of its own. A table
+/// is a run of at least two rows, each lined up with the one above, with a
+/// column narrow enough not to be running text.
+fn split_tables(lines: Vec<Line>) -> Vec<Line> {
+    let cells: Vec<Vec<Range<usize>>> = lines.iter().map(|line| if line.region.is_some() { Vec::new() } else { cells(line) }).collect();
+    let mut tables = Vec::new();
+    let mut start = 0;
+    for i in 1..=lines.len() {
+        if i < lines.len() && cells[i].len() >= 2 && lines_up(&lines[i - 1], &cells[i - 1], &lines[i], &cells[i]) {
+            continue;
+        }
+        if i - start >= 2 && has_narrow_column(&lines[start..i], &cells[start..i]) {
+            tables.push(start..i);
+        }
+        start = i;
+    }
+    if tables.is_empty() {
+        return lines;
+    }
+
+    let mut split = Vec::with_capacity(lines.len());
+    for (i, (line, cells)) in lines.into_iter().zip(cells).enumerate() {
+        let Some(table) = tables.iter().position(|t| t.contains(&i)) else {
+            split.push(line);
+            continue;
+        };
+        let row = i - tables[table].start;
+        for (column, cell) in cells.into_iter().enumerate() {
+            let glyphs = line.glyphs[cell].to_vec();
+            let font = glyphs[0].font.clone();
+            split.push(Line { glyphs, y: line.y, font, size: line.size, tag: None, region: Some(Region::Cell { table, row, column }) });
+        }
+    }
+    split
+}
+
+/// Glyph ranges of the cells of a line: text separated by gaps wider than
+/// `CELL_GAP`, without the whitespace around it.
+fn cells(line: &Line) -> Vec<Range<usize>> {
+    let mut cells: Vec<Range<usize>> = Vec::new();
+    for (i, glyph) in line.glyphs.iter().enumerate().filter(|(_, g)| !g.ch.is_whitespace()) {
+        match cells.last_mut() {
+            Some(cell) if glyph.x - extent(line, cell).1 <= line.size * CELL_GAP => cell.end = i + 1,
+            _ => cells.push(i..i + 1),
+        }
+    }
+    cells
+}
+
+/// Whether `line` is the next row of the table that `above` is a row of:
+/// as many cells, close enough below it, every column lined up.
+fn lines_up(above: &Line, above_cells: &[Range<usize>], line: &Line, cells: &[Range<usize>]) -> bool {
+    let size = above.size.max(line.size);
+    let gap = above.y - line.y;
+    if above_cells.len() != cells.len() || gap <= 0.0 || gap > size * ROW_LEADING {
+        return false;
+    }
+    above_cells.iter().zip(cells).all(|(a, b)| {
+        let ((a1, a2), (b1, b2)) = (extent(above, a), extent(line, b));
+        let tolerance = size * COLUMN_TOLERANCE;
+        (a1 - b1).abs() <= tolerance || (a2 - b2).abs() <= tolerance || ((a1 + a2) - (b1 + b2)).abs() / 2.0 <= tolerance
+    })
+}
+
+fn has_narrow_column(lines: &[Line], cells: &[Vec<Range<usize>>]) -> bool {
+    (0..cells[0].len()).any(|column| {
+        lines.iter().zip(cells).all(|(line, cells)| {
+            let (left, right) = extent(line, &cells[column]);
+            right - left < line.size * MAX_CELL_WIDTH
+        })
+    })
+}
+
+/// Left and right edge of the glyphs `cell` of `line`.
+fn extent(line: &Line, cell: &Range<usize>) -> (f32, f32) {
+    let last = &line.glyphs[cell.end - 1];
+    (line.glyphs[cell.start].x, last.x + last.w)
+}
+
+// ========== Structure of tagged inputs ==========
+
+/// Map the structure tree of a page onto its lines. A line holding content
+/// of several elements (e.g. two columns on one baseline) is split, every
+/// line gets the element it belongs to and the lines are put in the tree's
+/// reading order, top to bottom within an element. Lines outside the tree
+/// stay after the element before them.
+pub fn apply(lines: Vec<Line>, tree: &[StructNode]) -> Vec<Line> {
+    let mut tags = Vec::new();
+    let mut owners = HashMap::new();
+    for node in tree {
+        collect(node, None, &mut tags, &mut owners);
+    }
+    if owners.is_empty() {
+        return lines;
+    }
+
+    let mut split: Vec<Line> = Vec::with_capacity(lines.len());
+    for line in lines {
+        // Glyphs outside marked content, like the spaces pdfium inserts, go with the glyph before them
+        let mut owner = None;
+        let mut keys: Vec<Option<usize>> = line
+            .glyphs
+            .iter()
+            .map(|g| {
+                let key = g.mcid.and_then(|mcid| owners.get(&mcid).copied());
+                if key.is_some() || !g.ch.is_whitespace() {
+                    owner = key;
+                }
+                owner
+            })
+            .collect();
+        // ... or the first one after them at the start of the line
+        if let Some(first) = keys.iter().position(Option::is_some) {
+            let leading = line.glyphs[..first].iter().all(|g| g.ch.is_whitespace());
+            if leading {
+                let key = keys[first];
+                keys[..first].fill(key);
+            }
+        }
+        let runs: Vec<&[Option<usize>]> = keys.chunk_by(|a, b| a == b).collect();
+        let mut glyphs = line.glyphs.into_iter();
+        for run in &runs {
+            let mut glyphs: Vec<_> = glyphs.by_ref().take(run.len()).collect();
+            if runs.len() > 1 {
+                // The gap between the parts is no text of either
+                while glyphs.last().is_some_and(|g| g.ch.is_whitespace()) {
+                    glyphs.pop();
+                }
+                let start = glyphs.iter().take_while(|g| g.ch.is_whitespace()).count();
+                glyphs.drain(..start);
+                if glyphs.is_empty() {
+                    continue;
+                }
+            }
+            let tag = match run[0] {
+                Some(element) => {
+                    let (role, alt) = &tags[element];
+                    Some(SourceTag { element, role: *role, alt: alt.clone() })
+                }
+                // A line of nothing but whitespace is no artifact
+                None if glyphs.iter().all(|g| g.ch.is_whitespace()) => split.last().and_then(|l| l.tag.clone()),
+                None => None,
+            };
+            let (font, size) = (glyphs[0].font.clone(), glyphs[0].size);
+            split.push(Line { glyphs, y: line.y, font, size, tag, region: None });
+        }
+    }
+
+    let mut order = 0;
+    let keys: Vec<usize> = split
+        .iter()
+        .map(|line| {
+            if let Some(tag) = &line.tag {
+                order = tag.element;
+            }
+            order
+        })
+        .collect();
+    let mut indexed: Vec<(usize, Line)> = keys.into_iter().zip(split).collect();
+    indexed.sort_by_key(|(key, _)| *key);
+    indexed.into_iter().map(|(_, line)| line).collect()
+}
+
+/// Give every MCID of `node` and its descendants the element it belongs to.
+/// Block-level elements start a new element unless they are inside a
+/// heading, list item or figure, which keep all of their content together.
+fn collect(node: &StructNode, current: Option<usize>, tags: &mut Vec<(Role, Option<String>)>, owners: &mut HashMap<i32, usize>) {
+    let enclosing = current.map(|i| tags[i].0);
+    let keeps = matches!(enclosing, Some(Role::Heading(_) | Role::ListItem | Role::Figure));
+    let mut current = match source_role(&node.kind) {
+        Some(role) if !keeps => {
+            tags.push((role, node.alt.clone()));
+            Some(tags.len() - 1)
+        }
+        _ => current,
+    };
+    if current.is_none() && !node.mcids.is_empty() {
+        // Content directly in a grouping element such as Sect
+        tags.push((Role::Paragraph, node.alt.clone()));
+        current = Some(tags.len() - 1);
+    }
+    if let Some(element) = current {
+        for &mcid in &node.mcids {
+            owners.entry(mcid).or_insert(element);
+        }
+    }
+    for child in &node.children {
+        collect(child, current, tags, owners);
+    }
+}
+
+/// Role of a standard structure type; `None` for grouping and inline elements.
+fn source_role(kind: &str) -> Option<Role> {
+    match kind {
+        "H" => Some(Role::Heading(1)),
+        "H1" | "H2" | "H3" | "H4" | "H5" | "H6" => Some(Role::Heading(kind.as_bytes()[1] - b'0')),
+        "LI" | "Lbl" | "LBody" => Some(Role::ListItem),
+        "Figure" | "Formula" => Some(Role::Figure),
+        "P" | "Caption" | "BlockQuote" | "Quote" | "Note" | "Code" | "TOCI" | "TD" | "TH" | "Title" | "BibEntry" | "Index" => Some(Role::Paragraph),
+        _ => None,
+    }
+}
+
+/// The structure tree's /RoleMap: custom structure types and the standard
+/// type each stands for.
+pub(crate) fn role_map(doc: &LoDoc) -> HashMap<String, String> {
+    let root = doc.catalog().and_then(|c| c.get(b"StructTreeRoot")).map(|r| resolve(doc, r));
+    let map = root.ok().and_then(|r| r.as_dict().ok()).and_then(|r| r.get(b"RoleMap").ok()).map(|m| resolve(doc, m));
+    let Some(Ok(map)) = map.map(Object::as_dict) else { return HashMap::new() };
+    map.iter()
+        .filter_map(|(key, value)| Some((String::from_utf8_lossy(key).into_owned(), String::from_utf8_lossy(resolve(doc, value).as_name().ok()?).into_owned())))
+        .collect()
+}
+
+/// Follow the role map from `kind` to a standard type; chains end after a few steps.
+pub(crate) fn map_role(map: &HashMap<String, String>, kind: &str) -> String {
+    let mut kind = kind;
+    for _ in 0..8 {
+        match map.get(kind) {
+            Some(mapped) if mapped != kind => kind = mapped,
+            _ => break,
+        }
+    }
+    kind.to_string()
+}
+
+/// The structure tree of a lopdf document, read in one walk and indexed by
+/// the pages elements have content on.
+#[derive(Default)]
+pub(crate) struct StructIndex {
+    /// Elements in document order, parents before their children
+    elements: Vec<IndexedElement>,
+    /// Elements with content on each page and their ancestors, in document order
+    pages: HashMap<ObjectId, Vec<usize>>,
+}
+
+struct IndexedElement {
+    kind: String,
+    alt: Option<String>,
+    parent: Option<usize>,
+    /// Marked content held directly, with its page
+    mcids: Vec<(ObjectId, i32)>,
+}
+
+impl StructIndex {
+    pub(crate) fn from_lopdf(doc: &LoDoc, map: &HashMap<String, String>) -> StructIndex {
+        let mut index = StructIndex::default();
+        let root = doc.catalog().and_then(|c| c.get(b"StructTreeRoot")).map(|r| resolve(doc, r));
+        if let Ok(kids) = root.and_then(Object::as_dict).and_then(|r| r.get(b"K")) {
+            index.add_kids(doc, kids, None, None, map, &mut HashSet::new());
+        }
+        let mut listed = HashSet::new();
+        for element in 0..index.elements.len() {
+            for &(page, _) in &index.elements[element].mcids {
+                let mut current = Some(element);
+                while let Some(i) = current.filter(|&i| listed.insert((i, page))) {
+                    index.pages.entry(page).or_default().push(i);
+                    current = index.elements[i].parent;
+                }
+            }
+        }
+        for elements in index.pages.values_mut() {
+            elements.sort_unstable();
+        }
+        index
+    }
+
+    /// The structure tree of page `page`, elements without content on the
+    /// page left out.
+    pub(crate) fn page(&self, page: ObjectId) -> Vec<StructNode> {
+        let Some(elements) = self.pages.get(&page) else { return Vec::new() };
+        // Children follow their parent, so every node is complete when its parent is reached from the end
+        let mut children: HashMap<usize, Vec<StructNode>> = HashMap::new();
+        let mut roots = Vec::new();
+        for &i in elements.iter().rev() {
+            let element = &self.elements[i];
+            let mut kids = children.remove(&i).unwrap_or_default();
+            kids.reverse();
+            let mcids = element.mcids.iter().filter(|(on, _)| *on == page).map(|&(_, mcid)| mcid).collect();
+            let node = StructNode { kind: element.kind.clone(), alt: element.alt.clone(), mcids, children: kids };
+            match element.parent {
+                Some(parent) => children.entry(parent).or_default().push(node),
+                None => roots.push(node),
+            }
+        }
+        roots.reverse();
+        roots
+    }
+
+    /// Add the kids `obj` of element `parent`. `pg` is the page the
+    /// enclosing element names.
+    fn add_kids(
+        &mut self,
+        doc: &LoDoc,
+        obj: &Object,
+        parent: Option<usize>,
+        pg: Option<ObjectId>,
+        map: &HashMap<String, String>,
+        seen: &mut HashSet<ObjectId>,
+    ) {
+        let kids = match obj {
+            Object::Array(kids) => kids.as_slice(),
+            kid => std::slice::from_ref(kid),
+        };
+        for kid in kids {
+            match kid {
+                Object::Integer(mcid) => self.add_mcid(parent, pg, *mcid),
+                Object::Reference(id) if !seen.insert(*id) => {}
+                _ => {
+                    let Ok(dict) = resolve(doc, kid).as_dict() else { continue };
+                    let kid_pg = dict.get(b"Pg").and_then(Object::as_reference).ok().or(pg);
+                    match dict.get(b"Type").and_then(Object::as_name) {
+                        // Marked content in another stream than the page's is not extracted
+                        Ok(b"MCR") => {
+                            if let (false, Ok(mcid)) = (dict.has(b"Stm"), dict.get(b"MCID").and_then(Object::as_i64)) {
+                                self.add_mcid(parent, kid_pg, mcid);
+                            }
+                        }
+                        Ok(b"OBJR") => {}
+                        _ => self.add_element(doc, dict, parent, kid_pg, map, seen),
+                    }
+                }
+            }
+        }
+    }
+
+    fn add_mcid(&mut self, parent: Option<usize>, pg: Option<ObjectId>, mcid: i64) {
+        if let (Some(parent), Some(page)) = (parent, pg) {
+            self.elements[parent].mcids.push((page, mcid as i32));
+        }
+    }
+
+    fn add_element(
+        &mut self,
+        doc: &LoDoc,
+        dict: &Dictionary,
+        parent: Option<usize>,
+        pg: Option<ObjectId>,
+        map: &HashMap<String, String>,
+        seen: &mut HashSet<ObjectId>,
+    ) {
+        let Ok(kind) = dict.get(b"S").and_then(Object::as_name) else { return };
+        let alt = dict.get(b"Alt").ok().and_then(|a| decode_text_string(resolve(doc, a)).ok()).filter(|a| !a.trim().is_empty());
+        let index = self.elements.len();
+        self.elements.push(IndexedElement { kind: map_role(map, &String::from_utf8_lossy(kind)), alt, parent, mcids: Vec::new() });
+        if let Ok(kids) = dict.get(b"K") {
+            self.add_kids(doc, kids, Some(index), pg, map, seen);
+        }
+    }
+}
+
+fn resolve<'a>(doc: &'a LoDoc, obj: &'a Object) -> &'a Object {
+    match obj {
+        Object::Reference(id) => doc.get_object(*id).unwrap_or(obj),
+        obj => obj,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::Glyph;
+    use crate::testing::TestDocument;
+    use lopdf::{dictionary, Object, StringFormat};
+
+    /// `kind[mcids](children)` for every node, alt text as `kind"alt"`.
+    fn describe(nodes: &[StructNode]) -> String {
+        let describe_node = |node: &StructNode| {
+            let alt = node.alt.as_ref().map(|alt| format!("\"{}\"", alt)).unwrap_or_default();
+            let children = if node.children.is_empty() { String::new() } else { format!("({})", describe(&node.children)) };
+            format!("{}{}{:?}{}", node.kind, alt, node.mcids, children)
+        };
+        nodes.iter().map(describe_node).collect::<Vec<_>>().join(" ")
+    }
+
+    /// A document whose tree spans two pages: a paragraph per page, a custom
+    /// element with content on both, and a figure.
+    fn tagged_document() -> (LoDoc, ObjectId, ObjectId) {
+        let mut pages = TestDocument::new();
+        let (first, second) = (pages.page("", dictionary! {}), pages.page("", dictionary! {}));
+        let doc = &mut pages.doc;
+        let paragraph = doc.add_object(dictionary! { "S" => "P", "Pg" => first, "K" => 0 });
+        let mcr_first = dictionary! { "Type" => "MCR", "Pg" => first, "MCID" => 5 };
+        let mcr_other_stream = dictionary! { "Type" => "MCR", "Pg" => first, "MCID" => 6, "Stm" => Object::Null };
+        let custom = doc.add_object(dictionary! {
+            "S" => "Para",
+            "Pg" => second,
+            "K" => vec![1.into(), mcr_first.into(), mcr_other_stream.into()],
+        });
+        let figure = doc.add_object(dictionary! {
+            "S" => "Figure",
+            "Pg" => second,
+            "Alt" => Object::String(b"A chart".to_vec(), StringFormat::Literal),
+            "K" => vec![2.into(), dictionary! { "Type" => "OBJR", "Obj" => paragraph }.into()],
+        });
+        let document = doc.add_object(dictionary! { "S" => "Document", "K" => vec![paragraph.into(), custom.into(), figure.into(), paragraph.into()] });
+        let root = doc.add_object(dictionary! { "Type" => "StructTreeRoot", "K" => document, "RoleMap" => dictionary! { "Para" => "P" } });
+        (pages.finish(dictionary! { "StructTreeRoot" => root }), first, second)
+    }
+
+    #[test]
+    fn indexes_the_tree_by_page() {
+        let (doc, first, second) = tagged_document();
+        let index = StructIndex::from_lopdf(&doc, &role_map(&doc));
+        assert_eq!(describe(&index.page(first)), "Document[](P[0] P[5])");
+        assert_eq!(describe(&index.page(second)), "Document[](P[1] Figure\"A chart\"[2])");
+        assert_eq!(describe(&index.page((99, 0))), "");
+    }
+
+    #[test]
+    fn untagged_documents_have_no_structure() {
+        let doc = TestDocument::new().finish(dictionary! {});
+        assert!(StructIndex::from_lopdf(&doc, &role_map(&doc)).page((1, 0)).is_empty());
+    }
+
+    #[test]
+    fn follows_role_map_chains() {
+        let map = HashMap::from([("Para".to_string(), "Body".to_string()), ("Body".to_string(), "P".to_string()), ("Loop".to_string(), "Loop".to_string())]);
+        assert_eq!(map_role(&map, "Para"), "P");
+        assert_eq!(map_role(&map, "Loop"), "Loop");
+        assert_eq!(map_role(&map, "H1"), "H1");
+    }
+
+    fn line(text: &str, y: f32, size: f32) -> Line {
+        let glyphs = text
+            .chars()
+            .enumerate()
+            .map(|(i, ch)| Glyph { ch, x: 72.0 + i as f32 * size * 0.5, y, w: size * 0.5, size, font: "F".into(), mapped: true, mcid: None })
+            .collect();
+        Line { glyphs, y, font: "F".into(), size, tag: None, region: None }
+    }
+
+    #[test]
+    fn infers_headings_lists_and_paragraphs() {
+        let lines = vec![
+            line("Introduction", 760.0, 20.0),
+            line("Body text of the first", 730.0, 10.0),
+            line("paragraph goes on here.", 718.0, 10.0),
+            line("1. first item", 690.0, 10.0),
+            line("continued", 678.0, 10.0),
+            line("2. second item", 666.0, 10.0),
+        ];
+        let roles: Vec<(Role, Range<usize>)> = elements(&lines).into_iter().map(|e| (e.role, e.lines)).collect();
+        assert_eq!(roles, [(Role::Heading(1), 0..1), (Role::Paragraph, 1..3), (Role::ListItem, 3..5), (Role::ListItem, 5..6)]);
+    }
+
+    /// A line with each of `cells` starting at its x.
+    fn row(cells: &[(f32, &str)], y: f32) -> Line {
+        let glyph = |x: f32, i: usize, ch| Glyph { ch, x: x + i as f32 * 5.0, y, w: 5.0, size: 10.0, font: "F".into(), mapped: true, mcid: None };
+        let glyphs = cells.iter().flat_map(|&(x, text)| text.chars().enumerate().map(move |(i, ch)| glyph(x, i, ch))).collect();
+        Line { glyphs, y, font: "F".into(), size: 10.0, tag: None, region: None }
+    }
+
+    fn texts(lines: &[Line]) -> Vec<String> {
+        lines.iter().map(|line| line.glyphs.iter().map(|g| g.ch).collect()).collect()
+    }
+
+    #[test]
+    fn infers_figures_over_images() {
+        let lines = vec![line("Results", 760.0, 10.0), line("x axis", 500.0, 10.0), line("y axis", 488.0, 10.0), line("After the chart.", 300.0, 10.0)];
+        let chart = FigureRegion { rect: [50.0, 450.0, 400.0, 600.0], mcid: Some(3) };
+        let lines = regions(lines, std::slice::from_ref(&chart), &[]);
+        let roles: Vec<(Role, Range<usize>, Option<String>)> = elements(&lines).into_iter().map(|e| (e.role, e.lines, e.alt)).collect();
+        assert_eq!(roles, [
+            (Role::Paragraph, 0..1, None),
+            (Role::Figure, 1..3, Some("x axis y axis".to_string())),
+            (Role::Paragraph, 3..4, None),
+        ]);
+
+        // A tagged input describes the figure, its labels are outside the tree
+        let mut lines = vec![line("Results", 760.0, 10.0), line("x axis", 500.0, 10.0)];
+        lines[0].tag = Some(SourceTag { element: 0, role: Role::Paragraph, alt: None });
+        let tree = [StructNode { kind: "Figure".into(), alt: Some("Sales by year".into()), mcids: vec![3], children: Vec::new() }];
+        let lines = regions(lines, &[chart], &tree);
+        let roles: Vec<(Role, Option<String>)> = elements(&lines).into_iter().map(|e| (e.role, e.alt)).collect();
+        assert_eq!(roles, [(Role::Paragraph, None), (Role::Figure, Some("Sales by year".to_string()))]);
+    }
+
+    #[test]
+    fn infers_tables_from_aligned_columns() {
+        let lines = vec![
+            line("Sizes of the parts:", 760.0, 10.0),
+            row(&[(72.0, "Part"), (200.0, "Size")], 740.0),
+            row(&[(72.0, "alpha"), (195.0, "12 mm")], 728.0),
+            row(&[(72.0, "beta gamma"), (205.0, "3 m")], 716.0),
+            line("The end.", 690.0, 10.0),
+        ];
+        let lines = regions(lines, &[], &[]);
+        assert_eq!(texts(&lines), ["Sizes of the parts:", "Part", "Size", "alpha", "12 mm", "beta gamma", "3 m", "The end."]);
+        assert_eq!(lines[4].region, Some(Region::Cell { table: 0, row: 1, column: 1 }));
+        let roles: Vec<Role> = elements(&lines).into_iter().map(|e| e.role).collect();
+        let cell = |row| Role::Cell { table: 0, row };
+        assert_eq!(roles, [Role::Paragraph, cell(0), cell(0), cell(1), cell(1), cell(2), cell(2), Role::Paragraph]);
+    }
+
+    #[test]
+    fn two_columns_of_text_are_no_table() {
+        let column = "Running text set in two columns ";
+        let lines: Vec<Line> = (0..3).map(|i| row(&[(40.0, column), (320.0, column)], 700.0 - 12.0 * i as f32)).collect();
+        let lines = regions(lines, &[], &[]);
+        assert_eq!(lines.len(), 3);
+        assert!(lines.iter().all(|l| l.region.is_none()));
+    }
+}
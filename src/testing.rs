@@ -0,0 +1,53 @@
+// ========== Test documents ==========
+//
+// Unit tests build their inputs with lopdf in memory. `TestDocument` writes
+// the catalog and page tree around the pages and objects a test adds.
+
+use lopdf::{dictionary, Dictionary, Document, Object, ObjectId, Stream};
+
+pub(crate) struct TestDocument {
+    pub doc: Document,
+    pub pages: ObjectId,
+    kids: Vec<ObjectId>,
+}
+
+impl TestDocument {
+    pub fn new() -> Self {
+        let mut doc = Document::with_version("1.7");
+        let pages = doc.new_object_id();
+        TestDocument { doc, pages, kids: Vec::new() }
+    }
+
+    /// Append an A4 page drawing `content`; `entries` are added to its
+    /// dictionary, e.g. /Resources or /Annots.
+    pub fn page(&mut self, content: &str, entries: Dictionary) -> ObjectId {
+        let id = self.doc.new_object_id();
+        self.page_at(id, content, entries);
+        id
+    }
+
+    /// Like [`page`](Self::page) under an id taken earlier, so that other
+    /// objects can point to the page before it is written.
+    pub fn page_at(&mut self, id: ObjectId, content: &str, entries: Dictionary) {
+        let content = self.doc.add_object(Stream::new(dictionary! {}, content.as_bytes().to_vec()));
+        let mut page = dictionary! {
+            "Type" => "Page", "Parent" => self.pages, "Contents" => content,
+            "MediaBox" => vec![0.into(), 0.into(), 595.into(), 842.into()],
+        };
+        page.extend(&entries);
+        self.doc.objects.insert(id, Object::Dictionary(page));
+        self.kids.push(id);
+    }
+
+    /// Write the page tree and a catalog holding `entries` besides /Pages.
+    pub fn finish(mut self, entries: Dictionary) -> Document {
+        let kids: Vec<Object> = self.kids.iter().map(|&id| id.into()).collect();
+        let count = kids.len() as i64;
+        self.doc.objects.insert(self.pages, Object::Dictionary(dictionary! { "Type" => "Pages", "Kids" => kids, "Count" => count }));
+        let mut catalog = dictionary! { "Type" => "Catalog", "Pages" => self.pages };
+        catalog.extend(&entries);
+        let catalog = self.doc.add_object(catalog);
+        self.doc.trailer.set("Root", catalog);
+        self.doc
+    }
+}